@@ -0,0 +1,35 @@
+//! Build an image in memory and hand the finished chunks to an async sink.
+//! The writer itself is synchronous, so the image is built first and only the collected
+//! chunks are written asynchronously, here to a file with tokio.
+
+use ext4_image_writer::{BlockCollector, Ext4ImageWriter};
+use std::io::{self, SeekFrom};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> io::Result<()> {
+    let mut writer = Ext4ImageWriter::new(BlockCollector::default(), 1024 * 1024 * 1024);
+    writer.mkdir_p("etc")?;
+    writer.write_file(b"hello from the image\n", "etc/motd", 0o644)?;
+    let (chunks, info) = writer.finish_with_info()?;
+
+    let path = std::env::temp_dir().join("collect_blocks.img");
+    let mut file = tokio::fs::File::create(&path).await?;
+    // chunks that were never written stay holes in the file
+    file.set_len(info.size).await?;
+    for (chunk_index, data) in chunks.into_chunks() {
+        let start = chunk_index * BlockCollector::CHUNK_SIZE;
+        // the last chunk can reach past the end of images with smaller blocks
+        let len = (info.size - start).min(BlockCollector::CHUNK_SIZE);
+        file.seek(SeekFrom::Start(start)).await?;
+        file.write_all(&data[..len as usize]).await?;
+    }
+    file.flush().await?;
+
+    println!(
+        "wrote an image of {} bytes to {}",
+        info.size,
+        path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use crate::ext4_h::BitmapBlock;
+use std::collections::BTreeMap;
+
+/// A contiguous range of blocks `start..end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub start: u64,
+    pub end: u64,
+}
+impl Allocation {
+    pub fn from_start_len(start: u64, len: u64) -> Self {
+        Allocation {
+            start,
+            end: start + len,
+        }
+    }
+    pub fn as_single(self) -> u64 {
+        assert!(self.end == self.start + 1);
+        self.start
+    }
+    pub fn len(self) -> u64 {
+        self.end - self.start
+    }
+    pub fn is_empty(self) -> bool {
+        self.end == self.start
+    }
+}
+
+/// Decides where in the image new blocks are placed.
+/// Implement this to experiment with different allocation strategies (i.e. alignment-aware or best-fit allocators).
+pub trait BlockAllocator {
+    /// Allocate `n` contiguous blocks that were neither allocated nor marked as used before.
+    fn allocate(&mut self, n: u64) -> Allocation;
+    /// Allocate between one and `n` contiguous blocks. File contents are allocated with this,
+    /// so they may be split into several fragments, i.e. around reserved ranges.
+    fn allocate_up_to(&mut self, n: u64) -> Allocation {
+        self.allocate(n)
+    }
+    /// Notify the allocator that `block_num` is used by fixed metadata and must not be handed out.
+    fn mark_used(&mut self, block_num: u64);
+    /// Keep the given blocks free for metadata that is placed later.
+    fn reserve(&mut self, allocation: Allocation) {
+        for block in allocation.start..allocation.end {
+            self.mark_used(block);
+        }
+    }
+}
+
+/// The default allocator. It hands out blocks strictly in increasing order, which produces minimally sized images.
+/// Blocks that are marked as used or reserved ahead of the allocation cursor are skipped.
+#[derive(Default, Debug)]
+pub struct BumpAllocator {
+    next_free: u64,
+    reserved: BTreeMap<u64, u64>, // start -> end of the ranges ahead of `next_free` that must be skipped
+}
+impl BumpAllocator {
+    /// Returns the end of the reserved range that contains `block_num` (if any).
+    fn reserved_end(&self, block_num: u64) -> Option<u64> {
+        self.reserved
+            .range(..=block_num)
+            .next_back()
+            .map(|(_, &end)| end)
+            .filter(|&end| end > block_num)
+    }
+
+    fn advance(&mut self, allocation: Allocation) -> Allocation {
+        self.next_free = allocation.end;
+        while let Some((_, &end)) = self.reserved.first_key_value()
+            && end <= self.next_free
+        {
+            self.reserved.pop_first();
+        }
+        allocation
+    }
+}
+impl BlockAllocator for BumpAllocator {
+    fn allocate(&mut self, n: u64) -> Allocation {
+        let mut start = self.next_free;
+        // skip every reserved range that would overlap with the allocation
+        while let Some((_, &end)) = self
+            .reserved
+            .range(..start + n.max(1))
+            .next_back()
+            .filter(|(_, end)| **end > start)
+        {
+            start = end;
+        }
+        self.advance(Allocation::from_start_len(start, n))
+    }
+    fn allocate_up_to(&mut self, n: u64) -> Allocation {
+        let mut start = self.next_free;
+        while let Some(end) = self.reserved_end(start) {
+            start = end;
+        }
+        let len = match self.reserved.range(start..).next() {
+            Some((&reserved_start, _)) => n.min(reserved_start - start),
+            None => n,
+        };
+        self.advance(Allocation::from_start_len(start, len))
+    }
+    fn mark_used(&mut self, block_num: u64) {
+        self.reserve(Allocation::from_start_len(block_num, 1));
+    }
+    fn reserve(&mut self, allocation: Allocation) {
+        let mut start = allocation.start.max(self.next_free);
+        let mut end = allocation.end;
+        if start >= end {
+            return;
+        }
+        // merge with all overlapping or adjacent ranges
+        while let Some((&other_start, &other_end)) = self
+            .reserved
+            .range(..=end)
+            .next_back()
+            .filter(|(_, other_end)| **other_end >= start)
+        {
+            self.reserved.remove(&other_start);
+            start = start.min(other_start);
+            end = end.max(other_end);
+        }
+        if start == self.next_free {
+            self.advance(Allocation { start, end });
+        } else {
+            self.reserved.insert(start, end);
+        }
+    }
+}
+
+/// Keeps track of which blocks (or inodes) are used.
+#[derive(Default)]
+pub(crate) struct UsageBitmap {
+    data: Vec<u8>,
+    end: u64,
+}
+impl UsageBitmap {
+    pub fn mark_used(&mut self, block_num: u64) {
+        let byte_index = (block_num / 8) as usize;
+        let bit_index = (block_num % 8) as u8;
+        if byte_index >= self.data.len() {
+            self.data.resize(byte_index + 1, 0);
+        }
+        self.data[byte_index] |= 1 << bit_index;
+        self.end = self.end.max(block_num + 1);
+    }
+    /// Only clears the bit, [`UsageBitmap::end`] stays where it is.
+    pub fn mark_unused(&mut self, block_num: u64) {
+        if let Some(byte) = self.data.get_mut((block_num / 8) as usize) {
+            *byte &= !(1 << (block_num % 8));
+        }
+    }
+    pub fn is_used(&self, block_num: u64) -> bool {
+        let byte_index = (block_num / 8) as usize;
+        let bit_index = (block_num % 8) as u8;
+        self.data
+            .get(byte_index)
+            .is_some_and(|byte| byte & (1 << bit_index) != 0)
+    }
+    /// One past the highest used block.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+    /// Whether none of the `len` entries starting at `start` are used.
+    pub fn is_range_unused(&self, start: u64, len: u64) -> bool {
+        if start.is_multiple_of(8) && len.is_multiple_of(8) {
+            let start_byte = ((start / 8) as usize).min(self.data.len());
+            let end_byte = (((start + len) / 8) as usize).min(self.data.len());
+            return self.data[start_byte..end_byte]
+                .iter()
+                .all(|&byte| byte == 0);
+        }
+        (start..(start + len).min(self.end)).all(|n| !self.is_used(n))
+    }
+    /// The bitmap of the `len` entries starting at `start`, padded to a block of `block_size` bytes.
+    /// `start` is not necessarily byte aligned, i.e. the block groups of an image with 1024 byte blocks start at block 1.
+    pub fn get_bitmap(&self, start: u64, len: u32, block_size: u64) -> BitmapBlock {
+        assert!(len as u64 <= block_size * 8);
+        let mut bytes = vec![0u8; (len as usize).div_ceil(8)];
+        if start.is_multiple_of(8) {
+            let start_byte = ((start / 8) as usize).min(self.data.len());
+            let end_byte = (start_byte + bytes.len()).min(self.data.len());
+            bytes[..end_byte - start_byte].copy_from_slice(&self.data[start_byte..end_byte]);
+        } else {
+            for i in 0..len as u64 {
+                if self.is_used(start + i) {
+                    bytes[(i / 8) as usize] |= 1 << (i % 8);
+                }
+            }
+        }
+        // bits of a partial last byte that lie past `len` are set as padding by `BitmapBlock`
+        BitmapBlock::from_bytes(&bytes, len, block_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_bitmap_unused_block_group() {
+        let mut bitmap = UsageBitmap::default();
+        bitmap.mark_used(3);
+        bitmap.mark_used(40);
+        assert!(!bitmap.is_range_unused(0, 16));
+        assert!(bitmap.is_range_unused(16, 16));
+        assert!(!bitmap.is_range_unused(32, 16));
+        // beyond the last used entry
+        assert!(bitmap.is_range_unused(112, 16));
+        // not byte aligned
+        assert!(bitmap.is_range_unused(4, 36));
+        assert!(!bitmap.is_range_unused(4, 37));
+    }
+
+    #[test]
+    fn test_bump_allocator_skips_reserved() {
+        let mut allocator = BumpAllocator::default();
+        allocator.mark_used(0);
+        allocator.reserve(Allocation::from_start_len(10, 5));
+        assert_eq!(allocator.allocate(4), Allocation::from_start_len(1, 4));
+        // does not fit in front of the reserved range anymore
+        assert_eq!(allocator.allocate(6), Allocation::from_start_len(15, 6));
+
+        let mut allocator = BumpAllocator::default();
+        allocator.reserve(Allocation::from_start_len(10, 5));
+        assert_eq!(
+            allocator.allocate_up_to(20),
+            Allocation::from_start_len(0, 10)
+        );
+        assert_eq!(
+            allocator.allocate_up_to(10),
+            Allocation::from_start_len(15, 10)
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::{collections::BTreeMap, io};
+
+/// An in-memory sink that records everything written by [`crate::Ext4ImageWriter`] in chunks of
+/// [`BlockCollector::CHUNK_SIZE`] bytes. After finishing, the chunks can be retrieved in ascending order with
+/// [`BlockCollector::into_chunks`] and written out by the caller however they like (i.e. through async I/O).
+/// Chunks that were never written are not part of the output and must be treated as zeroed.
+/// The chunks don't depend on the block size of the image, so with blocks of 1024 or 2048 bytes
+/// a chunk holds several filesystem blocks.
+///
+/// The writer seeks back to the start of the image to write the superblock last, so this is also the way to
+/// stream an image to a sink that can't seek (see [`BlockCollector::write_to`]). The price is that every
+/// written block stays in memory until then, which is about the used part of the image, while holes cost nothing.
+#[derive(Default, Debug)]
+pub struct BlockCollector {
+    chunks: BTreeMap<u64, Box<[u8; CHUNK_SIZE as usize]>>,
+    position: u64,
+}
+const CHUNK_SIZE: u64 = 4096;
+impl BlockCollector {
+    /// The size of the chunks in bytes, chunk `n` starts at byte `n * CHUNK_SIZE` of the image.
+    pub const CHUNK_SIZE: u64 = CHUNK_SIZE;
+
+    /// Returns all written chunks as `(chunk_index, data)` pairs in ascending order.
+    pub fn into_chunks(self) -> impl Iterator<Item = (u64, Box<[u8; CHUNK_SIZE as usize]>)> {
+        self.chunks.into_iter()
+    }
+
+    /// Write the image of `size` bytes (i.e. [`crate::Ext4ImageInfo::size`]) front to back to `out`,
+    /// with zeros for the chunks that were never written. `out` doesn't need to be seekable,
+    /// so the image can be piped to stdout, a socket or a compressor like `zstd`.
+    pub fn write_to(self, mut out: impl io::Write, size: u64) -> io::Result<()> {
+        if self
+            .chunks
+            .last_key_value()
+            .is_some_and(|(&chunk, _)| chunk * CHUNK_SIZE >= size)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "chunks were written beyond the image size of {} bytes",
+                    size
+                ),
+            ));
+        }
+        let zeros = [0u8; CHUNK_SIZE as usize];
+        let mut position = 0;
+        for (chunk, data) in self.chunks {
+            let start = chunk * CHUNK_SIZE;
+            while position < start {
+                let len = (start - position).min(CHUNK_SIZE);
+                out.write_all(&zeros[..len as usize])?;
+                position += len;
+            }
+            let len = (size - start).min(CHUNK_SIZE);
+            out.write_all(&data[..len as usize])?;
+            position += len;
+        }
+        while position < size {
+            let len = (size - position).min(CHUNK_SIZE);
+            out.write_all(&zeros[..len as usize])?;
+            position += len;
+        }
+        out.flush()
+    }
+
+    fn len(&self) -> u64 {
+        self.chunks
+            .last_key_value()
+            .map(|(chunk, _)| (chunk + 1) * CHUNK_SIZE)
+            .unwrap_or(0)
+    }
+}
+impl io::Write for BlockCollector {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk_index = self.position / CHUNK_SIZE;
+        let offset = (self.position % CHUNK_SIZE) as usize;
+        let len = buf.len().min(CHUNK_SIZE as usize - offset);
+        let chunk = self
+            .chunks
+            .entry(chunk_index)
+            .or_insert_with(|| Box::new([0u8; CHUNK_SIZE as usize]));
+        chunk[offset..offset + len].copy_from_slice(&buf[..len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl io::Seek for BlockCollector {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            io::SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Ext4ImageWriter;
+    use std::io::{Cursor, Seek, Write};
+
+    #[test]
+    fn test_unaligned_writes() {
+        let mut collector = BlockCollector::default();
+        collector.seek(io::SeekFrom::Start(4090)).unwrap();
+        collector.write_all(&[1u8; 12]).unwrap();
+        let chunks: Vec<_> = collector.into_chunks().collect();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].0, 0);
+        assert_eq!(&chunks[0].1[4090..], &[1u8; 6]);
+        assert_eq!(chunks[1].0, 1);
+        assert_eq!(&chunks[1].1[..6], &[1u8; 6]);
+        assert_eq!(chunks[1].1[6], 0);
+    }
+
+    fn build<W: io::Write + io::Seek>(writer: &mut Ext4ImageWriter<W>) {
+        writer.mkdir("dir").unwrap();
+        writer
+            .write_file(&[0x55u8; 50000], "dir/file", 0o644)
+            .unwrap();
+    }
+
+    /// Only accepts sequential writes, like a pipe.
+    struct Pipe(Vec<u8>);
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn build_with_hole<W: io::Write + io::Seek>(
+        writer: Ext4ImageWriter<W>,
+        block_size: u64,
+    ) -> (W, crate::Ext4ImageInfo) {
+        let mut writer = writer.with_block_size(block_size).unwrap();
+        build(&mut writer);
+        writer
+            .write_file_at_block(b"far", "far", 0o644, 20000)
+            .unwrap();
+        writer.finish_with_info().unwrap()
+    }
+
+    #[test]
+    fn test_write_to_unseekable_sink() {
+        for block_size in [1024, 4096] {
+            let (image, _) = build_with_hole(
+                Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024),
+                block_size,
+            );
+            let (collector, info) = build_with_hole(
+                Ext4ImageWriter::new(BlockCollector::default(), 1024 * 1024 * 1024),
+                block_size,
+            );
+            let mut pipe = Pipe(vec![]);
+            collector.write_to(&mut pipe, info.size).unwrap();
+            assert_eq!(pipe.0.len() as u64, info.size);
+            assert_eq!(pipe.0, image.into_inner());
+        }
+
+        let mut collector = BlockCollector::default();
+        collector.seek(io::SeekFrom::Start(8192)).unwrap();
+        collector.write_all(&[1u8; 10]).unwrap();
+        assert!(collector.write_to(Pipe(vec![]), 8192).is_err());
+    }
+
+    #[test]
+    fn test_collected_chunks_match_image() {
+        fn new<W: io::Write + io::Seek>(file: W, block_size: u64) -> Ext4ImageWriter<W> {
+            let mut writer = Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+                .with_block_size(block_size)
+                .unwrap();
+            build(&mut writer);
+            writer
+        }
+        // the chunks don't line up with filesystem blocks of other sizes, but they still cover the image
+        for block_size in [1024, 2048, 4096] {
+            let image = new(Cursor::new(Vec::new()), block_size)
+                .finish()
+                .unwrap()
+                .into_inner();
+
+            let mut collected = vec![];
+            for (chunk_index, data) in new(BlockCollector::default(), block_size)
+                .finish()
+                .unwrap()
+                .into_chunks()
+            {
+                let start = (chunk_index * BlockCollector::CHUNK_SIZE) as usize;
+                collected.resize(start + data.len(), 0);
+                collected[start..].copy_from_slice(&data[..]);
+            }
+            assert_eq!(image[..], collected[..image.len()], "{}", block_size);
+            assert!(collected[image.len()..].iter().all(|&b| b == 0));
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{BLOCK_SIZE, BlockAllocator, BumpAllocator, Ext4Error, Ext4ImageWriter, Ext4Inode};
+use std::io;
+
+/// Collects the settings of an image that are fixed once anything was added and creates the [`Ext4ImageWriter`].
+/// Unlike the `with_*` methods of the writer, the methods can be called in any order: [`Ext4ImageWriterBuilder::build`]
+/// validates the combination and applies the settings in the order they depend on each other
+/// (i.e. the block size before the placement of the group metadata).
+#[derive(Debug, Clone)]
+pub struct Ext4ImageWriterBuilder {
+    max_size: u64,
+    uuid: Option<[u8; 16]>,
+    volume_label: String,
+    block_size: u64,
+    reserved_percent: f32,
+    inode_size: u16,
+    flex_bg_size: Option<u8>,
+    fixed_group_metadata: bool,
+    ext2: bool,
+}
+
+impl Ext4ImageWriterBuilder {
+    /// Start with the defaults of [`Ext4ImageWriter::new`] for an image of at most `max_size` bytes.
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            max_size,
+            uuid: None,
+            volume_label: String::new(),
+            block_size: BLOCK_SIZE,
+            reserved_percent: 0.0,
+            inode_size: Ext4Inode::DEFAULT_SIZE as u16,
+            flex_bg_size: None,
+            fixed_group_metadata: false,
+            ext2: false,
+        }
+    }
+
+    /// See [`Ext4ImageWriter::with_uuid`].
+    pub fn uuid(mut self, uuid: [u8; 16]) -> Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    /// See [`Ext4ImageWriter::set_volume_label`].
+    pub fn volume_label(mut self, label: &str) -> Self {
+        self.volume_label = label.to_string();
+        self
+    }
+
+    /// See [`Ext4ImageWriter::with_block_size`].
+    pub fn block_size(mut self, block_size: u64) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// See [`Ext4ImageWriter::set_reserved_percent`].
+    pub fn reserved_percent(mut self, percent: f32) -> Self {
+        self.reserved_percent = percent;
+        self
+    }
+
+    /// See [`Ext4ImageWriter::with_inode_size`].
+    pub fn inode_size(mut self, inode_size: u16) -> Self {
+        self.inode_size = inode_size;
+        self
+    }
+
+    /// See [`Ext4ImageWriter::with_flex_bg_size`].
+    pub fn flex_bg_size(mut self, log2: u8) -> Self {
+        self.flex_bg_size = Some(log2);
+        self
+    }
+
+    /// See [`Ext4ImageWriter::with_fixed_group_metadata`].
+    pub fn fixed_group_metadata(mut self) -> Self {
+        self.fixed_group_metadata = true;
+        self
+    }
+
+    /// See [`Ext4ImageWriter::ext2_compat`].
+    pub fn ext2_compat(mut self) -> Self {
+        self.ext2 = true;
+        self
+    }
+
+    /// Create the writer that writes to `writer` (i.e. a file or an in-memory buffer).
+    pub fn build<W: io::Write + io::Seek>(
+        self,
+        writer: W,
+    ) -> Result<Ext4ImageWriter<W>, Ext4Error> {
+        self.build_with_allocator(writer, BumpAllocator::default())
+    }
+
+    /// Like [`Ext4ImageWriterBuilder::build`] but places all blocks using the given [`BlockAllocator`].
+    pub fn build_with_allocator<W: io::Write + io::Seek, A: BlockAllocator>(
+        self,
+        writer: W,
+        allocator: A,
+    ) -> Result<Ext4ImageWriter<W, A>, Ext4Error> {
+        if self.ext2 && self.flex_bg_size.is_some_and(|log2| log2 != 0) {
+            return Err(Ext4Error::UnsupportedByExt2("flex_bg"));
+        }
+
+        let mut writer = Ext4ImageWriter::with_allocator(writer, self.max_size, allocator)
+            .with_block_size(self.block_size)?
+            .with_inode_size(self.inode_size)?;
+        if let Some(log2) = self.flex_bg_size {
+            writer = writer.with_flex_bg_size(log2)?;
+        }
+        if self.fixed_group_metadata {
+            writer = writer.with_fixed_group_metadata()?;
+        }
+        if self.ext2 {
+            writer = writer.ext2_compat()?;
+        }
+        if let Some(uuid) = self.uuid {
+            writer = writer.with_uuid(uuid)?;
+        }
+        writer.set_volume_label(&self.volume_label)?;
+        writer.set_reserved_percent(self.reserved_percent)?;
+        Ok(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_builder_validates_settings() {
+        let build = |builder: Ext4ImageWriterBuilder| builder.build(Cursor::new(Vec::new()));
+        let builder = || Ext4ImageWriterBuilder::new(1024 * 1024 * 1024);
+        for invalid in [
+            builder().block_size(512),
+            builder().inode_size(1024),
+            builder().reserved_percent(51.0),
+            builder().reserved_percent(-1.0),
+            builder().volume_label("a label that is too long"),
+            builder().ext2_compat().flex_bg_size(4),
+            // the GDT for 128 GiB needs more blocks than the resize inode can list with 1024 byte blocks
+            Ext4ImageWriterBuilder::new(128 * 1024 * 1024 * 1024).block_size(1024),
+        ] {
+            assert!(
+                matches!(
+                    build(invalid.clone()),
+                    Err(Ext4Error::Invalid(_) | Ext4Error::UnsupportedByExt2(_))
+                ),
+                "{:?}",
+                invalid
+            );
+        }
+        // the order of the calls does not matter, unlike for the `with_*` methods of the writer
+        build(builder().fixed_group_metadata().block_size(1024)).unwrap();
+        build(builder().ext2_compat().flex_bg_size(0).block_size(2048)).unwrap();
+        build(builder().fixed_group_metadata().inode_size(128)).unwrap();
+    }
+}
@@ -0,0 +1,430 @@
+//! Differential tests against `mkfs.ext4 -d`.
+//! The same source tree is written with this crate and with mke2fs, then the decoded on-disk structures
+//! (superblock, inodes and directory entries) are compared field by field.
+//! This catches layout bugs that `e2fsck -n` happily accepts.
+
+use crate::{BLOCK_SIZE, Ext4ImageReader, Ext4ImageWriter, ext4_h::*};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs,
+    io::{self, Cursor},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::Command,
+};
+
+/// The fixed creation time of all images our writer produces. mke2fs is pinned to it via `E2FSPROGS_FAKE_TIME`.
+const FAKE_TIME: &str = "1758215058";
+const UUID: &str = "12345678-9abc-def0-1234-56789abcdef0";
+
+/// Superblock fields that are expected to differ, mostly because mke2fs sizes the filesystem up front
+/// while we only use as much space as the content needs.
+const IGNORED_SUPERBLOCK_FIELDS: &[&str] = &[
+    "s_inodes_count",
+    "s_blocks_count_lo",
+    "s_blocks_count_hi",
+    "s_r_blocks_count_lo",
+    "s_r_blocks_count_hi",
+    "s_free_blocks_count_lo",
+    "s_free_blocks_count_hi",
+    "s_free_inodes_count",
+    "s_inodes_per_group",
+    "s_reserved_gdt_blocks",
+    "s_hash_seed",
+    "s_kbytes_written",
+    "s_overhead_clusters",
+    "s_checksum",
+];
+
+/// Inode fields that are expected to differ: checksums and timestamps (which mke2fs copies from the source files).
+/// `i_block` and the in-inode xattr area (`rest`) are compared by [`diff_i_block`] and [`diff_xattrs`] instead,
+/// as they contain block numbers and mke2fs always writes an xattr header and places the values differently.
+const IGNORED_INODE_FIELDS: &[&str] = &[
+    "i_block",
+    "rest",
+    "i_atime",
+    "i_ctime",
+    "i_mtime",
+    "i_crtime",
+    "i_atime_extra",
+    "i_ctime_extra",
+    "i_mtime_extra",
+    "i_crtime_extra",
+    "i_checksum_lo",
+    "i_checksum_hi",
+];
+
+/// Whether a directory is stored inline or in blocks is up to the writer, so only its entries are compared.
+const IGNORED_DIRECTORY_FIELDS: &[&str] = &["i_size_lo", "i_blocks_lo", "i_flags"];
+
+pub(crate) enum SourceEntry {
+    Directory,
+    File { contents: Vec<u8>, mode: u16 },
+}
+
+/// A directory tree that can be materialized both through [`Ext4ImageWriter`] and on disk for `mkfs.ext4 -d`.
+#[derive(Default)]
+pub(crate) struct SourceTree {
+    entries: Vec<(String, SourceEntry)>,
+}
+impl SourceTree {
+    pub fn mkdir(mut self, path: &str) -> Self {
+        self.entries
+            .push((path.to_string(), SourceEntry::Directory));
+        self
+    }
+
+    pub fn file(mut self, path: &str, contents: &[u8], mode: u16) -> Self {
+        self.entries.push((
+            path.to_string(),
+            SourceEntry::File {
+                contents: contents.to_vec(),
+                mode,
+            },
+        ));
+        self
+    }
+
+    fn build_with_writer(&self) -> io::Result<Vec<u8>> {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        for (path, entry) in &self.entries {
+            match entry {
+                SourceEntry::Directory => writer.mkdir(path)?,
+                SourceEntry::File { contents, mode } => writer.write_file(contents, path, *mode)?,
+            }
+        }
+        Ok(writer.finish()?.into_inner())
+    }
+
+    fn build_with_mkfs(&self, work_dir: &Path) -> io::Result<Vec<u8>> {
+        let source_dir = work_dir.join("source");
+        let _ = fs::remove_dir_all(work_dir);
+        fs::create_dir_all(&source_dir)?;
+        // mke2fs copies lost+found from the source if it exists, so mirror the mode we use
+        fs::create_dir(source_dir.join("lost+found"))?;
+        fs::set_permissions(
+            source_dir.join("lost+found"),
+            fs::Permissions::from_mode(0o700),
+        )?;
+        for (path, entry) in &self.entries {
+            match entry {
+                SourceEntry::Directory => {
+                    fs::create_dir(source_dir.join(path))?;
+                    fs::set_permissions(source_dir.join(path), fs::Permissions::from_mode(0o755))?;
+                }
+                SourceEntry::File { contents, mode } => {
+                    fs::write(source_dir.join(path), contents)?;
+                    fs::set_permissions(
+                        source_dir.join(path),
+                        fs::Permissions::from_mode(*mode as u32),
+                    )?;
+                }
+            }
+        }
+        fs::set_permissions(&source_dir, fs::Permissions::from_mode(0o755))?;
+
+        let image_path = work_dir.join("mkfs.img");
+        let output = Command::new("mkfs.ext4")
+            .env("E2FSPROGS_FAKE_TIME", FAKE_TIME)
+            .env("MKE2FS_CONFIG", "/dev/null")
+            .args([
+                "-q", "-F", "-t", "ext4", "-b", "4096", "-I", "256", "-U", UUID,
+            ])
+            .args(["-O", "^has_journal,sparse_super,large_file,filetype"])
+            .args([
+                "-O",
+                "resize_inode,dir_index,ext_attr,extent,flex_bg,metadata_csum",
+            ])
+            .args(["-O", "64bit,dir_nlink,extra_isize,inline_data,huge_file"])
+            .args(["-E", "root_owner=0:0", "-d"])
+            .arg(&source_dir)
+            .arg(&image_path)
+            .arg(format!("{}", 64 * 1024 * 1024 / BLOCK_SIZE))
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "mkfs.ext4 failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        fs::read(image_path)
+    }
+}
+
+/// The top level fields of a `buffer_struct!` as rendered by its `Debug` implementation.
+fn debug_fields(value: &impl Debug) -> BTreeMap<String, String> {
+    let rendered = format!("{:#?}", value);
+    let mut fields = BTreeMap::new();
+    let mut current: Option<(String, String)> = None;
+    for line in rendered.lines().skip(1) {
+        if let Some(field) = line.strip_prefix("    ")
+            && !field.starts_with(' ')
+            && let Some((name, value)) = field.split_once(": ")
+        {
+            fields.extend(current.take());
+            current = Some((name.to_string(), value.to_string()));
+        } else if line.starts_with("    ")
+            && let Some((_, value)) = &mut current
+        {
+            value.push_str(line.trim());
+        }
+    }
+    fields.extend(current);
+    fields
+        .into_iter()
+        .map(|(name, value)| (name, value.trim_end_matches(',').to_string()))
+        .collect()
+}
+
+fn diff_fields(
+    context: &str,
+    ours: &impl Debug,
+    theirs: &impl Debug,
+    ignored: &[&str],
+    divergences: &mut Vec<String>,
+) {
+    let theirs = debug_fields(theirs);
+    for (name, our_value) in debug_fields(ours) {
+        if ignored.contains(&name.as_str()) {
+            continue;
+        }
+        let their_value = &theirs[&name];
+        if *their_value != our_value {
+            divergences.push(format!(
+                "{}: {} is {} but mkfs.ext4 has {}",
+                context, name, our_value, their_value
+            ));
+        }
+    }
+}
+
+fn diff_inodes(
+    path: &str,
+    ours: &Ext4Inode,
+    theirs: &Ext4Inode,
+    divergences: &mut Vec<String>,
+) -> io::Result<()> {
+    let mut ignored = IGNORED_INODE_FIELDS.to_vec();
+    if ours.is_directory() {
+        ignored.extend(IGNORED_DIRECTORY_FIELDS);
+    }
+    diff_fields(path, ours, theirs, &ignored, divergences);
+    diff_xattrs(path, ours, theirs, divergences)
+}
+
+/// The parts of `i_block` that don't depend on where the blocks were placed: the logical blocks that the extents
+/// map, or the bytes themselves for inline data and fast symlinks. How the mapped ranges are split into extents
+/// and thus the depth of the tree depends on the placement, so adjacent extents are merged.
+fn i_block_layout(
+    reader: &mut Ext4ImageReader<Cursor<Vec<u8>>>,
+    inode: &Ext4Inode,
+) -> io::Result<String> {
+    Ok(match inode.storage_mode(BLOCK_SIZE) {
+        StorageMode::Inline | StorageMode::NoData => format!("{:?}", inode.block()),
+        StorageMode::InlineExtents | StorageMode::IndirectExtents => {
+            let mut ranges: Vec<(u32, u32)> = vec![];
+            for leaf in reader.read_extents(inode)? {
+                let (start, len) = (leaf.logical_block(), leaf.block_count() as u32);
+                match ranges.last_mut() {
+                    Some((last_start, last_len)) if *last_start + *last_len == start => {
+                        *last_len += len
+                    }
+                    _ => ranges.push((start, len)),
+                }
+            }
+            format!("extents mapping the logical blocks {:?}", ranges)
+        }
+        StorageMode::BlockMap => "a block map".to_string(),
+    })
+}
+
+/// Compare `i_block` of files. Directories may be stored inline by one tool and in blocks by the other.
+fn diff_i_block(
+    path: &str,
+    ours: &mut Ext4ImageReader<Cursor<Vec<u8>>>,
+    our_inode: &Ext4Inode,
+    theirs: &mut Ext4ImageReader<Cursor<Vec<u8>>>,
+    their_inode: &Ext4Inode,
+    divergences: &mut Vec<String>,
+) -> io::Result<()> {
+    let our_layout = i_block_layout(ours, our_inode)?;
+    let their_layout = i_block_layout(theirs, their_inode)?;
+    if our_layout != their_layout {
+        divergences.push(format!(
+            "{}: i_block has {} but mkfs.ext4 has {}",
+            path, our_layout, their_layout
+        ));
+    }
+    Ok(())
+}
+
+/// Compare the extended attributes in the inode body by name and value. The `system.data` attribute of
+/// directories is skipped, as it only holds entries if the directory is stored inline.
+fn diff_xattrs(
+    path: &str,
+    ours: &Ext4Inode,
+    theirs: &Ext4Inode,
+    divergences: &mut Vec<String>,
+) -> io::Result<()> {
+    let sorted_xattrs = |inode: &Ext4Inode| -> io::Result<Vec<Ext4Xattr>> {
+        let mut xattrs = inode.xattrs()?;
+        if inode.is_directory() {
+            xattrs.retain(|xattr| (xattr.name_index, &xattr.name[..]) != (7, b"data"));
+        }
+        xattrs.sort_by(|a, b| (a.name_index, &a.name).cmp(&(b.name_index, &b.name)));
+        Ok(xattrs)
+    };
+    let (our_xattrs, their_xattrs) = (sorted_xattrs(ours)?, sorted_xattrs(theirs)?);
+    if our_xattrs != their_xattrs {
+        divergences.push(format!(
+            "{}: the in-inode xattrs are {:?} but mkfs.ext4 has {:?}",
+            path, our_xattrs, their_xattrs
+        ));
+    }
+    Ok(())
+}
+
+fn diff_directory(
+    path: &str,
+    ours: &mut Ext4ImageReader<Cursor<Vec<u8>>>,
+    our_inode_num: u32,
+    theirs: &mut Ext4ImageReader<Cursor<Vec<u8>>>,
+    their_inode_num: u32,
+    divergences: &mut Vec<String>,
+) -> io::Result<()> {
+    let named_entries = |reader: &mut Ext4ImageReader<_>, inode_num| -> io::Result<_> {
+        let inode = reader.read_inode(inode_num)?;
+        Ok(reader
+            .read_directory_entries(&inode)?
+            .into_iter()
+            .filter(|entry| entry.name() != "." && entry.name() != "..")
+            .map(|entry| (entry.name().to_string(), entry))
+            .collect::<BTreeMap<_, _>>())
+    };
+    let our_entries = named_entries(ours, our_inode_num)?;
+    let their_entries = named_entries(theirs, their_inode_num)?;
+    for name in their_entries.keys() {
+        if !our_entries.contains_key(name) {
+            divergences.push(format!("{}: entry '{}' is missing", path, name));
+        }
+    }
+    for (name, our_entry) in our_entries {
+        let entry_path = format!("{}/{}", path, name);
+        let Some(their_entry) = their_entries.get(&name) else {
+            divergences.push(format!("{}: unexpected entry", entry_path));
+            continue;
+        };
+        if our_entry.is_directory() != their_entry.is_directory() {
+            divergences.push(format!("{}: file types differ", entry_path));
+            continue;
+        }
+        let our_inode = ours.read_inode(our_entry.inode())?;
+        let their_inode = theirs.read_inode(their_entry.inode())?;
+        diff_inodes(&entry_path, &our_inode, &their_inode, divergences)?;
+        if our_entry.is_directory() {
+            diff_directory(
+                &entry_path,
+                ours,
+                our_entry.inode(),
+                theirs,
+                their_entry.inode(),
+                divergences,
+            )?;
+        } else {
+            diff_i_block(
+                &entry_path,
+                ours,
+                &our_inode,
+                theirs,
+                &their_inode,
+                divergences,
+            )?;
+            if ours.read_file_contents(&our_inode)? != theirs.read_file_contents(&their_inode)? {
+                divergences.push(format!("{}: contents differ", entry_path));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Build `tree` with this crate and with `mkfs.ext4 -d` and return all unexpected divergences.
+pub(crate) fn differential_test(name: &str, tree: &SourceTree) -> io::Result<Vec<String>> {
+    let ours = tree.build_with_writer()?;
+    let theirs = tree.build_with_mkfs(&Path::new("target/differential").join(name))?;
+    let mut ours = Ext4ImageReader::new(Cursor::new(ours))?;
+    let mut theirs = Ext4ImageReader::new(Cursor::new(theirs))?;
+
+    let mut divergences = vec![];
+    diff_fields(
+        "superblock",
+        ours.superblock(),
+        theirs.superblock(),
+        IGNORED_SUPERBLOCK_FIELDS,
+        &mut divergences,
+    );
+    let (our_root, their_root) = (ours.read_inode(2)?, theirs.read_inode(2)?);
+    diff_inodes("/", &our_root, &their_root, &mut divergences)?;
+    diff_directory("", &mut ours, 2, &mut theirs, 2, &mut divergences)?;
+    Ok(divergences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_fields() {
+        let fields = debug_fields(&Ext4ExtentHeader::default());
+        assert_eq!(fields["eh_magic"], "62218");
+        assert_eq!(fields["eh_max"], "4");
+        assert_eq!(fields.len(), 5);
+    }
+
+    #[test]
+    fn test_diff_xattrs() {
+        let mut theirs = Ext4Inode::default();
+        theirs
+            .add_xattr(Ext4Xattr::new(1, b"a", b"1"), Ext4Inode::DEFAULT_SIZE)
+            .unwrap();
+        theirs
+            .add_xattr(Ext4Xattr::new(1, b"b", b"2"), Ext4Inode::DEFAULT_SIZE)
+            .unwrap();
+        // the order of the entries doesn't matter
+        let mut ours = Ext4Inode::default();
+        ours.add_xattr(Ext4Xattr::new(1, b"b", b"2"), Ext4Inode::DEFAULT_SIZE)
+            .unwrap();
+        ours.add_xattr(Ext4Xattr::new(1, b"a", b"1"), Ext4Inode::DEFAULT_SIZE)
+            .unwrap();
+        let mut divergences = vec![];
+        diff_xattrs("/file", &ours, &theirs, &mut divergences).unwrap();
+        assert!(divergences.is_empty(), "{:#?}", divergences);
+
+        ours.add_xattr(Ext4Xattr::new(1, b"b", b"3"), Ext4Inode::DEFAULT_SIZE)
+            .unwrap();
+        diff_xattrs("/file", &ours, &theirs, &mut divergences).unwrap();
+        assert_eq!(divergences.len(), 1);
+    }
+
+    #[test]
+    fn test_differential_small_files() {
+        let tree = SourceTree::default()
+            .file("hello.txt", b"hello, world", 0o644)
+            .file("empty", b"", 0o600)
+            .file("inline.bin", &[7u8; 100], 0o755);
+        let divergences = differential_test("small_files", &tree).unwrap();
+        assert!(divergences.is_empty(), "{:#?}", divergences);
+    }
+
+    #[test]
+    fn test_differential_nested_directories() {
+        let contents: Vec<u8> = (0..50000u32).map(|i| (i % 251) as u8).collect();
+        let mut tree = SourceTree::default().mkdir("a").mkdir("a/b");
+        tree = tree.file("a/b/data.bin", &contents, 0o644);
+        for i in 0..100 {
+            tree = tree.file(&format!("a/file-{i}"), format!("{i}").as_bytes(), 0o644);
+        }
+        let divergences = differential_test("nested_directories", &tree).unwrap();
+        assert!(divergences.is_empty(), "{:#?}", divergences);
+    }
+}
@@ -0,0 +1,256 @@
+//! The filename hashes that HTree directories are indexed by.
+//! This is a port of `ext4fs_dirhash` from the linux kernel (`fs/ext4/hash.c`).
+
+/// The hash algorithms as stored in `s_def_hash_version`.
+/// The `_unsigned` variants of the kernel are selected with the `signed` parameter of [`ext4_dir_hash`] instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DirHashVersion {
+    Legacy = 0,
+    HalfMd4 = 1,
+    Tea = 2,
+}
+
+/// The seed that is used if the superblock's `s_hash_seed` is all zeros.
+const DEFAULT_SEED: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+
+/// The name that is hashed for `name` in a case-insensitive directory. The kernel folds names with the
+/// Unicode tables of `utf8-12.1`, which for ASCII names is plain lowercasing. Other names return `None`,
+/// as this crate doesn't carry those tables.
+pub(crate) fn casefold_ascii(name: &str) -> Option<String> {
+    name.is_ascii().then(|| name.to_ascii_lowercase())
+}
+
+/// Hash `name` like the kernel does when looking it up in an HTree directory.
+/// `signed` selects whether the name bytes are treated as signed chars (see `EXT2_FLAGS_SIGNED_HASH`).
+/// Returns the major and the minor hash.
+pub fn ext4_dir_hash(
+    name: &[u8],
+    hash_version: DirHashVersion,
+    seed: [u32; 4],
+    signed: bool,
+) -> (u32, u32) {
+    let mut buf = if seed == [0; 4] { DEFAULT_SEED } else { seed };
+    let (hash, minor_hash) = match hash_version {
+        DirHashVersion::Legacy => (dx_hack_hash(name, signed), 0),
+        DirHashVersion::HalfMd4 => {
+            for (i, chunk) in name.chunks(32).enumerate() {
+                let input = str2hashbuf::<8>(chunk, name.len() - i * 32, signed);
+                half_md4_transform(&mut buf, &input);
+            }
+            (buf[1], buf[2])
+        }
+        DirHashVersion::Tea => {
+            for (i, chunk) in name.chunks(16).enumerate() {
+                let input = str2hashbuf::<4>(chunk, name.len() - i * 16, signed);
+                tea_transform(&mut buf, &input);
+            }
+            (buf[0], buf[1])
+        }
+    };
+    let hash = hash & !1;
+    // the all ones hash marks the end of the directory for 32 bit readdir cookies
+    if hash == 0x7fffffff << 1 {
+        ((0x7fffffff - 1) << 1, minor_hash)
+    } else {
+        (hash, minor_hash)
+    }
+}
+
+fn char_value(byte: u8, signed: bool) -> u32 {
+    if signed {
+        byte as i8 as i32 as u32
+    } else {
+        byte as u32
+    }
+}
+
+fn dx_hack_hash(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12a3fe2du32, 0x37abe8f9u32);
+    for &byte in name {
+        let mut hash = hash1.wrapping_add(hash0 ^ char_value(byte, signed).wrapping_mul(7152373));
+        if hash & 0x80000000 != 0 {
+            hash = hash.wrapping_sub(0x7fffffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// Pack the start of `msg` into `N` words. `remaining` is the length of the name from the start of `msg` on,
+/// which is also used as padding.
+fn str2hashbuf<const N: usize>(msg: &[u8], remaining: usize, signed: bool) -> [u32; N] {
+    let mut pad = remaining as u32 | ((remaining as u32) << 8);
+    pad |= pad << 16;
+    let mut words = [pad; N];
+    for (word, chunk) in words.iter_mut().zip(msg.chunks(4)) {
+        *word = chunk.iter().fold(pad, |val, &byte| {
+            char_value(byte, signed).wrapping_add(val << 8)
+        });
+    }
+    words
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+
+    let [mut a, mut b, mut c, mut d] = *buf;
+    macro_rules! round {
+        ($f:ident, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a
+                .wrapping_add($f($b, $c, $d))
+                .wrapping_add($x)
+                .rotate_left($s);
+        };
+    }
+
+    round!(f, a, b, c, d, input[0], 3);
+    round!(f, d, a, b, c, input[1], 7);
+    round!(f, c, d, a, b, input[2], 11);
+    round!(f, b, c, d, a, input[3], 19);
+    round!(f, a, b, c, d, input[4], 3);
+    round!(f, d, a, b, c, input[5], 7);
+    round!(f, c, d, a, b, input[6], 11);
+    round!(f, b, c, d, a, input[7], 19);
+
+    round!(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round!(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    for (word, value) in buf.iter_mut().zip([a, b, c, d]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    const DELTA: u32 = 0x9E3779B9;
+    let [a, b, c, d] = *input;
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let mut sum = 0u32;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            ((b1 << 4).wrapping_add(a)) ^ b1.wrapping_add(sum) ^ ((b1 >> 5).wrapping_add(b)),
+        );
+        b1 = b1.wrapping_add(
+            ((b0 << 4).wrapping_add(c)) ^ b0.wrapping_add(sum) ^ ((b0 >> 5).wrapping_add(d)),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // generated with `debugfs -R "dx_hash -h <alg> -s 01020304-0506-0708-090a-0b0c0d0e0f10 <name>"`
+    const SEED: [u32; 4] = [0x04030201, 0x08070605, 0x0c0b0a09, 0x100f0e0d];
+    const LONG_NAME: &[u8] = b"a-much-longer-file-name-that-spans-multiple-blocks.txt";
+    const UMLAUTS: &[u8] = b"d\xe4t\xe4";
+
+    #[test]
+    fn test_legacy_hash() {
+        use DirHashVersion::Legacy;
+        assert_eq!(ext4_dir_hash(b"hello", Legacy, SEED, true), (0x32252546, 0));
+        assert_eq!(
+            ext4_dir_hash(b"lost+found", Legacy, [0; 4], true),
+            (0x5e2aba24, 0)
+        );
+        assert_eq!(
+            ext4_dir_hash(LONG_NAME, Legacy, SEED, true),
+            (0x2c8953aa, 0)
+        );
+        assert_eq!(ext4_dir_hash(UMLAUTS, Legacy, SEED, true), (0x52247c56, 0));
+        assert_eq!(ext4_dir_hash(UMLAUTS, Legacy, SEED, false), (0x802a9a60, 0));
+    }
+
+    #[test]
+    fn test_half_md4_hash() {
+        use DirHashVersion::HalfMd4;
+        assert_eq!(
+            ext4_dir_hash(b"hello", HalfMd4, [0; 4], true),
+            (0x1746da32, 0x420013b5)
+        );
+        assert_eq!(
+            ext4_dir_hash(b"hello", HalfMd4, SEED, true),
+            (0xe2ddf968, 0xc8bc0924)
+        );
+        assert_eq!(
+            ext4_dir_hash(b"lost+found", HalfMd4, [0; 4], true),
+            (0x591de422, 0x6ffc56e0)
+        );
+        assert_eq!(
+            ext4_dir_hash(LONG_NAME, HalfMd4, [0; 4], true),
+            (0xe1073e9a, 0x2d4f9e00)
+        );
+        assert_eq!(
+            ext4_dir_hash(LONG_NAME, HalfMd4, SEED, false),
+            (0x85859b84, 0x0afbe01a)
+        );
+        assert_eq!(
+            ext4_dir_hash(UMLAUTS, HalfMd4, SEED, true),
+            (0x6d2b2874, 0xb3df4cfa)
+        );
+        assert_eq!(
+            ext4_dir_hash(UMLAUTS, HalfMd4, SEED, false),
+            (0x85d6b442, 0xed27c662)
+        );
+        assert_eq!(
+            ext4_dir_hash(UMLAUTS, HalfMd4, [0; 4], false),
+            (0x983333c2, 0xa0592a81)
+        );
+    }
+
+    #[test]
+    fn test_tea_hash() {
+        use DirHashVersion::Tea;
+        assert_eq!(
+            ext4_dir_hash(b"x", Tea, [0; 4], true),
+            (0xe958e760, 0x9772c62c)
+        );
+        assert_eq!(
+            ext4_dir_hash(b"hello", Tea, SEED, true),
+            (0x1a5692b6, 0x2fd9857d)
+        );
+        assert_eq!(
+            ext4_dir_hash(b"lost+found", Tea, [0; 4], true),
+            (0x2dbf9e80, 0xbfebee4f)
+        );
+        assert_eq!(
+            ext4_dir_hash(LONG_NAME, Tea, SEED, true),
+            (0xafd8c92a, 0xabe5449c)
+        );
+        assert_eq!(
+            ext4_dir_hash(UMLAUTS, Tea, SEED, true),
+            (0x6b8f1774, 0x9414abe9)
+        );
+        assert_eq!(
+            ext4_dir_hash(UMLAUTS, Tea, SEED, false),
+            (0xb3caeb8c, 0x0ada6752)
+        );
+        assert_eq!(
+            ext4_dir_hash(UMLAUTS, Tea, [0; 4], false),
+            (0x390f0d8a, 0x285d1ce4)
+        );
+    }
+}
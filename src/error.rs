@@ -0,0 +1,223 @@
+use std::{error::Error, fmt, io};
+
+/// The ways building or reading an image can fail.
+/// Converts into an [`io::Error`] (keeping this error as the inner error), so `?` still works in functions
+/// that return [`io::Result`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Ext4Error {
+    /// Reading from or writing to the underlying writer failed.
+    Io(io::Error),
+    /// There already is a file or directory at the path.
+    PathExists(String),
+    /// The parent directory of the path does not exist.
+    ParentMissing(String),
+    /// Nothing exists at the path.
+    NotFound(String),
+    /// The path (or one of its parents) is a file where a directory is needed.
+    NotADirectory(String),
+    /// The path is a directory where a file is needed.
+    IsADirectory(String),
+    /// The directory still has entries and can't be removed without removing them as well.
+    DirectoryNotEmpty(String),
+    /// A path component is longer than the 255 bytes a directory entry can hold.
+    NameTooLong(String),
+    /// A path component is empty, `.`, `..` or contains a NUL byte.
+    InvalidName(String),
+    /// Moving a directory into itself or one of its subdirectories.
+    DirectoryCycle { from: String, to: String },
+    /// A setting that has to be chosen right after construction was changed after files were added.
+    TooLate(&'static str),
+    /// The image needs more block groups than the GDT reserved for the maximum size has room for.
+    TooManyBlockGroups { needed: u64, max: u64 },
+    /// Inode numbers would no longer fit into 32 bits.
+    TooManyInodes(u64),
+    /// The contents (of `size` bytes) can't be represented, i.e. an overlong symlink target.
+    ContentTooLarge(u64),
+    /// The image needs more blocks than are available, either within its maximum size or from the allocator.
+    OutOfSpace { needed: u64, available: u64 },
+    /// The file already has as many hard links as its link count can hold.
+    TooManyLinks(String),
+    /// The root directory and lost+found are managed by the writer and can't be renamed, removed or written raw.
+    ReservedPath(String),
+    /// ext2 images lack the named feature.
+    UnsupportedByExt2(&'static str),
+    /// Revision 0 images lack what is needed to store the described content.
+    UnsupportedByRevision0(String),
+    /// The feature needs inodes of at least `min_size` bytes.
+    InodeTooSmall {
+        feature: &'static str,
+        min_size: u16,
+    },
+    /// The blocks passed for a raw directory are malformed or don't match the files added to it.
+    InvalidRawDirectory(String),
+    /// The encryption contexts in the tree don't form a filesystem the kernel accepts.
+    InvalidEncryption(String),
+    /// A requested block is already used or lies outside of the maximum image size.
+    BlockUnavailable(String),
+    /// The image being read is malformed or uses structures that [`crate::Ext4ImageReader`] doesn't support.
+    MalformedImage(String),
+    /// An argument or a combination of features is not valid, with a description of why.
+    Invalid(String),
+}
+
+impl fmt::Display for Ext4Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ext4Error::Io(error) => write!(f, "{}", error),
+            Ext4Error::PathExists(path) => write!(f, "path '{}' already exists", path),
+            Ext4Error::ParentMissing(path) => {
+                write!(f, "parent directory '{}' does not exist", path)
+            }
+            Ext4Error::NotFound(path) => write!(f, "path '{}' does not exist", path),
+            Ext4Error::NotADirectory(path) => write!(f, "path '{}' is not a directory", path),
+            Ext4Error::IsADirectory(path) => write!(f, "path '{}' is a directory", path),
+            Ext4Error::DirectoryNotEmpty(path) => {
+                write!(f, "directory '{}' is not empty", path)
+            }
+            Ext4Error::NameTooLong(name) => {
+                write!(f, "the name '{}' is longer than 255 bytes", name)
+            }
+            Ext4Error::InvalidName(name) => write!(f, "'{}' is not a valid name", name),
+            Ext4Error::DirectoryCycle { from, to } => write!(
+                f,
+                "cannot move '{}' to '{}' since this would create a directory cycle",
+                from, to
+            ),
+            Ext4Error::TooLate(setting) => {
+                write!(
+                    f,
+                    "{} can only be chosen before any files are added",
+                    setting
+                )
+            }
+            Ext4Error::TooManyBlockGroups { needed, max } => write!(
+                f,
+                "the image needs {} block groups but the maximum size only allows {}, try increasing it",
+                needed, max
+            ),
+            Ext4Error::TooManyInodes(max) => {
+                write!(f, "the filesystem can't hold more than {} inodes", max)
+            }
+            Ext4Error::ContentTooLarge(size) => {
+                write!(f, "contents of {} bytes are too large", size)
+            }
+            Ext4Error::OutOfSpace { needed, available } => write!(
+                f,
+                "the image needs {} blocks but only {} are available",
+                needed, available
+            ),
+            Ext4Error::TooManyLinks(path) => {
+                write!(f, "'{}' already has the maximum number of links", path)
+            }
+            Ext4Error::ReservedPath(path) => write!(
+                f,
+                "'{}' is managed by the writer and can't be renamed, removed or written raw",
+                path
+            ),
+            Ext4Error::UnsupportedByExt2(feature) => {
+                write!(f, "ext2 images don't support {}", feature)
+            }
+            Ext4Error::UnsupportedByRevision0(what) => {
+                write!(f, "revision 0 images don't support {}", what)
+            }
+            Ext4Error::InodeTooSmall { feature, min_size } => {
+                write!(f, "{} need inodes of at least {} bytes", feature, min_size)
+            }
+            Ext4Error::InvalidRawDirectory(message) => {
+                write!(f, "invalid raw directory: {}", message)
+            }
+            Ext4Error::MalformedImage(message) => write!(f, "malformed image: {}", message),
+            Ext4Error::BlockUnavailable(message)
+            | Ext4Error::InvalidEncryption(message)
+            | Ext4Error::Invalid(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl Error for Ext4Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Ext4Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Ext4Error {
+    /// Unwraps errors of this crate that were passed through an [`io::Error`].
+    fn from(error: io::Error) -> Self {
+        if error.get_ref().is_some_and(|inner| inner.is::<Ext4Error>()) {
+            *error.into_inner().unwrap().downcast().unwrap()
+        } else {
+            Ext4Error::Io(error)
+        }
+    }
+}
+
+impl From<Ext4Error> for io::Error {
+    fn from(error: Ext4Error) -> Self {
+        if let Ext4Error::Io(error) = error {
+            return error;
+        }
+        let kind = match &error {
+            Ext4Error::PathExists(_) => io::ErrorKind::AlreadyExists,
+            Ext4Error::ParentMissing(_) | Ext4Error::NotFound(_) => io::ErrorKind::NotFound,
+            Ext4Error::NotADirectory(_) => io::ErrorKind::NotADirectory,
+            Ext4Error::IsADirectory(_) => io::ErrorKind::IsADirectory,
+            Ext4Error::DirectoryNotEmpty(_) => io::ErrorKind::DirectoryNotEmpty,
+            Ext4Error::NameTooLong(_)
+            | Ext4Error::InvalidName(_)
+            | Ext4Error::ReservedPath(_)
+            | Ext4Error::InvalidEncryption(_)
+            | Ext4Error::Invalid(_) => io::ErrorKind::InvalidInput,
+            Ext4Error::ContentTooLarge(_) => io::ErrorKind::FileTooLarge,
+            Ext4Error::OutOfSpace { .. } => io::ErrorKind::StorageFull,
+            Ext4Error::TooManyLinks(_) => io::ErrorKind::TooManyLinks,
+            Ext4Error::UnsupportedByExt2(_)
+            | Ext4Error::UnsupportedByRevision0(_)
+            | Ext4Error::InodeTooSmall { .. } => io::ErrorKind::Unsupported,
+            Ext4Error::InvalidRawDirectory(_) | Ext4Error::MalformedImage(_) => {
+                io::ErrorKind::InvalidData
+            }
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_through_io_error() {
+        let error: io::Error = Ext4Error::PathExists("etc".to_string()).into();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(error.to_string(), "path 'etc' already exists");
+        assert!(matches!(
+            Ext4Error::from(error),
+            Ext4Error::PathExists(path) if path == "etc"
+        ));
+
+        let error: io::Error = Ext4Error::OutOfSpace {
+            needed: 3,
+            available: 2,
+        }
+        .into();
+        assert_eq!(error.kind(), io::ErrorKind::StorageFull);
+        assert!(matches!(
+            Ext4Error::from(error),
+            Ext4Error::OutOfSpace {
+                needed: 3,
+                available: 2
+            }
+        ));
+
+        let error: io::Error = Ext4Error::from(io::Error::from(io::ErrorKind::WriteZero)).into();
+        assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+        assert!(error.get_ref().is_none());
+    }
+}
@@ -1,9 +1,13 @@
 use crate::serialization::{
-    Buffer, StaticLenString, buffer_struct, hi_lo_field_u32, hi_lo_field_u48, hi_lo_field_u64,
-    impl_buffer_for_array,
+    Buffer, CheckMagic, StaticLenString, buffer_struct, hi_lo_field_u32, hi_lo_field_u48,
+    hi_lo_field_u64, impl_buffer_for_array,
+};
+use crate::{Allocation, BLOCK_SIZE, DirHashVersion, Timestamp, Timestamps};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    io,
 };
-use crate::{Allocation, BLOCK_SIZE};
-use std::fmt::Debug;
 
 macro_rules! calculate_checksum {
     ($($item:expr),*) => {
@@ -17,6 +21,22 @@ macro_rules! calculate_checksum {
     };
 }
 
+/// The seed that the metadata checksums of a filesystem with the given `uuid` start from, i.e. the value
+/// of `s_checksum_seed` with the `metadata_csum_seed` feature. Tools that change the uuid of an image
+/// written by this crate can store this seed to keep all existing checksums valid.
+pub fn uuid_checksum_seed(uuid: &[u8; 16]) -> u32 {
+    calculate_checksum![uuid]
+}
+
+/// A random (version 4) uuid as described in RFC 4122.
+pub fn random_uuid() -> io::Result<[u8; 16]> {
+    let mut uuid = [0u8; 16];
+    getrandom::fill(&mut uuid).map_err(io::Error::from)?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40; // version 4
+    uuid[8] = (uuid[8] & 0x3f) | 0x80; // variant 1
+    Ok(uuid)
+}
+
 buffer_struct! { Ext4SuperBlock {
     /*00*/ s_inodes_count: u32,         /* Inodes count */
     s_blocks_count_lo: u32,      /* Blocks count */
@@ -24,8 +44,8 @@ buffer_struct! { Ext4SuperBlock {
     s_free_blocks_count_lo: u32, /* Free blocks count */
     /*10*/ s_free_inodes_count: u32, /* Free inodes count */
     s_first_data_block: u32,  /* First Data Block */
-    s_log_block_size: u32 = 2,    /* Block size */
-    s_log_cluster_size: u32 = 2,  /* Allocation cluster size */
+    s_log_block_size: u32,    /* Block size */
+    s_log_cluster_size: u32,  /* Allocation cluster size */
     /*20*/ s_blocks_per_group: u32,   /* # Blocks per group */
     s_clusters_per_group: u32, /* # Clusters per group */
     s_inodes_per_group: u32,   /* # Inodes per group */
@@ -149,8 +169,16 @@ buffer_struct! { Ext4SuperBlock {
     s_checksum: u32, /* crc32c(superblock) */
 }}
 impl Ext4SuperBlock {
+    /// `EXT2_DYNAMIC_REV`, see [`Ext4SuperBlock::make_revision_0`] for the original revision.
+    const REV_LEVEL: u32 = 1;
+    /// The seed and the algorithm of the hashes that HTree directories are indexed by.
+    pub const HASH_SEED: [u32; 4] = [940062939, 3880703204, 772543626, 1391354066];
+    pub const DEF_HASH_VERSION: DirHashVersion = DirHashVersion::HalfMd4;
+
     pub fn new(uuid: [u8; 16], inodes_per_group: u32) -> Self {
         Ext4SuperBlock {
+            s_log_block_size: 2,
+            s_log_cluster_size: 2,
             s_blocks_per_group: 32768,
             s_clusters_per_group: 32768,
             s_inodes_per_group: inodes_per_group,
@@ -164,24 +192,26 @@ impl Ext4SuperBlock {
             s_minor_rev_level: 0,
             s_lastcheck: 1758215058,
             s_checkinterval: 0,
-            s_rev_level: 1,
+            s_rev_level: Self::REV_LEVEL,
             s_def_resuid: 0,
             s_def_resgid: 0,
             s_first_ino: 11,
             s_inode_size: 256,
             s_block_group_nr: 0,
-            s_feature_compat: 0x0038 | 0x0200,   /* sparse_super2 */
+            s_feature_compat: 0x0038,
             s_feature_incompat: 0x02c2 | 0x8000, /* inline_data */
-            s_feature_ro_compat: 0x046a,
+            // sparse_super, large_file, huge_file, dir_nlink, extra_isize and metadata_csum.
+            // metadata_csum replaces gdt_csum (0x0010), the kernel refuses images with both.
+            s_feature_ro_compat: 0x0001 | 0x0002 | 0x0008 | 0x0020 | 0x0040 | 0x0400,
             s_uuid: uuid,
-            s_hash_seed: [940062939, 3880703204, 772543626, 1391354066],
-            s_def_hash_version: 1,
+            s_hash_seed: Self::HASH_SEED,
+            s_def_hash_version: Self::DEF_HASH_VERSION as u8,
             s_default_mount_opts: 0x000c,
             s_first_meta_bg: 0,
             s_mkfs_time: 1758215058,
             s_min_extra_isize: 32,
             s_want_extra_isize: 32,
-            s_flags: 1,
+            s_flags: 1, /* signed_directory_hash */
             s_log_groups_per_flex: 4,
             s_kbytes_written: 9,
             ..Default::default()
@@ -200,10 +230,288 @@ impl Ext4SuperBlock {
         s_free_blocks_count_hi,
         s_free_blocks_count_lo
     );
+    hi_lo_field_u64!(
+        reserved_blocks_count,
+        set_reserved_blocks_count,
+        s_r_blocks_count_hi,
+        s_r_blocks_count_lo
+    );
+    /// The user and group that may use the reserved blocks besides root.
+    pub fn set_default_reserved_owner(&mut self, uid: u16, gid: u16) {
+        self.s_def_resuid = uid;
+        self.s_def_resgid = gid;
+    }
+
+    pub fn default_reserved_owner(&self) -> (u16, u16) {
+        (self.s_def_resuid, self.s_def_resgid)
+    }
+
     pub fn set_free_inodes_count(&mut self, count: u32) {
         self.s_free_inodes_count = count;
     }
 
+    pub fn free_inodes_count(&self) -> u32 {
+        self.s_free_inodes_count
+    }
+
+    pub fn inodes_count(&self) -> u32 {
+        self.s_inodes_count
+    }
+
+    /// The block group this copy of the superblock is stored in, 0 for the primary superblock.
+    #[cfg(test)]
+    pub fn block_group_nr(&self) -> u16 {
+        self.s_block_group_nr
+    }
+    pub fn set_block_group_nr(&mut self, block_group: u16) {
+        self.s_block_group_nr = block_group;
+    }
+
+    pub fn set_first_ino(&mut self, first_ino: u32) {
+        self.s_first_ino = first_ino;
+    }
+
+    pub fn volume_name(&self) -> &str {
+        self.s_volume_name.as_str()
+    }
+    pub fn set_volume_name(&mut self, name: &str) {
+        self.s_volume_name = StaticLenString::from_str(name);
+    }
+
+    /// Record whether directory hashes treat filename bytes as signed (`EXT2_FLAGS_SIGNED_HASH`) or unsigned (`EXT2_FLAGS_UNSIGNED_HASH`) chars.
+    pub fn set_directory_hash_signedness(&mut self, signed: bool) {
+        self.s_flags &= !0x3;
+        self.s_flags |= if signed { 0x1 } else { 0x2 };
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.s_flags
+    }
+
+    pub fn hash_seed(&self) -> [u32; 4] {
+        self.s_hash_seed
+    }
+
+    pub fn feature_incompat(&self) -> u32 {
+        self.s_feature_incompat
+    }
+
+    pub fn first_ino(&self) -> u32 {
+        self.s_first_ino
+    }
+
+    pub fn log_groups_per_flex(&self) -> u8 {
+        self.s_log_groups_per_flex
+    }
+
+    /// Inodes of 128 bytes end before `i_extra_isize`, so they have neither nanosecond timestamps nor room
+    /// for extended attributes. This disables the features that depend on them (inline_data and extra_isize).
+    pub fn set_inode_size(&mut self, inode_size: u16) {
+        self.s_inode_size = inode_size;
+        if inode_size == 128 {
+            self.s_feature_incompat &= !0x8000; /* inline_data */
+            self.s_feature_ro_compat &= !0x0040; /* extra_isize */
+            self.s_min_extra_isize = 0;
+            self.s_want_extra_isize = 0;
+        }
+    }
+
+    pub fn inode_size(&self) -> u64 {
+        self.s_inode_size as u64
+    }
+
+    /// Restrict the features to those of ext2: no extents, inline data, checksums, 64 bit descriptors,
+    /// flex_bg or any of the other ext4 additions. The inode size is set separately.
+    pub fn make_ext2_compatible(&mut self) {
+        self.s_feature_incompat = 0x0002; /* filetype */
+        self.s_feature_ro_compat = 0x0001 | 0x0002; /* sparse_super, large_file */
+        // s_desc_size and s_checksum_type keep their values, they are ignored without 64bit and metadata_csum
+        self.s_min_extra_isize = 0;
+        self.s_want_extra_isize = 0;
+        self.s_log_groups_per_flex = 0;
+    }
+
+    /// Downgrade an ext2 superblock (see [`Ext4SuperBlock::make_ext2_compatible`]) to `EXT2_GOOD_OLD_REV`,
+    /// which has no feature flags at all. Like `mke2fs -r 0`, `s_first_ino` and `s_inode_size` keep
+    /// the values that revision 0 implies (11 and 128).
+    pub fn make_revision_0(&mut self) {
+        self.s_rev_level = 0;
+        self.s_feature_compat = 0;
+        self.s_feature_incompat = 0;
+        self.s_feature_ro_compat = 0;
+    }
+
+    #[cfg(test)]
+    pub fn rev_level(&self) -> u32 {
+        self.s_rev_level
+    }
+
+    #[cfg(test)]
+    pub fn feature_ro_compat(&self) -> u32 {
+        self.s_feature_ro_compat
+    }
+
+    /// Whether the superblock, descriptors, bitmaps, inodes and directory and extent blocks carry checksums.
+    pub fn has_metadata_csum(&self) -> bool {
+        self.s_feature_ro_compat & 0x0400 != 0
+    }
+
+    /// Check that the image uses exactly the layout and features of [`Ext4SuperBlock::new`],
+    /// apart from the ones that can be toggled (flex_bg, encrypt and shared_blocks).
+    pub fn check_written_by_this_crate(&self) -> io::Result<()> {
+        self.check_block_size()?;
+        let mut expected = Self::new(self.s_uuid, self.s_inodes_per_group);
+        expected.set_block_size(self.block_size());
+        expected.set_inode_size(self.s_inode_size);
+        let toggled_compat = 0x0004; /* has_journal */
+        let toggled_incompat = 0x0200 | 0x10000 | 0x20000; /* flex_bg, encrypt, casefold */
+        let toggled_ro_compat = 0x4000 | 0x0100 | 0x2000; /* shared_blocks, quota, project */
+        if self.s_rev_level != expected.s_rev_level
+            || self.s_log_block_size != expected.s_log_block_size
+            || self.s_first_data_block != expected.s_first_data_block
+            || self.s_inode_size != expected.s_inode_size
+            || self.s_blocks_per_group != expected.s_blocks_per_group
+            || self.s_feature_compat & !toggled_compat
+                != expected.s_feature_compat & !toggled_compat
+            || self.s_feature_ro_compat & !toggled_ro_compat
+                != expected.s_feature_ro_compat & !toggled_ro_compat
+            || self.s_feature_incompat & !toggled_incompat
+                != expected.s_feature_incompat & !toggled_incompat
+        {
+            return Err(io::Error::other(
+                "the image uses features that this crate does not write",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Data blocks may be used by several files, i.e. files with identical contents.
+    /// e2fsck leaves them alone, the kernel only mounts such images read-only.
+    pub fn enable_shared_blocks(&mut self) {
+        self.s_feature_ro_compat |= 0x4000; /* shared_blocks */
+    }
+
+    pub fn has_shared_blocks(&self) -> bool {
+        self.s_feature_ro_compat & 0x4000 != 0
+    }
+
+    /// Point the superblock at the internal journal in `journal_inode` (inode 8) and keep a backup of its
+    /// extent tree root and size in `s_jnl_blocks`, like mkfs does.
+    pub fn enable_journal(&mut self, journal_inode: &Ext4Inode) {
+        self.s_feature_compat |= 0x0004; /* has_journal */
+        self.s_journal_inum = 8;
+        self.s_jnl_backup_type = 1; /* EXT3_JNL_BACKUP_BLOCKS */
+        for (backup, word) in self
+            .s_jnl_blocks
+            .iter_mut()
+            .zip(journal_inode.block().chunks(4))
+        {
+            *backup = u32::read_buffer(word);
+        }
+        self.s_jnl_blocks[15] = (journal_inode.size() >> 32) as u32;
+        self.s_jnl_blocks[16] = journal_inode.size() as u32;
+    }
+
+    pub fn has_journal(&self) -> bool {
+        self.s_feature_compat & 0x0004 != 0
+    }
+
+    pub fn enable_encryption(&mut self) {
+        self.s_feature_incompat |= 0x10000; /* encrypt */
+    }
+
+    /// Enable case-insensitive directories with the `utf8-12.1` encoding in non-strict mode,
+    /// so names that are not valid UTF-8 are still allowed.
+    pub fn enable_casefold(&mut self) {
+        self.s_feature_incompat |= 0x20000; /* casefold */
+        self.s_encoding = 1; /* EXT4_ENC_UTF8_12_1 */
+        self.s_encoding_flags = 0;
+    }
+
+    pub fn has_casefold(&self) -> bool {
+        self.s_feature_incompat & 0x20000 != 0
+    }
+
+    /// Point the superblock at the quota files for users, groups and, with the `project` feature, projects.
+    pub fn enable_quota(&mut self, usr_inode: u32, grp_inode: u32, prj_inode: Option<u32>) {
+        self.s_feature_ro_compat |= 0x0100; /* quota */
+        self.s_usr_quota_inum = usr_inode;
+        self.s_grp_quota_inum = grp_inode;
+        if let Some(prj_inode) = prj_inode {
+            self.enable_project();
+            self.s_prj_quota_inum = prj_inode;
+        }
+    }
+
+    /// Inodes carry a project id in `i_projid`.
+    pub fn enable_project(&mut self) {
+        self.s_feature_ro_compat |= 0x2000; /* project */
+    }
+
+    pub fn has_huge_file(&self) -> bool {
+        self.s_feature_ro_compat & 0x0008 != 0
+    }
+
+    pub fn has_project(&self) -> bool {
+        self.s_feature_ro_compat & 0x2000 != 0
+    }
+
+    pub fn has_quota(&self) -> bool {
+        self.s_feature_ro_compat & 0x0100 != 0
+    }
+
+    /// The inodes of the user, group and project quota files by quota type, 0 for none.
+    pub fn quota_inodes(&self) -> [u32; 3] {
+        [
+            self.s_usr_quota_inum,
+            self.s_grp_quota_inum,
+            self.s_prj_quota_inum,
+        ]
+    }
+
+    /// Setting this to `0` disables the flex_bg feature.
+    pub fn set_log_groups_per_flex(&mut self, log2: u8) {
+        self.s_log_groups_per_flex = log2;
+        if log2 == 0 {
+            self.s_feature_incompat &= !0x0200; /* flex_bg */
+        } else {
+            self.s_feature_incompat |= 0x0200;
+        }
+    }
+
+    /// Without sparse_super, every group holds a backup of the superblock and the GDT.
+    pub fn has_sparse_super(&self) -> bool {
+        self.s_feature_ro_compat & 0x0001 != 0
+    }
+
+    /// Whether `block_group` holds the superblock or one of its backups, see [`Ext4SuperBlock::has_super`].
+    pub fn group_has_super(&self, block_group: u64) -> bool {
+        !self.has_sparse_super() || Self::has_super(block_group)
+    }
+
+    pub fn has_resize_inode(&self) -> bool {
+        self.s_feature_compat & 0x0010 != 0
+    }
+
+    /// Directory entries record the file type of their inode.
+    pub fn has_filetype(&self) -> bool {
+        self.s_feature_incompat & 0x0002 != 0
+    }
+
+    /// The size of the block group descriptors: 64 bytes with the `64bit` feature and 32 bytes without.
+    pub fn desc_size(&self) -> u16 {
+        if self.s_feature_incompat & 0x80 != 0 {
+            /* 64bit */
+            self.s_desc_size
+        } else {
+            32
+        }
+    }
+
+    pub fn reserved_gdt_blocks(&self) -> u16 {
+        self.s_reserved_gdt_blocks
+    }
+
     pub fn set_reserved_gdt_blocks(&mut self, count: u16) {
         self.s_reserved_gdt_blocks = count;
     }
@@ -218,19 +526,71 @@ impl Ext4SuperBlock {
     }
 
     pub fn block_groups_count(&self) -> u32 {
-        let blocks_count = self.blocks_count() as u32;
+        let blocks_count = self.blocks_count() as u32 - self.s_first_data_block;
         let blocks_per_group = self.s_blocks_per_group;
         blocks_count.div_ceil(blocks_per_group)
     }
 
-    #[cfg(test)]
+    /// Use blocks of `block_size` bytes (1024, 2048 or 4096), each block group covering the blocks of one bitmap block.
+    /// With 1024 byte blocks the superblock occupies block 1, so the first block group starts there.
+    pub fn set_block_size(&mut self, block_size: u64) {
+        let log_block_size = (block_size / 1024).trailing_zeros();
+        self.s_log_block_size = log_block_size;
+        self.s_log_cluster_size = log_block_size;
+        self.s_blocks_per_group = (block_size * 8) as u32;
+        self.s_clusters_per_group = (block_size * 8) as u32;
+        self.s_first_data_block = (block_size == 1024) as u32;
+    }
+
+    /// The block size in bytes. Only 1024, 2048 and 4096 are supported, see [`Ext4SuperBlock::check_block_size`].
+    pub fn block_size(&self) -> u64 {
+        1024 << self.s_log_block_size
+    }
+
+    pub fn check_block_size(&self) -> io::Result<()> {
+        if self.s_log_block_size > 2 {
+            return Err(io::Error::other(format!(
+                "unsupported block size 2^{}",
+                10 + self.s_log_block_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// The block that holds the superblock and where the first block group starts: 1 for 1024 byte blocks, 0 otherwise.
+    pub fn first_data_block(&self) -> u64 {
+        self.s_first_data_block as u64
+    }
+
     pub fn uuid(&self) -> &[u8; 16] {
         &self.s_uuid
     }
 
+    /// With sparse_super, the superblock and the GDT are backed up in group 1 and the groups that are powers of 3, 5 and 7
+    /// (group 0 holds the primary copy).
+    pub fn has_super(block_group: u64) -> bool {
+        let is_power_of = |base: u64| {
+            let mut n = block_group;
+            while n > 1 && n.is_multiple_of(base) {
+                n /= base;
+            }
+            n == 1
+        };
+        block_group <= 1 || is_power_of(3) || is_power_of(5) || is_power_of(7)
+    }
+
+    #[cfg(test)]
+    pub fn checksum_seed(&self) -> u32 {
+        self.s_checksum_seed
+    }
+
     pub fn update_checksum(&mut self) {
         self.s_checksum = calculate_checksum![&self.as_bytes()[0..1020]];
     }
+
+    pub fn checksum_matches(&self) -> bool {
+        self.s_checksum == calculate_checksum![&self.as_bytes()[0..1020]]
+    }
 }
 
 buffer_struct! { Ext4BlockGroupDescriptor {
@@ -245,7 +605,7 @@ buffer_struct! { Ext4BlockGroupDescriptor {
     bg_block_bitmap_csum_lo: u16, /* crc32c(s_uuid+grp_num+bbitmap) LE */
     bg_inode_bitmap_csum_lo: u16, /* crc32c(s_uuid+grp_num+ibitmap) LE */
     bg_itable_unused_lo: u16,     /* Unused inodes count */
-    bg_checksum: u16,             /* crc16(sb_uuid+group+desc) */
+    bg_checksum: u16,             /* crc16(sb_uuid+group+desc), with metadata_csum the low 16 bits of crc32c */
     bg_block_bitmap_hi: u32,      /* Blocks bitmap block MSB */
     bg_inode_bitmap_hi: u32,      /* Inodes bitmap block MSB */
     bg_inode_table_hi: u32,       /* Inodes table block MSB */
@@ -259,6 +619,10 @@ buffer_struct! { Ext4BlockGroupDescriptor {
     bg_reserved: u32,
 } }
 impl Ext4BlockGroupDescriptor {
+    pub const INODE_UNINIT: u16 = 0x1; // inode table and bitmap are not initialized
+    pub const BLOCK_UNINIT: u16 = 0x2; // block bitmap is not initialized
+    pub const INODE_ZEROED: u16 = 0x4; // on-disk inode table is zeroed
+
     hi_lo_field_u64!(
         block_bitmap,
         set_block_bitmap,
@@ -307,40 +671,324 @@ impl Ext4BlockGroupDescriptor {
         bg_used_dirs_count_hi,
         bg_used_dirs_count_lo
     );
+    hi_lo_field_u32!(
+        itable_unused,
+        set_itable_unused,
+        bg_itable_unused_hi,
+        bg_itable_unused_lo
+    );
 
-    pub fn update_checksums(
+    pub fn flags(&self) -> u16 {
+        self.bg_flags
+    }
+
+    pub fn set_flags(&mut self, flags: u16) {
+        self.bg_flags = flags;
+    }
+
+    /// Store the checksums of the bitmaps, call [`Self::update_descriptor_checksum`] afterwards.
+    pub fn set_bitmap_checksums(
         &mut self,
         uuid: &[u8; 16],
-        n: u32,
         block_bitmap: &BitmapBlock,
         inode_bitmap: &BitmapBlock,
+        desc_size: u16,
     ) {
-        self.set_block_bitmap_csum(calculate_checksum![uuid, &block_bitmap.data]);
+        self.set_block_bitmap_csum(calculate_checksum![uuid, block_bitmap.as_bytes()]);
         self.set_inode_bitmap_csum(calculate_checksum![
             uuid,
             &inode_bitmap.data[0..inode_bitmap.len.div_ceil(8) as usize]
         ]);
-        self.bg_checksum = calculate_checksum!(uuid, &n.to_le_bytes(), &self.as_bytes()) as u16;
+        if desc_size < Self::SIZE as u16 {
+            // 32 byte descriptors only have room for the low halves
+            self.bg_block_bitmap_csum_hi = 0;
+            self.bg_inode_bitmap_csum_hi = 0;
+        }
+    }
+
+    /// Compute `bg_checksum` over the first `desc_size` bytes, as only those are stored on disk.
+    pub fn update_descriptor_checksum(&mut self, uuid: &[u8; 16], n: u32, desc_size: u16) {
+        self.bg_checksum = 0;
+        self.bg_checksum = calculate_checksum!(
+            uuid,
+            &n.to_le_bytes(),
+            &self.as_bytes()[..desc_size as usize]
+        ) as u16;
+    }
+}
+
+/// The superblock of an internal jbd2 journal, which is stored big-endian in the first block of the journal.
+/// It describes an empty journal (`s_start` is 0), so there is nothing to replay when the image is mounted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalSuperBlock {
+    pub block_size: u32,
+    /// The number of blocks of the journal, including the superblock.
+    pub max_len: u32,
+    pub uuid: [u8; 16],
+    /// Whether the journal uses 64 bit block numbers and `CSUM_V3` checksums, like the ext4 images we write.
+    pub ext4_features: bool,
+}
+impl JournalSuperBlock {
+    pub const MAGIC: u32 = 0xC03B3998;
+    const BLOCKTYPE_SUPERBLOCK_V2: u32 = 4;
+    const INCOMPAT_64BIT: u32 = 0x2;
+    const INCOMPAT_CSUM_V3: u32 = 0x10;
+    const CHECKSUM_TYPE_CRC32C: u8 = 4;
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; 1024];
+        let mut put = |offset: usize, value: u32| {
+            buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+        };
+        put(0x00, Self::MAGIC);
+        put(0x04, Self::BLOCKTYPE_SUPERBLOCK_V2);
+        put(0x0C, self.block_size);
+        put(0x10, self.max_len);
+        put(0x14, 1); // s_first: the log starts right after the superblock
+        put(0x18, 1); // s_sequence of the first transaction
+        put(0x40, 1); // s_nr_users: only this filesystem
+        if self.ext4_features {
+            put(0x28, Self::INCOMPAT_64BIT | Self::INCOMPAT_CSUM_V3);
+        }
+        buf[0x30..0x40].copy_from_slice(&self.uuid);
+        if self.ext4_features {
+            buf[0x50] = Self::CHECKSUM_TYPE_CRC32C;
+            let checksum: u32 = calculate_checksum![&buf];
+            buf[0xFC..0x100].copy_from_slice(&checksum.to_be_bytes());
+        }
+        buf
+    }
+
+    /// Check the magic, the block type and the checksum of a serialized journal superblock and parse it.
+    pub fn parse(buf: &[u8]) -> io::Result<Self> {
+        let get = |offset: usize| u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap());
+        if get(0x00) != Self::MAGIC || get(0x04) != Self::BLOCKTYPE_SUPERBLOCK_V2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid journal superblock",
+            ));
+        }
+        let journal = JournalSuperBlock {
+            block_size: get(0x0C),
+            max_len: get(0x10),
+            uuid: buf[0x30..0x40].try_into().unwrap(),
+            ext4_features: get(0x28) & Self::INCOMPAT_CSUM_V3 != 0,
+        };
+        if journal.as_bytes() != buf[..1024] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the journal superblock is not the empty journal this crate writes",
+            ));
+        }
+        Ok(journal)
+    }
+}
+
+/// The inodes and bytes that one user, group or project uses, as recorded in a [`QuotaFile`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub inodes: u64,
+    /// In bytes, that is `i_blocks * 512` summed up.
+    pub space: u64,
+}
+
+/// A quota file in the `vfsv1` format of the kernel and e2fsprogs: a header, followed by a radix tree of 1 KiB
+/// blocks that maps ids byte by byte to data blocks holding the entries. Only usage is recorded, no limits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaFile {
+    /// 0 for user, 1 for group and 2 for project quota.
+    pub quota_type: usize,
+    pub usage: BTreeMap<u32, QuotaUsage>,
+}
+impl QuotaFile {
+    const MAGICS: [u32; 3] = [0xd9c01f11, 0xd9c01927, 0xd9c03f14];
+    const VERSION: u32 = 1;
+    const BLOCK_SIZE: usize = 1024;
+    /// The root of the tree follows the header block.
+    const TREE_ROOT: u32 = 1;
+    const TREE_DEPTH: usize = 4;
+    const DATA_HEADER_SIZE: usize = 16;
+    const ENTRY_SIZE: usize = 72;
+    const ENTRIES_PER_BLOCK: usize = (Self::BLOCK_SIZE - Self::DATA_HEADER_SIZE) / Self::ENTRY_SIZE;
+    /// The default grace period of a week, for blocks and inodes.
+    const GRACE_PERIOD: u32 = 7 * 24 * 60 * 60;
+
+    /// An upper bound for the size in bytes of a quota file with `ids` entries.
+    pub fn max_size(ids: usize) -> u64 {
+        let blocks = 2 + (Self::TREE_DEPTH - 1) * ids + ids.div_ceil(Self::ENTRIES_PER_BLOCK);
+        (blocks * Self::BLOCK_SIZE) as u64
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut blocks = vec![[0u8; Self::BLOCK_SIZE]; 2];
+        let get = |block: &[u8; Self::BLOCK_SIZE], index: usize| {
+            u32::from_le_bytes(block[index * 4..index * 4 + 4].try_into().unwrap())
+        };
+        // the data block that is being filled and its number of entries
+        let mut data: Option<(u32, usize)> = None;
+        for (&id, usage) in &self.usage {
+            let (data_block, entries) = match data {
+                Some((block, entries)) if entries < Self::ENTRIES_PER_BLOCK => (block, entries),
+                _ => {
+                    blocks.push([0u8; Self::BLOCK_SIZE]);
+                    (blocks.len() as u32 - 1, 0)
+                }
+            };
+            let mut node = Self::TREE_ROOT;
+            for depth in 0..Self::TREE_DEPTH {
+                let index = (id >> ((Self::TREE_DEPTH - depth - 1) * 8)) as usize & 0xff;
+                let next = match get(&blocks[node as usize], index) {
+                    0 if depth == Self::TREE_DEPTH - 1 => data_block,
+                    0 => {
+                        blocks.push([0u8; Self::BLOCK_SIZE]);
+                        blocks.len() as u32 - 1
+                    }
+                    next => next,
+                };
+                blocks[node as usize][index * 4..index * 4 + 4]
+                    .copy_from_slice(&next.to_le_bytes());
+                node = next;
+            }
+
+            let block = &mut blocks[data_block as usize];
+            block[8..10].copy_from_slice(&(entries as u16 + 1).to_le_bytes()); // dqdh_entries
+            let entry = &mut block[Self::DATA_HEADER_SIZE + entries * Self::ENTRY_SIZE..]
+                [..Self::ENTRY_SIZE];
+            entry[0..4].copy_from_slice(&id.to_le_bytes());
+            entry[24..32].copy_from_slice(&usage.inodes.to_le_bytes()); // dqb_curinodes
+            entry[48..56].copy_from_slice(&usage.space.to_le_bytes()); // dqb_curspace
+            if entry.iter().all(|&b| b == 0) {
+                entry[64..72].copy_from_slice(&1u64.to_le_bytes()); // an all zero entry counts as unused
+            }
+            data = Some((data_block, entries + 1));
+        }
+
+        // only a data block that is not full yet is on the list of blocks with free entries
+        let free_entry = match data {
+            Some((block, entries)) if entries < Self::ENTRIES_PER_BLOCK => block,
+            _ => 0,
+        };
+        let header = &mut blocks[0];
+        for (i, value) in [
+            Self::MAGICS[self.quota_type],
+            Self::VERSION,
+            Self::GRACE_PERIOD,
+            Self::GRACE_PERIOD,
+            0, // dqi_flags
+            0, // dqi_blocks, set below
+            0, // dqi_free_blk: there are no free blocks
+            free_entry,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            header[i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        let num_blocks = blocks.len() as u32;
+        blocks[0][20..24].copy_from_slice(&num_blocks.to_le_bytes());
+        blocks.concat()
+    }
+
+    /// Check the header of a serialized quota file of `quota_type` and collect the entries of its tree.
+    pub fn parse(buf: &[u8], quota_type: usize) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let get = |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+        if buf.len() < 2 * Self::BLOCK_SIZE
+            || get(0) != Self::MAGICS[quota_type]
+            || get(4) != Self::VERSION
+        {
+            return Err(invalid("invalid quota file header".to_string()));
+        }
+        let num_blocks = get(20);
+        if num_blocks as usize * Self::BLOCK_SIZE > buf.len() {
+            return Err(invalid(format!(
+                "the quota file has {} blocks but is only {} bytes large",
+                num_blocks,
+                buf.len()
+            )));
+        }
+        let mut usage = BTreeMap::new();
+        let mut pending = vec![(Self::TREE_ROOT, 0)];
+        let mut data_blocks = BTreeSet::new();
+        while let Some((node, depth)) = pending.pop() {
+            if node < Self::TREE_ROOT || node >= num_blocks {
+                return Err(invalid(format!("invalid quota tree reference {}", node)));
+            }
+            let offset = node as usize * Self::BLOCK_SIZE;
+            if depth == Self::TREE_DEPTH {
+                if data_blocks.insert(node) {
+                    for i in 0..Self::ENTRIES_PER_BLOCK {
+                        let entry = &buf[offset + Self::DATA_HEADER_SIZE + i * Self::ENTRY_SIZE..]
+                            [..Self::ENTRY_SIZE];
+                        if entry.iter().all(|&b| b == 0) {
+                            continue;
+                        }
+                        let get_u64 =
+                            |at: usize| u64::from_le_bytes(entry[at..at + 8].try_into().unwrap());
+                        let id = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+                        let entry_usage = QuotaUsage {
+                            inodes: get_u64(24),
+                            space: get_u64(48),
+                        };
+                        if usage.insert(id, entry_usage).is_some() {
+                            return Err(invalid(format!(
+                                "id {} has more than one quota entry",
+                                id
+                            )));
+                        }
+                    }
+                }
+                continue;
+            }
+            for index in 0..256 {
+                let next = get(offset + index * 4);
+                if next != 0 {
+                    pending.push((next, depth + 1));
+                }
+            }
+        }
+        Ok(QuotaFile { quota_type, usage })
     }
 }
 
+/// A block or inode bitmap that fills a whole block, with the bits past `len` set as padding.
 pub struct BitmapBlock {
-    data: [u8; 4096],
+    data: Vec<u8>,
     len: u32,
 }
 impl BitmapBlock {
-    pub fn from_bytes(data: &[u8], len: u32) -> Self {
-        assert!(len <= 4096 * 8);
+    pub fn from_bytes(data: &[u8], len: u32, block_size: u64) -> Self {
+        assert!(len as u64 <= block_size * 8);
         let mut block = BitmapBlock {
-            data: [0u8; 4096],
+            data: vec![0u8; block_size as usize],
             len,
         };
         block.data[0..data.len()].copy_from_slice(data);
-        for i in len..(4096 * 8) {
+        for i in len..(block_size * 8) as u32 {
             block.set_bit(i);
         }
         block
     }
+    /// Read a bitmap from disk without touching the padding bits, so that they can be validated.
+    /// `buf` has to be exactly one block.
+    pub fn read_with_len(buf: &[u8], len: u32) -> Self {
+        assert!(len as usize <= buf.len() * 8);
+        BitmapBlock {
+            data: buf.to_vec(),
+            len,
+        }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+    /// Check that all bits past `len` are set, as e2fsck requires for the last block group.
+    pub fn validate_padding(&self) -> bool {
+        (self.len..(self.data.len() * 8) as u32)
+            .all(|i| self.data[(i / 8) as usize] & (1 << (i % 8)) != 0)
+    }
+    pub fn is_set(&self, n: u32) -> bool {
+        self.data[(n / 8) as usize] & (1 << (n % 8)) != 0
+    }
     pub fn set_bit(&mut self, n: u32) {
         let byte = (n / 8) as usize;
         let bit = n % 8;
@@ -383,18 +1031,6 @@ impl Debug for BitmapBlock {
         Ok(())
     }
 }
-impl Buffer<4096> for BitmapBlock {
-    fn read_buffer(buf: &[u8]) -> Self {
-        let mut data = [0u8; 4096];
-        data.copy_from_slice(&buf[0..4096]);
-        BitmapBlock { data, len: 4096 }
-    }
-
-    fn write_buffer(&self, buf: &mut [u8]) {
-        buf[0..4096].copy_from_slice(&self.data);
-    }
-}
-
 buffer_struct! { Ext4Inode {
     i_mode: u16,               /* File mode */
     i_uid: u16,                /* Low 16 bits of Owner Uid */
@@ -428,14 +1064,15 @@ buffer_struct! { Ext4Inode {
     i_crtime_extra: u32, /* extra FileCreationtime (nsec << 2 | epoch) */
     i_version_hi: u32,   /* high 32 bits for 64-bit version */
     i_projid: u32,       /* Project ID */
-    rest: [u8; 96] = [0; 96],
+    rest: [u8; 352] = [0; 352], /* in-inode xattrs, up to the end of 512 byte inodes */
 } }
 impl Ext4Inode {
+    /// An inode with extents but no blocks accounted for yet, see [`Ext4Inode::add_blocks`].
     pub fn new(size: u64, extents: impl Buffer<60>, ty: FileType) -> Self {
         let mut inode = Ext4Inode::default();
         inode.set_file_type(ty);
         inode.i_links_count = 1;
-        inode.update_size(size);
+        inode.set_size(size);
         extents.write_buffer(&mut inode.i_block);
         inode.i_flags = 0x80000; // EXT4_EXTENTS_FLAG
         inode
@@ -443,11 +1080,48 @@ impl Ext4Inode {
     hi_lo_field_u64!(size, set_size, i_size_high, i_size_lo);
     hi_lo_field_u48!(blocks, set_blocks, i_blocks_high, i_blocks_lo);
     hi_lo_field_u32!(checksum, set_checksum, i_checksum_hi, i_checksum_lo);
+    hi_lo_field_u32!(uid, set_uid, i_uid_high, i_uid);
+    hi_lo_field_u32!(gid, set_gid, i_gid_high, i_gid);
+
+    /// Only stored in inodes of more than 128 bytes.
+    pub fn projid(&self) -> u32 {
+        self.i_projid
+    }
+
+    pub fn set_projid(&mut self, projid: u32) {
+        self.i_projid = projid;
+    }
 
+    const XATTR_MAGIC: u32 = 0xEA020000;
+    pub const IMMUTABLE_FL: u32 = 0x10; // EXT4_IMMUTABLE_FL (`chattr +i`)
+    pub const APPEND_FL: u32 = 0x20; // EXT4_APPEND_FL (`chattr +a`)
+    /// `i_blocks` counts filesystem blocks instead of 512 byte sectors (with the huge_file feature).
+    pub(crate) const HUGE_FILE_FL: u32 = 0x40000; // EXT4_HUGE_FILE_FL
+    /// Set on directories with a project id, so that new entries inherit it (`chattr +P`).
+    pub(crate) const PROJINHERIT_FL: u32 = 0x20000000; // EXT4_PROJINHERIT_FL
+    /// Set on directories with [`crate::Ext4ImageWriter::set_casefold`] (`chattr +F`).
+    pub(crate) const CASEFOLD_FL: u32 = 0x40000000; // EXT4_CASEFOLD_FL
+    /// The flags that can be set with [`crate::Ext4ImageWriter::set_inode_flags`].
+    pub(crate) const SETTABLE_FLAGS: u32 = Self::IMMUTABLE_FL | Self::APPEND_FL;
+    /// The size of inodes unless configured otherwise. Smaller inodes are written as a prefix of [`Ext4Inode::SIZE`].
+    pub const DEFAULT_SIZE: u64 = 256;
     pub const MAX_INLINE_SIZE_BLOCK: usize = 60; // 60 bytes in i_block
-    pub const MAX_INLINE_SIZE_XATTR: usize = 96 - Ext4ExtAttrEntryData::SIZE as usize - 4 - 4; // rest - xattr header
+    /// The inline data capacity of the `system.data` xattr for inodes of [`Ext4Inode::DEFAULT_SIZE`].
+    pub const MAX_INLINE_SIZE_XATTR: usize = Self::max_inline_size_xattr(Self::DEFAULT_SIZE);
+    /// The inline data capacity of the `system.data` xattr for inodes of `inode_size` bytes.
+    pub const fn max_inline_size_xattr(inode_size: u64) -> usize {
+        let xattr_area = inode_size as usize - 128 - 32; // after the base inode and i_extra_isize
+        xattr_area - 4 - Ext4ExtAttrEntry::SIZE as usize - 4 - 4 // xattr header - entry with name "data" - end marker
+    }
     pub const MAX_INLINE_SIZE: usize = Self::MAX_INLINE_SIZE_BLOCK + Self::MAX_INLINE_SIZE_XATTR;
-    pub fn with_inline_data(block_data: &[u8], xattr_data: &[u8], ty: FileType) -> Self {
+    /// An inline data inode of `inode_size` bytes, `xattr_data` may take up to
+    /// [`Ext4Inode::max_inline_size_xattr`] of it.
+    pub fn with_inline_data(
+        block_data: &[u8],
+        xattr_data: &[u8],
+        ty: FileType,
+        inode_size: u64,
+    ) -> Self {
         let mut inode = Ext4Inode::default();
 
         inode.set_file_type(ty);
@@ -455,64 +1129,310 @@ impl Ext4Inode {
         inode.set_size((block_data.len() + xattr_data.len()) as u64);
 
         assert!(block_data.len() <= Self::MAX_INLINE_SIZE_BLOCK);
-        assert!(xattr_data.len() <= Self::MAX_INLINE_SIZE_XATTR);
+        assert!(xattr_data.len() <= Self::max_inline_size_xattr(inode_size));
         if block_data.len() < inode.i_block.len() {
             assert!(xattr_data.is_empty());
         }
 
         inode.i_flags |= 0x10000000; // EXT4_INLINE_DATA_FL
         inode.i_block[..block_data.len()].copy_from_slice(block_data);
+        inode
+            .write_xattrs(&[Ext4Xattr::new(7, b"data", xattr_data)], inode_size)
+            .unwrap();
 
-        let xattr_magic: u32 = 0xEA020000;
-        inode.rest[0..4].copy_from_slice(&xattr_magic.to_le_bytes());
-        let xattr = Ext4ExtAttrEntryData {
-            e_value_offs: (Ext4ExtAttrEntryData::SIZE + 4).try_into().unwrap(),
-            e_value_size: xattr_data.len().try_into().unwrap(),
-            ..Default::default()
-        };
-        xattr.write_buffer(&mut inode.rest[4..]);
-        let offset = 4 + 4 + Ext4ExtAttrEntryData::SIZE as usize;
-        inode.rest[offset..(offset + xattr_data.len())].copy_from_slice(xattr_data);
+        inode
+    }
+
+    /// Targets up to this length are stored as fast symlinks (see [`Ext4Inode::fast_symlink`]).
+    pub const MAX_FAST_SYMLINK_LEN: usize = 59;
+    /// A fast symlink stores its target in `i_block`. Unlike files it has neither extents nor inline data,
+    /// so the extents flag is cleared and `i_blocks` stays 0.
+    pub fn fast_symlink(target: &[u8]) -> Self {
+        assert!(target.len() <= Self::MAX_FAST_SYMLINK_LEN);
+        let mut inode = Ext4Inode::default();
+        inode.set_file_type(FileType::SymbolicLink);
+        inode.i_links_count = 1;
+        inode.set_size(target.len() as u64);
+        inode.i_block[..target.len()].copy_from_slice(target);
+        inode
+    }
 
+    /// A character or block device, FIFO or socket. These have neither data nor extents.
+    /// Device numbers are stored in `i_block` like the kernel does: numbers that fit into 8 bits each use
+    /// the old 16 bit encoding in the first word, others the new 32 bit encoding in the second word.
+    pub fn special_file(ty: FileType, major: u32, minor: u32) -> Self {
+        let mut inode = Ext4Inode::default();
+        inode.set_file_type(ty);
+        inode.i_links_count = 1;
+        if major < 256 && minor < 256 {
+            inode.i_block[0..4].copy_from_slice(&((major << 8) | minor).to_le_bytes());
+        } else {
+            let encoded = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+            inode.i_block[4..8].copy_from_slice(&encoded.to_le_bytes());
+        }
         inode
     }
 
-    pub fn update_size(&mut self, size: u64) {
-        self.set_size(size);
-        let blocks = size.div_ceil(BLOCK_SIZE);
-        self.set_blocks(blocks * 8); // TODO: is this correct?
+    /// The `(major, minor)` device number of a device inode, the inverse of [`Ext4Inode::special_file`].
+    pub fn device_number(&self) -> (u32, u32) {
+        let old = u32::read_buffer(&self.i_block[0..4]);
+        if old != 0 {
+            return ((old >> 8) & 0xff, old & 0xff);
+        }
+        let new = u32::read_buffer(&self.i_block[4..8]);
+        ((new & 0xfff00) >> 8, (new & 0xff) | ((new >> 12) & 0xfff00))
+    }
+
+    /// Account for `count` more blocks of `block_size` bytes that the inode owns: its data blocks, but also
+    /// the blocks of its extent tree or block map and its xattr block. `i_blocks` counts 512 byte sectors.
+    /// Inline data and fast symlinks own no blocks at all.
+    /// Once the sectors don't fit into the 48 bits of `i_blocks` anymore, it counts blocks and
+    /// `EXT4_HUGE_FILE_FL` is set. Files with 32 bit logical block numbers never get that large,
+    /// but the kernel would read a wrapped count as a tiny file.
+    pub fn add_blocks(&mut self, count: u64, block_size: u64) {
+        let sectors = self.sectors(block_size) + count * (block_size / 512);
+        if sectors < 1 << 48 {
+            self.set_blocks(sectors);
+        } else {
+            self.add_flags(Self::HUGE_FILE_FL);
+            self.set_blocks(sectors / (block_size / 512));
+        }
+    }
+
+    /// The number of 512 byte sectors the inode owns, regardless of the unit of `i_blocks`.
+    pub fn sectors(&self, block_size: u64) -> u64 {
+        if self.flags() & Self::HUGE_FILE_FL != 0 {
+            self.blocks() * (block_size / 512)
+        } else {
+            self.blocks()
+        }
     }
 
     pub fn update_checksum(&mut self, uuid: &[u8; 16], n: u32) {
+        self.update_checksum_with_size(uuid, n, Self::DEFAULT_SIZE);
+    }
+
+    /// Like [`Ext4Inode::update_checksum`] for an inode that is written as its first `inode_size` bytes.
+    /// 128 byte inodes only have room for the lower 16 bits of the checksum.
+    pub(crate) fn update_checksum_with_size(&mut self, uuid: &[u8; 16], n: u32, inode_size: u64) {
         self.set_checksum(0);
         self.set_checksum(calculate_checksum![
             uuid,
             &n.to_le_bytes(),
             &self.i_generation.to_le_bytes(),
-            &self.as_bytes()
+            &self.as_bytes()[..inode_size as usize]
         ]);
         let ext4_inode_csum_hi_extra_end = 18;
-        let has_hi = self.i_extra_isize >= ext4_inode_csum_hi_extra_end;
+        let has_hi = inode_size > 128 && self.i_extra_isize >= ext4_inode_csum_hi_extra_end;
         if !has_hi {
             self.i_checksum_hi = 0;
         }
     }
 
+    /// Reassemble the contents of an inline data inode from `i_block` and the `system.data` xattr.
+    /// This is the inverse of [`Ext4Inode::with_inline_data`].
+    pub fn inline_data(&self) -> io::Result<Vec<u8>> {
+        let size = self.size() as usize;
+        let block_len = size.min(Self::MAX_INLINE_SIZE_BLOCK);
+        let mut data = self.i_block[..block_len].to_vec();
+        if size > block_len {
+            let xattr = self.xattr(7, b"data")?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "inline data inode has no system.data xattr",
+                )
+            })?;
+            if xattr.len() != size - block_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "inline data xattr does not match the inode size",
+                ));
+            }
+            data.extend_from_slice(&xattr);
+        }
+        Ok(data)
+    }
+
+    /// Parse the extended attributes stored in the inode body.
+    pub fn xattrs(&self) -> io::Result<Vec<Ext4Xattr>> {
+        if u32::read_buffer(&self.rest[0..4]) != Self::XATTR_MAGIC {
+            return Ok(vec![]);
+        }
+        // in the inode body, the value offsets are relative to the first entry
+        Ext4Xattr::parse_entries(&self.rest[4..], &self.rest[4..])
+    }
+
+    /// The value of the in-inode xattr with the given name index and name (if present).
+    pub fn xattr(&self, name_index: u8, name: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(self
+            .xattrs()?
+            .into_iter()
+            .find(|xattr| xattr.name_index == name_index && xattr.name == name)
+            .map(|xattr| xattr.value))
+    }
+
+    /// Add an extended attribute to the body of an inode of `inode_size` bytes, replacing one with the same name.
+    /// Fails if it does not fit.
+    pub fn add_xattr(&mut self, xattr: Ext4Xattr, inode_size: u64) -> io::Result<()> {
+        let mut xattrs = self.xattrs()?;
+        xattrs.retain(|other| (other.name_index, &other.name) != (xattr.name_index, &xattr.name));
+        xattrs.push(xattr);
+        self.write_xattrs(&xattrs, inode_size)
+    }
+
+    /// Lay out all entries first, followed by the end marker and then the (4 byte aligned) values.
+    fn write_xattrs(&mut self, xattrs: &[Ext4Xattr], inode_size: u64) -> io::Result<()> {
+        let entries_len: usize = xattrs
+            .iter()
+            .map(|xattr| (Ext4ExtAttrEntry::SIZE as usize + xattr.name.len()).next_multiple_of(4))
+            .sum();
+        let values_len: usize = xattrs
+            .iter()
+            .map(|xattr| xattr.value.len().next_multiple_of(4))
+            .sum();
+        // the xattr area starts after the base inode and i_extra_isize
+        let xattr_area = (inode_size as usize).saturating_sub(128 + 32);
+        if 4 + entries_len + 4 + values_len > xattr_area {
+            return Err(io::Error::other(
+                "extended attributes do not fit into the inode",
+            ));
+        }
+        self.rest.fill(0);
+        self.rest[0..4].copy_from_slice(&Self::XATTR_MAGIC.to_le_bytes());
+        let entries = &mut self.rest[4..];
+        let mut entry_offset = 0;
+        let mut value_offset = entries_len + 4;
+        for xattr in xattrs {
+            let entry = Ext4ExtAttrEntry {
+                e_name_len: xattr.name.len().try_into().unwrap(),
+                e_name_index: xattr.name_index,
+                e_value_offs: value_offset.try_into().unwrap(),
+                e_value_size: xattr.value.len().try_into().unwrap(),
+                ..Default::default()
+            };
+            entry.write_buffer(&mut entries[entry_offset..]);
+            let name_start = entry_offset + Ext4ExtAttrEntry::SIZE as usize;
+            entries[name_start..name_start + xattr.name.len()].copy_from_slice(&xattr.name);
+            entries[value_offset..value_offset + xattr.value.len()].copy_from_slice(&xattr.value);
+            entry_offset = (name_start + xattr.name.len()).next_multiple_of(4);
+            value_offset += xattr.value.len().next_multiple_of(4);
+        }
+        Ok(())
+    }
+
+    /// Store an fscrypt context as the `encryption.c` xattr and mark the inode as encrypted.
+    pub fn set_encryption_context(&mut self, context: &[u8], inode_size: u64) -> io::Result<()> {
+        self.add_xattr(Ext4Xattr::new(9, b"c", context), inode_size)?; // EXT4_XATTR_INDEX_ENCRYPTION
+        self.add_flags(0x800); // EXT4_ENCRYPT_FL
+        Ok(())
+    }
+
+    pub fn block(&self) -> &[u8] {
+        &self.i_block
+    }
     pub fn block_mut(&mut self) -> &mut [u8] {
         &mut self.i_block
     }
+    pub fn links_count(&self) -> u16 {
+        self.i_links_count
+    }
     pub fn set_links_count(&mut self, count: u16) {
         self.i_links_count = count
     }
-    pub fn set_mode(&mut self, mode: u16) {
-        self.i_mode = (self.i_mode & 0xf000) | (mode & 0x0fff);
+    pub fn generation(&self) -> u32 {
+        self.i_generation
     }
-    pub fn set_file_type(&mut self, file_type: FileType) {
+    /// The block that holds the extended attributes which don't fit into the inode body (0 if there is none).
+    pub fn file_acl(&self) -> u64 {
+        ((self.i_file_acl_high as u64) << 32) | self.i_file_acl_lo as u64
+    }
+    pub fn set_file_acl(&mut self, block: u64) {
+        self.i_file_acl_lo = block as u32;
+        self.i_file_acl_high = (block >> 32) as u16;
+    }
+    pub fn set_timestamps(&mut self, timestamps: Timestamps) {
+        (self.i_atime, self.i_atime_extra) = timestamps.atime.encode();
+        (self.i_mtime, self.i_mtime_extra) = timestamps.mtime.encode();
+        (self.i_ctime, self.i_ctime_extra) = timestamps.ctime.encode();
+        (self.i_crtime, self.i_crtime_extra) =
+            timestamps.crtime.unwrap_or(timestamps.mtime).encode();
+    }
+    pub fn timestamps(&self) -> Timestamps {
+        Timestamps {
+            atime: Timestamp::decode(self.i_atime, self.i_atime_extra),
+            mtime: Timestamp::decode(self.i_mtime, self.i_mtime_extra),
+            ctime: Timestamp::decode(self.i_ctime, self.i_ctime_extra),
+            crtime: Some(Timestamp::decode(self.i_crtime, self.i_crtime_extra)),
+        }
+    }
+    #[cfg(test)]
+    pub fn mtime(&self) -> (u32, u32) {
+        (self.i_mtime, self.i_mtime_extra)
+    }
+    #[cfg(test)]
+    pub fn crtime(&self) -> (u32, u32) {
+        (self.i_crtime, self.i_crtime_extra)
+    }
+    pub fn mode(&self) -> u16 {
+        self.i_mode & 0x0fff
+    }
+    pub fn set_mode(&mut self, mode: u16) {
+        self.i_mode = (self.i_mode & 0xf000) | (mode & 0x0fff);
+    }
+    pub fn set_file_type(&mut self, file_type: FileType) {
         self.i_mode = (self.i_mode & 0x0fff) | file_type.as_mode();
     }
+    pub fn file_type(&self) -> FileType {
+        FileType::from_mode(self.i_mode)
+    }
     pub fn is_directory(&self) -> bool {
         (self.i_mode & 0xf000) == FileType::Directory.as_mode()
     }
+    pub fn flags(&self) -> u32 {
+        self.i_flags
+    }
+    pub fn add_flags(&mut self, flags: u32) {
+        self.i_flags |= flags;
+    }
+    pub fn has_inline_data(&self) -> bool {
+        self.i_flags & 0x10000000 != 0 // EXT4_INLINE_DATA_FL
+    }
+    pub fn storage_mode(&self, block_size: u64) -> StorageMode {
+        if self.has_inline_data() {
+            StorageMode::Inline
+        } else if self.i_flags & 0x80000 == 0 {
+            // EXT4_EXTENTS_FL
+            let xattr_blocks = if self.file_acl() != 0 {
+                block_size / 512
+            } else {
+                0
+            };
+            if self.blocks() == xattr_blocks {
+                StorageMode::NoData
+            } else {
+                StorageMode::BlockMap
+            }
+        } else if Ext4ExtentHeader::read_buffer(&self.i_block).eh_depth == 0 {
+            StorageMode::InlineExtents
+        } else {
+            StorageMode::IndirectExtents
+        }
+    }
+}
+
+/// Where the contents of an inode are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageMode {
+    /// In `i_block` and the `system.data` xattr.
+    Inline,
+    /// In data blocks referenced by up to four extents in `i_block`.
+    InlineExtents,
+    /// In data blocks referenced by an extent tree of separate leaf (and index) blocks.
+    IndirectExtents,
+    /// Neither inline data nor extents, i.e. bare empty files and fast symlinks.
+    NoData,
+    /// In data blocks referenced by direct and indirect block pointers in `i_block`, like ext2 does.
+    BlockMap,
 }
 
 #[allow(dead_code)]
@@ -540,6 +1460,18 @@ impl FileType {
             FileType::Socket => 0xC000,          // S_IFSOCK
         }
     }
+    pub fn from_mode(mode: u16) -> Self {
+        match mode & 0xf000 {
+            0x1000 => FileType::Fifo,
+            0x2000 => FileType::CharacterDevice,
+            0x4000 => FileType::Directory,
+            0x6000 => FileType::BlockDevice,
+            0x8000 => FileType::RegularFile,
+            0xA000 => FileType::SymbolicLink,
+            0xC000 => FileType::Socket,
+            _ => FileType::Null,
+        }
+    }
     pub fn as_directory_entry_type(&self) -> u8 {
         match self {
             FileType::Null => 0,
@@ -554,16 +1486,203 @@ impl FileType {
     }
 }
 
-buffer_struct! { Ext4ExtAttrEntryData {
-    e_name_len: u8 = 4,	    /* length of name */
-    e_name_index: u8 = 7,	/* attribute name index */
-    e_value_offs: u16 = 20,	/* offset of the value relative to the first entry */
-    e_value_inum: u32 = 0,	/* inode in which the value is stored */
-    e_value_size: u32,	    /* size of attribute value */
-    e_hash: u32 = 0,		/* hash value of name and value */
-    e_name: [u8; 4] = [0x64, 0x61, 0x74, 0x61],	/* attribute name = "data" */
+buffer_struct! { Ext4ExtAttrEntry {
+    e_name_len: u8,     /* length of name */
+    e_name_index: u8,   /* attribute name index */
+    e_value_offs: u16,  /* offset of the value relative to the first entry */
+    e_value_inum: u32,  /* inode in which the value is stored */
+    e_value_size: u32,  /* size of attribute value */
+    e_hash: u32,        /* hash value of name and value */
 } }
 
+/// An extended attribute like `system.data` (name index 7, name `data`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ext4Xattr {
+    pub name_index: u8,
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+impl Ext4Xattr {
+    /// The prefixes of the full attribute names and the name indices they are stored with (`fs/ext4/xattr.h`).
+    /// The ACLs have no name besides their prefix.
+    const PREFIXES: [(&'static str, u8); 7] = [
+        ("system.posix_acl_access", 2),
+        ("system.posix_acl_default", 3),
+        ("system.richacl", 8),
+        ("user.", 1),
+        ("trusted.", 4),
+        ("security.", 6),
+        ("system.", 7),
+    ];
+
+    pub fn new(name_index: u8, name: &[u8], value: &[u8]) -> Self {
+        Ext4Xattr {
+            name_index,
+            name: name.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
+    /// Resolve a full attribute name like `user.comment` into its name index and the rest of the name.
+    /// Returns `None` for unknown namespaces.
+    pub fn with_full_name(name: &str, value: &[u8]) -> Option<Self> {
+        Self::PREFIXES.iter().find_map(|&(prefix, name_index)| {
+            let rest = name.strip_prefix(prefix)?;
+            (rest.is_empty() != prefix.ends_with('.'))
+                .then(|| Self::new(name_index, rest.as_bytes(), value))
+        })
+    }
+
+    /// The inverse of [`Ext4Xattr::with_full_name`]. Returns `None` for internal attributes without a
+    /// namespace prefix, i.e. the encryption context.
+    pub fn full_name(&self) -> Option<String> {
+        let (prefix, _) = Self::PREFIXES
+            .iter()
+            .find(|&&(_, name_index)| name_index == self.name_index)?;
+        Some(format!("{}{}", prefix, String::from_utf8_lossy(&self.name)))
+    }
+
+    /// The hash of the name and the value as computed by `ext4_xattr_hash_entry`.
+    fn hash(&self) -> u32 {
+        let mut hash = 0u32;
+        for &c in &self.name {
+            hash = hash.rotate_left(5) ^ c as u32;
+        }
+        for chunk in self.value.chunks(4) {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            hash = hash.rotate_left(16) ^ u32::from_le_bytes(word);
+        }
+        hash
+    }
+
+    /// Parse the entries up to the end marker. The value offsets of the entries point into `values`.
+    fn parse_entries(entries: &[u8], values: &[u8]) -> io::Result<Vec<Ext4Xattr>> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut xattrs = vec![];
+        let mut offset = 0;
+        while offset + 4 <= entries.len() && u32::read_buffer(&entries[offset..offset + 4]) != 0 {
+            if offset + Ext4ExtAttrEntry::SIZE as usize > entries.len() {
+                return Err(invalid("xattr entry exceeds its inode or block"));
+            }
+            let entry = Ext4ExtAttrEntry::read_buffer(&entries[offset..]);
+            let name_start = offset + Ext4ExtAttrEntry::SIZE as usize;
+            let name_end = name_start + entry.e_name_len as usize;
+            let value_start = entry.e_value_offs as usize;
+            let value_end = value_start + entry.e_value_size as usize;
+            if name_end > entries.len() || value_end > values.len() {
+                return Err(invalid("xattr entry exceeds its inode or block"));
+            }
+            xattrs.push(Ext4Xattr::new(
+                entry.e_name_index,
+                &entries[name_start..name_end],
+                &values[value_start..value_end],
+            ));
+            offset = name_end.next_multiple_of(4);
+        }
+        Ok(xattrs)
+    }
+}
+
+buffer_struct! { Ext4ExtAttrHeader {
+    h_magic: u32 = 0xEA020000, /* magic number for identification */
+    h_refcount: u32,           /* reference count */
+    h_blocks: u32,             /* number of disk blocks used */
+    h_hash: u32,               /* hash value of all attributes */
+    h_checksum: u32,           /* crc32c(uuid+id+xattrblock) */
+    h_reserved: [u32; 3],
+} }
+impl Ext4ExtAttrHeader {
+    const CHECKSUM_OFFSET: usize = 16;
+
+    /// Lay out an xattr block for a single inode: the entries sorted like the kernel expects them,
+    /// followed by the end marker, and the values packed at the end of the block.
+    /// Returns `None` if the attributes don't fit. Without `fs_uuid` the checksum is left empty (i.e. for ext2).
+    pub fn create_block(
+        xattrs: &[Ext4Xattr],
+        block: u64,
+        fs_uuid: Option<&[u8; 16]>,
+        block_size: u64,
+    ) -> Option<Vec<u8>> {
+        let mut xattrs: Vec<&Ext4Xattr> = xattrs.iter().collect();
+        xattrs.sort_by(|a, b| {
+            (a.name_index, a.name.len(), &a.name).cmp(&(b.name_index, b.name.len(), &b.name))
+        });
+        let entries_len: usize = xattrs
+            .iter()
+            .map(|xattr| (Ext4ExtAttrEntry::SIZE as usize + xattr.name.len()).next_multiple_of(4))
+            .sum();
+        let values_len: usize = xattrs
+            .iter()
+            .map(|xattr| xattr.value.len().next_multiple_of(4))
+            .sum();
+        if Self::SIZE as usize + entries_len + 4 + values_len > block_size as usize {
+            return None;
+        }
+
+        let mut buf = vec![0u8; block_size as usize];
+        let mut block_hash = 0u32;
+        let mut entry_offset = Self::SIZE as usize;
+        let mut value_offset = block_size as usize;
+        for xattr in xattrs {
+            value_offset -= xattr.value.len().next_multiple_of(4);
+            let entry = Ext4ExtAttrEntry {
+                e_name_len: xattr.name.len().try_into().unwrap(),
+                e_name_index: xattr.name_index,
+                e_value_offs: value_offset.try_into().unwrap(),
+                e_value_size: xattr.value.len().try_into().unwrap(),
+                e_hash: xattr.hash(),
+                ..Default::default()
+            };
+            entry.write_buffer(&mut buf[entry_offset..]);
+            let name_start = entry_offset + Ext4ExtAttrEntry::SIZE as usize;
+            buf[name_start..name_start + xattr.name.len()].copy_from_slice(&xattr.name);
+            buf[value_offset..value_offset + xattr.value.len()].copy_from_slice(&xattr.value);
+            entry_offset = (name_start + xattr.name.len()).next_multiple_of(4);
+            block_hash = block_hash.rotate_left(16) ^ entry.e_hash; // ext4_xattr_rehash
+        }
+        Ext4ExtAttrHeader {
+            h_refcount: 1,
+            h_blocks: 1,
+            h_hash: block_hash,
+            ..Default::default()
+        }
+        .write_buffer(&mut buf);
+        if let Some(fs_uuid) = fs_uuid {
+            let checksum = Self::block_checksum(&buf, block, fs_uuid);
+            buf[Self::CHECKSUM_OFFSET..Self::CHECKSUM_OFFSET + 4]
+                .copy_from_slice(&checksum.to_le_bytes());
+        }
+        Some(buf)
+    }
+
+    /// The checksum of the xattr block `data` at `block`, computed as if `h_checksum` was 0.
+    pub fn block_checksum(data: &[u8], block: u64, fs_uuid: &[u8; 16]) -> u32 {
+        calculate_checksum![
+            fs_uuid,
+            &block.to_le_bytes(),
+            &data[..Self::CHECKSUM_OFFSET],
+            &[0; 4],
+            &data[Self::CHECKSUM_OFFSET + 4..]
+        ]
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.h_checksum
+    }
+
+    /// Whether the block is only used by a single inode and consists of one block, like the ones written by this crate.
+    pub fn is_private(&self) -> bool {
+        self.h_refcount == 1 && self.h_blocks == 1
+    }
+
+    /// Parse the attributes of an xattr block.
+    pub fn parse_block(data: &[u8]) -> io::Result<Vec<Ext4Xattr>> {
+        Self::read_buffer(data).check_magic()?;
+        Ext4Xattr::parse_entries(&data[Self::SIZE as usize..], data)
+    }
+}
+
 buffer_struct! { LegacyBlockDescriptor {
     direct: [u32; 12],
     indirect: u32,
@@ -577,10 +1696,32 @@ impl LegacyBlockDescriptor {
             ..Default::default()
         }
     }
-    pub fn maximum_addressable_size() -> u64 {
-        let direct = 12 * BLOCK_SIZE;
-        let indirect = (BLOCK_SIZE / 8) * BLOCK_SIZE;
-        let double_indirect = (BLOCK_SIZE / 8) * (BLOCK_SIZE / 8) * BLOCK_SIZE;
+    /// Map a file with the given `direct` blocks (at most 12) and the roots of its indirect block trees.
+    pub fn with_pointers(direct: &[u32], indirect: [u32; 3]) -> Self {
+        let mut descriptor = LegacyBlockDescriptor {
+            indirect: indirect[0],
+            double_indirect: indirect[1],
+            triple_indirect: indirect[2],
+            ..Default::default()
+        };
+        descriptor.direct[..direct.len()].copy_from_slice(direct);
+        descriptor
+    }
+    pub fn direct(&self) -> &[u32; 12] {
+        &self.direct
+    }
+    /// The roots of the single, double and triple indirect block trees.
+    pub fn indirect(&self) -> [u32; 3] {
+        [self.indirect, self.double_indirect, self.triple_indirect]
+    }
+    #[cfg(test)]
+    pub fn double_indirect(&self) -> u32 {
+        self.double_indirect
+    }
+    pub fn maximum_addressable_size(block_size: u64) -> u64 {
+        let direct = 12 * block_size;
+        let indirect = (block_size / 8) * block_size;
+        let double_indirect = (block_size / 8) * (block_size / 8) * block_size;
         direct + indirect + double_indirect
     }
 }
@@ -590,27 +1731,15 @@ buffer_struct! { Ext4InlineExtents {
     extents: [Ext4ExtentLeafNode; 4],
 } }
 impl Ext4InlineExtents {
-    pub const MAX_INLINE_BLOCKS: u64 = Ext4ExtentLeafNode::MAX_LEN as u64 * 4; // we can represent up to 4 extents, each with a maximum length of 65535 blocks
-    pub fn new(allocation: Allocation) -> Self {
-        let blocks = allocation.end - allocation.start;
-        assert!(blocks <= Self::MAX_INLINE_BLOCKS);
-        let extents_needed = blocks.div_ceil(Ext4ExtentLeafNode::MAX_LEN as u64);
+    pub const MAX_EXTENTS: usize = 4;
+    pub fn new(leaves: &[Ext4ExtentLeafNode]) -> Self {
+        assert!(leaves.len() <= Self::MAX_EXTENTS);
         let mut extents = [Ext4ExtentLeafNode::default(); 4];
-        for i in 0..extents_needed {
-            let len = if i == extents_needed - 1 {
-                u16::try_from(blocks - i * (Ext4ExtentLeafNode::MAX_LEN as u64)).unwrap()
-            } else {
-                Ext4ExtentLeafNode::MAX_LEN
-            };
-            let start = allocation.start + i * (Ext4ExtentLeafNode::MAX_LEN as u64);
-            extents[i as usize].set_start(start);
-            extents[i as usize].ee_len = len;
-            extents[i as usize].ee_block = (i * (Ext4ExtentLeafNode::MAX_LEN as u64)) as u32;
-        }
+        extents[..leaves.len()].copy_from_slice(leaves);
 
         Ext4InlineExtents {
             header: Ext4ExtentHeader {
-                eh_entries: extents_needed.try_into().unwrap(),
+                eh_entries: leaves.len() as u16,
                 ..Default::default()
             },
             extents,
@@ -630,59 +1759,114 @@ buffer_struct! { Ext4IndirectExtents {
     extents: [Ext4ExtentInternalNode; 4],
 } }
 impl Ext4IndirectExtents {
+    /// The deepest extent tree the kernel accepts (`EXT4_MAX_EXTENT_DEPTH`).
+    pub const MAX_DEPTH: u16 = 5;
+
+    /// A leaf block of an extent tree that holds the given extents.
     pub fn create_block(
-        allocation: Allocation,
+        leaves: &[Ext4ExtentLeafNode],
         inode_num: u32,
         fs_uuid: &[u8; 16],
-    ) -> [u8; BLOCK_SIZE as usize] {
-        let blocks = allocation.end - allocation.start;
-        let extents_needed = blocks.div_ceil(Ext4ExtentLeafNode::MAX_LEN as u64);
-        assert!(
-            Ext4ExtentHeader::SIZE + extents_needed * Ext4ExtentLeafNode::SIZE + 4 /* checksum */
-                <= BLOCK_SIZE
-        );
-        let mut buf = [0u8; BLOCK_SIZE as usize];
+        block_size: u64,
+    ) -> Vec<u8> {
+        Self::create_node(leaves, 0, inode_num, fs_uuid, block_size)
+    }
+
+    /// An index block at `depth` (1 right above the leaf blocks) that points at the given child blocks,
+    /// each with the first logical block it maps.
+    pub fn create_index_block(
+        children: &[(u32, u64)],
+        depth: u16,
+        inode_num: u32,
+        fs_uuid: &[u8; 16],
+        block_size: u64,
+    ) -> Vec<u8> {
+        assert!(depth > 0);
+        let entries: Vec<_> = children
+            .iter()
+            .map(|&(logical_block, block)| Self::index_entry(logical_block, block))
+            .collect();
+        Self::create_node(&entries, depth, inode_num, fs_uuid, block_size)
+    }
+
+    fn create_node<E: Buffer<12>>(
+        entries: &[E],
+        depth: u16,
+        inode_num: u32,
+        fs_uuid: &[u8; 16],
+        block_size: u64,
+    ) -> Vec<u8> {
+        assert!(entries.len() <= Self::max_leaves(block_size));
+        let mut buf = vec![0u8; block_size as usize];
         let header = Ext4ExtentHeader {
-            eh_entries: extents_needed.try_into().unwrap(),
-            eh_max: ((BLOCK_SIZE - Ext4ExtentHeader::SIZE - 4) / Ext4ExtentLeafNode::SIZE) as u16,
-            eh_depth: 1,
+            eh_entries: entries.len().try_into().unwrap(),
+            eh_max: Self::max_leaves(block_size) as u16,
+            eh_depth: depth,
             ..Default::default()
         };
         header.write_buffer(&mut buf);
-        for i in 0..extents_needed {
-            let len = if i == extents_needed - 1 {
-                u16::try_from(blocks - i * (Ext4ExtentLeafNode::MAX_LEN as u64)).unwrap()
-            } else {
-                Ext4ExtentLeafNode::MAX_LEN
-            };
-            let start = allocation.start + i * (Ext4ExtentLeafNode::MAX_LEN as u64);
-            let mut extent = Ext4ExtentLeafNode::default();
-            extent.ee_block = (i * (Ext4ExtentLeafNode::MAX_LEN as u64)) as u32;
-            extent.ee_len = len;
-            extent.set_start(start);
-            let start_offset =
-                Ext4ExtentHeader::SIZE as usize + i as usize * Ext4ExtentLeafNode::SIZE as usize;
-            extent.write_buffer(&mut buf[start_offset..]);
-        }
-        let checksum_offset = BLOCK_SIZE as usize - 4;
-        let inode_generation: u32 = 0;
-        let checksum = calculate_checksum![
+        for (i, entry) in entries.iter().enumerate() {
+            let start_offset = Ext4ExtentHeader::SIZE as usize + i * E::SIZE as usize;
+            entry.write_buffer(&mut buf[start_offset..]);
+        }
+        let checksum_offset = block_size as usize - 4;
+        let checksum = Self::block_checksum(&buf[0..checksum_offset], inode_num, 0, fs_uuid);
+        buf[checksum_offset..].copy_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+
+    fn index_entry(logical_block: u32, block: u64) -> Ext4ExtentInternalNode {
+        let mut entry = Ext4ExtentInternalNode {
+            ei_block: logical_block,
+            ..Default::default()
+        };
+        entry.set_leaf(block);
+        entry
+    }
+
+    /// How many blocks an extent tree with `extents` extents needs besides the root in the inode.
+    pub fn tree_blocks(extents: u64, block_size: u64) -> u64 {
+        let mut blocks = 0;
+        let mut level = extents;
+        while level > Ext4InlineExtents::MAX_EXTENTS as u64 {
+            level = level.div_ceil(Self::max_leaves(block_size) as u64);
+            blocks += level;
+        }
+        blocks
+    }
+
+    /// How many extents (or index entries) fit into a tree block, after the header and before the checksum tail.
+    pub fn max_leaves(block_size: u64) -> usize {
+        ((block_size - Ext4ExtentHeader::SIZE - 4) / Ext4ExtentLeafNode::SIZE) as usize
+    }
+
+    /// The checksum of an extent tree block, computed over everything before the tail (`node`).
+    pub fn block_checksum(
+        node: &[u8],
+        inode_num: u32,
+        inode_generation: u32,
+        fs_uuid: &[u8; 16],
+    ) -> u32 {
+        calculate_checksum![
             fs_uuid,
             &inode_num.to_le_bytes(),
             &inode_generation.to_le_bytes(),
-            &buf[0..checksum_offset]
-        ];
-        buf[checksum_offset..].copy_from_slice(&checksum.to_le_bytes());
-        buf
+            node
+        ]
     }
 
-    pub fn new(block: u64) -> Self {
+    /// The root of a tree whose top level of blocks are the given `children` (at most 4), each with the first
+    /// logical block it maps. `depth` is the number of levels of blocks below the root.
+    pub fn new(children: &[(u32, u64)], depth: u16) -> Self {
+        assert!(children.len() <= Ext4InlineExtents::MAX_EXTENTS && depth > 0);
         let mut extents = [Ext4ExtentInternalNode::default(); 4];
-        extents[0].set_leaf(block);
+        for (extent, &(logical_block, block)) in extents.iter_mut().zip(children) {
+            *extent = Self::index_entry(logical_block, block);
+        }
         Ext4IndirectExtents {
             header: Ext4ExtentHeader {
-                eh_entries: 1,
-                eh_depth: 1,
+                eh_entries: children.len() as u16,
+                eh_depth: depth,
                 ..Default::default()
             },
             extents,
@@ -697,6 +1881,22 @@ buffer_struct! { Ext4ExtentHeader {
     eh_depth: u16,          /* has tree real underlying blocks? */
     eh_generation: u32 = 0, /* generation of the tree */
 } }
+impl Ext4ExtentHeader {
+    pub const MAGIC: u16 = 0xF30A;
+    /// Unlike [`CheckMagic::check_magic`] this accepts any capacity, as nodes in separate blocks hold more than 4 entries.
+    pub fn has_valid_magic(&self) -> bool {
+        self.eh_magic == Self::MAGIC
+    }
+    pub fn max_entries(&self) -> u16 {
+        self.eh_max
+    }
+    pub fn entries(&self) -> u16 {
+        self.eh_entries
+    }
+    pub fn depth(&self) -> u16 {
+        self.eh_depth
+    }
+}
 
 buffer_struct! { Ext4ExtentInternalNode {
     ei_block: u32,      /* first logical block extent covers */
@@ -721,6 +1921,47 @@ impl_buffer_for_array!(4, Ext4ExtentLeafNode, 12);
 impl Ext4ExtentLeafNode {
     pub const MAX_LEN: u16 = 32768; // sizes bigger than this signify uninitialized extents
     hi_lo_field_u48!(start, set_start, ee_start_hi, ee_start_lo);
+    pub fn logical_block(&self) -> u32 {
+        self.ee_block
+    }
+    pub fn block_count(&self) -> u16 {
+        self.ee_len
+    }
+
+    /// Map the given allocations to consecutive logical blocks, splitting them into extents of at most `MAX_LEN` blocks.
+    pub fn from_allocations(allocations: &[Allocation]) -> Vec<Self> {
+        let mut logical_block = 0;
+        let mapped: Vec<_> = allocations
+            .iter()
+            .map(|allocation| {
+                logical_block += allocation.len();
+                (logical_block - allocation.len(), *allocation)
+            })
+            .collect();
+        Self::from_mapped(&mapped)
+    }
+
+    /// Like [`Ext4ExtentLeafNode::from_allocations`], but each allocation starts at the given logical block,
+    /// so that files can have holes between their extents.
+    pub fn from_mapped(mapped: &[(u64, Allocation)]) -> Vec<Self> {
+        let mut leaves = vec![];
+        for &(mut logical_block, allocation) in mapped {
+            let mut start = allocation.start;
+            while start < allocation.end {
+                let len = (allocation.end - start).min(Self::MAX_LEN as u64);
+                let mut leaf = Ext4ExtentLeafNode {
+                    ee_block: logical_block as u32,
+                    ee_len: len as u16,
+                    ..Default::default()
+                };
+                leaf.set_start(start);
+                leaves.push(leaf);
+                start += len;
+                logical_block += len;
+            }
+        }
+        leaves
+    }
 }
 
 buffer_struct! { Ext4DirEntryMeta {
@@ -764,11 +2005,22 @@ impl Ext4DirEntry {
     pub fn inode(&self) -> u32 {
         self.meta.inode
     }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn entry_type(&self) -> u8 {
+        self.meta.file_type
+    }
+    pub fn clear_entry_type(&mut self) {
+        self.meta.file_type = 0;
+    }
+    pub fn record_length(&self) -> u16 {
+        self.meta.rec_len
+    }
     pub fn set_record_length(&mut self, rec_len: u16) {
         self.meta.rec_len = rec_len;
     }
 
-    #[allow(dead_code)]
     pub fn read_buffer(buf: &[u8]) -> Self {
         let without_name = Ext4DirEntryMeta::read_buffer(buf);
         let name = String::from(
@@ -789,65 +2041,419 @@ buffer_struct! { Ext4DirEntryTail {
     det_checksum: u32,          /* Directory leaf block checksum. */
 } }
 
-#[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct LinearDirectoryBlock {
     entries: Vec<Ext4DirEntry>,
-    checksum: u32,
+    checksum: Option<u32>, // `None` if the block has no checksum tail (without metadata_csum)
+    size: usize,
+    used_bytes: usize, // sum of the `rec_len`s of `entries`, kept so that `fits` does not have to recompute it
+}
+impl Default for LinearDirectoryBlock {
+    fn default() -> Self {
+        Self::new(true, BLOCK_SIZE as usize)
+    }
 }
 impl LinearDirectoryBlock {
+    /// Create an empty block of `size` bytes. Without `checksums` the last entry extends to the end of the block
+    /// instead of leaving room for the [`Ext4DirEntryTail`].
+    pub fn new(checksums: bool, size: usize) -> Self {
+        LinearDirectoryBlock {
+            entries: Vec::new(),
+            checksum: checksums.then_some(0),
+            size,
+            used_bytes: 0,
+        }
+    }
     pub fn update_checksum(&mut self, uuid: &[u8; 16], inode: u32, inode_generation: u32) {
-        self.checksum = calculate_checksum![
-            uuid,
-            &inode.to_le_bytes(),
-            &inode_generation.to_le_bytes(),
-            &self.as_bytes()[0..4096 - 12]
-        ];
+        if self.checksum.is_some() {
+            self.checksum = Some(calculate_checksum![
+                uuid,
+                &inode.to_le_bytes(),
+                &inode_generation.to_le_bytes(),
+                &self.as_bytes()[0..self.size - 12]
+            ]);
+        }
+    }
+    fn entries_end(&self) -> usize {
+        match self.checksum {
+            Some(_) => self.size - Ext4DirEntryTail::SIZE as usize,
+            None => self.size,
+        }
     }
     pub fn fits(&self, entry: &Ext4DirEntry) -> bool {
-        self.entries
-            .iter()
-            .map(|e: &Ext4DirEntry| e.meta.rec_len as usize)
-            .sum::<usize>()
-            + (entry.meta.rec_len as usize + Ext4DirEntryMeta::SIZE as usize)
-            + Ext4DirEntryTail::SIZE as usize
-            <= 4096
+        self.used_bytes + (entry.meta.rec_len as usize + Ext4DirEntryMeta::SIZE as usize)
+            <= self.entries_end()
     }
     pub fn add_entry(&mut self, entry: Ext4DirEntry) {
         assert!(self.fits(&entry));
+        self.used_bytes += entry.meta.rec_len as usize;
         self.entries.push(entry);
     }
-}
-impl Buffer<4096> for LinearDirectoryBlock {
-    fn read_buffer(buf: &[u8]) -> Self {
+
+    /// Check that `buf` is a directory block with a checksum tail whose record lengths chain up exactly to the tail
+    /// and return the offset and entry of every used record (records with inode 0 are free space).
+    /// Unlike [`LinearDirectoryBlock::read_buffer`] this does not trust the input.
+    pub fn parse_raw(buf: &[u8]) -> io::Result<Vec<(usize, Ext4DirEntry)>> {
+        let tail_offset = buf.len() - Ext4DirEntryTail::SIZE as usize;
+        Ext4DirEntryTail::read_buffer(&buf[tail_offset..])
+            .check_magic()
+            .map_err(|_| io::Error::other("directory block has no checksum tail"))?;
+        Self::parse_raw_records(&buf[..tail_offset])
+    }
+
+    /// Like [`LinearDirectoryBlock::parse_raw`], but for records that chain up exactly to the end of `data`
+    /// (i.e. blocks without a checksum tail or inline directories).
+    pub fn parse_raw_records(data: &[u8]) -> io::Result<Vec<(usize, Ext4DirEntry)>> {
         let mut entries = Vec::new();
         let mut offset = 0;
-        while offset < 4096 - Ext4DirEntryTail::SIZE as usize {
+        while offset < data.len() {
+            if offset + Ext4DirEntryMeta::SIZE as usize > data.len() {
+                return Err(io::Error::other(format!(
+                    "truncated record at offset {} of directory block",
+                    offset
+                )));
+            }
+            let meta = Ext4DirEntryMeta::read_buffer(&data[offset..]);
+            let rec_len = meta.rec_len as usize;
+            let name_end = offset + Ext4DirEntryMeta::SIZE as usize + meta.name_len as usize;
+            if !rec_len.is_multiple_of(4)
+                || offset + rec_len > data.len()
+                || offset + rec_len < name_end
+            {
+                return Err(io::Error::other(format!(
+                    "invalid record length {} at offset {} of directory block",
+                    rec_len, offset
+                )));
+            }
+            if meta.inode != 0 {
+                let name = &data[offset + Ext4DirEntryMeta::SIZE as usize..name_end];
+                if name.is_empty() || std::str::from_utf8(name).is_err() {
+                    return Err(io::Error::other(format!(
+                        "invalid name at offset {} of directory block",
+                        offset
+                    )));
+                }
+                entries.push((offset, Ext4DirEntry::read_buffer(&data[offset..])));
+            }
+            offset += rec_len;
+        }
+        Ok(entries)
+    }
+
+    /// Point the leading `.` and `..` records of a serialized directory block at `inode` and `parent_inode`.
+    pub fn link_raw(buf: &mut [u8], inode: u32, parent_inode: u32) {
+        let dot_rec_len = u16::from_le_bytes([buf[4], buf[5]]) as usize;
+        buf[0..4].copy_from_slice(&inode.to_le_bytes());
+        if dot_rec_len + 4 <= buf.len() {
+            buf[dot_rec_len..dot_rec_len + 4].copy_from_slice(&parent_inode.to_le_bytes());
+        }
+    }
+
+    /// Recompute the checksum stored in the tail of a directory block that was serialized elsewhere.
+    pub fn update_raw_checksum(buf: &mut [u8], uuid: &[u8; 16], inode: u32, inode_generation: u32) {
+        let checksum = Self::raw_checksum(buf, uuid, inode, inode_generation);
+        let checksum_offset = buf.len() - 4;
+        buf[checksum_offset..].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    /// The checksum a serialized directory block should have in its tail.
+    pub fn raw_checksum(buf: &[u8], uuid: &[u8; 16], inode: u32, inode_generation: u32) -> u32 {
+        calculate_checksum![
+            uuid,
+            &inode.to_le_bytes(),
+            &inode_generation.to_le_bytes(),
+            &buf[0..buf.len() - 12]
+        ]
+    }
+
+    /// Read a whole directory block, `buf` is exactly one block.
+    #[cfg(test)]
+    pub fn read_buffer(buf: &[u8]) -> Self {
+        let tail_offset = buf.len() - Ext4DirEntryTail::SIZE as usize;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        let mut checksum = None;
+        while offset < buf.len() {
+            if offset == tail_offset {
+                let tail = Ext4DirEntryTail::read_buffer(&buf[offset..]);
+                if tail.check_magic().is_ok() {
+                    checksum = Some(tail.det_checksum);
+                    break;
+                }
+            }
             let entry = Ext4DirEntry::read_buffer(&buf[offset..]);
             offset += entry.meta.rec_len as usize;
             entries.push(entry);
         }
-        let tail = Ext4DirEntryTail::read_buffer(&buf[4096 - 12..]);
         LinearDirectoryBlock {
+            used_bytes: entries.iter().map(|e| e.meta.rec_len as usize).sum(),
             entries,
-            checksum: tail.det_checksum,
+            checksum,
+            size: buf.len(),
         }
     }
-    fn write_buffer(&self, buf: &mut [u8]) {
+    pub fn write_buffer(&self, buf: &mut [u8]) {
+        if self.entries.is_empty() {
+            // a block without entries holds a single unused record that spans it
+            let unused = Ext4DirEntryMeta {
+                inode: 0,
+                rec_len: self.entries_end().try_into().unwrap(),
+                name_len: 0,
+                file_type: 0,
+            };
+            unused.write_buffer(&mut buf[..Ext4DirEntryMeta::SIZE as usize]);
+        }
         let mut offset = 0;
         for (i, entry) in self.entries.iter().enumerate() {
             let mut entry = entry.clone();
             if i == self.entries.len() - 1 {
-                entry.meta.rec_len = (4096 - 12 - offset).try_into().unwrap();
+                entry.meta.rec_len = (self.entries_end() - offset).try_into().unwrap();
             }
             let entry_bytes = entry.as_bytes();
             buf[offset..(offset + entry_bytes.len())].copy_from_slice(&entry_bytes);
             offset += entry_bytes.len();
         }
-        let tail = Ext4DirEntryTail {
-            det_checksum: self.checksum,
+        if let Some(checksum) = self.checksum {
+            let tail = Ext4DirEntryTail {
+                det_checksum: checksum,
+                ..Default::default()
+            };
+            tail.write_buffer(&mut buf[self.size - 12..]);
+        }
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; self.size];
+        self.write_buffer(&mut buf);
+        buf
+    }
+}
+
+buffer_struct! { Ext4DxRootInfo {
+    reserved_zero: u32 = 0,
+    hash_version: u8,
+    info_length: u8 = 8,
+    indirect_levels: u8,
+    unused_flags: u8,
+} }
+
+buffer_struct! { Ext4DxCountLimit {
+    limit: u16,
+    count: u16,
+} }
+
+buffer_struct! { Ext4DxEntry {
+    hash: u32,
+    block: u32,
+} }
+
+buffer_struct! { Ext4DxTail {
+    dt_reserved: u32 = 0,
+    dt_checksum: u32,
+} }
+
+impl Ext4DxRootInfo {
+    pub fn hash_version(&self) -> u8 {
+        self.hash_version
+    }
+    pub fn indirect_levels(&self) -> u8 {
+        self.indirect_levels
+    }
+}
+
+impl Ext4DxEntry {
+    pub fn hash(&self) -> u32 {
+        self.hash
+    }
+    pub fn block(&self) -> u32 {
+        self.block
+    }
+}
+
+/// An index block of an HTree directory: either the root in the first block, which starts with the `.` and `..`
+/// entries, or an interior node, which looks like a single unused record to readers that ignore the index.
+/// Each entry points to the block holding the names that hash to at least its hash (the hash of the first entry
+/// is implied to be 0). A set lowest bit marks names with that hash that continue from the previous block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DxIndexBlock {
+    root: Option<(u32, u32, Ext4DxRootInfo)>, // the inodes of `.` and `..` and the root info
+    entries: Vec<Ext4DxEntry>,
+    checksums: bool,
+    size: usize,
+}
+impl DxIndexBlock {
+    const ROOT_HEADER_SIZE: usize = 32; // `.`, `..` and the root info
+    const NODE_HEADER_SIZE: usize = 8; // the empty record
+
+    pub fn root(
+        inode: u32,
+        parent: u32,
+        hash_version: DirHashVersion,
+        indirect_levels: u8,
+        checksums: bool,
+        size: usize,
+    ) -> Self {
+        let info = Ext4DxRootInfo {
+            hash_version: hash_version as u8,
+            indirect_levels,
+            ..Default::default()
+        };
+        DxIndexBlock {
+            root: Some((inode, parent, info)),
+            entries: Vec::new(),
+            checksums,
+            size,
+        }
+    }
+    pub fn node(checksums: bool, size: usize) -> Self {
+        DxIndexBlock {
+            root: None,
+            entries: Vec::new(),
+            checksums,
+            size,
+        }
+    }
+
+    /// How many entries a root (or an interior node) of `size` bytes can hold.
+    pub fn limit_of(root: bool, checksums: bool, size: usize) -> usize {
+        let header = if root {
+            Self::ROOT_HEADER_SIZE
+        } else {
+            Self::NODE_HEADER_SIZE
+        };
+        let tail = if checksums {
+            Ext4DxTail::SIZE as usize
+        } else {
+            0
+        };
+        (size - header - tail) / Ext4DxEntry::SIZE as usize
+    }
+    pub fn limit(&self) -> usize {
+        Self::limit_of(self.root.is_some(), self.checksums, self.size)
+    }
+    pub fn add_entry(&mut self, hash: u32, block: u32) {
+        assert!(self.entries.len() < self.limit());
+        self.entries.push(Ext4DxEntry { hash, block });
+    }
+
+    pub fn as_bytes(&self, uuid: &[u8; 16], inode: u32, inode_generation: u32) -> Vec<u8> {
+        let mut buf = vec![0u8; self.size];
+        let header_size = match &self.root {
+            Some((dot, dotdot, info)) => {
+                let mut dot = Ext4DirEntry::new(*dot, FileType::Directory, ".");
+                dot.set_record_length(12);
+                buf[..12].copy_from_slice(&dot.as_bytes());
+                // `..` covers the rest of the block, so readers without HTree support skip the index
+                let mut dotdot = Ext4DirEntry::new(*dotdot, FileType::Directory, "..");
+                dotdot.set_record_length((self.size - 12).try_into().unwrap());
+                buf[12..24].copy_from_slice(&dotdot.as_bytes()[..12]);
+                info.write_buffer(&mut buf[24..32]);
+                Self::ROOT_HEADER_SIZE
+            }
+            None => {
+                let mut empty = Ext4DirEntry::new(0, FileType::Null, "");
+                empty.set_record_length(self.size.try_into().unwrap());
+                buf[..8].copy_from_slice(&empty.as_bytes()[..8]);
+                Self::NODE_HEADER_SIZE
+            }
+        };
+        for (i, entry) in self.entries.iter().enumerate() {
+            entry.write_buffer(&mut buf[header_size + i * 8..]);
+        }
+        let count_limit = Ext4DxCountLimit {
+            limit: self.limit() as u16,
+            count: self.entries.len() as u16,
+        };
+        count_limit.write_buffer(&mut buf[header_size..]);
+        if self.checksums {
+            Self::update_raw_checksum(&mut buf, self.root.is_some(), uuid, inode, inode_generation);
+        }
+        buf
+    }
+
+    /// Check the header, count and limit of a serialized index block (`root` for the first block of the directory)
+    /// and return its root info (if `root`) and entries. The hash of the first entry is returned as 0.
+    pub fn parse_raw(
+        buf: &[u8],
+        root: bool,
+        checksums: bool,
+    ) -> io::Result<(Option<Ext4DxRootInfo>, Vec<Ext4DxEntry>)> {
+        let (info, header_size) = if root {
+            let info = Ext4DxRootInfo::read_buffer(&buf[24..32]);
+            info.check_magic()
+                .map_err(|_| io::Error::other("invalid HTree root info"))?;
+            (Some(info), Self::ROOT_HEADER_SIZE)
+        } else {
+            let empty = Ext4DirEntryMeta::read_buffer(buf);
+            if empty.inode != 0 || empty.rec_len as usize != buf.len() {
+                return Err(io::Error::other(
+                    "HTree node does not start with an empty record",
+                ));
+            }
+            (None, Self::NODE_HEADER_SIZE)
+        };
+        let count_limit = Ext4DxCountLimit::read_buffer(&buf[header_size..]);
+        let limit = Self::limit_of(root, checksums, buf.len());
+        if count_limit.limit as usize != limit {
+            return Err(io::Error::other(format!(
+                "HTree limit is {} instead of {}",
+                count_limit.limit, limit
+            )));
+        }
+        if count_limit.count == 0 || count_limit.count > count_limit.limit {
+            return Err(io::Error::other(format!(
+                "invalid HTree entry count {}",
+                count_limit.count
+            )));
+        }
+        let mut entries = (0..count_limit.count as usize)
+            .map(|i| Ext4DxEntry::read_buffer(&buf[header_size + i * 8..]))
+            .collect::<Vec<_>>();
+        entries[0].hash = 0;
+        Ok((info, entries))
+    }
+
+    /// Recompute the checksum in the [`Ext4DxTail`] of a serialized index block.
+    pub fn update_raw_checksum(
+        buf: &mut [u8],
+        root: bool,
+        uuid: &[u8; 16],
+        inode: u32,
+        inode_generation: u32,
+    ) {
+        let (tail_offset, checksum) = Self::raw_checksum(buf, root, uuid, inode, inode_generation);
+        Ext4DxTail {
+            dt_checksum: checksum,
             ..Default::default()
+        }
+        .write_buffer(&mut buf[tail_offset..]);
+    }
+
+    /// The offset of the [`Ext4DxTail`] in a serialized index block and the checksum it should contain,
+    /// which covers the header and the used entries.
+    pub fn raw_checksum(
+        buf: &[u8],
+        root: bool,
+        uuid: &[u8; 16],
+        inode: u32,
+        inode_generation: u32,
+    ) -> (usize, u32) {
+        let header_size = if root {
+            Self::ROOT_HEADER_SIZE
+        } else {
+            Self::NODE_HEADER_SIZE
         };
-        tail.write_buffer(&mut buf[4096 - 12..]);
+        let count_limit = Ext4DxCountLimit::read_buffer(&buf[header_size..]);
+        let tail_offset = header_size + count_limit.limit as usize * Ext4DxEntry::SIZE as usize;
+        let entries_end = header_size + count_limit.count as usize * Ext4DxEntry::SIZE as usize;
+        let checksum = calculate_checksum![
+            uuid,
+            &inode.to_le_bytes(),
+            &inode_generation.to_le_bytes(),
+            &buf[..entries_end.min(tail_offset)],
+            &buf[tail_offset..tail_offset + 4],
+            &[0u8; 4]
+        ];
+        (tail_offset, checksum)
     }
 }
 
@@ -855,25 +2461,24 @@ impl Buffer<4096> for LinearDirectoryBlock {
 pub struct InlineLinearDirectoryBlock {
     entries: Vec<Ext4DirEntry>,
     size: usize,
+    used_bytes: usize, // sum of the `rec_len`s of `entries`
 }
 impl InlineLinearDirectoryBlock {
     pub fn new(size: usize) -> Self {
         InlineLinearDirectoryBlock {
             entries: Vec::new(),
             size,
+            used_bytes: 0,
         }
     }
 
     pub fn fits(&self, entry: &Ext4DirEntry) -> bool {
-        self.entries
-            .iter()
-            .map(|e: &Ext4DirEntry| e.meta.rec_len as usize)
-            .sum::<usize>()
-            + (entry.meta.rec_len as usize + Ext4DirEntryMeta::SIZE as usize)
+        self.used_bytes + (entry.meta.rec_len as usize + Ext4DirEntryMeta::SIZE as usize)
             <= self.size
     }
     pub fn add_entry(&mut self, entry: Ext4DirEntry) {
         assert!(self.fits(&entry));
+        self.used_bytes += entry.meta.rec_len as usize;
         self.entries.push(entry);
     }
 
@@ -940,13 +2545,8 @@ mod tests {
         Ext4BlockGroupDescriptor::default(),
         64
     );
-    test_size_of!(
-        test_block_bitmap_size,
-        BitmapBlock::from_bytes(&[0u8; 4096], 128),
-        4096
-    );
     test_size_of!(test_single_extent_size, Ext4InlineExtents::default(), 60);
-    test_size_of!(test_inode_size, Ext4Inode::default(), 256);
+    test_size_of!(test_inode_size, Ext4Inode::default(), 512);
     test_size_of!(
         test_legacy_block_descriptor_size,
         LegacyBlockDescriptor::default(),
@@ -954,6 +2554,87 @@ mod tests {
     );
     test_size_of!(test_dir_entry_tail_size, Ext4DirEntryTail::default(), 12);
 
+    #[test]
+    fn test_xattr_names() {
+        for (name, name_index, short_name) in [
+            ("user.comment", 1, "comment"),
+            ("system.posix_acl_access", 2, ""),
+            ("trusted.overlay.opaque", 4, "overlay.opaque"),
+            ("security.selinux", 6, "selinux"),
+            ("system.data", 7, "data"),
+        ] {
+            let xattr = Ext4Xattr::with_full_name(name, b"").unwrap();
+            assert_eq!(
+                (xattr.name_index, xattr.name.as_slice()),
+                (name_index, short_name.as_bytes())
+            );
+            assert_eq!(xattr.full_name().unwrap(), name);
+        }
+        for name in ["comment", "user.", "security.", "system."] {
+            assert_eq!(Ext4Xattr::with_full_name(name, b""), None, "{}", name);
+        }
+        assert_eq!(Ext4Xattr::new(9, b"c", b"").full_name(), None);
+    }
+
+    #[test]
+    fn test_xattr_block() {
+        let uuid = [0x42; 16];
+        let xattrs = [
+            Ext4Xattr::new(6, b"selinux", b"unconfined"),
+            Ext4Xattr::new(1, b"long_name", &[1; 5]),
+            Ext4Xattr::new(1, b"short", &[2; 3000]),
+        ];
+        let block =
+            Ext4ExtAttrHeader::create_block(&xattrs, 1234, Some(&uuid), BLOCK_SIZE).unwrap();
+        let header = Ext4ExtAttrHeader::read_buffer(&block);
+        assert!(header.is_private());
+        assert_eq!(
+            header.checksum(),
+            Ext4ExtAttrHeader::block_checksum(&block, 1234, &uuid)
+        );
+        // sorted by name index, name length and name
+        let parsed = Ext4ExtAttrHeader::parse_block(&block).unwrap();
+        assert_eq!(
+            parsed,
+            [xattrs[2].clone(), xattrs[1].clone(), xattrs[0].clone()]
+        );
+        assert!(
+            Ext4ExtAttrHeader::create_block(
+                &[xattrs[2].clone(), xattrs[2].clone()],
+                0,
+                None,
+                BLOCK_SIZE
+            )
+            .is_none()
+        );
+        // a third of the value no longer fits into a 1024 byte block
+        assert!(Ext4ExtAttrHeader::create_block(&xattrs, 1234, Some(&uuid), 1024).is_none());
+    }
+
+    #[test]
+    fn test_bitmap_padding() {
+        for block_size in [1024, 2048, 4096] {
+            let bitmap = BitmapBlock::from_bytes(&[0u8; 16], 128, block_size);
+            assert_eq!(bitmap.as_bytes().len(), block_size as usize);
+            assert_eq!(bitmap.free_count(), 128);
+        }
+
+        let padded = BitmapBlock::from_bytes(&[0u8; 16], 100, BLOCK_SIZE);
+        assert!(padded.validate_padding());
+        let reread = BitmapBlock::read_with_len(padded.as_bytes(), 100);
+        assert!(reread.validate_padding());
+        assert_eq!(reread.free_count(), 100);
+
+        let mut unpadded = [0u8; 4096];
+        unpadded[..12].fill(0xff);
+        assert!(!BitmapBlock::read_with_len(&unpadded, 100).validate_padding());
+        unpadded[12] = 0xf0; // bits 96..100 free, 100.. used
+        assert!(!BitmapBlock::read_with_len(&unpadded, 100).validate_padding());
+        unpadded[13..].fill(0xff);
+        assert!(BitmapBlock::read_with_len(&unpadded, 100).validate_padding());
+        assert!(BitmapBlock::read_with_len(&[0u8; 4096], 4096 * 8).validate_padding());
+    }
+
     #[test]
     fn test_read_inline_dir_inode() {
         let buf = buffer_from_hexdump(
@@ -976,7 +2657,7 @@ mod tests {
 
         ",
         );
-        let inode = Ext4Inode::read_buffer(&buf);
+        let inode = read_inode(buf);
         dbg!(&inode);
 
         println!("{}", hexdump(&inode.i_block));
@@ -1026,12 +2707,18 @@ mod tests {
         assert_eq!(checksum, calculated_checksum);
     }
 
+    /// An inode of [`Ext4Inode::DEFAULT_SIZE`] bytes, padded to the size of [`Ext4Inode`].
+    fn read_inode(mut data: Vec<u8>) -> Ext4Inode {
+        data.resize(Ext4Inode::SIZE as usize, 0);
+        Ext4Inode::read_buffer(&data)
+    }
+
     fn open_image() -> impl FnMut(Range<u64>) -> Vec<u8> {
         let image_path = "target/example.img";
         let stamp_path = "target/example.img.stamp";
-        if !fs::exists(&image_path).unwrap() {
+        if !fs::exists(image_path).unwrap() {
             std::process::Command::new("mkfs.ext4")
-                .args(&[
+                .args([
                     "-d",
                     "src/",
                     "-O",
@@ -1082,6 +2769,194 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_block_group_descriptor_checksums() {
+        for (name, feature) in [("desc_64", "64bit"), ("desc_32", "^64bit")] {
+            let image_path = format!("target/{}.img", name);
+            let _ = fs::remove_file(&image_path);
+            let output = std::process::Command::new("mkfs.ext4")
+                .env("MKE2FS_CONFIG", "/dev/null")
+                .args([
+                    "-q",
+                    "-F",
+                    "-b",
+                    "4096",
+                    "-O",
+                    "extent,metadata_csum,flex_bg",
+                ])
+                .args(["-O", feature, &image_path, "40000"])
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{:?}", output);
+            let image = fs::read(&image_path).unwrap();
+            let sb = Ext4SuperBlock::read_buffer(&image[1024..2048]);
+            let desc_size = sb.desc_size() as usize;
+            assert_eq!(desc_size, if feature == "64bit" { 64 } else { 32 });
+            for group in 0..sb.block_groups_count() as usize {
+                let mut buf = [0u8; 64];
+                let start = 4096 + group * desc_size;
+                buf[..desc_size].copy_from_slice(&image[start..start + desc_size]);
+                let mut bgd = Ext4BlockGroupDescriptor::read_buffer(&buf);
+                let checksum = bgd.bg_checksum;
+                bgd.update_descriptor_checksum(&sb.s_uuid, group as u32, desc_size as u16);
+                assert_eq!(bgd.bg_checksum, checksum, "{} group {}", name, group);
+            }
+        }
+    }
+
+    #[test]
+    fn test_metadata_csum_without_gdt_csum() {
+        let uuid = [0x42; 16];
+        let sb = Ext4SuperBlock::new(uuid, 8192);
+        let ro_compat = sb.s_feature_ro_compat;
+        assert_ne!(ro_compat & 0x0400, 0); // metadata_csum
+        assert_eq!(ro_compat & 0x0010, 0); // gdt_csum
+        assert_eq!(
+            ro_compat & !(0x0001 | 0x0002 | 0x0008 | 0x0020 | 0x0040 | 0x0400),
+            0
+        );
+
+        // bg_checksum is the truncated crc32c over the uuid, the group number and the descriptor
+        let mut bgd = Ext4BlockGroupDescriptor::default();
+        bgd.set_inode_table(1234);
+        bgd.set_free_blocks_count(42);
+        bgd.update_descriptor_checksum(&uuid, 3, 64);
+        let mut bytes = bgd.as_bytes();
+        bytes[0x1E..0x20].fill(0);
+        let crc = crc32c::crc32c(&[&uuid[..], &3u32.to_le_bytes(), &bytes].concat());
+        assert_eq!(bgd.bg_checksum, !crc as u16);
+    }
+
+    #[test]
+    fn test_linear_directory_block_tail() {
+        for checksums in [true, false] {
+            let mut block = LinearDirectoryBlock::new(checksums, 4096);
+            block.add_entry(Ext4DirEntry::new(2, FileType::Directory, "."));
+            block.add_entry(Ext4DirEntry::new(2, FileType::Directory, ".."));
+            block.add_entry(Ext4DirEntry::new(12, FileType::RegularFile, "hello.txt"));
+            block.update_checksum(&[1; 16], 2, 0);
+            let bytes = block.as_bytes();
+            let last = Ext4DirEntry::read_buffer(&bytes[24..]);
+            let (entries_end, tail_ft) = if checksums {
+                (4096 - 12, 0xDE)
+            } else {
+                (4096, 0)
+            };
+            assert_eq!(24 + last.meta.rec_len as usize, entries_end);
+            assert_eq!(bytes[4096 - 12 + 7], tail_ft);
+            assert_eq!(block.checksum.is_some(), checksums);
+            let read_back = LinearDirectoryBlock::read_buffer(&bytes);
+            assert_eq!(read_back.checksum, block.checksum);
+            assert_eq!(read_back.as_bytes(), bytes);
+        }
+
+        // without the tail, 12 more bytes are available for entries
+        let (mut with_tail, mut without_tail) = (
+            LinearDirectoryBlock::new(true, 4096),
+            LinearDirectoryBlock::new(false, 4096),
+        );
+        for i in 0..15 {
+            let entry = Ext4DirEntry::new(i, FileType::RegularFile, &"x".repeat(247));
+            with_tail.add_entry(entry.clone());
+            without_tail.add_entry(entry);
+        }
+        let entry = Ext4DirEntry::new(15, FileType::RegularFile, &"y".repeat(236));
+        assert!(without_tail.fits(&entry));
+        assert!(!with_tail.fits(&entry));
+    }
+
+    #[test]
+    fn test_directory_block_fits_exact_boundary() {
+        let summed = |entries: &[Ext4DirEntry]| {
+            entries
+                .iter()
+                .map(|e| e.meta.rec_len as usize)
+                .sum::<usize>()
+        };
+        // 15 records of 256 bytes leave 244 bytes in front of the tail, 236 + 8 of them fill it exactly
+        let mut block = LinearDirectoryBlock::new(true, 4096);
+        for i in 0..15 {
+            block.add_entry(Ext4DirEntry::new(
+                i,
+                FileType::RegularFile,
+                &"x".repeat(247),
+            ));
+            assert_eq!(block.used_bytes, summed(&block.entries));
+        }
+        assert!(!block.fits(&Ext4DirEntry::new(
+            15,
+            FileType::RegularFile,
+            &"y".repeat(229)
+        )));
+        let last = Ext4DirEntry::new(15, FileType::RegularFile, &"y".repeat(228));
+        assert_eq!(last.record_length(), 236);
+        block.add_entry(last);
+        assert_eq!(block.used_bytes, summed(&block.entries));
+        assert_eq!(
+            block.used_bytes + Ext4DirEntryMeta::SIZE as usize,
+            block.entries_end()
+        );
+        assert!(!block.fits(&Ext4DirEntry::new(16, FileType::RegularFile, "z")));
+        let read_back = LinearDirectoryBlock::read_buffer(&block.as_bytes());
+        assert_eq!(read_back.entries.len(), 16);
+        assert_eq!(read_back.used_bytes, summed(&read_back.entries));
+
+        let mut inline = InlineLinearDirectoryBlock::new(60);
+        inline.add_entry(Ext4DirEntry::new(2, FileType::Directory, &"a".repeat(20)));
+        assert!(!inline.fits(&Ext4DirEntry::new(
+            12,
+            FileType::RegularFile,
+            &"b".repeat(17)
+        )));
+        inline.add_entry(Ext4DirEntry::new(
+            12,
+            FileType::RegularFile,
+            &"b".repeat(16),
+        ));
+        assert_eq!(inline.used_bytes, summed(&inline.entries));
+        assert_eq!(inline.used_bytes + Ext4DirEntryMeta::SIZE as usize, 60);
+    }
+
+    #[test]
+    fn test_inline_directory_capacity_scales_with_inode_size() {
+        assert_eq!(Ext4Inode::max_inline_size_xattr(256), 68);
+        assert_eq!(Ext4Inode::max_inline_size_xattr(512), 68 + 256);
+        let fill = |inode_size| {
+            let mut block =
+                InlineLinearDirectoryBlock::new(Ext4Inode::max_inline_size_xattr(inode_size));
+            let mut count = 0;
+            for i in 0..8 {
+                let entry =
+                    Ext4DirEntry::new(12 + i, FileType::RegularFile, &format!("file-{i:03}"));
+                if !block.fits(&entry) {
+                    break;
+                }
+                block.add_entry(entry);
+                count += 1;
+            }
+            count
+        };
+        // spills at 256 byte inodes but stays inline with 512 byte inodes
+        assert_eq!(fill(256), 3);
+        assert_eq!(fill(512), 8);
+    }
+
+    #[test]
+    fn test_huge_file_blocks() {
+        let mut inode = Ext4Inode::default();
+        inode.add_blocks(1 << 44, 4096);
+        assert_eq!(inode.flags() & Ext4Inode::HUGE_FILE_FL, 0);
+        assert_eq!(inode.blocks(), 1 << 47);
+        // 2^48 sectors don't fit into i_blocks anymore
+        inode.add_blocks(1 << 44, 4096);
+        assert_ne!(inode.flags() & Ext4Inode::HUGE_FILE_FL, 0);
+        assert_eq!(inode.blocks(), 1 << 45);
+        assert_eq!(inode.sectors(4096), 1 << 48);
+        inode.add_blocks(1, 4096);
+        assert_eq!(inode.blocks(), (1 << 45) + 1);
+        assert_eq!(inode.sectors(1024), ((1 << 45) + 1) * 2);
+    }
+
     #[test]
     fn test_read_inode_bitmap() {
         let mut image = open_image();
@@ -1089,10 +2964,10 @@ mod tests {
         sb.check_magic().unwrap();
         let bgd = Ext4BlockGroupDescriptor::read_buffer(&image(4096..8192));
         let inode_bitmap_block = bgd.inode_bitmap();
-        let inode_bitmap = BitmapBlock::read_buffer(&image(
-            (inode_bitmap_block * BLOCK_SIZE) as u64
-                ..((inode_bitmap_block + 1) * BLOCK_SIZE) as u64,
-        ));
+        let inode_bitmap = BitmapBlock::read_with_len(
+            &image((inode_bitmap_block * BLOCK_SIZE)..((inode_bitmap_block + 1) * BLOCK_SIZE)),
+            sb.inodes_per_group(),
+        );
         println!("{inode_bitmap:#?}")
     }
 
@@ -1105,9 +2980,9 @@ mod tests {
         let inode_table_block = bgd.inode_table();
         let resize_inode_num = 7;
         let inode_offset = (resize_inode_num - 1) * 256;
-        let mut inode = Ext4Inode::read_buffer(&image(
-            (inode_table_block * BLOCK_SIZE + inode_offset) as u64
-                ..(inode_table_block * BLOCK_SIZE + inode_offset + Ext4Inode::SIZE) as u64,
+        let mut inode = read_inode(image(
+            (inode_table_block * BLOCK_SIZE + inode_offset)
+                ..(inode_table_block * BLOCK_SIZE + inode_offset + Ext4Inode::DEFAULT_SIZE),
         ));
         let old_checksum = inode.checksum();
         inode.update_checksum(sb.uuid(), resize_inode_num as u32);
@@ -1118,10 +2993,8 @@ mod tests {
         let extent = LegacyBlockDescriptor::read_buffer(&inode.i_block);
         println!("{:#?}", extent);
         let block = extent.double_indirect;
-        let block_map = &image(
-            ((block as u64 + 0) * BLOCK_SIZE) as u64..((block as u64 + 2) * BLOCK_SIZE) as u64,
-        );
-        let block_map = <[u32; 1024]>::read_buffer(&block_map);
+        let block_map = &image(((block as u64) * BLOCK_SIZE)..((block as u64 + 2) * BLOCK_SIZE));
+        let block_map = <[u32; 1024]>::read_buffer(block_map);
         println!("Indirect: {:?}", &block_map);
     }
 
@@ -1134,12 +3007,12 @@ mod tests {
         let inode_table_block = bgd.inode_table();
         let root_dir_inode_num = 2;
         let inode_offset = (root_dir_inode_num - 1) * 256;
-        let mut inode = Ext4Inode::read_buffer(&image(
-            (inode_table_block * BLOCK_SIZE + inode_offset) as u64
-                ..(inode_table_block * BLOCK_SIZE + inode_offset + Ext4Inode::SIZE) as u64,
+        let mut inode = read_inode(image(
+            (inode_table_block * BLOCK_SIZE + inode_offset)
+                ..(inode_table_block * BLOCK_SIZE + inode_offset + Ext4Inode::DEFAULT_SIZE),
         ));
         println!("{:#?}", inode);
-        println!("{}", hexdump(&inode.block_mut()));
+        println!("{}", hexdump(inode.block_mut()));
         println!("{}", hexdump(&inode.rest));
 
         let old_checksum = inode.checksum();
@@ -1153,7 +3026,7 @@ mod tests {
 
         for block in extent.as_blocks_range() {
             dbg!(block);
-            let block_data = &image((block * BLOCK_SIZE) as u64..((block + 1) * BLOCK_SIZE) as u64);
+            let block_data = &image((block * BLOCK_SIZE)..((block + 1) * BLOCK_SIZE));
             let mut dir_block = LinearDirectoryBlock::read_buffer(block_data);
             let old_checksum = dir_block.checksum;
             dir_block.update_checksum(sb.uuid(), root_dir_inode_num as u32, inode.i_generation);
@@ -1170,9 +3043,9 @@ mod tests {
         let inode_table_block = bgd.inode_table();
         let file_inode_num = 12;
         let inode_offset = (file_inode_num - 1) * 256;
-        let mut inode = Ext4Inode::read_buffer(&image(
+        let mut inode = read_inode(image(
             (inode_table_block * BLOCK_SIZE + inode_offset)
-                ..(inode_table_block * BLOCK_SIZE + inode_offset + Ext4Inode::SIZE),
+                ..(inode_table_block * BLOCK_SIZE + inode_offset + Ext4Inode::DEFAULT_SIZE),
         ));
         println!("{:#?}", inode);
 
@@ -1,13 +1,83 @@
-use std::io;
+use crate::{
+    Ext4Error, Timestamps,
+    ext4_h::{Ext4Inode, Ext4Xattr},
+};
+use std::collections::BTreeMap;
 
 #[derive(Debug, Clone)]
 pub(crate) enum DirectoryEntry {
     Directory(Directory),
-    File(u64),
+    File(FileEntry),
+}
+
+/// A file, symlink or special file. Its inode is built when it is added, but `metadata` is only applied
+/// to the inode when finishing, so it can still be changed until then.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FileEntry {
+    pub inode: u64,
+    pub metadata: FileMetadata,
+}
+
+/// The inode fields of a file that are chosen by the user. All hard links of a file carry the same values.
+/// Fields that are not set keep the values of the inode, i.e. of a file in an opened image.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FileMetadata {
+    pub mode: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Set via [`crate::Ext4ImageWriter::set_project_id`].
+    pub projid: Option<u32>,
+    pub timestamps: Option<Timestamps>,
+    /// Set via [`crate::Ext4ImageWriter::set_inode_flags`] and [`crate::Ext4ImageWriter::set_journal_data`].
+    pub flags: u32,
+    /// Stored in the inode as far as they fit, see [`crate::Ext4ImageWriter::set_xattr`].
+    pub xattrs: Vec<Ext4Xattr>,
+}
+impl FileMetadata {
+    /// Apply everything but the extended attributes, which might need a block of their own.
+    pub(crate) fn apply(&self, inode: &mut Ext4Inode) {
+        if let Some(mode) = self.mode {
+            inode.set_mode(mode);
+        }
+        if let Some(uid) = self.uid {
+            inode.set_uid(uid);
+        }
+        if let Some(gid) = self.gid {
+            inode.set_gid(gid);
+        }
+        if let Some(projid) = self.projid {
+            inode.set_projid(projid);
+        }
+        if let Some(timestamps) = self.timestamps {
+            inode.set_timestamps(timestamps);
+        }
+        inode.add_flags(self.flags);
+    }
+}
+
+/// The inode fields of a directory that are chosen by the user. Directory inodes are only built when finishing.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct DirectoryMetadata {
+    /// Defaults to `0o755`.
+    pub mode: Option<u16>,
+    pub uid: u32,
+    pub gid: u32,
+    /// Set via [`crate::Ext4ImageWriter::set_project_id`].
+    pub projid: u32,
+    pub timestamps: Option<Timestamps>,
+    /// Set via [`crate::Ext4ImageWriter::set_inode_flags`].
+    pub flags: u32,
+    /// In-inode extended attributes besides `system.data` and the encryption context.
+    pub xattrs: Vec<Ext4Xattr>,
 }
 
 #[derive(Default, Debug, Clone)]
-pub(crate) struct Directory(Vec<(String, DirectoryEntry)>);
+pub(crate) struct Directory {
+    entries: Vec<(String, DirectoryEntry)>,
+    encryption_context: Option<Vec<u8>>,
+    raw_blocks: Option<Vec<[u8; 4096]>>,
+    metadata: DirectoryMetadata,
+}
 impl Directory {
     fn get_mut(&mut self, path: &str) -> Option<&mut DirectoryEntry> {
         let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
@@ -16,7 +86,7 @@ impl Directory {
             panic!("path cannot be empty");
         }
         for (i, part) in parts.iter().enumerate() {
-            let (_, entry) = current.0.iter_mut().find(|(name, _)| name == part)?;
+            let (_, entry) = current.entries.iter_mut().find(|(name, _)| name == part)?;
             if i == parts.len() - 1 {
                 return Some(entry);
             }
@@ -28,7 +98,21 @@ impl Directory {
         unreachable!();
     }
 
-    fn get_parent_directory_mut(&mut self, path: &str) -> io::Result<&mut Directory> {
+    fn get(&self, path: &str) -> Option<&DirectoryEntry> {
+        let mut current = self;
+        let mut parts = path.split('/').filter(|s| !s.is_empty()).peekable();
+        while let Some(part) = parts.next() {
+            let (_, entry) = current.entries.iter().find(|(name, _)| name == part)?;
+            match entry {
+                _ if parts.peek().is_none() => return Some(entry),
+                DirectoryEntry::Directory(d) => current = d,
+                DirectoryEntry::File(_) => return None,
+            }
+        }
+        None
+    }
+
+    fn get_parent_directory_mut(&mut self, path: &str) -> Result<&mut Directory, Ext4Error> {
         let path = match path.rsplit_once('/') {
             Some((p, _)) => p,
             None => "",
@@ -38,14 +122,8 @@ impl Directory {
         }
         match self.get_mut(path) {
             Some(DirectoryEntry::Directory(d)) => Ok(d),
-            Some(DirectoryEntry::File(_)) => Err(io::Error::other(format!(
-                "parent '{}' is a file, not a directory",
-                path
-            ))),
-            None => Err(io::Error::other(format!(
-                "parent directory '{}' does not exist",
-                path
-            ))),
+            Some(DirectoryEntry::File(_)) => Err(Ext4Error::NotADirectory(path.to_string())),
+            None => Err(Ext4Error::ParentMissing(path.to_string())),
         }
     }
     fn get_name(path: &str) -> &str {
@@ -55,40 +133,288 @@ impl Directory {
         }
     }
 
+    /// Directory entries hold names of 1 to 255 bytes, `.` and `..` are taken by the links to the directories.
+    fn check_name(name: &str) -> Result<(), Ext4Error> {
+        if name.len() > 255 {
+            return Err(Ext4Error::NameTooLong(name.to_string()));
+        }
+        if name.is_empty() || name == "." || name == ".." || name.contains('\0') {
+            return Err(Ext4Error::InvalidName(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Check the name that `path` creates and the components leading to it. Paths are never resolved,
+    /// so `.` and `..` are rejected there as well, while repeated and leading separators are ignored.
+    /// Returns the name.
+    fn check_path(path: &str) -> Result<&str, Ext4Error> {
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            for component in parent.split('/').filter(|s| !s.is_empty()) {
+                Self::check_name(component)?;
+            }
+        }
+        let name = Self::get_name(path);
+        Self::check_name(name)?;
+        Ok(name)
+    }
+
+    /// Look up the inode number of the regular file at `path`.
+    pub(crate) fn file_inode(&self, path: &str) -> Result<u64, Ext4Error> {
+        match self.get(path) {
+            Some(DirectoryEntry::File(file)) => Ok(file.inode),
+            Some(DirectoryEntry::Directory(_)) => Err(Ext4Error::IsADirectory(path.to_string())),
+            None => Err(Ext4Error::NotFound(path.to_string())),
+        }
+    }
+
+    /// Change the metadata of the file at `path` and of all of its hard links.
+    pub(crate) fn update_file_metadata(
+        &mut self,
+        path: &str,
+        update: impl Fn(&mut FileMetadata) + Copy,
+    ) -> Result<(), Ext4Error> {
+        let inode = self.file_inode(path)?;
+        self.update_file_metadata_of(inode, update);
+        Ok(())
+    }
+
+    fn update_file_metadata_of(&mut self, inode: u64, update: impl Fn(&mut FileMetadata) + Copy) {
+        for (_, entry) in &mut self.entries {
+            match entry {
+                DirectoryEntry::File(file) if file.inode == inode => update(&mut file.metadata),
+                DirectoryEntry::File(_) => {}
+                DirectoryEntry::Directory(d) => d.update_file_metadata_of(inode, update),
+            }
+        }
+    }
+
+    /// The metadata of every file below this directory by inode number, once for all hard links of a file.
+    pub(crate) fn file_metadata(&self) -> BTreeMap<u64, &FileMetadata> {
+        let mut files = BTreeMap::new();
+        self.collect_file_metadata(&mut files);
+        files
+    }
+
+    fn collect_file_metadata<'a>(&'a self, files: &mut BTreeMap<u64, &'a FileMetadata>) {
+        for (_, entry) in &self.entries {
+            match entry {
+                DirectoryEntry::File(file) => {
+                    files.insert(file.inode, &file.metadata);
+                }
+                DirectoryEntry::Directory(d) => d.collect_file_metadata(files),
+            }
+        }
+    }
+
+    /// Look up the directory at `path`.
+    pub(crate) fn directory_mut(&mut self, path: &str) -> Result<&mut Directory, Ext4Error> {
+        if path.split('/').all(|part| part.is_empty()) {
+            return Ok(self);
+        }
+        match self.get_mut(path) {
+            Some(DirectoryEntry::Directory(directory)) => Ok(directory),
+            Some(DirectoryEntry::File(_)) => Err(Ext4Error::NotADirectory(path.to_string())),
+            None => Err(Ext4Error::NotFound(path.to_string())),
+        }
+    }
+
+    /// The fscrypt context (`encryption.c` xattr) of this directory, if it is encrypted.
+    pub(crate) fn encryption_context(&self) -> Option<&[u8]> {
+        self.encryption_context.as_deref()
+    }
+
+    pub(crate) fn set_encryption_context(&mut self, context: &[u8]) {
+        self.encryption_context = Some(context.to_vec());
+    }
+
+    /// Directory blocks that are written verbatim instead of blocks built from the entries.
+    pub(crate) fn raw_blocks(&self) -> Option<&[[u8; 4096]]> {
+        self.raw_blocks.as_deref()
+    }
+
+    pub(crate) fn set_raw_blocks(&mut self, blocks: &[[u8; 4096]]) {
+        self.raw_blocks = Some(blocks.to_vec());
+    }
+
+    pub(crate) fn metadata(&self) -> &DirectoryMetadata {
+        &self.metadata
+    }
+
+    pub(crate) fn metadata_mut(&mut self) -> &mut DirectoryMetadata {
+        &mut self.metadata
+    }
+
     pub(crate) fn entries(&self) -> &[(String, DirectoryEntry)] {
-        &self.0
+        &self.entries
     }
 
-    pub(crate) fn create_file(&mut self, path: &str, inode: u64) -> io::Result<()> {
+    /// Check that [`Directory::create_file`] would succeed, so that callers can fail before allocating
+    /// an inode or blocks for the file.
+    pub(crate) fn check_new_file(&self, path: &str) -> Result<(), Ext4Error> {
+        let name = Self::check_path(path)?;
+        let parent = match path.rsplit_once('/') {
+            Some((parent, _)) if !parent.is_empty() => match self.get(parent) {
+                Some(DirectoryEntry::Directory(d)) => d,
+                Some(DirectoryEntry::File(_)) => {
+                    return Err(Ext4Error::NotADirectory(parent.to_string()));
+                }
+                None => return Err(Ext4Error::ParentMissing(parent.to_string())),
+            },
+            _ => self,
+        };
+        if parent.entries.iter().any(|(n, _)| n == name) {
+            return Err(Ext4Error::PathExists(path.to_string()));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn create_file(&mut self, path: &str, inode: u64) -> Result<(), Ext4Error> {
+        self.create_file_entry(
+            path,
+            FileEntry {
+                inode,
+                metadata: FileMetadata::default(),
+            },
+        )
+    }
+
+    fn create_file_entry(&mut self, path: &str, file: FileEntry) -> Result<(), Ext4Error> {
+        let name = Self::check_path(path)?;
         let parent = self.get_parent_directory_mut(path)?;
-        let name = Self::get_name(path);
-        if parent.0.iter_mut().any(|(n, _)| n == name) {
-            return Err(io::Error::other(format!("path '{}' already exists", path)));
+        if parent.entries.iter_mut().any(|(n, _)| n == name) {
+            return Err(Ext4Error::PathExists(path.to_string()));
         } else {
             parent
-                .0
-                .push((name.to_string(), DirectoryEntry::File(inode)));
+                .entries
+                .push((name.to_string(), DirectoryEntry::File(file)));
         }
         Ok(())
     }
 
-    pub(crate) fn mkdir(&mut self, path: &str) -> io::Result<&mut Directory> {
+    pub(crate) fn mkdir(&mut self, path: &str) -> Result<&mut Directory, Ext4Error> {
+        self.mkdir_with_metadata(path, DirectoryMetadata::default())
+    }
+
+    pub(crate) fn mkdir_with_metadata(
+        &mut self,
+        path: &str,
+        metadata: DirectoryMetadata,
+    ) -> Result<&mut Directory, Ext4Error> {
+        let name = Self::check_path(path)?;
         let parent = self.get_parent_directory_mut(path)?;
-        let name = Self::get_name(path);
-        if parent.0.iter_mut().any(|(n, _)| n == name) {
-            return Err(io::Error::other(format!("path '{}' already exists", path)));
+        if parent.entries.iter_mut().any(|(n, _)| n == name) {
+            return Err(Ext4Error::PathExists(path.to_string()));
         } else {
-            parent.0.push((
+            parent.entries.push((
                 name.to_string(),
-                DirectoryEntry::Directory(Directory::default()),
+                DirectoryEntry::Directory(Directory {
+                    metadata,
+                    ..Default::default()
+                }),
             ));
         }
-        match parent.0.iter_mut().find(|(n, _)| n == name) {
+        match parent.entries.iter_mut().find(|(n, _)| n == name) {
             Some((_, DirectoryEntry::Directory(d))) => Ok(d),
             _ => unreachable!(),
         }
     }
-    pub(crate) fn mkdir_p(&mut self, path: &str) -> io::Result<&mut Directory> {
+    /// Create another entry at `new_path` for the file at `existing_path` and return its inode number.
+    /// Hard links to directories are rejected since ext4 forbids them (they could form cycles).
+    pub(crate) fn link(&mut self, existing_path: &str, new_path: &str) -> Result<u64, Ext4Error> {
+        if let Some(DirectoryEntry::Directory(_)) = self.get_mut(existing_path) {
+            return Err(Ext4Error::IsADirectory(existing_path.to_string()));
+        }
+        let file = match self.get(existing_path) {
+            Some(DirectoryEntry::File(file)) => file.clone(),
+            _ => return Err(Ext4Error::NotFound(existing_path.to_string())),
+        };
+        let inode = file.inode;
+        // the new link shares the metadata of the inode with the existing ones
+        self.create_file_entry(new_path, file)?;
+        Ok(inode)
+    }
+
+    /// Move the entry at `from` to `to`. The parent of `to` must exist.
+    /// Moving a directory into itself or one of its descendants is rejected since it would create a cycle.
+    pub(crate) fn rename(&mut self, from: &str, to: &str) -> Result<(), Ext4Error> {
+        let from_parts: Vec<&str> = from.split('/').filter(|s| !s.is_empty()).collect();
+        let to_parts: Vec<&str> = to.split('/').filter(|s| !s.is_empty()).collect();
+        if from_parts.is_empty() || to_parts.is_empty() {
+            return Err(Ext4Error::ReservedPath("/".to_string()));
+        }
+        if self.get_mut(from).is_none() {
+            return Err(Ext4Error::NotFound(from.to_string()));
+        }
+        if to_parts.starts_with(&from_parts) && to_parts != from_parts {
+            return Err(Ext4Error::DirectoryCycle {
+                from: from.to_string(),
+                to: to.to_string(),
+            });
+        }
+        // check the destination before detaching the entry so that errors leave the tree untouched
+        let name = Self::check_path(to)?;
+        if self
+            .get_parent_directory_mut(to)?
+            .entries
+            .iter()
+            .any(|(n, _)| n == name)
+        {
+            return Err(Ext4Error::PathExists(to.to_string()));
+        }
+        let parent = self.get_parent_directory_mut(from)?;
+        let index = parent
+            .entries
+            .iter()
+            .position(|(n, _)| n == Self::get_name(from))
+            .unwrap();
+        let (_, entry) = parent.entries.remove(index);
+        self.get_parent_directory_mut(to)?
+            .entries
+            .push((name.to_string(), entry));
+        Ok(())
+    }
+
+    /// Detach the entry at `path` and return it. Directories that still have entries are only removed
+    /// if `recursive` is set.
+    pub(crate) fn remove(
+        &mut self,
+        path: &str,
+        recursive: bool,
+    ) -> Result<DirectoryEntry, Ext4Error> {
+        if path.split('/').all(|part| part.is_empty()) {
+            return Err(Ext4Error::ReservedPath("/".to_string()));
+        }
+        match self.get(path) {
+            Some(DirectoryEntry::Directory(d)) if !recursive && !d.entries.is_empty() => {
+                return Err(Ext4Error::DirectoryNotEmpty(path.to_string()));
+            }
+            Some(_) => {}
+            None => return Err(Ext4Error::NotFound(path.to_string())),
+        }
+        let name = path.trim_end_matches('/');
+        let parent = self.get_parent_directory_mut(name)?;
+        let index = parent
+            .entries
+            .iter()
+            .position(|(n, _)| n == Self::get_name(name))
+            .unwrap();
+        Ok(parent.entries.remove(index).1)
+    }
+
+    /// The inode numbers of all files in this directory and its subdirectories, once per entry.
+    pub(crate) fn file_inodes(&self) -> Vec<u64> {
+        let mut inodes = vec![];
+        for (_, entry) in &self.entries {
+            match entry {
+                DirectoryEntry::File(file) => inodes.push(file.inode),
+                DirectoryEntry::Directory(d) => inodes.extend(d.file_inodes()),
+            }
+        }
+        inodes
+    }
+
+    /// Like `mkdir -p`: missing directories are created and existing ones (including the last one) are reused.
+    pub(crate) fn mkdir_p(&mut self, path: &str) -> Result<&mut Directory, Ext4Error> {
         let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
         for i in 0..(parts.len() - 1) {
             let sub_path = parts[..=i].join("/");
@@ -96,13 +422,18 @@ impl Directory {
                 self.mkdir(&sub_path)?;
             }
         }
-        self.mkdir(path)
+        match self.get(path) {
+            Some(DirectoryEntry::Directory(_)) => self.directory_mut(path),
+            Some(DirectoryEntry::File(_)) => Err(Ext4Error::PathExists(path.to_string())),
+            None => self.mkdir(path),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::Timestamp;
 
     #[test]
     fn test_mkdir_and_create_file() {
@@ -114,7 +445,7 @@ mod tests {
         root.create_file("foo/bar.txt", 42).unwrap();
         // Check file exists
         match root.get_mut("foo/bar.txt") {
-            Some(DirectoryEntry::File(inode)) => assert_eq!(*inode, 42),
+            Some(DirectoryEntry::File(file)) => assert_eq!(file.inode, 42),
             _ => panic!("File not found or wrong type"),
         }
     }
@@ -124,7 +455,7 @@ mod tests {
         let mut root = Directory::default();
         root.mkdir("foo").unwrap();
         let res = root.mkdir("foo");
-        assert!(res.is_err());
+        assert!(matches!(res, Err(Ext4Error::PathExists(_))));
     }
 
     #[test]
@@ -133,14 +464,14 @@ mod tests {
         root.mkdir("foo").unwrap();
         root.create_file("foo/bar.txt", 1).unwrap();
         let res = root.create_file("foo/bar.txt", 2);
-        assert!(res.is_err());
+        assert!(matches!(res, Err(Ext4Error::PathExists(_))));
     }
 
     #[test]
     fn test_get_parent_directory_mut_nonexistent() {
         let mut root = Directory::default();
         let res = root.get_parent_directory_mut("foo/bar.txt");
-        assert!(res.is_err());
+        assert!(matches!(res, Err(Ext4Error::ParentMissing(path)) if path == "foo"));
     }
 
     #[test]
@@ -149,7 +480,7 @@ mod tests {
         root.mkdir("foo").unwrap();
         root.create_file("foo/bar", 1).unwrap();
         let res = root.get_parent_directory_mut("foo/bar/baz.txt");
-        assert!(res.is_err());
+        assert!(matches!(res, Err(Ext4Error::NotADirectory(path)) if path == "foo/bar"));
     }
 
     #[test]
@@ -181,6 +512,23 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_mkdir_p_twice() {
+        let mut root = Directory::default();
+        root.mkdir_p("a/b").unwrap();
+        root.create_file("a/b/file", 1).unwrap();
+        root.mkdir_p("a/b").unwrap();
+        assert_eq!(root.file_inode("a/b/file").unwrap(), 1);
+        assert!(matches!(
+            root.mkdir_p("a/b/file"),
+            Err(Ext4Error::PathExists(_))
+        ));
+        assert!(matches!(
+            root.mkdir_p("a/b/file/c"),
+            Err(Ext4Error::NotADirectory(_))
+        ));
+    }
+
     #[test]
     fn test_get_mut_file_and_directory() {
         let mut root = Directory::default();
@@ -193,7 +541,7 @@ mod tests {
         }
         // File
         match root.get_mut("dir1/dir2/file.txt") {
-            Some(DirectoryEntry::File(inode)) => assert_eq!(*inode, 99),
+            Some(DirectoryEntry::File(file)) => assert_eq!(file.inode, 99),
             _ => panic!("Expected file"),
         }
     }
@@ -204,13 +552,195 @@ mod tests {
         assert!(root.get_mut("no/such/path").is_none());
     }
 
+    #[test]
+    fn test_file_inode() {
+        let mut root = Directory::default();
+        root.mkdir("dir").unwrap();
+        root.create_file("dir/file.txt", 7).unwrap();
+        assert_eq!(root.file_inode("dir/file.txt").unwrap(), 7);
+        assert!(matches!(
+            root.file_inode("dir"),
+            Err(Ext4Error::IsADirectory(_))
+        ));
+        assert!(matches!(
+            root.file_inode("dir/missing"),
+            Err(Ext4Error::NotFound(_))
+        ));
+    }
+
     #[test]
     fn test_create_file_in_root() {
         let mut root = Directory::default();
         root.create_file("file.txt", 123).unwrap();
         match root.get_mut("file.txt") {
-            Some(DirectoryEntry::File(inode)) => assert_eq!(*inode, 123),
+            Some(DirectoryEntry::File(file)) => assert_eq!(file.inode, 123),
             _ => panic!("Expected file"),
         }
     }
+
+    #[test]
+    fn test_rename_directory_into_itself_is_rejected() {
+        let mut root = Directory::default();
+        root.mkdir_p("a/b/c").unwrap();
+        assert!(matches!(
+            root.rename("a", "a/b/c/a"),
+            Err(Ext4Error::DirectoryCycle { .. })
+        ));
+        assert!(matches!(
+            root.rename("a/b", "a/b/x"),
+            Err(Ext4Error::DirectoryCycle { .. })
+        ));
+        // the tree is untouched
+        assert!(matches!(
+            root.get_mut("a/b/c"),
+            Some(DirectoryEntry::Directory(_))
+        ));
+        root.rename("a/b/c", "c").unwrap();
+        root.rename("a", "c/a").unwrap();
+        assert!(matches!(
+            root.get_mut("c/a/b"),
+            Some(DirectoryEntry::Directory(_))
+        ));
+        assert!(root.get_mut("a").is_none());
+    }
+
+    #[test]
+    fn test_rename_to_existing_path_fails() {
+        let mut root = Directory::default();
+        root.create_file("a", 1).unwrap();
+        root.create_file("b", 2).unwrap();
+        assert!(matches!(
+            root.rename("a", "b"),
+            Err(Ext4Error::PathExists(_))
+        ));
+        assert!(matches!(
+            root.rename("missing", "c"),
+            Err(Ext4Error::NotFound(_))
+        ));
+        assert!(matches!(
+            root.rename("a", "missing/a"),
+            Err(Ext4Error::ParentMissing(_))
+        ));
+        assert_eq!(root.file_inode("a").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_link() {
+        let mut root = Directory::default();
+        root.mkdir("dir").unwrap();
+        root.create_file("file", 5).unwrap();
+        assert_eq!(root.link("file", "dir/link").unwrap(), 5);
+        assert_eq!(root.file_inode("dir/link").unwrap(), 5);
+        // hard links to directories are not allowed
+        assert!(matches!(
+            root.link("dir", "dir2"),
+            Err(Ext4Error::IsADirectory(_))
+        ));
+        assert!(root.get_mut("dir2").is_none());
+    }
+
+    #[test]
+    fn test_file_metadata_is_shared_by_links() {
+        let mut root = Directory::default();
+        root.mkdir("dir").unwrap();
+        root.create_file("file", 5).unwrap();
+        root.create_file("other", 6).unwrap();
+        let timestamps = Timestamps::all(Timestamp::from_seconds(1_700_000_000));
+        root.update_file_metadata("file", |metadata| metadata.timestamps = Some(timestamps))
+            .unwrap();
+        // a new link starts out with the metadata of the existing ones and later changes reach all of them
+        root.link("file", "dir/link").unwrap();
+        root.update_file_metadata("dir/link", |metadata| {
+            metadata.mode = Some(0o600);
+            metadata.flags |= Ext4Inode::IMMUTABLE_FL;
+        })
+        .unwrap();
+        let expected = FileMetadata {
+            mode: Some(0o600),
+            timestamps: Some(timestamps),
+            flags: Ext4Inode::IMMUTABLE_FL,
+            ..Default::default()
+        };
+        for path in ["file", "dir/link"] {
+            match root.get(path) {
+                Some(DirectoryEntry::File(file)) => assert_eq!(file.metadata, expected, "{}", path),
+                _ => panic!("expected a file at {}", path),
+            }
+        }
+        assert!(matches!(
+            root.get("other"),
+            Some(DirectoryEntry::File(FileEntry { metadata, .. })) if *metadata == FileMetadata::default()
+        ));
+        // the hard links of a file are only listed once
+        let files = root.file_metadata();
+        assert_eq!(files.keys().copied().collect::<Vec<_>>(), [5, 6]);
+        assert_eq!(files[&5], &expected);
+        assert!(matches!(
+            root.update_file_metadata("dir", |_| {}),
+            Err(Ext4Error::IsADirectory(_))
+        ));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut root = Directory::default();
+        root.mkdir("dir").unwrap();
+        root.create_file("dir/file", 5).unwrap();
+        root.link("dir/file", "link").unwrap();
+        assert!(matches!(
+            root.remove("dir", false),
+            Err(Ext4Error::DirectoryNotEmpty(_))
+        ));
+        assert!(matches!(
+            root.remove("missing", false),
+            Err(Ext4Error::NotFound(_))
+        ));
+        assert!(matches!(
+            root.remove("link", false),
+            Ok(DirectoryEntry::File(FileEntry { inode: 5, .. }))
+        ));
+        match root.remove("dir", true).unwrap() {
+            DirectoryEntry::Directory(d) => assert_eq!(d.file_inodes(), vec![5]),
+            DirectoryEntry::File(_) => panic!("expected a directory"),
+        }
+        assert!(root.entries().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        let mut root = Directory::default();
+        assert!(matches!(
+            root.create_file(&"a".repeat(256), 1),
+            Err(Ext4Error::NameTooLong(_))
+        ));
+        root.create_file(&"a".repeat(255), 1).unwrap();
+        for name in ["", ".", "..", "a\0b"] {
+            assert!(matches!(root.mkdir(name), Err(Ext4Error::InvalidName(_))));
+        }
+        assert!(matches!(root.mkdir("dir/"), Err(Ext4Error::InvalidName(_))));
+
+        // every component of the path is checked, not only the new name
+        root.mkdir("dir").unwrap();
+        for path in ["dir/../file", "./file", "dir/./file"] {
+            assert!(matches!(
+                root.create_file(path, 2),
+                Err(Ext4Error::InvalidName(name)) if name == "." || name == ".."
+            ));
+            assert!(matches!(
+                root.check_new_file(path),
+                Err(Ext4Error::InvalidName(_))
+            ));
+        }
+        assert!(matches!(
+            root.mkdir(&format!("{}/dir", "a".repeat(256))),
+            Err(Ext4Error::NameTooLong(_))
+        ));
+        assert!(matches!(
+            root.rename("dir", "x\0y/dir"),
+            Err(Ext4Error::InvalidName(_))
+        ));
+        // repeated and leading separators are fine
+        root.create_file("/dir//file", 2).unwrap();
+        assert_eq!(root.file_inode("dir/file").unwrap(), 2);
+    }
 }
@@ -0,0 +1,1441 @@
+use crate::{
+    DirHashVersion, MAX_DIRECTORY_LINKS,
+    allocator::UsageBitmap,
+    dir_hash::casefold_ascii,
+    ext4_dir_hash,
+    ext4_h::*,
+    serialization::{Buffer, CheckMagic},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fmt,
+};
+
+/// An inconsistency found by [`fsck_lite`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FsckError {
+    /// The superblock is missing, has a bad checksum or its totals don't match the block groups.
+    Superblock(String),
+    /// A block group descriptor has a bad checksum or counts, or its bitmaps don't match the actual usage.
+    BlockGroup { group: u32, message: String },
+    /// An inode has a bad checksum, link count or size, or references invalid blocks.
+    Inode { inode: u32, message: String },
+    /// A directory has malformed records or entries that point to unused inodes.
+    Directory { inode: u32, message: String },
+}
+
+impl fmt::Display for FsckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsckError::Superblock(message) => write!(f, "superblock: {}", message),
+            FsckError::BlockGroup { group, message } => {
+                write!(f, "block group {}: {}", group, message)
+            }
+            FsckError::Inode { inode, message } => write!(f, "inode {}: {}", inode, message),
+            FsckError::Directory { inode, message } => {
+                write!(f, "directory inode {}: {}", inode, message)
+            }
+        }
+    }
+}
+
+impl Error for FsckError {}
+
+/// Check a finished image for consistency without needing e2fsprogs, i.e. on platforms where `e2fsck`
+/// is not available. Returns every problem that was found.
+///
+/// This is not a full e2fsck: it only understands the subset of ext4 that [`crate::Ext4ImageWriter`] writes.
+/// For those images it verifies the superblock magic and checksum and its backups, the checksums and counts of all block
+/// group descriptors, that the bitmaps (including their padding) match the blocks and inodes that are
+/// actually in use, the inode and xattr block checksums, the extent trees and block maps (no block is used twice,
+/// except for data blocks of images with shared_blocks, or lies outside of the filesystem), the integrity of all directory entries, checksums and HTree indexes, that every inode is
+/// reachable, the link counts and the usage recorded in quota files.
+pub fn fsck_lite(image: &[u8]) -> Result<(), Vec<FsckError>> {
+    let mut checker = Checker::new(image).map_err(|error| vec![error])?;
+    checker.check_group_descriptors();
+    checker.check_inodes();
+    checker.check_quota();
+    checker.check_directories();
+    checker.check_bitmaps();
+    if checker.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(checker.errors)
+    }
+}
+
+/// The maximum depth of an extent tree, as enforced by the kernel.
+const MAX_EXTENT_DEPTH: u16 = 5;
+
+/// A leaf block of an HTree directory (as an index into its blocks) with the lowest and highest hash it may contain.
+type HtreeLeaf = (usize, u32, u32);
+
+struct Checker<'a> {
+    image: &'a [u8],
+    superblock: Ext4SuperBlock,
+    block_size: u64,
+    checksums: bool,
+    descriptors: Vec<Ext4BlockGroupDescriptor>,
+    /// The blocks and inodes in use according to the metadata, to compare the bitmaps with.
+    used_blocks: UsageBitmap,
+    used_inodes: UsageBitmap,
+    /// The data blocks of files, which may be shared between files with the shared_blocks feature.
+    data_blocks: UsageBitmap,
+    /// All inodes with a non-zero link count (and the root and resize inodes).
+    inodes: BTreeMap<u32, Ext4Inode>,
+    /// The blocks of directories in logical order.
+    directory_blocks: BTreeMap<u32, Vec<u64>>,
+    /// The contents of the quota files by quota type.
+    quota_files: BTreeMap<usize, Vec<u8>>,
+    errors: Vec<FsckError>,
+}
+
+impl<'a> Checker<'a> {
+    /// Read the superblock and the block group descriptors, which everything else depends on.
+    fn new(image: &'a [u8]) -> Result<Self, FsckError> {
+        let superblock_error = |message: &str| FsckError::Superblock(message.to_string());
+        let buf = image
+            .get(1024..1024 + Ext4SuperBlock::SIZE as usize)
+            .ok_or_else(|| superblock_error("the image is too small to hold a superblock"))?;
+        let superblock = Ext4SuperBlock::read_buffer(buf);
+        superblock
+            .check_magic()
+            .map_err(|error| FsckError::Superblock(error.to_string()))?;
+        let checksums = superblock.has_metadata_csum();
+        if checksums && !superblock.checksum_matches() {
+            return Err(superblock_error("checksum mismatch"));
+        }
+        superblock
+            .check_block_size()
+            .map_err(|error| FsckError::Superblock(error.to_string()))?;
+        let block_size = superblock.block_size();
+        if superblock.first_data_block() != (block_size == 1024) as u64 {
+            return Err(superblock_error(
+                "the first data block does not match the block size",
+            ));
+        }
+        if superblock.blocks_count() * block_size > image.len() as u64 {
+            return Err(FsckError::Superblock(format!(
+                "the filesystem has {} blocks but the image is only {} bytes large",
+                superblock.blocks_count(),
+                image.len()
+            )));
+        }
+        let inodes_per_group = superblock.inodes_per_group();
+        if inodes_per_group == 0
+            || inodes_per_group as u64 > block_size * 8
+            || !inodes_per_group.is_multiple_of(8)
+        {
+            return Err(FsckError::Superblock(format!(
+                "invalid number of inodes per group {}",
+                inodes_per_group
+            )));
+        }
+        let block_groups = superblock.block_groups_count();
+        if superblock.inodes_count() as u64 != block_groups as u64 * inodes_per_group as u64 {
+            return Err(FsckError::Superblock(format!(
+                "{} inodes don't match {} block groups of {} inodes",
+                superblock.inodes_count(),
+                block_groups,
+                inodes_per_group
+            )));
+        }
+
+        // without the 64bit feature the descriptors are truncated to their lower halves
+        let desc_size = superblock.desc_size() as usize;
+        let gdt_start = superblock.first_data_block() + 1;
+        let gdt_blocks = (block_groups as u64 * desc_size as u64).div_ceil(block_size);
+        if gdt_start + gdt_blocks > superblock.blocks_count() {
+            return Err(superblock_error(
+                "the block group descriptors don't fit into the filesystem",
+            ));
+        }
+        let descriptors = (0..block_groups as usize)
+            .map(|group| {
+                let mut buf = image[(gdt_start * block_size) as usize + group * desc_size..]
+                    [..desc_size]
+                    .to_vec();
+                buf.resize(Ext4BlockGroupDescriptor::SIZE as usize, 0);
+                Ext4BlockGroupDescriptor::read_buffer(&buf)
+            })
+            .collect();
+        Ok(Checker {
+            image,
+            superblock,
+            block_size,
+            checksums,
+            descriptors,
+            used_blocks: UsageBitmap::default(),
+            used_inodes: UsageBitmap::default(),
+            data_blocks: UsageBitmap::default(),
+            inodes: BTreeMap::new(),
+            directory_blocks: BTreeMap::new(),
+            quota_files: BTreeMap::new(),
+            errors: vec![],
+        })
+    }
+
+    fn block(&self, block: u64) -> Option<&'a [u8]> {
+        if block >= self.superblock.blocks_count() {
+            return None;
+        }
+        let start = (block * self.block_size) as usize;
+        Some(&self.image[start..start + self.block_size as usize])
+    }
+
+    /// Record that `block` is in use, failing if it is outside of the filesystem or already in use.
+    fn claim(&mut self, block: u64) -> Result<(), String> {
+        if block >= self.superblock.blocks_count() {
+            return Err(format!(
+                "block {} is beyond the end of the filesystem",
+                block
+            ));
+        }
+        if self.used_blocks.is_used(block) {
+            return Err(format!("block {} is used more than once", block));
+        }
+        self.used_blocks.mark_used(block);
+        Ok(())
+    }
+
+    fn inode_table_blocks(&self) -> u64 {
+        (self.superblock.inodes_per_group() as u64 * self.superblock.inode_size())
+            .div_ceil(self.block_size)
+    }
+
+    fn gdt_blocks(&self) -> u64 {
+        (self.descriptors.len() as u64 * self.superblock.desc_size() as u64)
+            .div_ceil(self.block_size)
+    }
+
+    fn group_start(&self, group: u64) -> u64 {
+        self.superblock.first_data_block() + group * self.block_size * 8
+    }
+
+    /// The superblock, the GDT and the reserved GDT blocks at the start of a group that holds a backup.
+    fn superblock_region(&self, group: u64) -> Option<(u64, u64)> {
+        let len = 1 + self.gdt_blocks() + self.superblock.reserved_gdt_blocks() as u64;
+        self.superblock
+            .group_has_super(group)
+            .then(|| (self.group_start(group), len))
+    }
+
+    /// The groups besides group 0 that hold a backup of the superblock and the GDT.
+    fn backup_groups(&self) -> Vec<u64> {
+        (1..self.descriptors.len() as u64)
+            .filter(|&group| self.superblock.group_has_super(group))
+            .collect()
+    }
+
+    /// Check the descriptor checksums and claim the superblock, the GDT and the bitmaps and inode tables.
+    fn check_group_descriptors(&mut self) {
+        let reserved_blocks = self.superblock.first_data_block()
+            + 1
+            + self.gdt_blocks()
+            + self.superblock.reserved_gdt_blocks() as u64;
+        for block in 0..reserved_blocks {
+            if let Err(message) = self.claim(block) {
+                self.errors.push(FsckError::Superblock(format!(
+                    "the reserved GDT blocks don't fit: {}",
+                    message
+                )));
+                break;
+            }
+        }
+        for group in self.backup_groups() {
+            if let Err(message) = self.check_superblock_backup(group) {
+                self.errors.push(FsckError::BlockGroup {
+                    group: group as u32,
+                    message,
+                });
+            }
+        }
+
+        let desc_size = self.superblock.desc_size();
+        for group in 0..self.descriptors.len() {
+            let descriptor = &self.descriptors[group];
+            let mut expected = descriptor.clone();
+            expected.update_descriptor_checksum(self.superblock.uuid(), group as u32, desc_size);
+            let mut errors = vec![];
+            if self.checksums && expected != *descriptor {
+                errors.push("checksum mismatch".to_string());
+            }
+            let metadata = [
+                (descriptor.block_bitmap(), 1),
+                (descriptor.inode_bitmap(), 1),
+                (descriptor.inode_table(), self.inode_table_blocks()),
+            ];
+            for (start, len) in metadata {
+                for block in start..start + len {
+                    if let Err(message) = self.claim(block) {
+                        errors.push(format!(
+                            "invalid bitmap or inode table location: {}",
+                            message
+                        ));
+                        break;
+                    }
+                }
+            }
+            self.errors
+                .extend(errors.into_iter().map(|message| FsckError::BlockGroup {
+                    group: group as u32,
+                    message,
+                }));
+        }
+    }
+
+    /// Claim the backup of the superblock and the GDT in `group` and compare it with the primary copy.
+    fn check_superblock_backup(&mut self, group: u64) -> Result<(), String> {
+        let (start, len) = self.superblock_region(group).unwrap();
+        for block in start..start + len {
+            self.claim(block)
+                .map_err(|message| format!("invalid superblock backup location: {}", message))?;
+        }
+        let mut backup = Ext4SuperBlock::read_buffer(&self.block(start).unwrap()[..1024]);
+        if self.checksums && !backup.checksum_matches() {
+            return Err("superblock backup checksum mismatch".to_string());
+        }
+        let mut expected = self.superblock.clone();
+        expected.set_block_group_nr(group as u16);
+        // without metadata_csum the checksum field is meaningless
+        expected.update_checksum();
+        backup.update_checksum();
+        if backup != expected {
+            return Err("the superblock backup differs from the primary superblock".to_string());
+        }
+        let gdt_len = (self.gdt_blocks() * self.block_size) as usize;
+        let gdt_start = ((self.superblock.first_data_block() + 1) * self.block_size) as usize;
+        let backup_start = ((start + 1) * self.block_size) as usize;
+        if self.image[gdt_start..][..gdt_len] != self.image[backup_start..][..gdt_len] {
+            return Err("the GDT backup differs from the primary GDT".to_string());
+        }
+        Ok(())
+    }
+
+    /// Check all inodes in use and claim the blocks they reference.
+    fn check_inodes(&mut self) {
+        let inodes_per_group = self.superblock.inodes_per_group();
+        let first_ino = self.superblock.first_ino();
+        let inode_size = self.superblock.inode_size();
+        for inode_num in 1..first_ino {
+            self.used_inodes.mark_used(inode_num as u64 - 1);
+        }
+        let image = self.image;
+        for group in 0..self.descriptors.len() {
+            let descriptor = &self.descriptors[group];
+            if descriptor.flags() & Ext4BlockGroupDescriptor::INODE_UNINIT != 0 {
+                continue; // the kernel treats all inodes of the group as unused without looking at them
+            }
+            let table_end = descriptor.inode_table() + self.inode_table_blocks();
+            if table_end > self.superblock.blocks_count() {
+                continue; // already reported as an invalid location
+            }
+            let table = &image[(descriptor.inode_table() * self.block_size) as usize..];
+            for index in 0..inodes_per_group {
+                let inode_num = group as u32 * inodes_per_group + index + 1;
+                let mut buf =
+                    table[(index as u64 * inode_size) as usize..][..inode_size as usize].to_vec();
+                buf.resize(Ext4Inode::SIZE as usize, 0);
+                let inode = Ext4Inode::read_buffer(&buf);
+                // apart from the root and the resize inode, reserved inodes are unused but always allocated
+                let in_use = match inode_num {
+                    2 => true,
+                    7 => self.superblock.has_resize_inode(),
+                    8 => self.superblock.has_journal(),
+                    3 | 4 => self.superblock.has_quota(),
+                    _ if inode_num < first_ino => false,
+                    _ => inode.links_count() > 0,
+                };
+                if !in_use {
+                    continue;
+                }
+                self.used_inodes.mark_used(inode_num as u64 - 1);
+                if let Err(message) = self.check_inode(inode_num, &inode) {
+                    self.errors.push(FsckError::Inode {
+                        inode: inode_num,
+                        message,
+                    });
+                }
+                self.inodes.insert(inode_num, inode);
+            }
+        }
+        if !self.inodes.get(&2).is_some_and(Ext4Inode::is_directory) {
+            self.errors.push(FsckError::Inode {
+                inode: 2,
+                message: "the root inode is not a directory".to_string(),
+            });
+        }
+    }
+
+    fn check_inode(&mut self, inode_num: u32, inode: &Ext4Inode) -> Result<(), String> {
+        if self.checksums {
+            let mut expected = inode.clone();
+            expected.update_checksum_with_size(
+                self.superblock.uuid(),
+                inode_num,
+                self.superblock.inode_size(),
+            );
+            if expected != *inode {
+                return Err("checksum mismatch".to_string());
+            }
+        }
+        if inode_num == 7 {
+            return self.check_resize_inode(inode);
+        }
+        if inode.projid() != 0 && !self.superblock.has_project() {
+            return Err("has a project id without the project feature".to_string());
+        }
+        if inode.flags() & Ext4Inode::HUGE_FILE_FL != 0 && !self.superblock.has_huge_file() {
+            return Err("counts i_blocks in blocks without the huge_file feature".to_string());
+        }
+
+        let size = inode.size();
+        let (data_blocks, metadata_blocks) = match inode.storage_mode(self.block_size) {
+            StorageMode::Inline => {
+                inode.inline_data().map_err(|error| error.to_string())?;
+                (vec![], vec![])
+            }
+            StorageMode::NoData => {
+                let fast_symlink = inode.file_type() == FileType::SymbolicLink
+                    && size <= Ext4Inode::MAX_FAST_SYMLINK_LEN as u64;
+                // a regular file with a block map that only has holes
+                let sparse_file = inode.file_type() == FileType::RegularFile;
+                if size > 0 && !fast_symlink && !sparse_file {
+                    return Err(format!("has a size of {} bytes but no data", size));
+                }
+                (vec![], vec![])
+            }
+            StorageMode::InlineExtents | StorageMode::IndirectExtents => {
+                self.extent_blocks(inode_num, inode)?
+            }
+            StorageMode::BlockMap => self.block_map_blocks(inode)?,
+        };
+        let mut owned_blocks =
+            data_blocks.iter().flatten().count() as u64 + metadata_blocks.len() as u64;
+        let xattr_block = inode.file_acl();
+        if xattr_block != 0 {
+            self.claim(xattr_block)?;
+            self.check_xattr_block(xattr_block)?;
+            owned_blocks += 1;
+        }
+        inode.xattrs().map_err(|error| error.to_string())?;
+        for &block in data_blocks.iter().flatten() {
+            if self.superblock.has_shared_blocks() && self.data_blocks.is_used(block) {
+                continue;
+            }
+            self.claim(block)?;
+            self.data_blocks.mark_used(block);
+        }
+        for &block in &metadata_blocks {
+            self.claim(block)?;
+        }
+        if inode.sectors(self.block_size) != owned_blocks * (self.block_size / 512) {
+            return Err(format!(
+                "i_blocks is {} but {} blocks are used",
+                inode.blocks(),
+                owned_blocks
+            ));
+        }
+        if inode_num == 8 {
+            self.check_journal(inode, &data_blocks)?;
+        }
+        if let Some(quota_type) = self.quota_type_of(inode_num) {
+            let mut contents = vec![];
+            for block in &data_blocks {
+                match block.and_then(|block| self.block(block)) {
+                    Some(data) => contents.extend_from_slice(data),
+                    None => contents.resize(contents.len() + self.block_size as usize, 0),
+                }
+            }
+            contents.truncate(size as usize);
+            self.quota_files.insert(quota_type, contents);
+        }
+        if inode.is_directory() && !inode.has_inline_data() {
+            if !size.is_multiple_of(self.block_size)
+                || size / self.block_size != data_blocks.len() as u64
+            {
+                return Err(format!(
+                    "directory size {} does not match its {} blocks",
+                    size,
+                    data_blocks.len()
+                ));
+            }
+            let blocks = data_blocks.into_iter().collect::<Option<Vec<_>>>();
+            let blocks = blocks.ok_or_else(|| "directory has holes".to_string())?;
+            self.directory_blocks.insert(inode_num, blocks);
+        }
+        Ok(())
+    }
+
+    /// The quota type of the file in `inode_num`, if it is one of the quota files of the superblock.
+    fn quota_type_of(&self, inode_num: u32) -> Option<usize> {
+        if !self.superblock.has_quota() {
+            return None;
+        }
+        self.superblock
+            .quota_inodes()
+            .iter()
+            .position(|&quota_inode| quota_inode == inode_num)
+    }
+
+    /// Every quota file records the usage of the root and all inodes from `s_first_ino` on, like e2fsck counts it.
+    fn check_quota(&mut self) {
+        if !self.superblock.has_quota() {
+            return;
+        }
+        let first_ino = self.superblock.first_ino();
+        let mut expected: [BTreeMap<u32, QuotaUsage>; 3] = Default::default();
+        for (&inode_num, inode) in &self.inodes {
+            if (inode_num != 2 && inode_num < first_ino) || self.quota_type_of(inode_num).is_some()
+            {
+                continue;
+            }
+            for (quota_type, id) in [inode.uid(), inode.gid(), inode.projid()]
+                .into_iter()
+                .enumerate()
+            {
+                let usage = expected[quota_type].entry(id).or_default();
+                usage.inodes += 1;
+                usage.space += inode.sectors(self.block_size) * 512;
+            }
+        }
+        for (quota_type, &quota_inode) in self.superblock.quota_inodes().iter().enumerate() {
+            if quota_inode == 0 {
+                continue;
+            }
+            let error = |message| FsckError::Inode {
+                inode: quota_inode,
+                message,
+            };
+            let Some(contents) = self.quota_files.get(&quota_type) else {
+                self.errors
+                    .push(error("the quota file is not in use".to_string()));
+                continue;
+            };
+            match QuotaFile::parse(contents, quota_type) {
+                Ok(file) if file.usage != expected[quota_type] => self.errors.push(error(format!(
+                    "the quota file records {:?} but the usage is {:?}",
+                    file.usage, expected[quota_type]
+                ))),
+                Ok(_) => {}
+                Err(parse_error) => self.errors.push(error(parse_error.to_string())),
+            }
+        }
+    }
+
+    /// The journal has no holes and starts with a superblock that describes all of its blocks.
+    fn check_journal(&self, inode: &Ext4Inode, data_blocks: &[Option<u64>]) -> Result<(), String> {
+        let blocks = data_blocks.iter().copied().collect::<Option<Vec<_>>>();
+        let blocks = blocks.ok_or_else(|| "the journal has holes".to_string())?;
+        let first_block = blocks
+            .first()
+            .and_then(|&block| self.block(block))
+            .ok_or_else(|| "the journal has no blocks".to_string())?;
+        let journal = JournalSuperBlock::parse(first_block).map_err(|error| error.to_string())?;
+        if journal.block_size as u64 != self.block_size
+            || journal.max_len as u64 != blocks.len() as u64
+            || inode.size() != blocks.len() as u64 * self.block_size
+            || journal.uuid != *self.superblock.uuid()
+        {
+            return Err(format!(
+                "the journal superblock does not match the {} blocks of the journal",
+                blocks.len()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the magic, checksum and entries of a private xattr block.
+    fn check_xattr_block(&self, block: u64) -> Result<(), String> {
+        let data = self.block(block).unwrap();
+        let header = Ext4ExtAttrHeader::read_buffer(data);
+        if header.check_magic().is_err() || !header.is_private() {
+            return Err(format!("invalid xattr block {}", block));
+        }
+        if self.checksums
+            && header.checksum()
+                != Ext4ExtAttrHeader::block_checksum(data, block, self.superblock.uuid())
+        {
+            return Err(format!("checksum mismatch in xattr block {}", block));
+        }
+        Ext4ExtAttrHeader::parse_block(data)
+            .map(|_| ())
+            .map_err(|error| format!("xattr block {}: {}", block, error))
+    }
+
+    /// The resize inode owns its double indirect block, which lists the reserved GDT blocks,
+    /// and with them their backups, which each reserved GDT block lists in turn.
+    fn check_resize_inode(&mut self, inode: &Ext4Inode) -> Result<(), String> {
+        let reserved = self.superblock.reserved_gdt_blocks() as u64;
+        let double_indirect = LegacyBlockDescriptor::read_buffer(inode.block()).indirect()[1];
+        if double_indirect == 0 {
+            return match reserved {
+                0 => Ok(()),
+                _ => Err("reserved GDT blocks without a resize inode".to_string()),
+            };
+        }
+        self.claim(double_indirect as u64)?;
+        let block = self.block(double_indirect as u64).unwrap();
+        let gdt_blocks = self.gdt_blocks();
+        for (index, pointer) in block.chunks(4).enumerate() {
+            let index = index as u64;
+            let expected = if index >= gdt_blocks && index < gdt_blocks + reserved {
+                self.superblock.first_data_block() + 1 + index
+            } else {
+                0
+            };
+            if u32::read_buffer(pointer) as u64 != expected {
+                return Err(format!(
+                    "entry {} of the double indirect block does not point to the reserved GDT block",
+                    index
+                ));
+            }
+        }
+        let backup_groups = self.backup_groups();
+        for index in gdt_blocks..gdt_blocks + reserved {
+            let reserved_block = self.superblock.first_data_block() + 1 + index;
+            let block = self.block(reserved_block).unwrap();
+            for (position, pointer) in block.chunks(4).enumerate() {
+                let expected = backup_groups.get(position).map_or(0, |&group| {
+                    reserved_block + self.group_start(group) - self.group_start(0)
+                });
+                if u32::read_buffer(pointer) as u64 != expected {
+                    return Err(format!(
+                        "reserved GDT block {} does not list its backups",
+                        reserved_block
+                    ));
+                }
+            }
+        }
+        let owned_blocks = 1 + reserved * (1 + backup_groups.len() as u64);
+        if inode.blocks() != owned_blocks * (self.block_size / 512) {
+            return Err(format!(
+                "i_blocks is {} but {} blocks are used",
+                inode.blocks(),
+                owned_blocks
+            ));
+        }
+        Ok(())
+    }
+
+    /// The data blocks (in logical order, `None` for holes) and the extent tree blocks of an inode.
+    fn extent_blocks(
+        &self,
+        inode_num: u32,
+        inode: &Ext4Inode,
+    ) -> Result<(Vec<Option<u64>>, Vec<u64>), String> {
+        let mut leaves = vec![];
+        let mut tree_blocks = vec![];
+        self.walk_extent_node(
+            inode_num,
+            inode,
+            inode.block(),
+            None,
+            &mut leaves,
+            &mut tree_blocks,
+        )?;
+        let mut data_blocks = vec![];
+        for leaf in leaves {
+            let logical = leaf.logical_block() as u64;
+            // lengths above MAX_LEN mark uninitialized extents
+            let len = match leaf.block_count() {
+                len if len > Ext4ExtentLeafNode::MAX_LEN => len - Ext4ExtentLeafNode::MAX_LEN,
+                len => len,
+            } as u64;
+            if len == 0 || logical < data_blocks.len() as u64 {
+                return Err(format!(
+                    "extent at logical block {} is empty or overlaps the previous one",
+                    logical
+                ));
+            }
+            if leaf.start() == 0 || leaf.start() + len > self.superblock.blocks_count() {
+                return Err(format!(
+                    "extent of {} blocks at block {} is outside of the filesystem",
+                    len,
+                    leaf.start()
+                ));
+            }
+            data_blocks.resize(logical as usize, None);
+            data_blocks.extend((leaf.start()..leaf.start() + len).map(Some));
+        }
+        if data_blocks.len() as u64 > inode.size().div_ceil(self.block_size) {
+            return Err(format!(
+                "extents cover {} blocks but the size is only {} bytes",
+                data_blocks.len(),
+                inode.size()
+            ));
+        }
+        Ok((data_blocks, tree_blocks))
+    }
+
+    fn walk_extent_node(
+        &self,
+        inode_num: u32,
+        inode: &Ext4Inode,
+        node: &[u8],
+        expected_depth: Option<u16>,
+        leaves: &mut Vec<Ext4ExtentLeafNode>,
+        tree_blocks: &mut Vec<u64>,
+    ) -> Result<(), String> {
+        let header = Ext4ExtentHeader::read_buffer(node);
+        let capacity =
+            (node.len() - Ext4ExtentHeader::SIZE as usize) / Ext4ExtentLeafNode::SIZE as usize;
+        if !header.has_valid_magic()
+            || header.entries() > header.max_entries()
+            || header.max_entries() as usize > capacity
+            || header.depth() > MAX_EXTENT_DEPTH
+            || expected_depth.is_some_and(|depth| depth != header.depth())
+        {
+            return Err("invalid extent header".to_string());
+        }
+        if expected_depth.is_some() && self.checksums {
+            let tail = Ext4ExtentHeader::SIZE as usize
+                + header.max_entries() as usize * Ext4ExtentLeafNode::SIZE as usize;
+            let checksum = Ext4IndirectExtents::block_checksum(
+                &node[..tail],
+                inode_num,
+                inode.generation(),
+                self.superblock.uuid(),
+            );
+            if u32::read_buffer(&node[tail..]) != checksum {
+                return Err("extent tree block checksum mismatch".to_string());
+            }
+        }
+        for i in 0..header.entries() as usize {
+            let offset = Ext4ExtentHeader::SIZE as usize + i * Ext4ExtentLeafNode::SIZE as usize;
+            if header.depth() == 0 {
+                leaves.push(Ext4ExtentLeafNode::read_buffer(&node[offset..]));
+                continue;
+            }
+            let block = Ext4ExtentInternalNode::read_buffer(&node[offset..]).leaf();
+            let child = self.block(block).ok_or_else(|| {
+                format!("extent tree block {} is outside of the filesystem", block)
+            })?;
+            tree_blocks.push(block);
+            self.walk_extent_node(
+                inode_num,
+                inode,
+                child,
+                Some(header.depth() - 1),
+                leaves,
+                tree_blocks,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The data blocks (in logical order, `None` for holes) and the indirect blocks of an inode with a block map.
+    fn block_map_blocks(&self, inode: &Ext4Inode) -> Result<(Vec<Option<u64>>, Vec<u64>), String> {
+        let needed = inode.size().div_ceil(self.block_size) as usize;
+        let descriptor = LegacyBlockDescriptor::read_buffer(inode.block());
+        let mut data_blocks = vec![];
+        let mut indirect_blocks = vec![];
+        let pointers = descriptor.direct().iter().map(|&block| (block, 0));
+        let pointers = pointers.chain(
+            (1..)
+                .zip(descriptor.indirect())
+                .map(|(depth, block)| (block, depth)),
+        );
+        for (block, depth) in pointers {
+            self.walk_mapped_block(block, depth, needed, &mut data_blocks, &mut indirect_blocks)?;
+        }
+        Ok((data_blocks, indirect_blocks))
+    }
+
+    fn walk_mapped_block(
+        &self,
+        block: u32,
+        depth: u32,
+        needed: usize,
+        data_blocks: &mut Vec<Option<u64>>,
+        indirect_blocks: &mut Vec<u64>,
+    ) -> Result<(), String> {
+        if data_blocks.len() >= needed {
+            return Ok(());
+        }
+        if block == 0 {
+            // a hole that spans everything the pointer would map
+            let span = (self.block_size as usize / 4).pow(depth);
+            data_blocks.resize((data_blocks.len() + span).min(needed), None);
+            return Ok(());
+        }
+        if depth == 0 {
+            data_blocks.push(Some(block as u64));
+            return Ok(());
+        }
+        let buf = self
+            .block(block as u64)
+            .ok_or_else(|| format!("indirect block {} is outside of the filesystem", block))?;
+        indirect_blocks.push(block as u64);
+        for pointer in buf.chunks(4) {
+            self.walk_mapped_block(
+                u32::read_buffer(pointer),
+                depth - 1,
+                needed,
+                data_blocks,
+                indirect_blocks,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Walk the tree from the root, checking all entries, that every inode is reachable and the link counts.
+    fn check_directories(&mut self) {
+        let first_ino = self.superblock.first_ino();
+        let mut references = BTreeMap::<u32, u32>::new();
+        let mut subdirectories = BTreeMap::<u32, u32>::new();
+        let mut visited = BTreeSet::from([2]);
+        let mut pending = vec![(2, 2)];
+        while let Some((directory, parent)) = pending.pop() {
+            // subdirectories are only queued once their inode was found, but the root is always checked
+            let Some(inode) = self.inodes.get(&directory) else {
+                self.errors.push(FsckError::Inode {
+                    inode: directory,
+                    message: "the directory is not in use".to_string(),
+                });
+                continue;
+            };
+            let entries = match self.directory_entries(directory, inode, parent) {
+                Ok(entries) => entries,
+                Err(message) => {
+                    self.errors.push(FsckError::Directory {
+                        inode: directory,
+                        message,
+                    });
+                    continue;
+                }
+            };
+            for entry in entries {
+                let error = |message| FsckError::Directory {
+                    inode: directory,
+                    message: format!("entry '{}': {}", entry.name(), message),
+                };
+                let Some(inode) = self
+                    .inodes
+                    .get(&entry.inode())
+                    .filter(|_| entry.inode() >= first_ino)
+                else {
+                    self.errors
+                        .push(error(format!("inode {} is not in use", entry.inode())));
+                    continue;
+                };
+                let entry_type = match self.superblock.has_filetype() {
+                    true => inode.file_type().as_directory_entry_type(),
+                    false => 0,
+                };
+                if entry.entry_type() != entry_type {
+                    self.errors
+                        .push(error("the file type does not match the inode".to_string()));
+                }
+                *references.entry(entry.inode()).or_default() += 1;
+                if inode.is_directory() {
+                    *subdirectories.entry(directory).or_default() += 1;
+                    if visited.insert(entry.inode()) {
+                        pending.push((entry.inode(), directory));
+                    } else {
+                        self.errors
+                            .push(error("the directory is linked more than once".to_string()));
+                    }
+                }
+            }
+        }
+
+        for (&inode_num, inode) in &self.inodes {
+            if (inode_num < first_ino && inode_num != 2)
+                || inode_num == self.superblock.quota_inodes()[2]
+            {
+                continue;
+            }
+            if inode_num != 2 && !references.contains_key(&inode_num) {
+                self.errors.push(FsckError::Inode {
+                    inode: inode_num,
+                    message: "in use but not referenced by any directory".to_string(),
+                });
+                continue;
+            }
+            let links = inode.links_count() as u32;
+            let expected = if inode.is_directory() {
+                2 + subdirectories.get(&inode_num).copied().unwrap_or(0)
+            } else {
+                references[&inode_num]
+            };
+            if links != expected && !(expected >= MAX_DIRECTORY_LINKS && links == 1) {
+                self.errors.push(FsckError::Inode {
+                    inode: inode_num,
+                    message: format!("link count is {} but should be {}", links, expected),
+                });
+            }
+        }
+    }
+
+    /// The entries of a directory apart from `.` and `..`, which are checked to point to itself and `parent`.
+    fn directory_entries(
+        &self,
+        directory: u32,
+        inode: &Ext4Inode,
+        parent: u32,
+    ) -> Result<Vec<Ext4DirEntry>, String> {
+        let dotdot = |found: u32| match found == parent {
+            true => Ok(()),
+            false => Err(format!("'..' points to {} instead of {}", found, parent)),
+        };
+        if inode.has_inline_data() {
+            // the first four bytes hold the parent inode number instead of `.` and `..` entries,
+            // after that the records in i_block and the ones in the system.data xattr each form a chain
+            let data = inode.inline_data().map_err(|error| error.to_string())?;
+            if data.len() < Ext4Inode::MAX_INLINE_SIZE_BLOCK {
+                return Err("inline directory is too short".to_string());
+            }
+            dotdot(u32::read_buffer(&data[0..4]))?;
+            let (block_part, xattr_part) = data.split_at(Ext4Inode::MAX_INLINE_SIZE_BLOCK);
+            let mut entries = LinearDirectoryBlock::parse_raw_records(&block_part[4..])
+                .map_err(|error| error.to_string())?;
+            entries.extend(
+                LinearDirectoryBlock::parse_raw_records(xattr_part)
+                    .map_err(|error| error.to_string())?,
+            );
+            return Ok(entries.into_iter().map(|(_, entry)| entry).collect());
+        }
+
+        let Some(blocks) = self.directory_blocks.get(&directory) else {
+            return Err("the directory has no data".to_string());
+        };
+        let indexed = inode.flags() & 0x1000 != 0; // EXT4_INDEX_FL
+        let mut entries = vec![];
+        let (leaves, hash_version) = if indexed {
+            let (leaves, hash_version) =
+                self.htree_leaves(directory, inode, blocks, &mut entries)?;
+            (leaves, Some(hash_version))
+        } else {
+            (
+                (0..blocks.len())
+                    .map(|index| (index, 0, u32::MAX))
+                    .collect(),
+                None,
+            )
+        };
+        for (index, min_hash, max_hash) in leaves {
+            let buf = self.block(blocks[index]).unwrap();
+            let records = if self.checksums {
+                let checksum = LinearDirectoryBlock::raw_checksum(
+                    buf,
+                    self.superblock.uuid(),
+                    directory,
+                    inode.generation(),
+                );
+                if u32::read_buffer(&buf[buf.len() - 4..]) != checksum {
+                    return Err(format!("checksum mismatch in block {}", index));
+                }
+                LinearDirectoryBlock::parse_raw(buf)
+            } else {
+                LinearDirectoryBlock::parse_raw_records(buf)
+            };
+            let records = records.map_err(|error| format!("block {}: {}", index, error))?;
+            if let Some(hash_version) = hash_version {
+                let signed = self.superblock.flags() & 0x2 == 0;
+                let casefold = inode.flags() & Ext4Inode::CASEFOLD_FL != 0;
+                for (_, entry) in &records {
+                    let name = match casefold {
+                        // names that can't be folded here are not checked
+                        true => match casefold_ascii(entry.name()) {
+                            Some(name) => name,
+                            None => continue,
+                        },
+                        false => entry.name().to_string(),
+                    };
+                    let (hash, _) = ext4_dir_hash(
+                        name.as_bytes(),
+                        hash_version,
+                        self.superblock.hash_seed(),
+                        signed,
+                    );
+                    if hash < min_hash || hash > max_hash {
+                        return Err(format!(
+                            "entry '{}' in block {} is outside of the hash range of its HTree leaf",
+                            entry.name(),
+                            index
+                        ));
+                    }
+                }
+            }
+            entries.extend(records.into_iter().map(|(_, entry)| entry));
+        }
+        match entries.get(..2) {
+            Some([dot, dotdot_entry]) if dot.name() == "." && dotdot_entry.name() == ".." => {
+                if dot.inode() != directory {
+                    return Err(format!("'.' points to {}", dot.inode()));
+                }
+                dotdot(dotdot_entry.inode())?;
+            }
+            _ => return Err("the directory does not start with '.' and '..'".to_string()),
+        }
+        Ok(entries.split_off(2))
+    }
+
+    /// Check the index of an HTree directory, add the `.` and `..` entries of its root to `entries` and return
+    /// every leaf block (as an index into `blocks`) with the range of hashes it may contain, and the hash algorithm.
+    fn htree_leaves(
+        &self,
+        directory: u32,
+        inode: &Ext4Inode,
+        blocks: &[u64],
+        entries: &mut Vec<Ext4DirEntry>,
+    ) -> Result<(Vec<HtreeLeaf>, DirHashVersion), String> {
+        let root = self.block(blocks[0]).unwrap();
+        // `.` takes 12 bytes and `..` covers the rest of the block including the index
+        let dots_valid = u16::read_buffer(&root[4..6]) == 12
+            && root[6..9] == [1, 2, b'.']
+            && u16::read_buffer(&root[16..18]) as u64 == self.block_size - 12
+            && root[18..22] == [2, 2, b'.', b'.'];
+        if !dots_valid {
+            return Err("the HTree root does not start with '.' and '..'".to_string());
+        }
+        entries.push(Ext4DirEntry::read_buffer(&root[..12]));
+        entries.push(Ext4DirEntry::read_buffer(&root[12..24]));
+        let (info, root_entries) = DxIndexBlock::parse_raw(root, true, self.checksums)
+            .map_err(|error| format!("block 0: {}", error))?;
+        self.check_dx_checksum(directory, inode, root, 0, true)?;
+        let info = info.unwrap();
+        let hash_version = match info.hash_version() {
+            0 => DirHashVersion::Legacy,
+            1 => DirHashVersion::HalfMd4,
+            2 => DirHashVersion::Tea,
+            version => return Err(format!("unknown HTree hash version {}", version)),
+        };
+        // a third level needs the largedir feature
+        if info.indirect_levels() > 1 {
+            return Err(format!(
+                "the HTree has {} indirect levels",
+                info.indirect_levels()
+            ));
+        }
+
+        let mut referenced = BTreeSet::from([0]);
+        let mut leaves = vec![];
+        let mut pending = vec![(root_entries, 0, u32::MAX, info.indirect_levels())];
+        while let Some((dx_entries, range_start, range_end, levels)) = pending.pop() {
+            for (i, entry) in dx_entries.iter().enumerate() {
+                let start = if i == 0 {
+                    range_start
+                } else {
+                    entry.hash() & !1
+                };
+                let end = dx_entries
+                    .get(i + 1)
+                    .map_or(range_end, |next| next.hash() & !1);
+                if start > end || start < range_start {
+                    return Err("the HTree hashes are not sorted".to_string());
+                }
+                let index = entry.block() as usize;
+                if index >= blocks.len() || !referenced.insert(index) {
+                    return Err(format!(
+                        "the HTree references block {} more than once or past the end",
+                        index
+                    ));
+                }
+                if levels == 0 {
+                    leaves.push((index, start, end));
+                } else {
+                    let node = self.block(blocks[index]).unwrap();
+                    let (_, node_entries) = DxIndexBlock::parse_raw(node, false, self.checksums)
+                        .map_err(|error| format!("block {}: {}", index, error))?;
+                    self.check_dx_checksum(directory, inode, node, index, false)?;
+                    pending.push((node_entries, start, end, levels - 1));
+                }
+            }
+        }
+        if let Some(index) = (0..blocks.len()).find(|index| !referenced.contains(index)) {
+            return Err(format!("block {} is not referenced by the HTree", index));
+        }
+        Ok((leaves, hash_version))
+    }
+
+    fn check_dx_checksum(
+        &self,
+        directory: u32,
+        inode: &Ext4Inode,
+        buf: &[u8],
+        index: usize,
+        root: bool,
+    ) -> Result<(), String> {
+        if !self.checksums {
+            return Ok(());
+        }
+        let (tail_offset, checksum) = DxIndexBlock::raw_checksum(
+            buf,
+            root,
+            self.superblock.uuid(),
+            directory,
+            inode.generation(),
+        );
+        if u32::read_buffer(&buf[tail_offset + 4..]) != checksum {
+            return Err(format!("checksum mismatch in HTree block {}", index));
+        }
+        Ok(())
+    }
+
+    /// Compare the bitmaps and free counts with the usage found while checking the inodes.
+    fn check_bitmaps(&mut self) {
+        let blocks_per_group = self.block_size * 8;
+        let inodes_per_group = self.superblock.inodes_per_group();
+        let mut total_free_blocks = 0;
+        let mut total_free_inodes = 0;
+        for group in 0..self.descriptors.len() {
+            let descriptor = self.descriptors[group].clone();
+            let group_start = self.superblock.first_data_block() + group as u64 * blocks_per_group;
+            let len = (self.superblock.blocks_count() - group_start).min(blocks_per_group) as u32;
+            let expected_blocks = self
+                .used_blocks
+                .get_bitmap(group_start, len, self.block_size);
+            let expected_inodes = self.used_inodes.get_bitmap(
+                group as u64 * inodes_per_group as u64,
+                inodes_per_group,
+                self.block_size,
+            );
+            total_free_blocks += expected_blocks.free_count() as u64;
+            total_free_inodes += expected_inodes.free_count();
+
+            let mut errors = vec![];
+            let flags = descriptor.flags();
+            let block_bitmap = if flags & Ext4BlockGroupDescriptor::BLOCK_UNINIT != 0 {
+                // the kernel only considers the group's own metadata and superblock backup to be in use
+                let mut bitmap = BitmapBlock::from_bytes(&[], len, self.block_size);
+                let metadata = [
+                    (descriptor.block_bitmap(), 1),
+                    (descriptor.inode_bitmap(), 1),
+                    (descriptor.inode_table(), self.inode_table_blocks()),
+                ]
+                .into_iter()
+                .chain(self.superblock_region(group as u64));
+                for (start, metadata_len) in metadata {
+                    for block in start..start + metadata_len {
+                        if (group_start..group_start + len as u64).contains(&block) {
+                            bitmap.set_bit((block - group_start) as u32);
+                        }
+                    }
+                }
+                bitmap
+            } else {
+                let Some(buf) = self.block(descriptor.block_bitmap()) else {
+                    continue;
+                };
+                BitmapBlock::read_with_len(buf, len)
+            };
+            let inode_bitmap = if flags & Ext4BlockGroupDescriptor::INODE_UNINIT != 0 {
+                BitmapBlock::from_bytes(&[], inodes_per_group, self.block_size)
+            } else {
+                let Some(buf) = self.block(descriptor.inode_bitmap()) else {
+                    continue;
+                };
+                BitmapBlock::read_with_len(buf, inodes_per_group)
+            };
+
+            if self.checksums {
+                let mut expected = descriptor.clone();
+                expected.set_bitmap_checksums(
+                    self.superblock.uuid(),
+                    &block_bitmap,
+                    &inode_bitmap,
+                    self.superblock.desc_size(),
+                );
+                if flags & Ext4BlockGroupDescriptor::BLOCK_UNINIT == 0
+                    && expected.block_bitmap_csum() != descriptor.block_bitmap_csum()
+                {
+                    errors.push("block bitmap checksum mismatch".to_string());
+                }
+                if flags & Ext4BlockGroupDescriptor::INODE_UNINIT == 0
+                    && expected.inode_bitmap_csum() != descriptor.inode_bitmap_csum()
+                {
+                    errors.push("inode bitmap checksum mismatch".to_string());
+                }
+            }
+            if !block_bitmap.validate_padding() {
+                errors.push("the block bitmap is not padded".to_string());
+            }
+            if !inode_bitmap.validate_padding() {
+                errors.push("the inode bitmap is not padded".to_string());
+            }
+            errors.extend(bitmap_differences(
+                "block",
+                group_start,
+                len,
+                &expected_blocks,
+                &block_bitmap,
+            ));
+            errors.extend(bitmap_differences(
+                "inode",
+                group as u64 * inodes_per_group as u64 + 1,
+                inodes_per_group,
+                &expected_inodes,
+                &inode_bitmap,
+            ));
+
+            let counts = [
+                (
+                    "free blocks",
+                    descriptor.free_blocks_count(),
+                    expected_blocks.free_count(),
+                ),
+                (
+                    "free inodes",
+                    descriptor.free_inodes_count(),
+                    expected_inodes.free_count(),
+                ),
+                (
+                    "directories",
+                    descriptor.used_dirs_count(),
+                    (1..=inodes_per_group)
+                        .map(|index| group as u32 * inodes_per_group + index)
+                        .filter(|inode_num| {
+                            self.inodes
+                                .get(inode_num)
+                                .is_some_and(Ext4Inode::is_directory)
+                        })
+                        .count() as u32,
+                ),
+            ];
+            for (name, found, expected) in counts {
+                if found != expected {
+                    errors.push(format!(
+                        "{} count is {} but should be {}",
+                        name, found, expected
+                    ));
+                }
+            }
+            if self.checksums {
+                let unused_start = inodes_per_group.saturating_sub(descriptor.itable_unused());
+                if let Some(index) =
+                    (unused_start..inodes_per_group).find(|&index| expected_inodes.is_set(index))
+                {
+                    errors.push(format!(
+                        "inode {} is in use but bg_itable_unused claims it is not",
+                        group as u32 * inodes_per_group + index + 1
+                    ));
+                }
+            }
+            self.errors
+                .extend(errors.into_iter().map(|message| FsckError::BlockGroup {
+                    group: group as u32,
+                    message,
+                }));
+        }
+
+        if self.superblock.free_blocks_count() != total_free_blocks {
+            self.errors.push(FsckError::Superblock(format!(
+                "free blocks count is {} but should be {}",
+                self.superblock.free_blocks_count(),
+                total_free_blocks
+            )));
+        }
+        if self.superblock.free_inodes_count() != total_free_inodes {
+            self.errors.push(FsckError::Superblock(format!(
+                "free inodes count is {} but should be {}",
+                self.superblock.free_inodes_count(),
+                total_free_inodes
+            )));
+        }
+    }
+}
+
+/// Describe where the first `len` entries of a bitmap on disk differ from the `expected` usage,
+/// numbering the entries from `first`.
+fn bitmap_differences(
+    kind: &str,
+    first: u64,
+    len: u32,
+    expected: &BitmapBlock,
+    found: &BitmapBlock,
+) -> Option<String> {
+    let differences = (0..len)
+        .filter(|&i| expected.is_set(i) != found.is_set(i))
+        .collect::<Vec<_>>();
+    let &difference = differences.first()?;
+    let state = match expected.is_set(difference) {
+        true => "in use but marked as free",
+        false => "free but marked as in use",
+    };
+    Some(format!(
+        "the {} bitmap differs in {} entries, i.e. {} {} is {}",
+        kind,
+        differences.len(),
+        kind,
+        first + difference as u64,
+        state
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BLOCK_SIZE, Ext4ImageReader, Ext4ImageWriter,
+        test_image::{FRAGMENTED_LEN, build_image},
+    };
+    use std::io::Cursor;
+
+    /// The byte offset of the inode at `path` in the image.
+    fn inode_offset(image: &[u8], path: &str) -> usize {
+        let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+        let inode_num = reader.lookup(path).unwrap();
+        let inode_table = reader.read_block_group_descriptor(0).unwrap().inode_table();
+        let inode_size = reader.superblock().inode_size();
+        (inode_table * BLOCK_SIZE + (inode_num as u64 - 1) * inode_size) as usize
+    }
+
+    #[test]
+    fn test_fsck_lite_accepts_written_images() {
+        for ext2 in [false, true] {
+            let image = build_image(ext2);
+            fsck_lite(&image).unwrap();
+            let mut reader = Ext4ImageReader::new(Cursor::new(&image)).unwrap();
+            assert_eq!(
+                reader.read_file("fragmented").unwrap(),
+                vec![0x42; FRAGMENTED_LEN]
+            );
+        }
+    }
+
+    #[test]
+    fn test_fsck_lite_detects_checksum_mismatches() {
+        let image = build_image(false);
+
+        let mut corrupted = image.clone();
+        corrupted[1024 + 0x60] ^= 0x1; // s_feature_incompat
+        assert_eq!(
+            fsck_lite(&corrupted),
+            Err(vec![FsckError::Superblock("checksum mismatch".to_string())])
+        );
+
+        let mut corrupted = image.clone();
+        corrupted[inode_offset(&image, "etc/hostname") + 26] += 1; // i_links_count
+        assert_eq!(
+            fsck_lite(&corrupted).unwrap_err()[0],
+            FsckError::Inode {
+                inode: 12,
+                message: "checksum mismatch".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_fsck_lite_detects_structural_errors() {
+        // ext2 images have no checksums, so the structural checks have to catch these
+        let image = build_image(true);
+
+        let mut corrupted = image.clone();
+        corrupted[inode_offset(&image, "etc/hostname") + 26] += 1; // i_links_count
+        assert_eq!(
+            fsck_lite(&corrupted),
+            Err(vec![FsckError::Inode {
+                inode: 12,
+                message: "link count is 2 but should be 1".to_string()
+            }])
+        );
+
+        let mut corrupted = image.clone();
+        corrupted[inode_offset(&image, "etc/ssh/key") + 26] = 0; // unlink the inode, but keep its entry
+        let errors = fsck_lite(&corrupted).unwrap_err();
+        assert!(matches!(
+            &errors[0],
+            FsckError::Directory { message, .. } if message == "entry 'key': inode 13 is not in use"
+        ));
+        // its blocks and inode are still marked as used and are counted as free in the descriptor
+        assert!(errors.iter().any(|error| matches!(
+            error,
+            FsckError::BlockGroup { message, .. } if message.starts_with("the inode bitmap differs in 1 entries")
+        )));
+
+        let mut corrupted = image.clone();
+        let mut reader = Ext4ImageReader::new(Cursor::new(&image)).unwrap();
+        let last_group = reader.superblock().block_groups_count() - 1;
+        let block_bitmap = reader
+            .read_block_group_descriptor(last_group)
+            .unwrap()
+            .block_bitmap();
+        corrupted[(block_bitmap * BLOCK_SIZE) as usize + 4095] = 0; // the padding of the last group
+        let errors = fsck_lite(&corrupted).unwrap_err();
+        assert_eq!(
+            errors[0],
+            FsckError::BlockGroup {
+                group: last_group,
+                message: "the block bitmap is not padded".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_fsck_lite_reports_missing_root() {
+        let image = build_image(true);
+        let mut corrupted = image.clone();
+        // move the inode table of the first group, which holds the root directory, past the end of the image
+        let descriptor = BLOCK_SIZE as usize;
+        corrupted[descriptor + 8..descriptor + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let errors = fsck_lite(&corrupted).unwrap_err();
+        assert!(
+            errors.contains(&FsckError::Inode {
+                inode: 2,
+                message: "the directory is not in use".to_string()
+            }),
+            "{:?}",
+            errors
+        );
+    }
+
+    #[test]
+    fn test_fsck_lite_checks_htree() {
+        for ext2 in [false, true] {
+            let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+            if ext2 {
+                writer = writer.ext2_compat().unwrap();
+            }
+            writer.write_file(b"x", "file", 0o644).unwrap();
+            writer.mkdir("many").unwrap();
+            for i in 0..500 {
+                writer.link("file", &format!("many/{:040}", i)).unwrap();
+            }
+            let image = writer.finish().unwrap().into_inner();
+            fsck_lite(&image).unwrap();
+
+            let mut reader = Ext4ImageReader::new(Cursor::new(&image)).unwrap();
+            let inode_num = reader.lookup("many").unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            assert_ne!(inode.flags() & 0x1000, 0);
+            let root = if ext2 {
+                LegacyBlockDescriptor::read_buffer(inode.block()).direct()[0] as u64
+            } else {
+                reader.read_data_blocks(&inode).unwrap()[0].start
+            };
+            let mut corrupted = image.clone();
+            // the hash of the second entry, so the names of the first leaf no longer fit below it
+            corrupted[(root * BLOCK_SIZE) as usize + 40] = 0;
+            corrupted[(root * BLOCK_SIZE) as usize + 43] = 0;
+            let errors = fsck_lite(&corrupted).unwrap_err();
+            let FsckError::Directory { inode, message } = &errors[0] else {
+                panic!("{:?}", errors);
+            };
+            assert_eq!(*inode, inode_num);
+            if ext2 {
+                assert!(message.contains("outside of the hash range"), "{}", message);
+            } else {
+                assert_eq!(message, "checksum mismatch in HTree block 0");
+            }
+        }
+    }
+
+    #[test]
+    fn test_fsck_lite_rejects_garbage() {
+        assert!(matches!(
+            fsck_lite(&[0u8; 512]).unwrap_err().as_slice(),
+            [FsckError::Superblock(_)]
+        ));
+        let image = build_image(false);
+        assert!(matches!(
+            fsck_lite(&image[..image.len() - 4096])
+                .unwrap_err()
+                .as_slice(),
+            [FsckError::Superblock(_)]
+        ));
+    }
+}
@@ -0,0 +1,176 @@
+//! A small matcher for host glob patterns, used by [`crate::Ext4ImageWriter::add_glob`].
+//! Supports `*`, `?`, `[...]` (including ranges and `[!...]`) within a path component and `**` for any number of directories.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Expand `pattern` on the host and return all matching regular files, keyed by their path relative to
+/// the leading components of the pattern that contain no wildcards.
+pub(crate) fn expand(pattern: &str) -> io::Result<BTreeMap<String, PathBuf>> {
+    let components: Vec<&str> = pattern.split('/').collect();
+    let literal_len = components
+        .iter()
+        .position(|component| has_wildcards(component))
+        .ok_or_else(|| io::Error::other(format!("'{}' contains no wildcards", pattern)))?;
+    let base = match components[..literal_len].join("/") {
+        base if base.is_empty() && pattern.starts_with('/') => "/".to_string(),
+        base if base.is_empty() => ".".to_string(),
+        base => base,
+    };
+    let mut matches = BTreeMap::new();
+    walk(
+        Path::new(&base),
+        "",
+        &components[literal_len..],
+        &mut matches,
+    )?;
+    Ok(matches)
+}
+
+fn has_wildcards(component: &str) -> bool {
+    component.contains(['*', '?', '['])
+}
+
+fn walk(
+    dir: &Path,
+    relative: &str,
+    pattern: &[&str],
+    matches: &mut BTreeMap<String, PathBuf>,
+) -> io::Result<()> {
+    let Some((&first, rest)) = pattern.split_first() else {
+        return Ok(());
+    };
+    if first == "**" {
+        // match zero directories here, more directories are matched by recursing with `**` below
+        walk(dir, relative, rest, matches)?;
+    }
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue; // not representable in the image's '/' separated paths
+        };
+        let path = entry.path();
+        let metadata = fs::metadata(&path)?;
+        let entry_relative = if relative.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", relative, name)
+        };
+        if first == "**" {
+            if metadata.is_dir() {
+                walk(&path, &entry_relative, pattern, matches)?;
+            }
+        } else if component_matches(first.as_bytes(), name.as_bytes()) {
+            if rest.is_empty() {
+                if metadata.is_file() {
+                    matches.insert(entry_relative, path);
+                }
+            } else if metadata.is_dir() {
+                walk(&path, &entry_relative, rest, matches)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Match a single path component against a pattern without `/`.
+/// Like most shells, a mismatch after a `*` only retries from the last `*`, which keeps this linear
+/// in the length of the name for each `*` instead of exponential in their number.
+fn component_matches(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // the pattern position after the last `*` and the name position that `*` matched up to
+    let mut star = None;
+    while n < name.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, n));
+        } else if let Some(len) = single_matches(&pattern[p..], name[n]) {
+            p += len;
+            n += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // let the last `*` match one more byte
+            p = star_p;
+            n = star_n + 1;
+            star = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Match `c` against the `?`, class or literal at the start of `pattern`.
+/// Returns the length of that part of the pattern if it matched.
+fn single_matches(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern.split_first()? {
+        (b'?', _) => Some(1),
+        (b'[', rest) => match class_matches(rest, c) {
+            Some((matched, after)) => matched.then_some(pattern.len() - after.len()),
+            // an unterminated class is matched literally
+            None => (c == b'[').then_some(1),
+        },
+        (&literal, _) => (c == literal).then_some(1),
+    }
+}
+
+/// Match `c` against the character class starting after the `[`.
+/// Returns whether it matched and the rest of the pattern after the `]`, or `None` if the class is unterminated.
+fn class_matches(pattern: &[u8], c: u8) -> Option<(bool, &[u8])> {
+    let (negated, mut pattern) = match pattern.split_first() {
+        Some((b'!' | b'^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        match pattern {
+            [b']', rest @ ..] if !first => return Some((matched != negated, rest)),
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                matched |= (*start..=*end).contains(&c);
+                pattern = rest;
+            }
+            [literal, rest @ ..] => {
+                matched |= c == *literal;
+                pattern = rest;
+            }
+            [] => return None,
+        }
+        first = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_component_matches() {
+        assert!(component_matches(b"*.conf", b"resolv.conf"));
+        assert!(component_matches(b"*", b""));
+        assert!(!component_matches(b"*.conf", b"resolv.conf.bak"));
+        assert!(component_matches(b"file-?", b"file-1"));
+        assert!(!component_matches(b"file-?", b"file-10"));
+        assert!(component_matches(b"[a-c]*", b"bin"));
+        assert!(!component_matches(b"[!a-c]*", b"bin"));
+        assert!(component_matches(b"[]]", b"]"));
+        assert!(component_matches(b"[ab", b"[ab"));
+        assert!(component_matches(b"*a*b", b"xaxxb"));
+        assert!(!component_matches(b"*a*b", b"xaxxbx"));
+        assert!(component_matches(b"a**", b"a"));
+        // each `*` only retries from its own position, so this fails quickly
+        let name = [b'a'; 100];
+        assert!(!component_matches(b"*a*a*a*a*a*a*a*a*a*a*a*b", &name));
+    }
+
+    #[test]
+    fn test_expand() {
+        let matches = expand("src/**/*_h.rs").unwrap();
+        assert_eq!(matches.keys().collect::<Vec<_>>(), vec!["ext4_h.rs"]);
+        assert_eq!(matches["ext4_h.rs"], Path::new("src/ext4_h.rs"));
+        assert!(expand("src/lib.rs").is_err());
+    }
+}
@@ -1,78 +1,182 @@
 #![doc = include_str!("../README.md")]
 
-use crate::{ext4_h::*, file_tree::Directory, serialization::Buffer};
-use std::io::{self, Cursor, Write};
+use crate::{
+    allocator::UsageBitmap,
+    ext4_h::*,
+    file_tree::{Directory, DirectoryMetadata},
+    serialization::Buffer,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{self, Cursor, Write},
+};
 
+mod allocator;
+mod block_collector;
+mod builder;
+#[cfg(test)]
+mod differential;
+mod dir_hash;
+mod error;
 mod ext4_h;
 mod file_tree;
+mod fsck;
+#[cfg(feature = "glob")]
+mod glob;
+mod metadata;
+mod observer;
+mod reader;
 mod serialization;
+#[cfg(test)]
+mod test_image;
 mod util;
+mod verify;
 
+pub use allocator::{Allocation, BlockAllocator, BumpAllocator};
+pub use block_collector::BlockCollector;
+pub use builder::Ext4ImageWriterBuilder;
+pub use dir_hash::{DirHashVersion, ext4_dir_hash};
+pub use error::Ext4Error;
+pub use ext4_h::{Ext4Inode, FileType, StorageMode, uuid_checksum_seed};
+pub use fsck::{FsckError, fsck_lite};
+pub use metadata::{Timestamp, Timestamps};
+pub use observer::{FinishStage, NoopObserver, Observer};
+pub use reader::Ext4ImageReader;
+pub use verify::{ChecksumMismatch, VerifyReport, verify};
+
+/// The default block size, see [`Ext4ImageWriter::with_block_size`].
 const BLOCK_SIZE: u64 = 4096;
+/// Directories with more subdirectories than this have a link count of 1 (`dir_nlink`).
+const MAX_DIRECTORY_LINKS: u32 = 65000;
+/// lost+found is preallocated to this size, like mkfs does.
+const LOST_FOUND_SIZE: u64 = 16 * 1024;
 
-#[derive(Default)]
-struct UsageBitmap {
-    data: Vec<u8>,
-    next_free: u64,
+/// The geometry of a finished image as returned by [`Ext4ImageWriter::finish_with_info`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ext4ImageInfo {
+    pub uuid: [u8; 16],
+    pub block_size: u64,
+    pub blocks_count: u64,
+    pub block_groups: u64,
+    pub inodes_count: u64,
+    pub inodes_per_group: u64,
+    pub free_blocks_count: u64,
+    pub free_inodes_count: u64,
+    /// The size of the image in bytes, see also [`Ext4ImageReader::minimal_size`].
+    pub size: u64,
+    /// The smallest `max_size` that the same contents can be written with again, which is `size` without the GDT
+    /// blocks (and their backups) that are only reserved for resizing beyond the current block groups.
+    /// Writing the same files into a writer created with this `max_size` gives the tightest image for them,
+    /// unless files were placed with [`Ext4ImageWriter::write_file_at_block`].
+    pub minimal_max_size: u64,
 }
-impl UsageBitmap {
-    fn mark_used(&mut self, block_num: u64) {
-        let byte_index = (block_num / 8) as usize;
-        let bit_index = (block_num % 8) as u8;
-        if byte_index >= self.data.len() {
-            self.data.resize(byte_index + 1, 0);
-        }
-        self.data[byte_index] |= 1 << bit_index;
-    }
-    fn get_for_block_group(&mut self, block_group: u64, len: u32) -> BitmapBlock {
-        let start = (block_group * BLOCK_SIZE) as usize;
-        let end = ((block_group + 1) * BLOCK_SIZE) as usize;
-        if self.data.len() < end {
-            self.data.resize(end, 0);
-        }
-        BitmapBlock::from_bytes(&self.data[start..end], len)
-    }
-    fn allocate(&mut self, n: u64) -> Allocation {
-        let start = self.next_free;
-        for i in 0..n {
-            self.mark_used(self.next_free + i);
-        }
-        self.next_free += n;
-        Allocation {
-            start,
-            end: self.next_free,
-        }
+impl Ext4ImageInfo {
+    /// The number of blocks in use, including all metadata.
+    pub fn used_blocks(&self) -> u64 {
+        self.blocks_count - self.free_blocks_count
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-struct Allocation {
-    pub start: u64,
-    pub end: u64,
+/// One path of the listing returned by [`Ext4ImageWriter::manifest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The path without a leading '/', the root directory is the empty string.
+    pub path: String,
+    pub file_type: FileType,
+    pub mode: u16,
+    /// The size of the contents in bytes. Directories report 0 as their blocks are only laid out when finishing.
+    pub size: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub inode: u32,
 }
-impl Allocation {
-    pub fn from_start_len(start: u64, len: u64) -> Self {
-        Allocation {
-            start,
-            end: start + len,
-        }
-    }
-    pub fn as_single(self) -> u64 {
-        assert!(self.end == self.start + 1);
-        self.start
-    }
-    pub fn len(self) -> u64 {
-        self.end - self.start
-    }
+
+/// The order in which the entries of a directory are written to its blocks.
+/// `.` and `..` always come first, HTree indexed directories are ordered by hash within each leaf block anyway.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EntryOrdering {
+    /// The order in which the entries were added.
+    #[default]
+    Insertion,
+    /// Sorted by the bytes of the name. Independent of the insertion order, so images are reproducible.
+    ByName,
 }
 
-pub struct Ext4ImageWriter<W: io::Write + io::Seek> {
+/// How files without contents are stored. All variants are valid ext4, they only differ in the inode flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyFileRepresentation {
+    /// An inline data inode with an empty `system.data` xattr.
+    #[default]
+    Inline,
+    /// An extent tree without any extents.
+    EmptyExtents,
+    /// Neither inline data nor extents: no flags and `i_block` is all zeros.
+    Bare,
+}
+
+/// The superblock revision (`s_rev_level`) of the image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Revision {
+    /// The original ext2 format: 128 byte inodes, user inodes start at 11 and there are no feature flags.
+    Rev0,
+    /// Feature flags, configurable inode sizes and everything ext4 builds on.
+    #[default]
+    Dynamic,
+}
+
+/// The SHA-256 digest of contents written with deduplication.
+type ContentsDigest = [u8; 32];
+
+pub struct Ext4ImageWriter<W: io::Write + io::Seek, A: BlockAllocator = BumpAllocator> {
     writer: W,
+    allocator: A,
     uuid: [u8; 16],
     max_size: u64,
+    block_size: u64,
+    inode_size: u64,
+    lost_found_mode: u16,
+    volume_label: String,
+    reserved_percent: f64,
+    reserved_owner: (u16, u16),
+    /// The size of the internal journal in blocks, 0 for none.
+    journal_blocks: u64,
+    /// Whether quota files are written, see [`Ext4ImageWriter::set_quota`].
+    quota: bool,
+    min_inode_count: u64,
+    lost_found_inode: u64,
+    log_groups_per_flex: u8,
+    fixed_group_metadata: bool,
+    superblock_backups_reserved: bool,
+    signed_directory_hash: bool,
+    encryption: bool,
+    casefold: bool,
+    /// Whether a non-zero project id was set, which needs the `project` feature.
+    project_ids: bool,
+    empty_files: EmptyFileRepresentation,
+    entry_ordering: EntryOrdering,
+    root_indexed: bool,
+    default_timestamps: Timestamps,
+    group_flags: BTreeMap<u64, u16>,
+    ext2: bool,
+    revision: Revision,
+    max_inodes: u64,
+    observer: Box<dyn Observer>,
 
     directories: Directory,
     inodes: Vec<Ext4Inode>,
+    /// The data and extent tree (or block map) blocks by inode number, so they can be freed when a file is replaced.
+    file_blocks: BTreeMap<u64, Vec<Allocation>>,
+    /// Set with [`Ext4ImageWriter::with_deduplication`].
+    deduplication: bool,
+    /// The data blocks of the contents written so far, by the SHA-256 digest of the contents.
+    deduplicated: HashMap<ContentsDigest, Vec<Allocation>>,
+    /// The key in `deduplicated` of each of its data block allocations, by the first block.
+    deduplicated_allocations: HashMap<u64, ContentsDigest>,
+    /// How many files use each data block allocation that is shared, by the first block.
+    shared_allocations: HashMap<u64, usize>,
+    /// Whether data blocks are used by more than one file.
+    shared_blocks: bool,
     used_blocks: UsageBitmap,
     used_inodes: UsageBitmap,
 }
@@ -80,446 +184,7420 @@ impl<W: io::Write + io::Seek> Ext4ImageWriter<W> {
     /// Create a new `Ext4ImageWriter` that writes to the given writer (i.e. a file or an in-memory buffer).
     /// The `max_size` parameter specifies the maximum size of the image in bytes (potentially after resizing).
     /// This is used to determine the space reserved for block group descriptors.
+    /// Finishing fails with [`Ext4Error::OutOfSpace`] if the contents end up needing more.
+    /// To choose the block size, the uuid and other settings up front, use [`Ext4ImageWriterBuilder`].
     pub fn new(writer: W, max_size: u64) -> Self {
+        Self::with_allocator(writer, max_size, BumpAllocator::default())
+    }
+
+    /// Like [`Ext4ImageWriter::new`], but with a random uuid instead of a fixed one,
+    /// so that separately built images can be told apart (i.e. by `blkid` or `UUID=` in `/etc/fstab`).
+    pub fn new_with_random_uuid(writer: W, max_size: u64) -> Result<Self, Ext4Error> {
+        Self::new(writer, max_size).with_uuid(random_uuid()?)
+    }
+}
+impl Ext4ImageWriter<Cursor<Vec<u8>>> {
+    /// Like [`Ext4ImageWriter::new`], but builds the image in memory, see [`Ext4ImageWriter::finish_to_vec`].
+    pub fn new_in_memory(max_size: u64) -> Self {
+        Self::new(Cursor::new(Vec::new()), max_size)
+    }
+}
+impl<A: BlockAllocator> Ext4ImageWriter<Cursor<Vec<u8>>, A> {
+    /// Like [`Ext4ImageWriter::finish`], but returns the bytes of the whole image.
+    /// Blocks that were never written (i.e. free blocks in between) are zeros.
+    pub fn finish_to_vec(self) -> Result<Vec<u8>, Ext4Error> {
+        Ok(self.finish()?.into_inner())
+    }
+}
+impl<W: io::Read + io::Write + io::Seek> Ext4ImageWriter<W> {
+    /// Open an image that was written by this crate to add more files to it and finish it again.
+    /// The contents of all files stay where they are. Directories, inode tables and the other metadata
+    /// are rebuilt when finishing, so the blocks they occupied become free again.
+    /// Directories keep their mode, owner, timestamps and extended attributes.
+    /// The maximum size, the uuid and the settings recorded in the superblock are taken over,
+    /// all others (i.e. [`Ext4ImageWriter::set_entry_ordering`]) start out at their defaults.
+    pub fn open(mut writer: W) -> Result<Self, Ext4Error> {
+        let mut reader = Ext4ImageReader::new(&mut writer)?;
+        let superblock = reader.superblock().clone();
+        superblock.check_written_by_this_crate()?;
+        let block_size = superblock.block_size();
+        let bgdt_blocks = (superblock.block_groups_count() as u64 * Ext4BlockGroupDescriptor::SIZE)
+            .div_ceil(block_size)
+            + superblock.reserved_gdt_blocks() as u64;
+        // the largest size that still results in the same number of GDT blocks
+        let max_size = bgdt_blocks
+            * (block_size / Ext4BlockGroupDescriptor::SIZE)
+            * block_size
+            * block_size
+            * 8;
+        let fixed_group_metadata = superblock.inodes_per_group() as u64
+            == Self::NO_FLEX_INODES_PER_GROUP
+            && reader.read_block_group_descriptor(0)?.block_bitmap()
+                == superblock.first_data_block() + 1 + bgdt_blocks;
+
+        let root = reader.read_inode(2)?;
+        let mut directories = Directory::default();
+        if let Some(context) = root.xattr(9, b"c")? {
+            directories.set_encryption_context(&context);
+        }
+        *directories.metadata_mut() = Self::read_directory_metadata(&mut reader, &root)?;
+        let mut files = vec![];
+        Self::read_directory(&mut reader, &root, "", &mut directories, &mut files)?;
+        let lost_found_mode = reader.read_inode(superblock.first_ino())?.mode();
+        // the journal is allocated anew when finishing, so its blocks are free until then
+        let journal_blocks = if superblock.has_journal() {
+            reader.read_inode(8)?.size() / block_size
+        } else {
+            0
+        };
+        // the mode of lost+found is kept in `lost_found_mode` so that it can still be changed
+        directories.directory_mut("lost+found")?.metadata_mut().mode = None;
+
+        let mut this = Self::new(writer, max_size)
+            .with_block_size(block_size)?
+            .with_inode_size(superblock.inode_size() as u16)?
+            .with_flex_bg_size(superblock.log_groups_per_flex())?
+            .with_directory_hash_signedness(superblock.flags() & 0x1 != 0);
+        if fixed_group_metadata {
+            this = this.with_fixed_group_metadata()?;
+        }
+        this.uuid = *superblock.uuid();
+        this.reserve_inodes(superblock.first_ino() as u64 - this.lost_found_inode)?;
+        this.lost_found_mode = lost_found_mode;
+        this.volume_label = superblock.volume_name().to_string();
+        this.reserved_percent =
+            superblock.reserved_blocks_count() as f64 * 100.0 / superblock.blocks_count() as f64;
+        this.reserved_owner = superblock.default_reserved_owner();
+        this.encryption = superblock.feature_incompat() & 0x10000 != 0; /* encrypt */
+        this.casefold = superblock.has_casefold();
+        this.project_ids = superblock.has_project();
+        this.shared_blocks = superblock.has_shared_blocks();
+        this.journal_blocks = journal_blocks;
+        // like the journal, the quota files are written anew when finishing
+        this.quota = superblock.has_quota();
+        this.directories = directories;
+        for (inode_num, inode, allocations) in files {
+            if this.inodes.len() < inode_num as usize {
+                // the inodes of directories are assigned anew when finishing
+                this.inodes.resize(inode_num as usize, Ext4Inode::default());
+            }
+            this.inodes[inode_num as usize - 1] = inode;
+            this.used_inodes.mark_used(inode_num as u64 - 1);
+            for allocation in &allocations {
+                for block in allocation.start..allocation.end {
+                    this.mark_block_used(block);
+                }
+                if this.shared_blocks {
+                    *this.shared_allocations.entry(allocation.start).or_default() += 1;
+                }
+            }
+            this.file_blocks.insert(inode_num as u64, allocations);
+        }
+        this.shared_allocations.retain(|_, users| *users > 1);
+        Ok(this)
+    }
+
+    /// Add the entries of the on-disk directory `inode` at `path` to `directories` and collect its files.
+    fn read_directory<R: io::Read + io::Seek>(
+        reader: &mut Ext4ImageReader<R>,
+        inode: &Ext4Inode,
+        path: &str,
+        directories: &mut Directory,
+        files: &mut Vec<(u32, Ext4Inode, Vec<Allocation>)>,
+    ) -> Result<(), Ext4Error> {
+        for entry in reader.read_directory_entries(inode)? {
+            if entry.name() == "." || entry.name() == ".." {
+                continue;
+            }
+            let entry_path = format!("{}/{}", path, entry.name());
+            let entry_inode = reader.read_inode(entry.inode())?;
+            if entry_inode.is_directory() {
+                let metadata = Self::read_directory_metadata(reader, &entry_inode)?;
+                let directory = directories.mkdir_with_metadata(&entry_path, metadata)?;
+                if let Some(context) = entry_inode.xattr(9, b"c")? {
+                    directory.set_encryption_context(&context);
+                }
+                Self::read_directory(reader, &entry_inode, &entry_path, directories, files)?;
+            } else {
+                directories.create_file(&entry_path, entry.inode() as u64)?;
+                let mut allocations = reader.read_data_blocks(&entry_inode)?;
+                if entry_inode.file_acl() != 0 {
+                    allocations.push(Allocation::from_start_len(entry_inode.file_acl(), 1));
+                }
+                files.push((entry.inode(), entry_inode, allocations));
+            }
+        }
+        Ok(())
+    }
+
+    /// The xattr block of a directory is not kept, its attributes are stored anew when finishing.
+    fn read_directory_metadata<R: io::Read + io::Seek>(
+        reader: &mut Ext4ImageReader<R>,
+        inode: &Ext4Inode,
+    ) -> Result<DirectoryMetadata, Ext4Error> {
+        Ok(DirectoryMetadata {
+            mode: Some(inode.mode()),
+            uid: inode.uid(),
+            gid: inode.gid(),
+            projid: inode.projid(),
+            timestamps: Some(inode.timestamps()),
+            flags: inode.flags() & (Ext4Inode::SETTABLE_FLAGS | Ext4Inode::CASEFOLD_FL),
+            xattrs: reader
+                .inode_xattrs(inode)?
+                .into_iter()
+                .filter(|xattr| {
+                    // system.data and the encryption context are recreated when finishing
+                    !matches!(
+                        (xattr.name_index, xattr.name.as_slice()),
+                        (7, b"data") | (9, b"c")
+                    )
+                })
+                .collect(),
+        })
+    }
+}
+impl<W: io::Write + io::Seek, A: BlockAllocator> Ext4ImageWriter<W, A> {
+    const NO_FLEX_INODES_PER_GROUP: u64 = 8192;
+    /// The limits of `mke2fs -J size=`, see [`Ext4ImageWriter::set_journal_blocks`].
+    const MIN_JOURNAL_BLOCKS: u64 = 1024;
+    const MAX_JOURNAL_BLOCKS: u64 = 10_240_000;
+
+    /// Like [`Ext4ImageWriter::new`] but places all blocks using the given [`BlockAllocator`].
+    pub fn with_allocator(writer: W, max_size: u64, allocator: A) -> Self {
         let mut this = Self {
             writer,
+            allocator,
             uuid: [
                 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC,
                 0xDE, 0xF0,
             ],
             max_size,
+            block_size: BLOCK_SIZE,
+            inode_size: Ext4Inode::DEFAULT_SIZE,
+            lost_found_mode: 0o700,
+            volume_label: String::new(),
+            reserved_percent: 0.0,
+            reserved_owner: (0, 0),
+            journal_blocks: 0,
+            quota: false,
+            min_inode_count: 0,
+            lost_found_inode: 11,
+            log_groups_per_flex: 4,
+            fixed_group_metadata: false,
+            superblock_backups_reserved: false,
+            signed_directory_hash: true,
+            encryption: false,
+            casefold: false,
+            project_ids: false,
+            empty_files: EmptyFileRepresentation::default(),
+            entry_ordering: EntryOrdering::Insertion,
+            root_indexed: true,
+            default_timestamps: Timestamps::default(),
+            group_flags: BTreeMap::new(),
+            ext2: false,
+            revision: Revision::Dynamic,
+            max_inodes: u32::MAX as u64,
+            observer: Box::new(NoopObserver),
 
             directories: Default::default(),
             inodes: Default::default(),
+            file_blocks: BTreeMap::new(),
+            deduplication: false,
+            deduplicated: HashMap::new(),
+            deduplicated_allocations: HashMap::new(),
+            shared_allocations: HashMap::new(),
+            shared_blocks: false,
             used_blocks: UsageBitmap::default(),
             used_inodes: UsageBitmap::default(),
         };
-        this.used_blocks.allocate(1); // superblock
-        this.used_blocks.allocate(this.bgdt_blocks());
-
-        this.alloc_inode(); // inode 1 is the bad blocks inode
-        this.alloc_inode(); // inode 2 is the root directory (we will populate it later)
-        this.alloc_inode(); // inode 3 is the user quota inode (we won't use it)
-        this.alloc_inode(); // inode 4 is the group quota inode (we won't use it)
-        this.alloc_inode(); // inode 5 is the boot loader inode (we won't use it)
-        this.alloc_inode(); // inode 6 is the undelete inode (we won't use it)
-        this.alloc_inode(); // inode 7 is the resize inode
-        this.alloc_inode(); // inode 8 is the journal inode (we won't use it)
-        this.alloc_inode(); // inode 9 is the "exclude" inode (we won't use it)
-        this.alloc_inode(); // inode 10 is for some obscure non-upstream feature (we won't use it)
-        this.alloc_inode(); // inode 11 is the "lost+found" directory (we will populate it later)
+        this.mark_superblock_and_bgdt_used();
+
+        this.push_inode(); // inode 1 is the bad blocks inode
+        this.push_inode(); // inode 2 is the root directory (we will populate it later)
+        this.push_inode(); // inode 3 is the user quota inode (we won't use it)
+        this.push_inode(); // inode 4 is the group quota inode (we won't use it)
+        this.push_inode(); // inode 5 is the boot loader inode (we won't use it)
+        this.push_inode(); // inode 6 is the undelete inode (we won't use it)
+        this.push_inode(); // inode 7 is the resize inode
+        this.push_inode(); // inode 8 is the journal inode (see `set_journal_blocks`)
+        this.push_inode(); // inode 9 is the "exclude" inode (we won't use it)
+        this.push_inode(); // inode 10 is for some obscure non-upstream feature (we won't use it)
+        this.push_inode(); // inode 11 is the "lost+found" directory (we will populate it later)
 
         this.directories.mkdir("lost+found").unwrap();
 
         this
     }
 
-    /// Write a file to the filesystem at the given path with the given mode.
-    /// The path must use '/' as the separator.
-    pub fn write_file(&mut self, contents: &[u8], path: &str, mode: u16) -> io::Result<()> {
-        let inode_num = self.alloc_inode();
-        let mut inode =
-            self.create_inode_with_contents(inode_num as u32, contents, FileType::RegularFile)?;
-        inode.set_mode(mode);
-        self.inodes[(inode_num - 1) as usize] = inode;
-        self.directories.create_file(path, inode_num)?;
+    /// Reserve `count` additional inodes after the 10 inodes that ext4 always reserves.
+    /// This moves `s_first_ino` (and with it lost+found) up, so user files start at a higher inode number.
+    /// Must be called right after construction, before anything else was added.
+    pub fn reserve_inodes(&mut self, count: u64) -> Result<(), Ext4Error> {
+        self.ensure_pristine("the number of reserved inodes")?;
+        for _ in 0..count {
+            self.alloc_inode()?;
+        }
+        self.lost_found_inode = self.inodes.len() as u64;
         Ok(())
     }
 
-    /// Create a directory at the given path. All parent directories must already exist.
-    /// The path must use '/' as the separator.
-    pub fn mkdir(&mut self, path: &str) -> io::Result<()> {
-        self.directories.mkdir(path)?;
+    /// Fails with [`Ext4Error::TooLate`] naming `what` if anything was added to the image already,
+    /// i.e. more inodes than the reserved ones or more blocks than the superblock and the GDT.
+    fn ensure_pristine(&self, what: &'static str) -> Result<(), Ext4Error> {
+        if self.inodes.len() as u64 != self.lost_found_inode
+            || self.used_blocks.end() != self.gdt_start() + self.bgdt_blocks()
+        {
+            return Err(Ext4Error::TooLate(what));
+        }
         Ok(())
     }
 
-    /// Create a directory at the given path, creating all parent directories as needed.
-    /// The path must use '/' as the separator.
-    pub fn mkdir_p(&mut self, path: &str) -> io::Result<()> {
-        self.directories.mkdir_p(path)?;
-        Ok(())
+    /// Set the flex_bg size to `2^log2` block groups (the default is 4, i.e. 16 groups).
+    /// The block bitmaps, inode bitmaps and inode tables of the groups of a flex group are each placed next to each other.
+    /// `0` disables flex_bg, so the bitmaps and the inode table of each block group are placed inside of that group.
+    /// Must be called right after construction, before anything else was added. Calling it again replaces
+    /// the size, unless flex_bg was disabled or the group metadata placement was fixed in the meantime.
+    pub fn with_flex_bg_size(mut self, log2: u8) -> Result<Self, Ext4Error> {
+        if log2 >= 32 {
+            return Err(Ext4Error::Invalid(format!(
+                "flex_bg size 2^{} is too large",
+                log2
+            )));
+        }
+        self.ensure_pristine("the flex_bg size")?;
+        // disabling flex_bg (like `with_fixed_group_metadata` and `ext2_compat`) reserved the metadata regions
+        // inside of the groups, which can't be undone, while other sizes can still be replaced
+        if self.fixed_group_metadata {
+            return Err(Ext4Error::TooLate("the flex_bg size"));
+        }
+        self.log_groups_per_flex = log2;
+        if log2 == 0 {
+            self.reserve_group_metadata_regions();
+        }
+        Ok(self)
     }
 
-    /// Write all metadata to the underlying block device and finish writing the filesystem
-    pub fn finish(mut self) -> io::Result<W> {
-        let directories = std::mem::take(&mut self.directories);
-        self.write_hierarchy_to_inodes(&directories, 2, 2)?;
-
-        let num_inodes = self.inodes.len() as u64;
-        let blocks_needed_for_inodes = (num_inodes * Ext4Inode::SIZE).div_ceil(BLOCK_SIZE);
-        let num_blocks = self.used_blocks.next_free + blocks_needed_for_inodes + 1 /* resize inode indirect block */ ;
-        let num_block_groups = num_blocks.div_ceil(BLOCK_SIZE * 8);
-        let num_blocks = num_blocks + num_block_groups * 2; // for the block and inode bitmaps;
-        let num_block_groups = num_blocks.div_ceil(BLOCK_SIZE * 8);
-        let inodes_per_group = ((num_inodes / num_block_groups)
-            .div_ceil(BLOCK_SIZE / Ext4Inode::SIZE)
-            * (BLOCK_SIZE / Ext4Inode::SIZE)) as usize;
-        assert!(num_block_groups >= self.inodes.len().div_ceil(inodes_per_group) as u64);
-        let num_blocks = self.used_blocks.next_free
-            + (inodes_per_group as u64 * Ext4Inode::SIZE).div_ceil(BLOCK_SIZE) * num_block_groups
-            + num_block_groups * 2 // for the block and inode bitmaps
-            + 1; // resize inode indirect block
-
-        self.inodes[6 /*inode 7*/] = self.create_resize_inode(num_block_groups)?;
-
-        // write inodes and build block group descriptors for each block group.
-        let mut total_free_inodes = 0;
-        let mut total_free_blocks = 0;
-        let mut bgdt_buf = Cursor::new(Vec::new());
-        let max_bgdt_table_len = self.max_size.div_ceil(BLOCK_SIZE * BLOCK_SIZE * 8) as u32;
-        let mut inodes = std::mem::take(&mut self.inodes);
-        inodes.resize(
-            num_block_groups as usize * inodes_per_group,
-            Ext4Inode::default(),
-        );
-        for (block_group, inodes) in inodes.chunks_mut(inodes_per_group).enumerate() {
-            if block_group >= max_bgdt_table_len as usize {
-                panic!("too many block groups, try increasing the max_size parameter");
-            }
-            let mut inode_buf = Cursor::new(vec![0u8; inodes_per_group * Ext4Inode::SIZE as usize]);
-            let mut directories = 0;
-            for (i, inode) in inodes.iter_mut().enumerate() {
-                let inode_num = (block_group * inodes_per_group + i + 1) as u32;
-                inode.update_checksum(&self.uuid, inode_num);
-                inode_buf.write_all(&inode.as_bytes())?;
-                if inode.is_directory() {
-                    directories += 1;
-                }
-            }
+    /// Use `uuid` as the filesystem uuid instead of the fixed default one. All metadata checksums depend on it.
+    /// Must be called right after construction, before anything else was added.
+    pub fn with_uuid(mut self, uuid: [u8; 16]) -> Result<Self, Ext4Error> {
+        self.ensure_pristine("the uuid")?;
+        self.uuid = uuid;
+        Ok(self)
+    }
 
-            // write out the inode table for this block group
-            let block_bitmap_len = if block_group == num_block_groups as usize - 1 {
-                (num_blocks % (BLOCK_SIZE * 8)) as u32
-            } else {
-                (BLOCK_SIZE * 8) as u32
-            };
-            // we need to allocate everything first to make sure that the block bitmaps are represented in themselves
-            let block_bitmap_alloc = self.used_blocks.allocate(1);
-            let inode_bitmap_alloc = self.used_blocks.allocate(1);
-            let inode_table_alloc = self
-                .used_blocks
-                .allocate((inodes_per_group as u64 * Ext4Inode::SIZE).div_ceil(BLOCK_SIZE));
-            let block_bitmap = self
-                .used_blocks
-                .get_for_block_group(block_group as u64, block_bitmap_len);
-            self.write_blocks(block_bitmap_alloc, &block_bitmap.as_bytes())?;
-            let inode_bitmap = self
-                .used_inodes
-                .get_for_block_group(block_group as u64, inodes_per_group as u32);
-            self.write_blocks(inode_bitmap_alloc, &inode_bitmap.as_bytes())?;
-            self.write_blocks(inode_table_alloc, &inode_buf.into_inner())?;
-            let mut block_group_descriptor = Ext4BlockGroupDescriptor::default();
-            block_group_descriptor.set_block_bitmap(block_bitmap_alloc.as_single());
-            block_group_descriptor.set_free_blocks_count(block_bitmap.free_count());
-            total_free_blocks += block_bitmap.free_count() as u64;
-            block_group_descriptor.set_inode_bitmap(inode_bitmap_alloc.as_single());
-            block_group_descriptor.set_free_inodes_count(inode_bitmap.free_count());
-            total_free_inodes += inode_bitmap.free_count();
-            block_group_descriptor.set_inode_table(inode_table_alloc.start);
-            block_group_descriptor.set_used_dirs_count(directories);
-            block_group_descriptor.update_checksums(
-                &self.uuid,
-                block_group as u32,
-                &block_bitmap,
-                &inode_bitmap,
-            );
-            bgdt_buf.write_all(&block_group_descriptor.as_bytes())?;
+    /// Use blocks of `block_size` bytes instead of 4096. Supported are 1024, 2048 and 4096 bytes.
+    /// Smaller blocks waste less space on small files, but each block group covers fewer blocks,
+    /// so the maximum size that the GDT can be reserved for shrinks (i.e. to 32 GiB with 1024 byte blocks).
+    /// Must be called right after construction, before anything else was added
+    /// and before choosing the placement of the group metadata.
+    pub fn with_block_size(mut self, block_size: u64) -> Result<Self, Ext4Error> {
+        if ![1024, 2048, 4096].contains(&block_size) {
+            return Err(Ext4Error::Invalid(format!(
+                "the block size {} is not supported, it has to be 1024, 2048 or 4096",
+                block_size
+            )));
         }
-        self.write_blocks(
-            Allocation::from_start_len(1, self.bgdt_blocks()),
-            &bgdt_buf.into_inner(),
-        )?;
-
-        assert_eq!(self.used_blocks.next_free, num_blocks);
-
-        // finally write the superblock
-        let mut superblock = ext4_h::Ext4SuperBlock::new(self.uuid, inodes_per_group as u32);
-        let used_bgdt_blocks =
-            (num_block_groups * Ext4BlockGroupDescriptor::SIZE).div_ceil(BLOCK_SIZE);
-        superblock
-            .set_reserved_gdt_blocks((self.bgdt_blocks() - used_bgdt_blocks).try_into().unwrap());
-        superblock.set_free_inodes_count(total_free_inodes);
-        superblock.set_free_blocks_count(total_free_blocks);
-        superblock.update_blocks_count(num_blocks);
-        superblock.update_checksum();
-        let mut first_block = [0u8; BLOCK_SIZE as usize];
-        first_block[1024..1024 + 1024].copy_from_slice(&superblock.as_bytes());
-        self.write_blocks(Allocation::from_start_len(0, 1), &first_block)?;
-        Ok(self.writer)
+        self.ensure_pristine("the block size")?;
+        // the group metadata regions were reserved for the previous block size
+        if self.fixed_group_metadata {
+            return Err(Ext4Error::TooLate("the block size"));
+        }
+        self.block_size = block_size;
+        // the resize inode lists all GDT blocks in a single indirect block
+        if self.bgdt_blocks() > block_size / 4 {
+            return Err(Ext4Error::Invalid(format!(
+                "the maximum size of {} bytes needs too many block group descriptors for {} byte blocks",
+                self.max_size, block_size
+            )));
+        }
+        // smaller blocks need at least as many blocks for the superblock and the GDT, so this only adds to them
+        self.mark_superblock_and_bgdt_used();
+        Ok(self)
     }
 
-    fn create_resize_inode(&mut self, block_groups: u64) -> io::Result<Ext4Inode> {
-        // this is actually not correct since when we call this function it might still happen that we modify these values
-        let used_bgdt_blocks = (block_groups * Ext4BlockGroupDescriptor::SIZE).div_ceil(BLOCK_SIZE);
-
-        let bgdt_block_list = (1 + used_bgdt_blocks)..(self.bgdt_blocks() + 1);
-        let mut indirect_buffer = vec![];
-        indirect_buffer.extend_from_slice(&(0u32).to_le_bytes());
-        for block in bgdt_block_list {
-            self.used_blocks.mark_used(block);
-            indirect_buffer.extend_from_slice(&(block as u32).to_le_bytes());
+    /// Write inodes of `inode_size` bytes, 256 (the default), 128 or 512 like `mkfs.ext4 -I`.
+    /// 128 byte inodes make the inode tables half as large, but they have no room for extended attributes,
+    /// so inline data is disabled and all extended attributes are stored in a separate block. They also lose
+    /// the creation time and the nanoseconds and bits beyond 2038 of the other timestamps.
+    /// 512 byte inodes have room for 256 more bytes of extended attributes in the inode body,
+    /// which also lets larger files and directories be stored inline.
+    /// Must be called right after construction (and before [`Ext4ImageWriter::with_fixed_group_metadata`]).
+    pub fn with_inode_size(mut self, inode_size: u16) -> Result<Self, Ext4Error> {
+        if ![128, 256, 512].contains(&inode_size) {
+            return Err(Ext4Error::Invalid(format!(
+                "the inode size {} is not supported, it has to be 128, 256 or 512",
+                inode_size
+            )));
         }
-        assert!(indirect_buffer.len() <= BLOCK_SIZE as usize);
-        let block_indirect = self.write_blocks_alloc(&indirect_buffer)?;
-        let descr = LegacyBlockDescriptor::new(block_indirect.as_single() as u32);
-        let mut inode = Ext4Inode::default();
+        self.ensure_pristine("the inode size")?;
+        // the group metadata regions were reserved for inode tables of the previous inode size
+        if self.fixed_group_metadata {
+            return Err(Ext4Error::TooLate("the inode size"));
+        }
+        self.inode_size = inode_size as u64;
+        Ok(self)
+    }
 
-        descr.write_buffer(inode.block_mut());
-        inode.update_size((self.bgdt_blocks() - used_bgdt_blocks + 1) * BLOCK_SIZE);
-        inode.set_file_type(FileType::RegularFile);
-        inode.set_links_count(1);
-        inode.set_size(LegacyBlockDescriptor::maximum_addressable_size());
-        Ok(inode)
+    /// Whether inodes have room for extended attributes, which inline data is stored in as well.
+    fn in_inode_xattrs(&self) -> bool {
+        !self.ext2 && self.inode_size > 128
     }
 
-    fn bgdt_blocks(&self) -> u64 {
-        let max_bgdt_table_len = self.max_size.div_ceil(BLOCK_SIZE * BLOCK_SIZE * 8);
-        (max_bgdt_table_len * Ext4BlockGroupDescriptor::SIZE).div_ceil(BLOCK_SIZE)
+    /// The largest contents that can be stored inline, see [`Ext4Inode::with_inline_data`].
+    fn max_inline_size(&self) -> usize {
+        Ext4Inode::MAX_INLINE_SIZE_BLOCK + Ext4Inode::max_inline_size_xattr(self.inode_size)
     }
 
-    fn write_hierarchy_to_inodes(
-        &mut self,
-        directory: &Directory,
-        inode_num: u64,
-        parent_inode_num: u64,
-    ) -> io::Result<()> {
-        let base_entries = vec![
-            Ok(Ext4DirEntry::new(
-                inode_num as u32,
-                FileType::Directory,
-                ".",
-            )),
-            Ok(Ext4DirEntry::new(
-                parent_inode_num as u32,
-                FileType::Directory,
-                "..",
-            )),
-        ];
-        let entries = base_entries
-            .into_iter()
-            .chain(directory.entries().iter().map(|(name, entry)| {
-                Ok(match entry {
-                    file_tree::DirectoryEntry::Directory(directory) => {
-                        let entry_inode_num = if inode_num == 2 && name == "lost+found" {
-                            11
-                        } else {
-                            self.alloc_inode()
-                        };
-                        self.write_hierarchy_to_inodes(directory, entry_inode_num, inode_num)?;
-                        Ext4DirEntry::new(entry_inode_num as u32, FileType::Directory, name)
-                    }
-                    file_tree::DirectoryEntry::File(inode) => {
-                        Ext4DirEntry::new(*inode as u32, FileType::RegularFile, name)
-                    }
-                })
-            }))
-            .collect::<io::Result<Vec<_>>>()?;
+    /// The superblock and the block group descriptor table always live at the start of the image.
+    fn mark_superblock_and_bgdt_used(&mut self) {
+        for block in 0..(self.gdt_start() + self.bgdt_blocks()) {
+            self.mark_block_used(block);
+        }
+    }
 
-        self.inodes[inode_num as usize - 1] = self.create_directory_inode(
-            inode_num,
-            &entries,
-            inode_num != 11, /* lost+found cant be inline */
-        )?;
-        Ok(())
+    /// Place the bitmaps and the inode table of each block group at a fixed offset from the start of that group
+    /// (like without flex_bg) while keeping the flex_bg feature enabled.
+    /// This yields the classic layout that some tools expect. Each group then has room for 8192 inodes.
+    /// Must be called right after construction, before anything else was added.
+    pub fn with_fixed_group_metadata(mut self) -> Result<Self, Ext4Error> {
+        self.ensure_pristine("the group metadata placement")?;
+        self.reserve_group_metadata_regions();
+        Ok(self)
     }
 
-    fn create_directory_inode(
-        &mut self,
-        inode_num: u64,
-        entries: &[Ext4DirEntry],
-        allow_inline: bool,
-    ) -> io::Result<Ext4Inode> {
-        let mut inode = if let Some(inode) = self.create_directory_inode_inline(entries)
-            && allow_inline
-        {
-            inode
-        } else {
-            self.create_directory_inode_with_blocks(inode_num, entries)?
-        };
-        let subdirectories = entries.iter().filter(|e| e.is_directory()).count();
-        inode.set_links_count(2 + (<u16>::try_from(subdirectories).unwrap() - 2)); // 1 for the parent, one for '.' and 1 for each subdirectory
-        inode.set_mode(0o755);
-        Ok(inode)
+    /// Write an ext2 image for targets that only understand the classic layout, i.e. old bootloaders.
+    /// This disables extents, inline data, metadata checksums, 64 bit block group descriptors, flex_bg
+    /// and the other ext4 additions, so file contents are mapped with direct and indirect block pointers.
+    /// The inodes are 128 bytes large like in classic ext2, with the limits described for
+    /// [`Ext4ImageWriter::with_inode_size`]. Like [`Ext4ImageWriter::with_fixed_group_metadata`], this places
+    /// the bitmaps and the inode table at a fixed offset from the start of each group.
+    /// Encryption is not available and the resulting image can't be extended with [`Ext4ImageWriter::open`].
+    /// Must be called right after construction, before anything else was added.
+    pub fn ext2_compat(mut self) -> Result<Self, Ext4Error> {
+        self.ensure_pristine("ext2 compatibility")?;
+        self.ext2 = true;
+        self.inode_size = 128;
+        self.log_groups_per_flex = 0;
+        self.reserve_group_metadata_regions();
+        Ok(self)
     }
 
-    fn create_directory_inode_inline(&mut self, entries: &[Ext4DirEntry]) -> Option<Ext4Inode> {
-        let mut block_entries =
-            InlineLinearDirectoryBlock::new(Ext4Inode::MAX_INLINE_SIZE_BLOCK - 4);
-        let mut xattr_entries = InlineLinearDirectoryBlock::new(Ext4Inode::MAX_INLINE_SIZE_XATTR);
-        for entry in entries[2..].iter() {
-            if block_entries.fits(entry) {
-                block_entries.add_entry(entry.clone());
-            } else if xattr_entries.fits(entry) {
-                xattr_entries.add_entry(entry.clone());
-            } else {
-                return None; // cant fit entries inline
+    /// Choose the superblock revision. [`Revision::Rev0`] writes a revision 0 image like `mke2fs -r 0` on top of
+    /// [`Ext4ImageWriter::ext2_compat`]. Without any feature flags, directory entries don't record file types,
+    /// every group holds a backup of the superblock and the GDT, there is no resize inode and directories are
+    /// not indexed. Extended attributes, files of 2 GiB or more and [`Ext4ImageWriter::reserve_inodes`]
+    /// are not available and make [`Ext4ImageWriter::finish`] fail.
+    /// Must be called right after construction, before anything else was added.
+    pub fn with_revision(mut self, revision: Revision) -> Result<Self, Ext4Error> {
+        match revision {
+            Revision::Rev0 => {
+                // the metadata regions reserved by ext2_compat follow the superblock backups of revision 0
+                self.revision = revision;
+                self.ext2_compat()
+            }
+            Revision::Dynamic if self.revision == Revision::Rev0 => {
+                Err(Ext4Error::UnsupportedByRevision0(
+                    "turning them back into dynamic revision images".to_string(),
+                ))
             }
+            Revision::Dynamic => Ok(self),
         }
-
-        let parent_inode = entries[1].inode();
-        let mut block_data = [0u8; Ext4Inode::MAX_INLINE_SIZE_BLOCK];
-        block_data[0..4].copy_from_slice(&parent_inode.to_le_bytes());
-        block_data[4..].copy_from_slice(&block_entries.as_bytes());
-
-        Some(Ext4Inode::with_inline_data(
-            &block_data,
-            &xattr_entries.as_bytes(),
-            FileType::Directory,
-        ))
     }
 
-    fn create_directory_inode_with_blocks(
-        &mut self,
-        inode_num: u64,
-        entries: &[Ext4DirEntry],
-    ) -> io::Result<Ext4Inode> {
-        let mut dir_blocks = vec![LinearDirectoryBlock::default()];
-        for entry in entries {
-            if !dir_blocks.last().unwrap().fits(entry) {
-                dir_blocks.push(LinearDirectoryBlock::default());
-            }
-            dir_blocks.last_mut().unwrap().add_entry(entry.clone());
+    fn reserve_group_metadata_regions(&mut self) {
+        if self.fixed_group_metadata {
+            return;
         }
-        let mut dir_buffer = vec![0u8; dir_blocks.len() * BLOCK_SIZE as usize];
-        for (i, block) in dir_blocks.iter().enumerate() {
-            let mut dir_block = block.clone();
-            dir_block.update_checksum(&self.uuid, inode_num as u32, 0);
-            dir_block.write_buffer(
-                &mut dir_buffer[i * BLOCK_SIZE as usize..(i + 1) * BLOCK_SIZE as usize],
-            );
+        self.fixed_group_metadata = true;
+        // keep the metadata regions of all potential block groups free so that data is allocated around them
+        for block_group in 0..self.max_block_groups() {
+            let region = self.group_metadata_region(block_group);
+            self.allocator.reserve(region);
         }
-        self.create_inode_with_contents(inode_num as u32, &dir_buffer, FileType::Directory)
     }
 
-    fn create_inode_with_contents(
-        &mut self,
-        inode_num: u32,
-        contents: &[u8],
-        ty: FileType,
-    ) -> io::Result<Ext4Inode> {
-        if contents.len() <= Ext4Inode::MAX_INLINE_SIZE {
-            let block_data = &contents[..Ext4Inode::MAX_INLINE_SIZE_BLOCK.min(contents.len())];
-            let xattr_data = if contents.len() > Ext4Inode::MAX_INLINE_SIZE_BLOCK {
-                &contents[Ext4Inode::MAX_INLINE_SIZE_BLOCK..]
-            } else {
-                &[]
-            };
-            Ok(Ext4Inode::with_inline_data(block_data, xattr_data, ty))
-        } else {
-            let allocation = self.write_blocks_alloc(contents)?;
-            let inode =
-                self.create_inode_with_extents(inode_num, contents.len() as u64, allocation, ty)?;
-            Ok(inode)
-        }
+    /// Choose whether directory hashes are computed over signed (the default) or unsigned chars.
+    /// Linux hashes filenames with the platform's `char`, which is signed on x86 but unsigned on i.e. ARM and PowerPC,
+    /// so images for such systems may want `false`. The choice is recorded in the superblock flags,
+    /// which the kernel and e2fsck consult whenever they compute a directory hash.
+    pub fn with_directory_hash_signedness(mut self, signed: bool) -> Self {
+        self.signed_directory_hash = signed;
+        self
     }
 
-    fn create_inode_with_extents(
-        &mut self,
-        inode_num: u32,
-        size: u64,
-        allocation: Allocation,
-        ty: FileType,
-    ) -> io::Result<Ext4Inode> {
-        let blocks = allocation.end - allocation.start;
-        if blocks <= Ext4InlineExtents::MAX_INLINE_BLOCKS {
-            // we can fit the extents inline into the inode
-            Ok(Ext4Inode::new(size, Ext4InlineExtents::new(allocation), ty))
-        } else {
-            // we need to allocate a separate block for the extents
-            let indirect_block =
-                Ext4IndirectExtents::create_block(allocation, inode_num, &self.uuid);
-            let indirect_block_allocation = self.write_blocks_alloc(&indirect_block)?;
-            let extents = Ext4IndirectExtents::new(indirect_block_allocation.start);
-            let mut inode = Ext4Inode::new(size, extents, ty);
-            inode.set_blocks(inode.blocks() + 8); // account for the indirect block
-            Ok(inode)
-        }
+    /// Choose how zero-length files are stored, see [`EmptyFileRepresentation`].
+    pub fn with_empty_file_representation(
+        mut self,
+        representation: EmptyFileRepresentation,
+    ) -> Self {
+        self.empty_files = representation;
+        self
     }
 
-    fn alloc_inode(&mut self) -> u64 {
-        let n = self.inodes.len() as u64;
-        self.inodes.push(Ext4Inode::default());
-        self.used_inodes.mark_used(n);
-        n + 1
+    /// Store files with identical contents only once: their inodes point at the same data blocks.
+    /// Only contents that need blocks (as opposed to inline data) and are written after calling this are deduplicated.
+    /// Contents are identified by their SHA-256 digest, so only the digests are kept in memory until finishing.
+    /// The image gets the `shared_blocks` feature, so that e2fsck accepts the shared blocks.
+    /// Since writing to a shared block would change all files that use it, the kernel only mounts such images read-only.
+    pub fn with_deduplication(mut self) -> Self {
+        self.deduplication = true;
+        self
     }
 
-    fn write_blocks(&mut self, allocation: Allocation, data: &[u8]) -> io::Result<()> {
-        assert!(allocation.len() * BLOCK_SIZE >= data.len() as u64);
-        self.writer
-            .seek(io::SeekFrom::Start(allocation.start * BLOCK_SIZE))?;
-        self.writer.write_all(data)
+    /// Write the entries of every directory sorted by name instead of in insertion order (`.` and `..` stay first).
+    /// Together with adding files in any order this makes directory contents reproducible.
+    /// Same as [`Ext4ImageWriter::set_entry_ordering`] with [`EntryOrdering::ByName`].
+    #[deprecated(note = "use `set_entry_ordering(EntryOrdering::ByName)` instead")]
+    pub fn with_sorted_directories(mut self) -> Self {
+        self.set_entry_ordering(EntryOrdering::ByName);
+        self
     }
 
-    fn write_blocks_alloc(&mut self, data: &[u8]) -> io::Result<Allocation> {
-        let num_blocks = (data.len() as u64).div_ceil(BLOCK_SIZE);
-        let allocation = self.used_blocks.allocate(num_blocks);
-        self.write_blocks(allocation, data)?;
-        Ok(allocation)
+    /// Choose whether the root directory gets an HTree index once its entries need more than one block,
+    /// like all other directories do (the default). Some minimal readers (i.e. in bootloaders) can read
+    /// a linear root directory, but not an indexed one.
+    pub fn with_root_indexed(mut self, indexed: bool) -> Self {
+        self.root_indexed = indexed;
+        self
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Read;
 
-    macro_rules! test_create_fs {
-        ($test_name:ident, |$writer:ident| $test_code:tt) => {
-            #[test]
-            fn $test_name() {
-                let file_name = format!("target/{}.img", stringify!($test_name));
-                let _ = std::fs::remove_file(&file_name);
-                let file = std::fs::File::create(&file_name).unwrap();
-                #[allow(unused_mut)]
-                let mut $writer = Ext4ImageWriter::new(file, 1024 * 1024 * 1024 * 128);
-                $test_code
-                $writer.finish().unwrap();
-                let (mut reader, writer) = std::io::pipe().unwrap();
-                let status = std::process::Command::new("e2fsck")
-                    .args(&["-fn", &file_name])
-                    .stdout(writer.try_clone().unwrap())
-                    .stderr(writer)
-                    .status()
-                    .unwrap();
-                if !status.success() {
-                    let mut output = String::new();
-                    reader.read_to_string(&mut output).unwrap();
-                    panic!("e2fsck failed: {}", output);
-                }
-            }
-        };
+    /// Use `timestamps` for all files and directories that don't get their own with
+    /// [`Ext4ImageWriter::set_timestamps`], i.e. a fixed build date for reproducible images.
+    /// Without this, all timestamps are 0 (1970-01-01). Files added before calling this keep their timestamps.
+    pub fn with_default_timestamps(mut self, timestamps: Timestamps) -> Self {
+        self.default_timestamps = timestamps;
+        self
     }
 
-    test_create_fs!(test_ext4_image_writer_minimal, |writer| {});
+    /// Report files being added, block groups being finalized and warnings to `observer`.
+    /// Defaults to a [`NoopObserver`].
+    pub fn with_observer(mut self, observer: Box<dyn Observer>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Write a file to the filesystem at the given path with the given mode.
+    /// The path must use '/' as the separator.
+    ///
+    /// `contents` is only read once front to back and never copied as a whole, so for large host files
+    /// a memory-mapped slice (i.e. from the `memmap2` crate) avoids reading them into memory first:
+    /// the OS pages the data in on demand and can drop it again right after it was written.
+    pub fn write_file(&mut self, contents: &[u8], path: &str, mode: u16) -> Result<(), Ext4Error> {
+        self.write_file_with_owner(contents, path, mode, 0, 0)
+    }
+
+    /// Like [`Ext4ImageWriter::write_file`], but replaces the file (or symlink or special file) at `path` if there is one.
+    /// The file keeps its inode number and with it all of its hard links. Everything else (owner, timestamps, flags
+    /// and extended attributes) starts over like for a new file. The blocks of the old contents are freed,
+    /// but not reused for anything else, so the image doesn't get smaller.
+    pub fn write_file_replace(
+        &mut self,
+        contents: &[u8],
+        path: &str,
+        mode: u16,
+    ) -> Result<(), Ext4Error> {
+        let inode_num = match self.directories.file_inode(path) {
+            Ok(inode_num) => inode_num,
+            Err(Ext4Error::NotFound(_)) => return self.write_file(contents, path, mode),
+            Err(error) => return Err(error),
+        };
+        let old_blocks = self.file_blocks.remove(&inode_num).unwrap_or_default();
+        let mut inode = match self.create_inode_with_contents(
+            inode_num as u32,
+            contents,
+            FileType::RegularFile,
+        ) {
+            Ok(inode) => inode,
+            Err(error) => {
+                // the old contents stay in place
+                self.file_blocks.insert(inode_num, old_blocks);
+                return Err(error);
+            }
+        };
+        self.free_blocks(old_blocks);
+        self.directories
+            .update_file_metadata(path, |metadata| *metadata = Default::default())?;
+        self.set_file_mode(&mut inode, mode, path);
+        inode.set_timestamps(self.default_timestamps);
+        inode.set_links_count(self.inodes[(inode_num - 1) as usize].links_count());
+        self.inodes[(inode_num - 1) as usize] = inode;
+        self.observer.on_file_added(path, inode_num);
+        Ok(())
+    }
+
+    /// Like [`Ext4ImageWriter::write_file`], but owned by the user `uid` and the group `gid` instead of root.
+    pub fn write_file_with_owner(
+        &mut self,
+        contents: &[u8],
+        path: &str,
+        mode: u16,
+        uid: u32,
+        gid: u32,
+    ) -> Result<(), Ext4Error> {
+        self.directories.check_new_file(path)?;
+        let inode_num = self.alloc_inode()?;
+        let mut inode =
+            self.create_inode_with_contents(inode_num as u32, contents, FileType::RegularFile)?;
+        self.set_file_mode(&mut inode, mode, path);
+        inode.set_timestamps(self.default_timestamps);
+        self.inodes[(inode_num - 1) as usize] = inode;
+        self.directories.create_file(path, inode_num)?;
+        self.directories.update_file_metadata(path, |metadata| {
+            metadata.uid = Some(uid);
+            metadata.gid = Some(gid);
+        })?;
+        self.observer.on_file_added(path, inode_num);
+        Ok(())
+    }
+
+    /// Like [`Ext4ImageWriter::write_file`], but the contents are read from `reader` one block at a time,
+    /// so neither the host file nor a copy of it has to fit into memory.
+    /// The first bytes are peeked to decide whether the file is small enough to be stored inline.
+    pub fn write_file_from_reader(
+        &mut self,
+        mut reader: impl io::Read,
+        path: &str,
+        mode: u16,
+    ) -> Result<(), Ext4Error> {
+        self.directories.check_new_file(path)?;
+        let inode_num = self.alloc_inode()?;
+        let mut inode = self.create_inode_from_reader(inode_num as u32, &mut reader)?;
+        self.set_file_mode(&mut inode, mode, path);
+        inode.set_timestamps(self.default_timestamps);
+        self.inodes[(inode_num - 1) as usize] = inode;
+        self.directories.create_file(path, inode_num)?;
+        self.observer.on_file_added(path, inode_num);
+        Ok(())
+    }
+
+    /// Like [`Ext4ImageWriter::write_file`], but blocks that only contain zeros are not written. They become holes,
+    /// which read as zeros as well, so the file keeps its size and contents while needing far fewer blocks
+    /// (i.e. for disk images or preallocated databases).
+    pub fn write_sparse_file(
+        &mut self,
+        contents: &[u8],
+        path: &str,
+        mode: u16,
+    ) -> Result<(), Ext4Error> {
+        self.directories.check_new_file(path)?;
+        let inode_num = self.alloc_inode()?;
+        let mut inode = self.create_sparse_inode(inode_num as u32, contents)?;
+        self.set_file_mode(&mut inode, mode, path);
+        inode.set_timestamps(self.default_timestamps);
+        self.inodes[(inode_num - 1) as usize] = inode;
+        self.directories.create_file(path, inode_num)?;
+        self.observer.on_file_added(path, inode_num);
+        Ok(())
+    }
+
+    /// Create a symbolic link at `path` that points to `target`.
+    /// Short targets are stored as fast symlinks in the inode itself, longer ones like file contents.
+    pub fn symlink(&mut self, target: &str, path: &str) -> Result<(), Ext4Error> {
+        self.write_symlink(target, path, 0o777)
+    }
+
+    /// Like [`Ext4ImageWriter::symlink`], but with the given `mode` instead of `0o777`.
+    /// Linux ignores the permissions of symlinks, but some tools (i.e. `ls -l` or archivers) show them.
+    pub fn write_symlink(&mut self, target: &str, path: &str, mode: u16) -> Result<(), Ext4Error> {
+        if target.is_empty() {
+            return Err(Ext4Error::Invalid(
+                "symlink targets can't be empty".to_string(),
+            ));
+        }
+        if target.len() >= self.block_size as usize {
+            return Err(Ext4Error::ContentTooLarge(target.len() as u64));
+        }
+        self.directories.check_new_file(path)?;
+        let inode_num = self.alloc_inode()?;
+        let mut inode = if target.len() <= Ext4Inode::MAX_FAST_SYMLINK_LEN {
+            Ext4Inode::fast_symlink(target.as_bytes())
+        } else {
+            self.create_inode_with_contents(
+                inode_num as u32,
+                target.as_bytes(),
+                FileType::SymbolicLink,
+            )?
+        };
+        self.set_file_mode(&mut inode, mode, path);
+        inode.set_timestamps(self.default_timestamps);
+        self.inodes[(inode_num - 1) as usize] = inode;
+        self.directories.create_file(path, inode_num)?;
+        self.observer.on_file_added(path, inode_num);
+        Ok(())
+    }
+
+    /// Create a device node, FIFO or socket at `path`, i.e. to populate `/dev` of a root filesystem.
+    /// `file_type` must be [`FileType::CharacterDevice`], [`FileType::BlockDevice`], [`FileType::Fifo`] or
+    /// [`FileType::Socket`]. The device number is ignored for FIFOs and sockets.
+    pub fn mknod(
+        &mut self,
+        path: &str,
+        file_type: FileType,
+        major: u32,
+        minor: u32,
+        mode: u16,
+    ) -> Result<(), Ext4Error> {
+        let (major, minor) = match file_type {
+            FileType::CharacterDevice | FileType::BlockDevice => (major, minor),
+            FileType::Fifo | FileType::Socket => (0, 0),
+            _ => {
+                return Err(Ext4Error::Invalid(format!(
+                    "mknod can't create a {:?}",
+                    file_type
+                )));
+            }
+        };
+        // dev_t has 12 bits for the major and 20 bits for the minor number
+        if major >= 1 << 12 || minor >= 1 << 20 {
+            return Err(Ext4Error::Invalid(format!(
+                "invalid device number {}:{}",
+                major, minor
+            )));
+        }
+        self.directories.check_new_file(path)?;
+        let inode_num = self.alloc_inode()?;
+        let mut inode = Ext4Inode::special_file(file_type, major, minor);
+        self.set_file_mode(&mut inode, mode, path);
+        inode.set_timestamps(self.default_timestamps);
+        self.inodes[(inode_num - 1) as usize] = inode;
+        self.directories.create_file(path, inode_num)?;
+        self.observer.on_file_added(path, inode_num);
+        Ok(())
+    }
+
+    /// Create a named pipe at `path`, like `mkfifo`.
+    pub fn mkfifo(&mut self, path: &str, mode: u16) -> Result<(), Ext4Error> {
+        self.mknod(path, FileType::Fifo, 0, 0, mode)
+    }
+
+    /// Create a unix domain socket inode at `path`. Like on any filesystem, it only becomes usable once a
+    /// process binds to it at runtime.
+    pub fn mksocket(&mut self, path: &str, mode: u16) -> Result<(), Ext4Error> {
+        self.mknod(path, FileType::Socket, 0, 0, mode)
+    }
+
+    /// Create a hard link at `new_path` to the file or symlink at `existing_path`, so both entries share one inode.
+    /// Directories can't be hard linked. Changes made through either path (i.e. the mode) apply to both.
+    pub fn link(&mut self, existing_path: &str, new_path: &str) -> Result<(), Ext4Error> {
+        let inode_num = self.directories.file_inode(existing_path)?;
+        let inode = &self.inodes[(inode_num - 1) as usize];
+        if inode.links_count() >= 65000 {
+            // EXT4_LINK_MAX
+            return Err(Ext4Error::TooManyLinks(existing_path.to_string()));
+        }
+        self.directories.link(existing_path, new_path)?;
+        let inode = &mut self.inodes[(inode_num - 1) as usize];
+        inode.set_links_count(inode.links_count() + 1);
+        Ok(())
+    }
+
+    /// Add all regular files on the host that match the glob `pattern` below `dest_dir`.
+    /// Each file keeps its path relative to the leading components of the pattern without wildcards,
+    /// i.e. `add_glob("/etc/**/*.conf", "etc")` adds `/etc/ssh/sshd.conf` as `etc/ssh/sshd.conf`.
+    /// Missing directories are created. `*`, `?`, `[...]` and `**` are supported.
+    /// All matches are checked against the image before the first one is added, so a path that is already
+    /// taken fails without adding anything. Errors while reading or writing the contents can still leave
+    /// the files before them in the image.
+    #[cfg(feature = "glob")]
+    pub fn add_glob(&mut self, pattern: &str, dest_dir: &str) -> Result<(), Ext4Error> {
+        let mut files = vec![];
+        let mut directories = self.directories.clone();
+        for (relative, host_path) in glob::expand(pattern)? {
+            let path = format!("{}/{}", dest_dir.trim_end_matches('/'), relative);
+            if let Some((parent, _)) = path.rsplit_once('/')
+                && directories.directory_mut(parent).is_err()
+            {
+                directories.mkdir_p(parent)?;
+            }
+            // the inode number does not matter, the copy of the tree is only used for the checks
+            directories.create_file(&path, 0)?;
+            // keep the setuid, setgid and sticky bits but not the file type
+            #[cfg(unix)]
+            let mode = std::os::unix::fs::PermissionsExt::mode(
+                &std::fs::metadata(&host_path)?.permissions(),
+            ) as u16
+                & 0o7777;
+            #[cfg(not(unix))]
+            let mode = 0o644;
+            files.push((path, host_path, mode));
+        }
+        for (path, host_path, mode) in files {
+            if let Some((parent, _)) = path.rsplit_once('/')
+                && self.directories.directory_mut(parent).is_err()
+            {
+                self.directories.mkdir_p(parent)?;
+            }
+            self.write_file(&std::fs::read(&host_path)?, &path, mode)?;
+        }
+        Ok(())
+    }
+
+    /// Write a file whose data starts exactly at the physical block `goal_block`.
+    /// This is useful for layout sensitive images, i.e. when a bootloader expects a kernel at a known block.
+    /// Blocks between the previously written data and `goal_block` are left unused.
+    /// Fails if any of the requested blocks is already in use.
+    pub fn write_file_at_block(
+        &mut self,
+        contents: &[u8],
+        path: &str,
+        mode: u16,
+        goal_block: u64,
+    ) -> Result<(), Ext4Error> {
+        let num_blocks = (contents.len() as u64).div_ceil(self.block_size);
+        let end = goal_block
+            .checked_add(num_blocks)
+            .and_then(|end| end.checked_mul(self.block_size));
+        if end.is_none_or(|end| end > self.max_size) {
+            return Err(Ext4Error::BlockUnavailable(format!(
+                "goal block {} is outside of the maximum image size",
+                goal_block
+            )));
+        }
+        let allocation = Allocation::from_start_len(goal_block, num_blocks);
+        if let Some(block) =
+            (allocation.start..allocation.end).find(|&b| self.used_blocks.is_used(b))
+        {
+            return Err(Ext4Error::BlockUnavailable(format!(
+                "block {} requested for the file at '{}' is already in use",
+                block, path
+            )));
+        }
+        if let Some(block) = (allocation.start..allocation.end).find(|&b| self.is_group_metadata(b))
+        {
+            return Err(Ext4Error::BlockUnavailable(format!(
+                "block {} requested for the file at '{}' is reserved for the group metadata",
+                block, path
+            )));
+        }
+        self.directories.check_new_file(path)?;
+        let inode_num = self.alloc_inode()?;
+        for block in allocation.start..allocation.end {
+            self.mark_block_used(block);
+        }
+        self.write_blocks(allocation, contents)?;
+        let mut inode = self.create_inode_with_extents(
+            inode_num as u32,
+            contents.len() as u64,
+            &[allocation],
+            FileType::RegularFile,
+        )?;
+        self.set_file_mode(&mut inode, mode, path);
+        inode.set_timestamps(self.default_timestamps);
+        self.inodes[(inode_num - 1) as usize] = inode;
+        self.directories.create_file(path, inode_num)?;
+        self.observer.on_file_added(path, inode_num);
+        Ok(())
+    }
+
+    /// Set the permission bits of a regular file, warning about file type bits in `mode` as they are ignored.
+    fn set_file_mode(&mut self, inode: &mut Ext4Inode, mode: u16, path: &str) {
+        self.check_file_type_bits(mode, path);
+        inode.set_mode(mode);
+    }
+
+    fn check_file_type_bits(&mut self, mode: u16, path: &str) {
+        if mode & 0xf000 != 0 {
+            self.observer.on_warning(&format!(
+                "ignoring the file type bits of mode {:#o} for '{}'",
+                mode, path
+            ));
+        }
+    }
+
+    /// Report how the contents of the file at `path` are stored, i.e. to check whether it ended up inline.
+    pub fn storage_mode_of(&self, path: &str) -> Result<StorageMode, Ext4Error> {
+        let inode_num = self.directories.file_inode(path)?;
+        Ok(self.inodes[(inode_num - 1) as usize].storage_mode(self.block_size))
+    }
+
+    /// Predict whether adding a file with `content_size` bytes keeps the finished image within `max_size`.
+    /// Besides the data blocks and the inode of the file this accounts for the directory blocks and the
+    /// group metadata that are only allocated when finishing. Directories are assumed to need blocks
+    /// even if they end up inline, so the estimate errs on the side of `false`.
+    pub fn would_fit(&self, content_size: u64) -> bool {
+        let data_blocks = if self.in_inode_xattrs() && content_size <= self.max_inline_size() as u64
+        {
+            0
+        } else {
+            let blocks = content_size.div_ceil(self.block_size);
+            let extents = blocks.div_ceil(Ext4ExtentLeafNode::MAX_LEN as u64);
+            blocks + Ext4IndirectExtents::tree_blocks(extents, self.block_size)
+        };
+        // the entry of the new file might need another directory block
+        let (num_block_groups, num_blocks) = self.finished_size(data_blocks + 1, 1);
+        num_block_groups <= self.max_block_groups() && num_blocks * self.block_size <= self.max_size
+    }
+
+    /// Estimate the size in bytes of the image if it was finished now, without writing anything.
+    /// Like [`Ext4ImageWriter::would_fit`], this is an upper bound: directories that end up inline and
+    /// extended attributes that end up in the inode are counted with a block. Useful to preallocate
+    /// the output file or to choose `max_size` for a second run.
+    pub fn estimated_size(&self) -> u64 {
+        self.finished_size(0, 0).1 * self.block_size
+    }
+
+    /// An upper bound for the blocks of the quota files and the inodes they need besides the reserved ones.
+    fn quota_usage(&self) -> (u64, u64) {
+        if !self.quota {
+            return (0, 0);
+        }
+        let mut ids: [BTreeSet<u32>; 3] = Default::default();
+        for inode in &self.inodes {
+            for (quota_type, id) in [inode.uid(), inode.gid(), inode.projid()]
+                .into_iter()
+                .enumerate()
+            {
+                ids[quota_type].insert(id);
+            }
+        }
+        Self::directory_owners(&self.directories, &mut ids);
+        let project = self.inode_size > 128;
+        let blocks = ids[..2 + project as usize]
+            .iter()
+            .map(|ids| QuotaFile::max_size(ids.len()).div_ceil(self.block_size))
+            .sum();
+        (blocks, project as u64)
+    }
+
+    /// Collect the owners of `directory` and all directories below it by quota type, as well as the owners
+    /// of files that are only applied to their inodes when finishing.
+    fn directory_owners(directory: &Directory, ids: &mut [BTreeSet<u32>; 3]) {
+        let metadata = directory.metadata();
+        ids[0].insert(metadata.uid);
+        ids[1].insert(metadata.gid);
+        ids[2].insert(metadata.projid);
+        for (_, entry) in directory.entries() {
+            match entry {
+                file_tree::DirectoryEntry::Directory(subdirectory) => {
+                    Self::directory_owners(subdirectory, ids)
+                }
+                file_tree::DirectoryEntry::File(file) => {
+                    let metadata = &file.metadata;
+                    for (quota_type, id) in [metadata.uid, metadata.gid, metadata.projid]
+                        .into_iter()
+                        .enumerate()
+                    {
+                        ids[quota_type].extend(id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The number of block groups and blocks of the finished image if `extra_blocks` more blocks
+    /// and `extra_inodes` more inodes were used. Mirrors what [`Ext4ImageWriter::finish`] allocates.
+    fn finished_size(&self, extra_blocks: u64, extra_inodes: u64) -> (u64, u64) {
+        let (directory_inodes, directory_blocks) =
+            Self::directory_usage(&self.directories, self.block_size);
+        let xattr_blocks =
+            self.directories
+                .file_metadata()
+                .into_iter()
+                .filter(|(inode_num, metadata)| {
+                    let mut inode = self.inodes[*inode_num as usize - 1].clone();
+                    !metadata.xattrs.is_empty()
+                        && (!self.in_inode_xattrs()
+                            || metadata.xattrs.iter().any(|xattr| {
+                                inode.add_xattr(xattr.clone(), self.inode_size).is_err()
+                            }))
+                })
+                .count() as u64;
+        let (quota_blocks, quota_inodes) = self.quota_usage();
+        // lost+found is padded with empty blocks, `directory_usage` only counts the first one
+        let lost_found_padding = LOST_FOUND_SIZE.div_ceil(self.block_size) - 1;
+        let mut used_blocks = self.used_blocks.end()
+            + extra_blocks
+            + directory_blocks
+            + xattr_blocks
+            + lost_found_padding
+            + self.journal_blocks
+            + quota_blocks;
+        if self.fixed_group_metadata {
+            // the allocator skips the metadata regions (and superblock backups) of the groups the new blocks run into
+            let mut block_group = self.block_group_of(self.used_blocks.end()) + 1;
+            while self.group_start(block_group) < used_blocks {
+                used_blocks +=
+                    self.group_metadata_region(block_group).end - self.group_start(block_group);
+                block_group += 1;
+            }
+        }
+        let num_inodes = self.inodes.len() as u64 + directory_inodes + extra_inodes + quota_inodes;
+        let (num_block_groups, inodes_per_group) = self.geometry(used_blocks, num_inodes);
+        let num_blocks = if self.fixed_group_metadata {
+            (used_blocks + 1).max(self.group_metadata_region(num_block_groups - 1).end)
+        } else {
+            let backup_blocks = (self.block_groups_for(used_blocks)..num_block_groups)
+                .filter_map(|block_group| self.superblock_backup_region(block_group))
+                .map(Allocation::len)
+                .sum::<u64>();
+            used_blocks
+                + 1 /* resize inode indirect block */
+                + backup_blocks
+                + num_block_groups * (2 + self.inode_table_blocks(inodes_per_group))
+        };
+        // groups that are only needed for their inodes hold at most a superblock backup
+        let last_group = num_block_groups - 1;
+        let num_blocks = num_blocks.max(self.group_start(last_group) + 1).max(
+            self.superblock_backup_region(last_group)
+                .map_or(0, |region| region.end),
+        );
+        (num_block_groups, num_blocks)
+    }
+
+    /// The number of blocks of the inode table of a block group with `inodes_per_group` inodes.
+    fn inode_table_blocks(&self, inodes_per_group: usize) -> u64 {
+        (inodes_per_group as u64 * self.inode_size).div_ceil(self.block_size)
+    }
+
+    /// The inodes that are only allocated when finishing and an upper bound of the blocks
+    /// needed by `directory` and all directories below it.
+    fn directory_usage(directory: &Directory, block_size: u64) -> (u64, u64) {
+        let mut entries_len = 24; // `.` and `..`
+        let (mut inodes, mut blocks) = (0, 0);
+        for (name, entry) in directory.entries() {
+            entries_len += (name.len() as u64 + 8).next_multiple_of(4);
+            if let file_tree::DirectoryEntry::Directory(subdirectory) = entry {
+                let (subdirectory_inodes, subdirectory_blocks) =
+                    Self::directory_usage(subdirectory, block_size);
+                inodes += 1 + subdirectory_inodes;
+                blocks += subdirectory_blocks;
+            }
+        }
+        // the extended attributes might not fit into the inode
+        blocks += !directory.metadata().xattrs.is_empty() as u64;
+        blocks += match directory.raw_blocks() {
+            Some(raw_blocks) => raw_blocks.len() as u64,
+            None => {
+                let leaves = entries_len.div_ceil(block_size - 12);
+                // directories with more than one block get an HTree root and maybe a level of nodes
+                let root_limit = DxIndexBlock::limit_of(true, true, block_size as usize) as u64;
+                let node_limit = DxIndexBlock::limit_of(false, true, block_size as usize) as u64;
+                match leaves {
+                    0 | 1 => leaves,
+                    _ if leaves <= root_limit => leaves + 1,
+                    _ => leaves + 1 + leaves.div_ceil(node_limit),
+                }
+            }
+        };
+        (inodes, blocks)
+    }
+
+    /// List every path of the image, each directory followed by its contents, with the inode numbers
+    /// that [`Ext4ImageWriter::finish`] will assign. Useful to compare against an expected listing.
+    pub fn manifest(&self) -> Vec<ManifestEntry> {
+        let root = self.directories.metadata();
+        let mut manifest = vec![ManifestEntry {
+            path: String::new(),
+            file_type: FileType::Directory,
+            mode: root.mode.unwrap_or(0o755),
+            size: 0,
+            uid: root.uid,
+            gid: root.gid,
+            inode: 2,
+        }];
+        let mut next_inode_num = self.inodes.len() as u64 + 1;
+        self.manifest_directory(&self.directories, "", &mut next_inode_num, &mut manifest);
+        manifest
+    }
+
+    /// The entries of `directory` in the order they are written, see [`EntryOrdering`].
+    fn ordered_entries<'d>(
+        &self,
+        directory: &'d Directory,
+    ) -> Vec<&'d (String, file_tree::DirectoryEntry)> {
+        let mut children: Vec<_> = directory.entries().iter().collect();
+        if self.entry_ordering == EntryOrdering::ByName {
+            children.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        children
+    }
+
+    /// Mirrors the order in which [`Ext4ImageWriter::write_hierarchy_to_inodes`] assigns directory inode numbers.
+    fn manifest_directory(
+        &self,
+        directory: &Directory,
+        path: &str,
+        next_inode_num: &mut u64,
+        manifest: &mut Vec<ManifestEntry>,
+    ) {
+        let children = self.ordered_entries(directory);
+        for (name, entry) in children {
+            let entry_path = if path.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", path, name)
+            };
+            match entry {
+                file_tree::DirectoryEntry::Directory(subdirectory) => {
+                    let metadata = subdirectory.metadata();
+                    let (inode_num, mode) = if path.is_empty() && name == "lost+found" {
+                        (self.lost_found_inode, self.lost_found_mode)
+                    } else {
+                        *next_inode_num += 1;
+                        (*next_inode_num - 1, metadata.mode.unwrap_or(0o755))
+                    };
+                    manifest.push(ManifestEntry {
+                        path: entry_path.clone(),
+                        file_type: FileType::Directory,
+                        mode,
+                        size: 0,
+                        uid: metadata.uid,
+                        gid: metadata.gid,
+                        inode: inode_num as u32,
+                    });
+                    self.manifest_directory(subdirectory, &entry_path, next_inode_num, manifest);
+                }
+                file_tree::DirectoryEntry::File(file) => {
+                    let mut inode = self.inodes[file.inode as usize - 1].clone();
+                    file.metadata.apply(&mut inode);
+                    manifest.push(ManifestEntry {
+                        path: entry_path,
+                        file_type: inode.file_type(),
+                        mode: inode.mode(),
+                        size: inode.size(),
+                        uid: inode.uid(),
+                        gid: inode.gid(),
+                        inode: file.inode as u32,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Create a directory at the given path. All parent directories must already exist.
+    /// The path must use '/' as the separator.
+    pub fn mkdir(&mut self, path: &str) -> Result<(), Ext4Error> {
+        self.directories.mkdir(path)?;
+        Ok(())
+    }
+
+    /// Like [`Ext4ImageWriter::mkdir`], but with the permissions `mode` instead of `0o755` and
+    /// owned by the user `uid` and the group `gid` instead of root.
+    pub fn mkdir_with(
+        &mut self,
+        path: &str,
+        mode: u16,
+        uid: u32,
+        gid: u32,
+    ) -> Result<(), Ext4Error> {
+        self.directories.mkdir_with_metadata(
+            path,
+            DirectoryMetadata {
+                mode: Some(mode),
+                uid,
+                gid,
+                ..Default::default()
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Create a directory at the given path, creating all parent directories as needed.
+    /// Like `mkdir -p` it is fine if the directory already exists, but not if a file is in the way.
+    /// The path must use '/' as the separator.
+    pub fn mkdir_p(&mut self, path: &str) -> Result<(), Ext4Error> {
+        self.directories.mkdir_p(path)?;
+        Ok(())
+    }
+
+    /// Store the given directory blocks verbatim for the existing directory at `path` instead of building them
+    /// from its entries. This is an escape hatch for tools that already have directory blocks from another source.
+    ///
+    /// The caller is responsible for the layout: every block needs a checksum tail (`ext4_dir_entry_tail`)
+    /// and the first block has to start with the `.` and `..` records. Their inode numbers are filled in
+    /// and the tail checksums are recomputed when finishing, everything else is written as is.
+    /// The remaining records have to list exactly the files added below `path` with their inode numbers and types,
+    /// so a raw directory can't contain subdirectories. Hashed (htree) directories are not supported.
+    /// Only available with the default block size of 4096 bytes.
+    pub fn write_directory_raw(
+        &mut self,
+        path: &str,
+        blocks: &[[u8; 4096]],
+    ) -> Result<(), Ext4Error> {
+        if self.block_size != BLOCK_SIZE {
+            return Err(Ext4Error::InvalidRawDirectory(format!(
+                "raw directories need 4096 byte blocks, not {} byte blocks",
+                self.block_size
+            )));
+        }
+        if path.trim_matches('/') == "lost+found" {
+            return Err(Ext4Error::ReservedPath(path.to_string()));
+        }
+        let Some(first_block) = blocks.first() else {
+            return Err(Ext4Error::InvalidRawDirectory(
+                "a directory needs at least one block".to_string(),
+            ));
+        };
+        for block in blocks {
+            LinearDirectoryBlock::parse_raw(block)?;
+        }
+        let mut first_block = *first_block;
+        LinearDirectoryBlock::link_raw(&mut first_block, 2, 2);
+        let entries = LinearDirectoryBlock::parse_raw(&first_block)?;
+        if !matches!(entries.as_slice(), [(0, dot), (_, dotdot), ..] if dot.name() == "." && dotdot.name() == "..")
+        {
+            return Err(Ext4Error::InvalidRawDirectory(
+                "the first directory block has to start with '.' and '..'".to_string(),
+            ));
+        }
+        self.directories.directory_mut(path)?.set_raw_blocks(blocks);
+        Ok(())
+    }
+
+    /// Move the file or directory at `from` to `to`. The parent directory of `to` must already exist.
+    /// Moving a directory into one of its own subdirectories is rejected as it would create a cycle.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), Ext4Error> {
+        if from.trim_matches('/') == "lost+found" {
+            return Err(Ext4Error::ReservedPath(from.to_string()));
+        }
+        self.directories.rename(from, to)
+    }
+
+    /// Remove the file, symlink or empty directory at `path`, like `rm` or `rmdir`.
+    /// Once the last link to a file is gone, its inode and blocks become free again
+    /// (the blocks are not reused for later files, but the image stays consistent).
+    pub fn remove(&mut self, path: &str) -> Result<(), Ext4Error> {
+        self.remove_entry(path, false)
+    }
+
+    /// Like [`Ext4ImageWriter::remove`], but directories are removed together with everything in them (`rm -r`).
+    pub fn remove_dir_all(&mut self, path: &str) -> Result<(), Ext4Error> {
+        self.remove_entry(path, true)
+    }
+
+    fn remove_entry(&mut self, path: &str, recursive: bool) -> Result<(), Ext4Error> {
+        if path.trim_matches('/') == "lost+found" {
+            return Err(Ext4Error::ReservedPath(path.to_string()));
+        }
+        let inodes = match self.directories.remove(path, recursive)? {
+            file_tree::DirectoryEntry::File(file) => vec![file.inode],
+            // directories have no inodes before finishing
+            file_tree::DirectoryEntry::Directory(directory) => directory.file_inodes(),
+        };
+        for inode_num in inodes {
+            let inode = &mut self.inodes[(inode_num - 1) as usize];
+            inode.set_links_count(inode.links_count() - 1);
+            if inode.links_count() == 0 {
+                self.free_inode(inode_num);
+            }
+        }
+        Ok(())
+    }
+
+    /// Free blocks that no file uses anymore. With shared blocks, the ones that other files still use are kept.
+    fn free_blocks(&mut self, allocations: Vec<Allocation>) {
+        for allocation in allocations {
+            if let Some(users) = self.shared_allocations.get_mut(&allocation.start) {
+                *users -= 1;
+                if *users == 1 {
+                    self.shared_allocations.remove(&allocation.start);
+                }
+                continue;
+            }
+            // later files with the same contents must not point at the freed blocks
+            if let Some(key) = self.deduplicated_allocations.remove(&allocation.start) {
+                for other in self.deduplicated.remove(&key).unwrap() {
+                    self.deduplicated_allocations.remove(&other.start);
+                }
+            }
+            for block in allocation.start..allocation.end {
+                self.used_blocks.mark_unused(block);
+            }
+        }
+    }
+
+    /// Free an inode that is no longer referenced together with its blocks.
+    fn free_inode(&mut self, inode_num: u64) {
+        let allocations = self.file_blocks.remove(&inode_num).unwrap_or_default();
+        self.free_blocks(allocations);
+        self.inodes[(inode_num - 1) as usize] = Ext4Inode::default();
+        self.used_inodes.mark_unused(inode_num - 1);
+    }
+
+    /// Request data journaling for the file at `path` by setting `EXT4_JOURNAL_DATA_FL` (`chattr +j`).
+    /// The flag is only honored by the kernel if the filesystem has a journal.
+    pub fn set_journal_data(&mut self, path: &str) -> Result<(), Ext4Error> {
+        self.directories
+            .update_file_metadata(path, |metadata| metadata.flags |= 0x4000) // EXT4_JOURNAL_DATA_FL
+    }
+
+    /// Set inode flags of the file or directory at `path`, i.e. [`Ext4Inode::IMMUTABLE_FL`] (`chattr +i`)
+    /// or [`Ext4Inode::APPEND_FL`] (`chattr +a`). The flags are added to the ones the writer sets itself.
+    /// Other flags are rejected, as they either don't apply to images or are managed by the writer.
+    pub fn set_inode_flags(&mut self, path: &str, flags: u32) -> Result<(), Ext4Error> {
+        if flags & !Ext4Inode::SETTABLE_FLAGS != 0 {
+            return Err(Ext4Error::Invalid(format!(
+                "unsupported inode flags {:#x}",
+                flags & !Ext4Inode::SETTABLE_FLAGS
+            )));
+        }
+        if let Ok(directory) = self.directories.directory_mut(path) {
+            directory.metadata_mut().flags |= flags;
+            return Ok(());
+        }
+        self.directories
+            .update_file_metadata(path, |metadata| metadata.flags |= flags)
+    }
+
+    /// Set the access, modification, change and creation times of the file or directory at `path`.
+    pub fn set_timestamps(&mut self, path: &str, timestamps: Timestamps) -> Result<(), Ext4Error> {
+        if let Ok(directory) = self.directories.directory_mut(path) {
+            directory.metadata_mut().timestamps = Some(timestamps);
+            return Ok(());
+        }
+        self.directories
+            .update_file_metadata(path, |metadata| metadata.timestamps = Some(timestamps))
+    }
+
+    /// Set the permissions of the directory at `path` (`""` is the root directory). Defaults to `0o755`.
+    /// For `lost+found` this is the same as [`Ext4ImageWriter::set_lost_found_mode`].
+    pub fn set_directory_mode(&mut self, path: &str, mode: u16) -> Result<(), Ext4Error> {
+        if path.trim_matches('/') == "lost+found" {
+            self.lost_found_mode = mode;
+            return Ok(());
+        }
+        self.directories.directory_mut(path)?.metadata_mut().mode = Some(mode);
+        Ok(())
+    }
+
+    /// Change the permissions of the file, symlink, special file or directory at `path` (`""` is the root directory)
+    /// like `chmod`. For files this applies to all of their hard links. The file type bits of `mode` are ignored.
+    pub fn chmod(&mut self, path: &str, mode: u16) -> Result<(), Ext4Error> {
+        self.check_file_type_bits(mode, path);
+        if self.directories.directory_mut(path).is_ok() {
+            return self.set_directory_mode(path, mode & 0o7777);
+        }
+        self.directories
+            .update_file_metadata(path, |metadata| metadata.mode = Some(mode))
+    }
+
+    /// Set the owning user and group of the directory at `path` (`""` is the root directory). Defaults to root.
+    pub fn set_directory_owner(&mut self, path: &str, uid: u32, gid: u32) -> Result<(), Ext4Error> {
+        let metadata = self.directories.directory_mut(path)?.metadata_mut();
+        metadata.uid = uid;
+        metadata.gid = gid;
+        Ok(())
+    }
+
+    /// Set the extended attribute `name` (i.e. `user.comment` or `security.selinux`) of the file, symlink or
+    /// directory at `path`, replacing a previous value. Attributes are stored in the inode as far as they fit
+    /// and in a separate block otherwise. The `user.`, `trusted.`, `security.` and `system.` namespaces are
+    /// supported. Values are stored verbatim, so ACLs have to be passed in the on-disk format of ext4.
+    pub fn set_xattr(&mut self, path: &str, name: &str, value: &[u8]) -> Result<(), Ext4Error> {
+        let xattr = Ext4Xattr::with_full_name(name, value).ok_or_else(|| {
+            Ext4Error::Invalid(format!("unsupported extended attribute '{}'", name))
+        })?;
+        if xattr.name.len() > 255 {
+            return Err(Ext4Error::NameTooLong(name.to_string()));
+        }
+        if xattr.name_index == 7 && xattr.name == b"data" {
+            return Err(Ext4Error::Invalid(
+                "system.data is reserved for inline data".to_string(),
+            ));
+        }
+        if Ext4ExtAttrHeader::create_block(std::slice::from_ref(&xattr), 0, None, self.block_size)
+            .is_none()
+        {
+            return Err(Ext4Error::ContentTooLarge(value.len() as u64));
+        }
+        let replace = |xattrs: &mut Vec<Ext4Xattr>| {
+            xattrs
+                .retain(|other| (other.name_index, &other.name) != (xattr.name_index, &xattr.name));
+            xattrs.push(xattr.clone());
+        };
+        match self.directories.directory_mut(path) {
+            Ok(directory) => replace(&mut directory.metadata_mut().xattrs),
+            Err(Ext4Error::NotADirectory(_)) => self
+                .directories
+                .update_file_metadata(path, |metadata| replace(&mut metadata.xattrs))?,
+            Err(error) => return Err(error),
+        }
+        Ok(())
+    }
+
+    /// Record an fscrypt encryption policy for the directory (or file) at `path`.
+    /// `context` is the raw `encryption.c` xattr value, i.e. a v1 (28 bytes) or v2 (40 bytes) `fscrypt_context`
+    /// including the contents/filenames modes, flags, the master key identifier and the per-inode nonce.
+    /// This also enables the `encrypt` feature.
+    ///
+    /// This crate does not encrypt anything: the kernel expects every file below an encrypted directory
+    /// to be encrypted with the same policy, so the caller has to write already encrypted contents
+    /// and set a matching context on each of them. Only use this if you know what you are doing.
+    pub fn set_encryption_policy(&mut self, path: &str, context: &[u8]) -> Result<(), Ext4Error> {
+        match (context.first(), context.len()) {
+            (Some(1), 28) | (Some(2), 40) => {}
+            _ => {
+                return Err(Ext4Error::Invalid(format!(
+                    "invalid fscrypt context of {} bytes",
+                    context.len()
+                )));
+            }
+        }
+        match self.directories.file_inode(path) {
+            Ok(inode_num) => {
+                self.inodes[(inode_num - 1) as usize]
+                    .set_encryption_context(context, self.inode_size)?;
+            }
+            Err(_) => self
+                .directories
+                .directory_mut(path)?
+                .set_encryption_context(context),
+        }
+        self.encryption = true;
+        Ok(())
+    }
+
+    /// Assign the file or directory at `path` to the project `projid` (`chattr -p`), which project quota
+    /// accounts its usage to. Directories with a project id also get `EXT4_PROJINHERIT_FL` (`chattr +P`),
+    /// so that files created in them later on belong to the same project.
+    /// Any non-zero id enables the `project` feature, which needs inodes of more than 128 bytes
+    /// and is not available for ext2 images.
+    pub fn set_project_id(&mut self, path: &str, projid: u32) -> Result<(), Ext4Error> {
+        if self.ext2 {
+            return Err(Ext4Error::UnsupportedByExt2("project ids"));
+        }
+        if self.inode_size == 128 {
+            return Err(Ext4Error::InodeTooSmall {
+                feature: "project ids",
+                min_size: 256,
+            });
+        }
+        if let Ok(directory) = self.directories.directory_mut(path) {
+            directory.metadata_mut().projid = projid;
+        } else {
+            self.directories
+                .update_file_metadata(path, |metadata| metadata.projid = Some(projid))?;
+        }
+        self.project_ids |= projid != 0;
+        Ok(())
+    }
+
+    /// Make the directory at `path` (`""` is the root directory) case-insensitive (`chattr +F`).
+    /// This also enables the `casefold` feature with the `utf8-12.1` encoding.
+    /// Subdirectories are only case-insensitive if they are marked as well, and no two entries may
+    /// differ in case only. HTree indexes of such directories hash the lowercased names, which matches
+    /// the kernel for ASCII names, so directories with other names stay unindexed.
+    pub fn set_casefold(&mut self, path: &str) -> Result<(), Ext4Error> {
+        self.directories.directory_mut(path)?.metadata_mut().flags |= Ext4Inode::CASEFOLD_FL;
+        self.casefold = true;
+        Ok(())
+    }
+
+    /// Choose the order in which directory entries are written, see [`EntryOrdering`]. Can be changed until finishing.
+    pub fn set_entry_ordering(&mut self, ordering: EntryOrdering) {
+        self.entry_ordering = ordering;
+    }
+
+    /// Set the permissions of the `lost+found` directory. Defaults to `0o700` like `mke2fs`,
+    /// since fsck may place sensitive recovered files there.
+    pub fn set_lost_found_mode(&mut self, mode: u16) {
+        self.lost_found_mode = mode;
+    }
+
+    /// Set the volume label that i.e. `blkid` and `lsblk -f` show and that `LABEL=` in `/etc/fstab` refers to.
+    /// Labels can be up to 16 bytes long.
+    pub fn set_volume_label(&mut self, label: &str) -> Result<(), Ext4Error> {
+        if label.len() > 16 || label.contains('\0') {
+            return Err(Ext4Error::Invalid(format!(
+                "the volume label '{}' is not valid, it has to be at most 16 bytes long without NUL bytes",
+                label
+            )));
+        }
+        self.volume_label = label.to_string();
+        Ok(())
+    }
+
+    /// Reserve `percent` of the blocks for root (`s_r_blocks_count`), like `mkfs.ext4 -m`. Defaults to 0,
+    /// as images are usually sized to their contents. Has to be between 0 and 50.
+    /// The reserved blocks are computed from the final size of the image when finishing.
+    pub fn set_reserved_percent(&mut self, percent: f32) -> Result<(), Ext4Error> {
+        if !(0.0..=50.0).contains(&percent) {
+            return Err(Ext4Error::Invalid(format!(
+                "{}% of the blocks can't be reserved, it has to be between 0 and 50",
+                percent
+            )));
+        }
+        self.reserved_percent = percent as f64;
+        Ok(())
+    }
+
+    /// Make room for at least `count` inodes (like `mke2fs -N`), i.e. for images that are written to after
+    /// they were built. By default there are only as many inodes as the image needs, rounded up to fill
+    /// the inode table blocks. A block group can hold at most 8 inodes per byte of the block size,
+    /// so many inodes may need more block groups and with them a larger image.
+    pub fn set_inode_count(&mut self, count: u64) -> Result<(), Ext4Error> {
+        if count > self.max_inodes {
+            return Err(Ext4Error::TooManyInodes(self.max_inodes));
+        }
+        self.min_inode_count = count;
+        Ok(())
+    }
+
+    /// Allow the user `uid` and the group `gid` to use the reserved blocks as well (`tune2fs -u` and `-g`).
+    /// Both default to root.
+    pub fn set_reserved_owner(&mut self, uid: u16, gid: u16) {
+        self.reserved_owner = (uid, gid);
+    }
+
+    /// Add an internal journal of `blocks` blocks (`has_journal`), so that the image can be mounted read-write
+    /// without risking its consistency on a crash. The journal is empty and is allocated in one piece
+    /// (as far as possible) when finishing. Like mkfs, this requires between 1024 and 10240000 blocks,
+    /// 0 removes the journal again. ext2 images can't have a journal.
+    pub fn set_journal_blocks(&mut self, blocks: u64) -> Result<(), Ext4Error> {
+        if blocks != 0 && !(Self::MIN_JOURNAL_BLOCKS..=Self::MAX_JOURNAL_BLOCKS).contains(&blocks) {
+            return Err(Ext4Error::Invalid(format!(
+                "a journal of {} blocks is not between {} and {} blocks",
+                blocks,
+                Self::MIN_JOURNAL_BLOCKS,
+                Self::MAX_JOURNAL_BLOCKS
+            )));
+        }
+        if blocks != 0 && self.ext2 {
+            return Err(Ext4Error::UnsupportedByExt2("journals"));
+        }
+        self.journal_blocks = blocks;
+        Ok(())
+    }
+
+    /// Track the usage of every user and group (and project, with inodes of more than 128 bytes) in quota files
+    /// (`quota` feature), so that the image can be mounted with quota enforcement. The files record the usage
+    /// of the finished image without any limits, those can be set with i.e. `setquota` once mounted.
+    /// Changes of the owner by the `inode_hook` of [`Ext4ImageWriter::finish_with_inode_hook`] are not accounted for.
+    /// ext2 images can't have quota files.
+    pub fn set_quota(&mut self, enabled: bool) -> Result<(), Ext4Error> {
+        if enabled && self.ext2 {
+            return Err(Ext4Error::UnsupportedByExt2("quota files"));
+        }
+        self.quota = enabled;
+        Ok(())
+    }
+
+    /// Override the `bg_flags` of the descriptor of `block_group` (i.e. `0x4` for `EXT4_BG_INODE_ZEROED`).
+    /// This is an advanced knob to produce specific group states, i.e. for testing the kernel.
+    /// The flags are written as given, so flags that don't match the group (like `EXT4_BG_BLOCK_UNINIT`
+    /// on a group with used blocks) result in an image that e2fsck complains about.
+    /// Groups without any used block or inode are marked `INODE_UNINIT | BLOCK_UNINIT | INODE_ZEROED` by default.
+    /// Finishing fails if the image ends up with fewer block groups.
+    pub fn set_group_flags(&mut self, block_group: u64, flags: u16) -> Result<(), Ext4Error> {
+        let max_block_groups = self.max_block_groups();
+        if block_group >= max_block_groups {
+            return Err(Ext4Error::Invalid(format!(
+                "block group {} is beyond the {} block groups of the maximum image size",
+                block_group, max_block_groups
+            )));
+        }
+        self.group_flags.insert(block_group, flags);
+        Ok(())
+    }
+
+    /// Write all metadata to the underlying block device and finish writing the filesystem.
+    /// The writer is flushed, but not synced: call i.e. [`std::fs::File::sync_all`] on the returned writer for durability.
+    /// Fails if features are combined in a way the kernel refuses, i.e. unencrypted entries in an encrypted directory.
+    pub fn finish(self) -> Result<W, Ext4Error> {
+        Ok(self.finish_with_info()?.0)
+    }
+
+    /// Like [`Ext4ImageWriter::finish`] but also returns the final geometry of the image.
+    pub fn finish_with_info(self) -> Result<(W, Ext4ImageInfo), Ext4Error> {
+        self.finish_inner(|_, _| {}, |_, _, _| {})
+    }
+
+    /// Like [`Ext4ImageWriter::finish`] but calls `progress` with the [`FinishStage`] that was just written
+    /// and how many of how many block groups are done, i.e. to show a progress bar for large images.
+    pub fn finish_with_progress(
+        self,
+        progress: impl FnMut(FinishStage, u64, u64),
+    ) -> Result<W, Ext4Error> {
+        Ok(self.finish_inner(|_, _| {}, progress)?.0)
+    }
+
+    /// Like [`Ext4ImageWriter::finish`] but calls `inode_hook` with the inode number and the fully built inode
+    /// for every used inode right before it is checksummed and written.
+    /// This is an escape hatch to set fields that the high-level API does not expose (i.e. flags).
+    /// Note that `i_generation` is not exposed as directory and extent blocks are already checksummed with it.
+    pub fn finish_with_inode_hook(
+        self,
+        inode_hook: impl FnMut(u32, &mut Ext4Inode),
+    ) -> Result<W, Ext4Error> {
+        Ok(self.finish_inner(inode_hook, |_, _, _| {})?.0)
+    }
+
+    fn finish_inner(
+        mut self,
+        mut inode_hook: impl FnMut(u32, &mut Ext4Inode),
+        mut progress: impl FnMut(FinishStage, u64, u64),
+    ) -> Result<(W, Ext4ImageInfo), Ext4Error> {
+        self.validate_feature_combination()?;
+        let directories = std::mem::take(&mut self.directories);
+        let files = directories.file_metadata();
+        for (&inode_num, metadata) in &files {
+            metadata.apply(&mut self.inodes[inode_num as usize - 1]);
+        }
+        self.write_hierarchy_to_inodes(&directories, 2, 2)?;
+        for (inode_num, metadata) in files {
+            let mut inode = self.inodes[inode_num as usize - 1].clone();
+            self.store_xattrs(inode_num, &mut inode, &metadata.xattrs)?;
+            self.inodes[inode_num as usize - 1] = inode;
+        }
+        if self.revision == Revision::Rev0 {
+            self.validate_revision_0()?;
+        }
+        if self.journal_blocks > 0 {
+            self.create_journal()?;
+        }
+        let project_quota_inode = if self.quota {
+            Some(self.create_quota_files()?)
+        } else {
+            None
+        };
+
+        let (num_block_groups, inodes_per_group) =
+            self.geometry(self.used_blocks.end(), self.inodes.len() as u64);
+        if num_block_groups * (inodes_per_group as u64) < self.inodes.len() as u64 {
+            return Err(Ext4Error::TooManyInodes(
+                num_block_groups * inodes_per_group as u64,
+            ));
+        }
+        if num_block_groups * inodes_per_group as u64 > self.max_inodes {
+            return Err(Ext4Error::TooManyInodes(self.max_inodes));
+        }
+
+        let max_bgdt_table_len = self.max_block_groups();
+        if num_block_groups > max_bgdt_table_len {
+            return Err(Ext4Error::TooManyBlockGroups {
+                needed: num_block_groups,
+                max: max_bgdt_table_len,
+            });
+        }
+
+        let superblock_backups = (1..num_block_groups)
+            .filter_map(|block_group| self.superblock_backup_region(block_group))
+            .collect::<Vec<_>>();
+        for region in &superblock_backups {
+            for block in region.start..region.end {
+                self.mark_block_used(block);
+            }
+        }
+
+        // we need to allocate everything first to make sure that the block bitmaps are represented in themselves
+        let inode_table_blocks = self.inode_table_blocks(inodes_per_group);
+        let group_metadata = if self.fixed_group_metadata {
+            (0..num_block_groups)
+                .map(|block_group| {
+                    let region = self.group_metadata_region(block_group);
+                    for block in region.start..region.end {
+                        self.mark_block_used(block);
+                    }
+                    (
+                        Allocation::from_start_len(region.start, 1),
+                        Allocation::from_start_len(region.start + 1, 1),
+                        Allocation::from_start_len(region.start + 2, inode_table_blocks),
+                    )
+                })
+                .collect::<Vec<_>>()
+        } else {
+            // like mkfs with flex_bg, the block bitmaps of all groups of a flex group are followed by
+            // their inode bitmaps and then by their inode tables. They are allocated one by one,
+            // so that they only skip the superblock backups instead of leaving gaps in front of them.
+            let flex_groups = 1 << self.log_groups_per_flex;
+            let mut group_metadata = vec![];
+            for first_group in (0..num_block_groups).step_by(flex_groups) {
+                let groups = (num_block_groups - first_group).min(flex_groups as u64);
+                let block_bitmaps = (0..groups)
+                    .map(|_| self.allocate_blocks(1))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let inode_bitmaps = (0..groups)
+                    .map(|_| self.allocate_blocks(1))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for (block_bitmap, inode_bitmap) in block_bitmaps.into_iter().zip(inode_bitmaps) {
+                    let inode_table = self.allocate_blocks(inode_table_blocks)?;
+                    group_metadata.push((block_bitmap, inode_bitmap, inode_table));
+                }
+            }
+            group_metadata
+        };
+        if self.revision == Revision::Rev0 {
+            // without a resize inode to own them, the reserved GDT blocks and their backups stay free
+            let used_bgdt_blocks = (num_block_groups * self.desc_size()).div_ceil(self.block_size);
+            let gdt_starts = superblock_backups.iter().map(|region| region.start + 1);
+            for gdt_start in std::iter::once(self.gdt_start()).chain(gdt_starts) {
+                for block in gdt_start + used_bgdt_blocks..gdt_start + self.bgdt_blocks() {
+                    self.used_blocks.mark_unused(block);
+                }
+            }
+        } else {
+            // the resize inode is built last, so that its double indirect block is the final allocation
+            // and the check below confirms the number of block groups its GDT block list is based on
+            self.inodes[6 /*inode 7*/] =
+                self.create_resize_inode(num_block_groups, &superblock_backups)?;
+        }
+        // groups that are only needed for their inodes (see `geometry`) may hold no blocks at all,
+        // the image then ends with the first block of the last group
+        let num_blocks = self
+            .used_blocks
+            .end()
+            .max(self.group_start(num_block_groups - 1) + 1);
+        if let Some((&block_group, _)) = self.group_flags.range(num_block_groups..).next() {
+            return Err(Ext4Error::Invalid(format!(
+                "flags were set for block group {} but the image only has {} block groups",
+                block_group, num_block_groups
+            )));
+        }
+        if num_blocks * self.block_size > self.max_size {
+            return Err(Ext4Error::OutOfSpace {
+                needed: num_blocks,
+                available: self.max_size / self.block_size,
+            });
+        }
+        if self.block_groups_for(num_blocks) != num_block_groups {
+            return Err(Ext4Error::Invalid(format!(
+                "the allocator used {} blocks which does not fit into {} block groups",
+                num_blocks, num_block_groups
+            )));
+        }
+
+        // write inodes and build block group descriptors for each block group.
+        let mut total_free_inodes = 0;
+        let mut total_free_blocks = 0;
+        let mut bgdt_buf = Cursor::new(Vec::new());
+        let mut inodes = std::mem::take(&mut self.inodes);
+        inodes.resize(
+            num_block_groups as usize * inodes_per_group,
+            Ext4Inode::default(),
+        );
+        // groups without a single used block or inode (i.e. far out in a sparse image) are flagged as uninitialized
+        // and share all-free bitmaps, a zeroed inode table and their descriptor contents, so nothing has to be
+        // computed for them. Their metadata always lives in other groups, which is what the kernel assumes
+        // when it reconstructs the bitmap of a BLOCK_UNINIT group.
+        let blocks_per_group = self.blocks_per_group();
+        let empty_block_bitmap =
+            BitmapBlock::from_bytes(&[], blocks_per_group as u32, self.block_size);
+        let empty_inode_bitmap =
+            BitmapBlock::from_bytes(&[], inodes_per_group as u32, self.block_size);
+        let empty_inode_table = vec![0u8; inodes_per_group * self.inode_size as usize];
+        let mut empty_group_descriptor = Ext4BlockGroupDescriptor::default();
+        empty_group_descriptor.set_free_blocks_count(empty_block_bitmap.free_count());
+        empty_group_descriptor.set_free_inodes_count(empty_inode_bitmap.free_count());
+        empty_group_descriptor.set_flags(
+            Ext4BlockGroupDescriptor::INODE_UNINIT
+                | Ext4BlockGroupDescriptor::BLOCK_UNINIT
+                | Ext4BlockGroupDescriptor::INODE_ZEROED,
+        );
+        empty_group_descriptor.set_itable_unused(inodes_per_group as u32);
+        empty_group_descriptor.set_bitmap_checksums(
+            &self.uuid,
+            &empty_block_bitmap,
+            &empty_inode_bitmap,
+            self.desc_size() as u16,
+        );
+        for (block_group, inodes) in inodes.chunks_mut(inodes_per_group).enumerate() {
+            let (block_bitmap_alloc, inode_bitmap_alloc, inode_table_alloc) =
+                group_metadata[block_group];
+            // ext2 has no group descriptor checksums, without which the uninit flags are not allowed
+            let group_start = self.group_start(block_group as u64);
+            // the shared descriptor counts a full group of free blocks, which a shorter last group doesn't have
+            let uninitialized = !self.ext2
+                && group_start + blocks_per_group <= num_blocks
+                && self
+                    .used_blocks
+                    .is_range_unused(group_start, blocks_per_group)
+                && self.used_inodes.is_range_unused(
+                    block_group as u64 * inodes_per_group as u64,
+                    inodes_per_group as u64,
+                );
+            let mut block_group_descriptor = if uninitialized {
+                self.write_blocks(block_bitmap_alloc, empty_block_bitmap.as_bytes())?;
+                self.write_blocks(inode_bitmap_alloc, empty_inode_bitmap.as_bytes())?;
+                self.write_blocks(inode_table_alloc, &empty_inode_table)?;
+                empty_group_descriptor.clone()
+            } else {
+                let mut inode_buf =
+                    Cursor::new(vec![0u8; inodes_per_group * self.inode_size as usize]);
+                let mut directories = 0;
+                for (i, inode) in inodes.iter_mut().enumerate() {
+                    let inode_num = (block_group * inodes_per_group + i + 1) as u32;
+                    if self.used_inodes.is_used(inode_num as u64 - 1) {
+                        inode_hook(inode_num, inode);
+                    }
+                    inode.update_checksum_with_size(&self.uuid, inode_num, self.inode_size);
+                    inode_buf.write_all(&inode.as_bytes()[..self.inode_size as usize])?;
+                    if inode.is_directory() {
+                        directories += 1;
+                    }
+                }
+
+                // write out the inode table for this block group
+                let block_bitmap_len = (num_blocks - group_start).min(blocks_per_group) as u32;
+                let block_bitmap =
+                    self.used_blocks
+                        .get_bitmap(group_start, block_bitmap_len, self.block_size);
+                self.write_blocks(block_bitmap_alloc, block_bitmap.as_bytes())?;
+                let inode_bitmap = self.used_inodes.get_bitmap(
+                    block_group as u64 * inodes_per_group as u64,
+                    inodes_per_group as u32,
+                    self.block_size,
+                );
+                self.write_blocks(inode_bitmap_alloc, inode_bitmap.as_bytes())?;
+                self.write_blocks(inode_table_alloc, &inode_buf.into_inner())?;
+                let mut block_group_descriptor = Ext4BlockGroupDescriptor::default();
+                block_group_descriptor.set_free_blocks_count(block_bitmap.free_count());
+                block_group_descriptor.set_free_inodes_count(inode_bitmap.free_count());
+                block_group_descriptor.set_used_dirs_count(directories);
+                block_group_descriptor.set_bitmap_checksums(
+                    &self.uuid,
+                    &block_bitmap,
+                    &inode_bitmap,
+                    self.desc_size() as u16,
+                );
+                block_group_descriptor
+            };
+            total_free_blocks += block_group_descriptor.free_blocks_count() as u64;
+            total_free_inodes += block_group_descriptor.free_inodes_count();
+            block_group_descriptor.set_block_bitmap(block_bitmap_alloc.as_single());
+            block_group_descriptor.set_inode_bitmap(inode_bitmap_alloc.as_single());
+            block_group_descriptor.set_inode_table(inode_table_alloc.start);
+            if let Some(&flags) = self.group_flags.get(&(block_group as u64)) {
+                block_group_descriptor.set_flags(flags);
+            }
+            block_group_descriptor.update_descriptor_checksum(
+                &self.uuid,
+                block_group as u32,
+                self.desc_size() as u16,
+            );
+            bgdt_buf.write_all(&block_group_descriptor.as_bytes()[..self.desc_size() as usize])?;
+            self.observer.on_block_group_finalized(block_group as u64);
+            progress(
+                FinishStage::BlockGroup,
+                block_group as u64 + 1,
+                num_block_groups,
+            );
+        }
+        let mut bgdt = bgdt_buf.into_inner();
+        self.write_blocks(
+            Allocation::from_start_len(self.gdt_start(), self.bgdt_blocks()),
+            &bgdt,
+        )?;
+        progress(
+            FinishStage::GroupDescriptors,
+            num_block_groups,
+            num_block_groups,
+        );
+
+        assert!(self.used_blocks.end() <= num_blocks);
+        if self.used_blocks.end() < num_blocks {
+            // the image has to extend to its last (unused) block
+            self.write_blocks(
+                Allocation::from_start_len(num_blocks - 1, 1),
+                &vec![0u8; self.block_size as usize],
+            )?;
+        }
+
+        // finally write the superblock
+        let mut superblock = ext4_h::Ext4SuperBlock::new(self.uuid, inodes_per_group as u32);
+        superblock.set_block_size(self.block_size);
+        superblock.set_first_ino(self.lost_found_inode as u32);
+        superblock.set_volume_name(&self.volume_label);
+        superblock.set_log_groups_per_flex(self.log_groups_per_flex);
+        superblock.set_directory_hash_signedness(self.signed_directory_hash);
+        if self.encryption {
+            superblock.enable_encryption();
+        }
+        if self.casefold {
+            superblock.enable_casefold();
+        }
+        if self.project_ids {
+            superblock.enable_project();
+        }
+        if self.ext2 {
+            superblock.make_ext2_compatible();
+        }
+        if self.revision == Revision::Rev0 {
+            superblock.make_revision_0();
+        }
+        if self.shared_blocks {
+            superblock.enable_shared_blocks();
+        }
+        if self.journal_blocks > 0 {
+            superblock.enable_journal(&inodes[7]);
+        }
+        if let Some(project_inode) = project_quota_inode {
+            superblock.enable_quota(3, 4, project_inode.map(|inode_num| inode_num as u32));
+        }
+        superblock.set_inode_size(self.inode_size as u16);
+        let used_bgdt_blocks = (num_block_groups * self.desc_size()).div_ceil(self.block_size);
+        if superblock.has_resize_inode() {
+            superblock.set_reserved_gdt_blocks(
+                (self.bgdt_blocks() - used_bgdt_blocks).try_into().unwrap(),
+            );
+        }
+        let resize_reservation =
+            (self.bgdt_blocks() - used_bgdt_blocks) * (1 + superblock_backups.len() as u64);
+        superblock.set_free_inodes_count(total_free_inodes);
+        superblock.set_free_blocks_count(total_free_blocks);
+        superblock.update_blocks_count(num_blocks);
+        superblock
+            .set_reserved_blocks_count((num_blocks as f64 * self.reserved_percent / 100.0) as u64);
+        superblock.set_default_reserved_owner(self.reserved_owner.0, self.reserved_owner.1);
+        superblock.update_checksum();
+        // the backups start at the first block of their group and include zeroed reserved GDT blocks
+        bgdt.resize((self.bgdt_blocks() * self.block_size) as usize, 0);
+        for region in superblock_backups {
+            let mut backup = superblock.clone();
+            backup.set_block_group_nr(self.block_group_of(region.start) as u16);
+            backup.update_checksum();
+            self.write_blocks(
+                Allocation::from_start_len(region.start, 1),
+                &backup.as_bytes(),
+            )?;
+            self.write_blocks(
+                Allocation::from_start_len(region.start + 1, self.bgdt_blocks()),
+                &bgdt,
+            )?;
+        }
+        // the superblock always starts at byte 1024, which is block 1 for 1024 byte blocks
+        let mut first_bytes = [0u8; 2048];
+        first_bytes[1024..2048].copy_from_slice(&superblock.as_bytes());
+        self.write_blocks(
+            Allocation::from_start_len(0, 2048_u64.div_ceil(self.block_size)),
+            &first_bytes,
+        )?;
+        // make sure nothing is left in buffers of the writer (i.e. a `BufWriter`) when we return
+        self.writer.flush()?;
+        progress(FinishStage::Superblock, num_block_groups, num_block_groups);
+        let info = Ext4ImageInfo {
+            uuid: self.uuid,
+            block_size: self.block_size,
+            blocks_count: num_blocks,
+            block_groups: num_block_groups,
+            inodes_count: num_block_groups * inodes_per_group as u64,
+            inodes_per_group: inodes_per_group as u64,
+            free_blocks_count: total_free_blocks,
+            free_inodes_count: total_free_inodes as u64,
+            size: num_blocks * self.block_size,
+            minimal_max_size: (num_blocks - resize_reservation) * self.block_size,
+        };
+        Ok((self.writer, info))
+    }
+
+    /// The number of block groups and the inodes per group of an image with `used_blocks` blocks of data
+    /// and `num_inodes` inodes (or the count of [`Ext4ImageWriter::set_inode_count`] if that is larger),
+    /// before the bitmaps, the inode tables and the resize inode are allocated.
+    fn geometry(&self, used_blocks: u64, num_inodes: u64) -> (u64, usize) {
+        let num_inodes = num_inodes.max(self.min_inode_count);
+        if self.fixed_group_metadata {
+            // every block group carries a fixed size inode table in its (already reserved) metadata region
+            let data_block_groups =
+                self.block_groups_for(used_blocks + 1 /* resize inode indirect block */);
+            let inode_block_groups = num_inodes.div_ceil(Self::NO_FLEX_INODES_PER_GROUP);
+            (
+                data_block_groups.max(inode_block_groups),
+                Self::NO_FLEX_INODES_PER_GROUP as usize,
+            )
+        } else {
+            let inodes_per_block = self.block_size / self.inode_size;
+            let blocks_needed_for_inodes = num_inodes.div_ceil(inodes_per_block);
+            let num_blocks = used_blocks + blocks_needed_for_inodes + 1 /* resize inode indirect block */ ;
+            let num_block_groups = self.block_groups_for(num_blocks);
+            let num_blocks = num_blocks + num_block_groups * 2; // for the block and inode bitmaps;
+            // the metadata allocated when finishing skips the superblock backups of the groups it runs into
+            let num_blocks = num_blocks
+                + (self.block_groups_for(used_blocks)..self.block_groups_for(num_blocks))
+                    .filter_map(|block_group| self.superblock_backup_region(block_group))
+                    .map(Allocation::len)
+                    .sum::<u64>();
+            // the inode bitmap of a group is a single block, so it can't track more inodes than that
+            let num_block_groups = self
+                .block_groups_for(num_blocks)
+                .max(num_inodes.div_ceil(self.block_size * 8));
+            // groups far out may only hold data, but every group needs at least one inode table block.
+            // e2fsck also wants a multiple of 8, which is more than a block holds with 1024 byte blocks.
+            let inodes_per_group_multiple = inodes_per_block.max(8);
+            let inodes_per_group = (num_inodes
+                .div_ceil(num_block_groups)
+                .div_ceil(inodes_per_group_multiple)
+                * inodes_per_group_multiple) as usize;
+            (num_block_groups, inodes_per_group)
+        }
+    }
+
+    /// Reject combinations of features that would produce an image the kernel or e2fsck refuses.
+    fn validate_feature_combination(&self) -> Result<(), Ext4Error> {
+        if self.ext2 && self.journal_blocks > 0 {
+            return Err(Ext4Error::UnsupportedByExt2("journals"));
+        }
+        if self.ext2 && self.quota {
+            return Err(Ext4Error::UnsupportedByExt2("quota files"));
+        }
+        if self.ext2 && self.encryption {
+            return Err(Ext4Error::UnsupportedByExt2("encryption"));
+        }
+        if self.inode_size == 128 && self.encryption {
+            return Err(Ext4Error::InodeTooSmall {
+                feature: "encryption contexts",
+                min_size: 256,
+            });
+        }
+        if self.ext2 && self.project_ids {
+            return Err(Ext4Error::UnsupportedByExt2("project ids"));
+        }
+        if self.inode_size == 128 && self.project_ids {
+            return Err(Ext4Error::InodeTooSmall {
+                feature: "project ids",
+                min_size: 256,
+            });
+        }
+        if self.ext2 && self.casefold {
+            return Err(Ext4Error::UnsupportedByExt2("case-insensitive directories"));
+        }
+        if self.revision == Revision::Rev0 && self.lost_found_inode != 11 {
+            return Err(Ext4Error::UnsupportedByRevision0(
+                "reserving additional inodes".to_string(),
+            ));
+        }
+        self.validate_casefold(&self.directories, "")?;
+        self.validate_encryption(&self.directories, "")
+    }
+
+    /// Revision 0 has neither the ext_attr nor the large_file feature, which the inodes would need.
+    fn validate_revision_0(&self) -> Result<(), Ext4Error> {
+        for (index, inode) in self.inodes.iter().enumerate() {
+            if inode.file_acl() != 0 {
+                return Err(Ext4Error::UnsupportedByRevision0(format!(
+                    "the extended attributes of inode {}",
+                    index + 1
+                )));
+            }
+            if inode.file_type() == FileType::RegularFile && inode.size() > i32::MAX as u64 {
+                return Err(Ext4Error::UnsupportedByRevision0(format!(
+                    "files of 2 GiB or more like inode {} of {} bytes",
+                    index + 1,
+                    inode.size()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Case-insensitive directories can't be encrypted, as their entries would need to store the hash
+    /// of the plaintext name, and must not hold names that only differ in case.
+    fn validate_casefold(&self, directory: &Directory, path: &str) -> Result<(), Ext4Error> {
+        if directory.metadata().flags & Ext4Inode::CASEFOLD_FL != 0 {
+            if directory.encryption_context().is_some() {
+                return Err(Ext4Error::InvalidEncryption(format!(
+                    "the case-insensitive directory '{}' can't be encrypted",
+                    path
+                )));
+            }
+            let mut folded = std::collections::HashSet::new();
+            for (name, _) in directory.entries() {
+                if !folded.insert(name.to_lowercase()) {
+                    return Err(Ext4Error::PathExists(format!("{}/{}", path, name)));
+                }
+            }
+        }
+        for (name, entry) in directory.entries() {
+            if let file_tree::DirectoryEntry::Directory(subdirectory) = entry {
+                self.validate_casefold(subdirectory, &format!("{}/{}", path, name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// All entries of an encrypted directory need the policy of their parent (the per-inode nonce aside)
+    /// and names of at least 16 bytes, the minimum length of an encrypted filename.
+    /// Symlinks can't be encrypted as we store their target in plaintext instead of as `fscrypt_symlink_data`.
+    fn validate_encryption(&self, directory: &Directory, path: &str) -> Result<(), Ext4Error> {
+        // the last 16 bytes of a fscrypt context are the nonce
+        let policy = |context: &[u8]| context[..context.len() - 16].to_vec();
+        for (name, entry) in directory.entries() {
+            let entry_path = format!("{}/{}", path, name);
+            let context = match entry {
+                file_tree::DirectoryEntry::Directory(subdirectory) => {
+                    self.validate_encryption(subdirectory, &entry_path)?;
+                    subdirectory.encryption_context().map(<[u8]>::to_vec)
+                }
+                file_tree::DirectoryEntry::File(file) => {
+                    let inode = &self.inodes[file.inode as usize - 1];
+                    let context = inode.xattr(9, b"c")?;
+                    if context.is_some() && inode.file_type() == FileType::SymbolicLink {
+                        return Err(Ext4Error::InvalidEncryption(format!(
+                            "the symlink '{}' cannot be encrypted",
+                            entry_path
+                        )));
+                    }
+                    context
+                }
+            };
+            let is_lost_found = path.is_empty() && name == "lost+found";
+            if let Some(parent_context) = directory.encryption_context()
+                && !is_lost_found
+            {
+                if context.as_deref().map(policy) != Some(policy(parent_context)) {
+                    return Err(Ext4Error::InvalidEncryption(format!(
+                        "'{}' is not encrypted with the policy of its encrypted parent directory",
+                        entry_path
+                    )));
+                }
+                if name.len() < 16 {
+                    return Err(Ext4Error::InvalidEncryption(format!(
+                        "'{}' is too short to be an encrypted name",
+                        entry_path
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn create_resize_inode(
+        &mut self,
+        block_groups: u64,
+        superblock_backups: &[Allocation],
+    ) -> Result<Ext4Inode, Ext4Error> {
+        // `block_groups` is final: every other block is allocated by now and `finish_inner` rejects
+        // images whose last allocation spilled into another group
+        let used_bgdt_blocks = (block_groups * self.desc_size()).div_ceil(self.block_size);
+
+        // the double indirect block lists the reserved GDT blocks at the index they will have in the GDT
+        let reserved_gdt_blocks = Allocation {
+            start: self.gdt_start() + used_bgdt_blocks,
+            end: self.gdt_start() + self.bgdt_blocks(),
+        };
+        let mut indirect_buffer = vec![0u8; used_bgdt_blocks as usize * 4];
+        for block in reserved_gdt_blocks.start..reserved_gdt_blocks.end {
+            self.mark_block_used(block);
+            indirect_buffer.extend_from_slice(&(block as u32).to_le_bytes());
+        }
+        if indirect_buffer.len() > self.block_size as usize {
+            return Err(Ext4Error::Invalid(format!(
+                "the maximum size of {} bytes needs too many block group descriptors for {} byte blocks",
+                self.max_size, self.block_size
+            )));
+        }
+        // each reserved GDT block lists its own backups, which sit at the same offset in the backup regions
+        for block in reserved_gdt_blocks.start..reserved_gdt_blocks.end {
+            let offset = block - self.group_start(0);
+            let mut backups = vec![0u8; self.block_size as usize];
+            for (entry, region) in backups.chunks_mut(4).zip(superblock_backups) {
+                entry.copy_from_slice(&((region.start + offset) as u32).to_le_bytes());
+            }
+            self.write_blocks(Allocation::from_start_len(block, 1), &backups)?;
+        }
+        let block_indirect = self.allocate_blocks(1)?;
+        self.write_blocks(block_indirect, &indirect_buffer)?;
+        let descr = LegacyBlockDescriptor::new(block_indirect.as_single() as u32);
+        let mut inode = Ext4Inode::default();
+
+        descr.write_buffer(inode.block_mut());
+        // the reserved GDT blocks with their backups and the double indirect block
+        inode.add_blocks(
+            reserved_gdt_blocks.len() * (1 + superblock_backups.len() as u64) + 1,
+            self.block_size,
+        );
+        inode.set_file_type(FileType::RegularFile);
+        inode.set_links_count(1);
+        inode.set_size(LegacyBlockDescriptor::maximum_addressable_size(
+            self.block_size,
+        ));
+        Ok(inode)
+    }
+
+    /// Without flex_bg, the bitmaps and the inode table of a block group are placed at the start of the group
+    /// (or right after the block group descriptor table or its backup).
+    fn group_metadata_region(&self, block_group: u64) -> Allocation {
+        let start = if block_group == 0 {
+            self.gdt_start() + self.bgdt_blocks()
+        } else if let Some(backup) = self.superblock_backup_region(block_group) {
+            backup.end
+        } else {
+            self.group_start(block_group)
+        };
+        let inode_table_blocks =
+            (Self::NO_FLEX_INODES_PER_GROUP * self.inode_size) / self.block_size;
+        Allocation::from_start_len(start, 2 + inode_table_blocks)
+    }
+
+    /// The backup of the superblock and the GDT (including the reserved GDT blocks) at the start of `block_group`.
+    fn superblock_backup_region(&self, block_group: u64) -> Option<Allocation> {
+        let has_super = self.revision == Revision::Rev0 || Ext4SuperBlock::has_super(block_group);
+        (block_group != 0 && has_super).then(|| {
+            Allocation::from_start_len(self.group_start(block_group), 1 + self.bgdt_blocks())
+        })
+    }
+
+    /// Keep the superblock backups of all potential block groups free, so that data is allocated around them.
+    /// This happens right before the first allocation, as the block size may still change until then.
+    fn reserve_superblock_backups(&mut self) {
+        if self.superblock_backups_reserved {
+            return;
+        }
+        self.superblock_backups_reserved = true;
+        for block_group in 1..self.max_block_groups() {
+            if let Some(region) = self.superblock_backup_region(block_group) {
+                self.allocator.reserve(region);
+            }
+        }
+    }
+
+    /// Whether `block` is part of a superblock backup or (with fixed group metadata) the bitmaps and inode table of its group.
+    fn is_group_metadata(&self, block: u64) -> bool {
+        let block_group = self.block_group_of(block);
+        let contains = |region: Allocation| (region.start..region.end).contains(&block);
+        self.superblock_backup_region(block_group)
+            .is_some_and(contains)
+            || (self.fixed_group_metadata && contains(self.group_metadata_region(block_group)))
+    }
+
+    /// ext2 has no `64bit` feature and thus only 32 byte block group descriptors.
+    fn desc_size(&self) -> u64 {
+        if self.ext2 {
+            32
+        } else {
+            Ext4BlockGroupDescriptor::SIZE
+        }
+    }
+
+    /// The blocks reserved for the GDT. These are sized for 64 byte descriptors even for ext2,
+    /// which leaves more room for online resizing.
+    fn bgdt_blocks(&self) -> u64 {
+        (self.max_block_groups() * Ext4BlockGroupDescriptor::SIZE).div_ceil(self.block_size)
+    }
+
+    /// The block after the superblock where the GDT starts.
+    fn gdt_start(&self) -> u64 {
+        self.first_data_block() + 1
+    }
+
+    /// With 1024 byte blocks, block 0 is left for the boot loader and the first block group starts at block 1.
+    fn first_data_block(&self) -> u64 {
+        (self.block_size == 1024) as u64
+    }
+
+    /// Each block group covers as many blocks as its block bitmap has bits.
+    fn blocks_per_group(&self) -> u64 {
+        self.block_size * 8
+    }
+
+    fn group_start(&self, block_group: u64) -> u64 {
+        self.first_data_block() + block_group * self.blocks_per_group()
+    }
+
+    fn block_group_of(&self, block: u64) -> u64 {
+        (block - self.first_data_block()) / self.blocks_per_group()
+    }
+
+    /// The number of block groups needed for an image of `num_blocks` blocks.
+    fn block_groups_for(&self, num_blocks: u64) -> u64 {
+        (num_blocks - self.first_data_block()).div_ceil(self.blocks_per_group())
+    }
+
+    /// The number of block groups that the GDT is reserved for, enough for an image of `max_size` bytes.
+    fn max_block_groups(&self) -> u64 {
+        self.max_size
+            .div_ceil(self.block_size * self.blocks_per_group())
+    }
+
+    fn write_hierarchy_to_inodes(
+        &mut self,
+        directory: &Directory,
+        inode_num: u64,
+        parent_inode_num: u64,
+    ) -> Result<(), Ext4Error> {
+        if let Some(blocks) = directory.raw_blocks() {
+            let mut inode =
+                self.create_raw_directory_inode(directory, blocks, inode_num, parent_inode_num)?;
+            if let Some(context) = directory.encryption_context() {
+                inode.set_encryption_context(context, self.inode_size)?;
+            }
+            self.apply_directory_metadata(directory, inode_num, &mut inode)?;
+            self.inodes[inode_num as usize - 1] = inode;
+            return Ok(());
+        }
+        let base_entries = vec![
+            Ok(Ext4DirEntry::new(
+                inode_num as u32,
+                FileType::Directory,
+                ".",
+            )),
+            Ok(Ext4DirEntry::new(
+                parent_inode_num as u32,
+                FileType::Directory,
+                "..",
+            )),
+        ];
+        let children = self.ordered_entries(directory);
+        let entries = base_entries
+            .into_iter()
+            .chain(children.into_iter().map(|(name, entry)| {
+                Ok(match entry {
+                    file_tree::DirectoryEntry::Directory(directory) => {
+                        let entry_inode_num = if inode_num == 2 && name == "lost+found" {
+                            self.lost_found_inode
+                        } else {
+                            self.alloc_inode()?
+                        };
+                        self.write_hierarchy_to_inodes(directory, entry_inode_num, inode_num)?;
+                        Ext4DirEntry::new(entry_inode_num as u32, FileType::Directory, name)
+                    }
+                    file_tree::DirectoryEntry::File(file) => {
+                        let file_type = self.inodes[file.inode as usize - 1].file_type();
+                        Ext4DirEntry::new(file.inode as u32, file_type, name)
+                    }
+                })
+            }))
+            .collect::<Result<Vec<_>, Ext4Error>>()?;
+
+        let mut inode = self.create_directory_inode(
+            inode_num,
+            &entries,
+            directory.metadata().flags & Ext4Inode::CASEFOLD_FL != 0,
+            // lost+found cant be inline and other xattrs need the space of the system.data xattr
+            inode_num != self.lost_found_inode
+                && directory.encryption_context().is_none()
+                && directory.metadata().xattrs.is_empty()
+                && self.in_inode_xattrs(),
+        )?;
+        if let Some(context) = directory.encryption_context() {
+            inode.set_encryption_context(context, self.inode_size)?;
+        }
+        self.apply_directory_metadata(directory, inode_num, &mut inode)?;
+        self.inodes[inode_num as usize - 1] = inode;
+        Ok(())
+    }
+
+    /// Apply the mode, owner, timestamps and xattrs that were set for `directory` to its freshly built inode.
+    fn apply_directory_metadata(
+        &mut self,
+        directory: &Directory,
+        inode_num: u64,
+        inode: &mut Ext4Inode,
+    ) -> Result<(), Ext4Error> {
+        let metadata = directory.metadata();
+        self.store_xattrs(inode_num, inode, &metadata.xattrs)?;
+        if let Some(mode) = metadata.mode {
+            inode.set_mode(mode);
+        }
+        inode.set_uid(metadata.uid);
+        inode.set_gid(metadata.gid);
+        if metadata.projid != 0 {
+            inode.set_projid(metadata.projid);
+            inode.add_flags(Ext4Inode::PROJINHERIT_FL);
+        }
+        inode.set_timestamps(metadata.timestamps.unwrap_or(self.default_timestamps));
+        inode.add_flags(metadata.flags);
+        Ok(())
+    }
+
+    /// Add `xattrs` to the body of `inode` as far as they fit and put the rest into a newly allocated xattr block.
+    /// The ext2 driver only reads xattr blocks, so for ext2 all of them go into the block.
+    fn store_xattrs(
+        &mut self,
+        inode_num: u64,
+        inode: &mut Ext4Inode,
+        xattrs: &[Ext4Xattr],
+    ) -> Result<(), Ext4Error> {
+        let mut spilled = vec![];
+        for xattr in xattrs {
+            if !self.in_inode_xattrs() || inode.add_xattr(xattr.clone(), self.inode_size).is_err() {
+                spilled.push(xattr.clone());
+            }
+        }
+        if spilled.is_empty() {
+            return Ok(());
+        }
+        if inode.file_acl() != 0 {
+            return Err(Ext4Error::Invalid(format!(
+                "inode {} already has an xattr block",
+                inode_num
+            )));
+        }
+        let block = self.allocate_blocks(1)?;
+        let uuid = (!self.ext2).then_some(&self.uuid);
+        let data = Ext4ExtAttrHeader::create_block(&spilled, block.start, uuid, self.block_size)
+            .ok_or_else(|| {
+                Ext4Error::ContentTooLarge(
+                    spilled
+                        .iter()
+                        .map(|xattr| (xattr.name.len() + xattr.value.len()) as u64)
+                        .sum(),
+                )
+            })?;
+        self.write_blocks(block, &data)?;
+        inode.set_file_acl(block.start);
+        inode.add_blocks(1, self.block_size);
+        Ok(())
+    }
+
+    /// Link the verbatim blocks of a directory and check that they list exactly the entries of the tree.
+    fn create_raw_directory_inode(
+        &mut self,
+        directory: &Directory,
+        blocks: &[[u8; 4096]],
+        inode_num: u64,
+        parent_inode_num: u64,
+    ) -> Result<Ext4Inode, Ext4Error> {
+        let directory_type = FileType::Directory.as_directory_entry_type();
+        let mut expected = vec![
+            (".".to_string(), inode_num as u32, directory_type),
+            ("..".to_string(), parent_inode_num as u32, directory_type),
+        ];
+        for (name, entry) in directory.entries() {
+            match entry {
+                file_tree::DirectoryEntry::Directory(_) => {
+                    return Err(Ext4Error::InvalidRawDirectory(format!(
+                        "the raw directory of inode {} cannot contain the subdirectory '{}'",
+                        inode_num, name
+                    )));
+                }
+                file_tree::DirectoryEntry::File(file) => {
+                    let file_type = self.inodes[file.inode as usize - 1].file_type();
+                    expected.push((
+                        name.clone(),
+                        file.inode as u32,
+                        file_type.as_directory_entry_type(),
+                    ));
+                }
+            }
+        }
+
+        let mut found = vec![];
+        let mut dir_buffer = Vec::with_capacity(blocks.len() * self.block_size as usize);
+        for (i, block) in blocks.iter().enumerate() {
+            let mut block = *block;
+            if i == 0 {
+                LinearDirectoryBlock::link_raw(
+                    &mut block,
+                    inode_num as u32,
+                    parent_inode_num as u32,
+                );
+            }
+            found.extend(
+                LinearDirectoryBlock::parse_raw(&block)?
+                    .into_iter()
+                    .map(|(_, e)| (e.name().to_string(), e.inode(), e.entry_type())),
+            );
+            LinearDirectoryBlock::update_raw_checksum(&mut block, &self.uuid, inode_num as u32, 0);
+            dir_buffer.extend_from_slice(&block);
+        }
+        expected.sort();
+        found.sort();
+        if expected != found {
+            return Err(Ext4Error::InvalidRawDirectory(format!(
+                "the raw directory blocks of inode {} do not match the files added to it",
+                inode_num
+            )));
+        }
+
+        let mut inode =
+            self.create_inode_with_contents(inode_num as u32, &dir_buffer, FileType::Directory)?;
+        inode.set_links_count(2);
+        inode.set_mode(0o755);
+        Ok(inode)
+    }
+
+    fn create_directory_inode(
+        &mut self,
+        inode_num: u64,
+        entries: &[Ext4DirEntry],
+        casefold: bool,
+        allow_inline: bool,
+    ) -> Result<Ext4Inode, Ext4Error> {
+        let mut inode =
+            if allow_inline && let Some(inode) = self.create_directory_inode_inline(entries) {
+                inode
+            } else {
+                self.create_directory_inode_with_blocks(inode_num, entries, casefold)?
+            };
+        // 1 for the entry in the parent, 1 for '.' and 1 for the '..' of each subdirectory
+        let subdirectories = entries[2..].iter().filter(|e| e.is_directory()).count();
+        let links = 2 + subdirectories as u32;
+        // with dir_nlink, a link count of 1 means that there are too many subdirectories to count
+        inode.set_links_count(if links > MAX_DIRECTORY_LINKS {
+            1
+        } else {
+            links as u16
+        });
+        inode.set_mode(if inode_num == self.lost_found_inode {
+            self.lost_found_mode
+        } else {
+            0o755
+        });
+        Ok(inode)
+    }
+
+    fn create_directory_inode_inline(&mut self, entries: &[Ext4DirEntry]) -> Option<Ext4Inode> {
+        let mut block_entries =
+            InlineLinearDirectoryBlock::new(Ext4Inode::MAX_INLINE_SIZE_BLOCK - 4);
+        let mut xattr_entries =
+            InlineLinearDirectoryBlock::new(Ext4Inode::max_inline_size_xattr(self.inode_size));
+        for entry in entries[2..].iter() {
+            if block_entries.fits(entry) {
+                block_entries.add_entry(entry.clone());
+            } else if xattr_entries.fits(entry) {
+                xattr_entries.add_entry(entry.clone());
+            } else {
+                return None; // cant fit entries inline
+            }
+        }
+
+        let parent_inode = entries[1].inode();
+        let mut block_data = [0u8; Ext4Inode::MAX_INLINE_SIZE_BLOCK];
+        block_data[0..4].copy_from_slice(&parent_inode.to_le_bytes());
+        block_data[4..].copy_from_slice(&block_entries.as_bytes());
+
+        Some(Ext4Inode::with_inline_data(
+            &block_data,
+            &xattr_entries.as_bytes(),
+            FileType::Directory,
+            self.inode_size,
+        ))
+    }
+
+    /// `casefold` directories are only indexed if all names can be folded, see [`dir_hash::casefold_ascii`].
+    fn create_directory_inode_with_blocks(
+        &mut self,
+        inode_num: u64,
+        entries: &[Ext4DirEntry],
+        casefold: bool,
+    ) -> Result<Ext4Inode, Ext4Error> {
+        // without metadata_csum there is no checksum tail
+        let block_size = self.block_size as usize;
+        let mut dir_blocks = vec![LinearDirectoryBlock::new(!self.ext2, block_size)];
+        for entry in entries {
+            if !dir_blocks.last().unwrap().fits(entry) {
+                dir_blocks.push(LinearDirectoryBlock::new(!self.ext2, block_size));
+            }
+            let mut entry = entry.clone();
+            if self.revision == Revision::Rev0 {
+                // without the filetype feature, this byte is the upper half of the name length
+                entry.clear_entry_type();
+            }
+            dir_blocks.last_mut().unwrap().add_entry(entry);
+        }
+        if dir_blocks.len() > 1
+            && self.revision != Revision::Rev0
+            && (inode_num != 2 || self.root_indexed)
+            && (!casefold || entries.iter().all(|entry| entry.name().is_ascii()))
+        {
+            return self.create_indexed_directory_inode(inode_num, entries, casefold);
+        }
+        if inode_num == self.lost_found_inode {
+            // like mkfs, preallocate 16 KiB so that e2fsck can reconnect files without allocating blocks
+            let min_blocks = LOST_FOUND_SIZE.div_ceil(self.block_size) as usize;
+            while dir_blocks.len() < min_blocks {
+                dir_blocks.push(LinearDirectoryBlock::new(!self.ext2, block_size));
+            }
+        }
+        let mut dir_buffer = vec![0u8; dir_blocks.len() * block_size];
+        for (i, block) in dir_blocks.iter().enumerate() {
+            let mut dir_block = block.clone();
+            dir_block.update_checksum(&self.uuid, inode_num as u32, 0);
+            dir_block.write_buffer(&mut dir_buffer[i * block_size..(i + 1) * block_size]);
+        }
+        self.create_inode_with_contents(inode_num as u32, &dir_buffer, FileType::Directory)
+    }
+
+    /// Build an HTree directory: the entries are sorted by their hash into leaf blocks, which are indexed by
+    /// the root block and, if the root can't list all of them, by a level of interior nodes.
+    fn create_indexed_directory_inode(
+        &mut self,
+        inode_num: u64,
+        entries: &[Ext4DirEntry],
+        casefold: bool,
+    ) -> Result<Ext4Inode, Ext4Error> {
+        let checksums = !self.ext2;
+        let block_size = self.block_size as usize;
+        let mut hashed = entries[2..]
+            .iter()
+            .map(|entry| {
+                let name = match casefold {
+                    true => dir_hash::casefold_ascii(entry.name()).unwrap(),
+                    false => entry.name().to_string(),
+                };
+                let (hash, minor_hash) = ext4_dir_hash(
+                    name.as_bytes(),
+                    Ext4SuperBlock::DEF_HASH_VERSION,
+                    Ext4SuperBlock::HASH_SEED,
+                    self.signed_directory_hash,
+                );
+                ((hash, minor_hash), entry)
+            })
+            .collect::<Vec<_>>();
+        hashed.sort_by_key(|(hash, _)| *hash);
+
+        // each leaf with the hash it is indexed by
+        let mut leaves: Vec<(u32, LinearDirectoryBlock)> = vec![];
+        for (i, ((hash, _), entry)) in hashed.iter().enumerate() {
+            if leaves.last().is_none_or(|(_, leaf)| !leaf.fits(entry)) {
+                let continued = i > 0 && hashed[i - 1].0.0 == *hash;
+                leaves.push((
+                    hash | continued as u32,
+                    LinearDirectoryBlock::new(checksums, block_size),
+                ));
+            }
+            leaves.last_mut().unwrap().1.add_entry((*entry).clone());
+        }
+
+        let root_limit = DxIndexBlock::limit_of(true, checksums, block_size);
+        let node_limit = DxIndexBlock::limit_of(false, checksums, block_size);
+        let num_nodes = if leaves.len() > root_limit {
+            leaves.len().div_ceil(node_limit)
+        } else {
+            0
+        };
+        if num_nodes > root_limit {
+            // a third level needs the largedir feature
+            return Err(Ext4Error::Invalid(format!(
+                "a directory with {} entries is too large for a two level HTree",
+                hashed.len()
+            )));
+        }
+        let first_leaf = 1 + num_nodes as u32;
+        let mut root = DxIndexBlock::root(
+            inode_num as u32,
+            entries[1].inode(),
+            Ext4SuperBlock::DEF_HASH_VERSION,
+            (num_nodes > 0) as u8,
+            checksums,
+            block_size,
+        );
+        let mut nodes = vec![];
+        if num_nodes == 0 {
+            for (i, (hash, _)) in leaves.iter().enumerate() {
+                root.add_entry(*hash, first_leaf + i as u32);
+            }
+        } else {
+            for (i, chunk) in leaves.chunks(node_limit).enumerate() {
+                let mut node = DxIndexBlock::node(checksums, block_size);
+                for (j, (hash, _)) in chunk.iter().enumerate() {
+                    node.add_entry(*hash, first_leaf + (i * node_limit + j) as u32);
+                }
+                root.add_entry(chunk[0].0, 1 + i as u32);
+                nodes.push(node);
+            }
+        }
+
+        let mut dir_buffer = root.as_bytes(&self.uuid, inode_num as u32, 0);
+        for node in &nodes {
+            dir_buffer.extend_from_slice(&node.as_bytes(&self.uuid, inode_num as u32, 0));
+        }
+        for (_, mut leaf) in leaves {
+            leaf.update_checksum(&self.uuid, inode_num as u32, 0);
+            dir_buffer.extend_from_slice(&leaf.as_bytes());
+        }
+        let mut inode =
+            self.create_inode_with_contents(inode_num as u32, &dir_buffer, FileType::Directory)?;
+        inode.add_flags(0x1000); // EXT4_INDEX_FL
+        Ok(inode)
+    }
+
+    fn create_inode_with_contents(
+        &mut self,
+        inode_num: u32,
+        contents: &[u8],
+        ty: FileType,
+    ) -> Result<Ext4Inode, Ext4Error> {
+        // ext2 has neither inline data nor extents
+        let empty_files = if self.ext2 {
+            EmptyFileRepresentation::Bare
+        } else {
+            self.empty_files
+        };
+        if contents.is_empty() && empty_files != EmptyFileRepresentation::Inline {
+            return Ok(match empty_files {
+                EmptyFileRepresentation::EmptyExtents => {
+                    Ext4Inode::new(0, Ext4InlineExtents::new(&[]), ty)
+                }
+                _ => {
+                    let mut inode = Ext4Inode::default();
+                    inode.set_file_type(ty);
+                    inode.set_links_count(1);
+                    inode
+                }
+            });
+        }
+        if self.in_inode_xattrs() && contents.len() <= self.max_inline_size() {
+            let block_data = &contents[..Ext4Inode::MAX_INLINE_SIZE_BLOCK.min(contents.len())];
+            let xattr_data = if contents.len() > Ext4Inode::MAX_INLINE_SIZE_BLOCK {
+                &contents[Ext4Inode::MAX_INLINE_SIZE_BLOCK..]
+            } else {
+                &[]
+            };
+            Ok(Ext4Inode::with_inline_data(
+                block_data,
+                xattr_data,
+                ty,
+                self.inode_size,
+            ))
+        } else {
+            let key: Option<ContentsDigest> =
+                self.deduplication.then(|| Sha256::digest(contents).into());
+            let shared = key.and_then(|key| self.deduplicated.get(&key)).cloned();
+            let allocations = match &shared {
+                Some(allocations) => allocations.clone(),
+                None => {
+                    let allocations = self.write_blocks_alloc(contents)?;
+                    if let Some(key) = key {
+                        for allocation in &allocations {
+                            self.deduplicated_allocations.insert(allocation.start, key);
+                        }
+                        self.deduplicated.insert(key, allocations.clone());
+                    }
+                    allocations
+                }
+            };
+            let inode =
+                self.create_inode_with_extents(inode_num, contents.len() as u64, &allocations, ty)?;
+            if shared.is_some() {
+                self.shared_blocks = true;
+                for allocation in &allocations {
+                    *self.shared_allocations.entry(allocation.start).or_insert(1) += 1;
+                }
+            }
+            Ok(inode)
+        }
+    }
+
+    /// Write an empty journal to zeroed blocks and point inode 8 at it.
+    fn create_journal(&mut self) -> Result<(), Ext4Error> {
+        let zeros = vec![0u8; (self.block_size * 256) as usize];
+        let mut allocations: Vec<Allocation> = vec![];
+        let mut remaining = self.journal_blocks;
+        while remaining > 0 {
+            let allocation = self.allocate_blocks_up_to(remaining)?;
+            for start in (allocation.start..allocation.end).step_by(256) {
+                let chunk = Allocation::from_start_len(start, (allocation.end - start).min(256));
+                self.write_blocks(chunk, &zeros[..(chunk.len() * self.block_size) as usize])?;
+            }
+            remaining -= allocation.len();
+            allocations.push(allocation);
+        }
+        let journal_superblock = JournalSuperBlock {
+            block_size: self.block_size as u32,
+            max_len: self.journal_blocks as u32,
+            uuid: self.uuid,
+            ext4_features: !self.ext2,
+        };
+        self.write_blocks(
+            Allocation::from_start_len(allocations[0].start, 1),
+            &journal_superblock.as_bytes(),
+        )?;
+        let mut inode = self.create_inode_with_extents(
+            8,
+            self.journal_blocks * self.block_size,
+            &allocations,
+            FileType::RegularFile,
+        )?;
+        inode.set_mode(0o600);
+        inode.set_timestamps(self.default_timestamps);
+        self.inodes[7] = inode;
+        Ok(())
+    }
+
+    /// Write the quota files with the usage of the root and all inodes from `s_first_ino` on (like e2fsck counts it)
+    /// to inodes 3 and 4 and, if inodes have room for project ids, to a newly allocated inode, which is returned.
+    fn create_quota_files(&mut self) -> Result<Option<u64>, Ext4Error> {
+        let project_inode = if self.inode_size > 128 {
+            Some(self.alloc_inode()?)
+        } else {
+            None
+        };
+        let mut usage: [BTreeMap<u32, QuotaUsage>; 3] = Default::default();
+        for (index, inode) in self.inodes.iter().enumerate() {
+            let inode_num = index as u64 + 1;
+            if !self.used_inodes.is_used(index as u64)
+                || (inode_num != 2 && inode_num < self.lost_found_inode)
+                || Some(inode_num) == project_inode
+            {
+                continue;
+            }
+            for (quota_type, id) in [inode.uid(), inode.gid(), inode.projid()]
+                .into_iter()
+                .enumerate()
+            {
+                let entry = usage[quota_type].entry(id).or_default();
+                entry.inodes += 1;
+                entry.space += inode.sectors(self.block_size) * 512;
+            }
+        }
+        for (quota_type, (usage, inode_num)) in usage
+            .into_iter()
+            .zip([Some(3), Some(4), project_inode])
+            .enumerate()
+        {
+            let Some(inode_num) = inode_num else {
+                continue;
+            };
+            let contents = QuotaFile { quota_type, usage }.as_bytes();
+            let mut inode = self.create_inode_with_contents(
+                inode_num as u32,
+                &contents,
+                FileType::RegularFile,
+            )?;
+            inode.set_mode(0o600);
+            inode.add_flags(Ext4Inode::IMMUTABLE_FL);
+            inode.set_timestamps(self.default_timestamps);
+            self.inodes[inode_num as usize - 1] = inode;
+        }
+        Ok(project_inode)
+    }
+
+    fn create_sparse_inode(
+        &mut self,
+        inode_num: u32,
+        contents: &[u8],
+    ) -> Result<Ext4Inode, Ext4Error> {
+        let block_size = self.block_size as usize;
+        if self.in_inode_xattrs() && contents.len() <= self.max_inline_size() {
+            return self.create_inode_with_contents(inode_num, contents, FileType::RegularFile);
+        }
+        let mut mapped: Vec<(u64, Allocation)> = vec![];
+        let is_zero = |block: &[u8]| block.iter().all(|&b| b == 0);
+        let mut blocks = contents.chunks(block_size).enumerate().peekable();
+        loop {
+            while blocks.next_if(|(_, block)| is_zero(block)).is_some() {}
+            let Some((first, _)) = blocks.next() else {
+                break;
+            };
+            // the run of blocks that are not all zeros starting at `first`
+            let mut end = first + 1;
+            while blocks.next_if(|(_, block)| !is_zero(block)).is_some() {
+                end += 1;
+            }
+            let mut logical_block = first as u64;
+            let run = &contents[first * block_size..(end * block_size).min(contents.len())];
+            for allocation in self.write_blocks_alloc(run)? {
+                mapped.push((logical_block, allocation));
+                logical_block += allocation.len();
+            }
+        }
+        self.create_inode_with_mapped_extents(
+            inode_num,
+            contents.len() as u64,
+            &mapped,
+            FileType::RegularFile,
+        )
+    }
+
+    fn create_inode_from_reader(
+        &mut self,
+        inode_num: u32,
+        reader: &mut impl io::Read,
+    ) -> Result<Ext4Inode, Ext4Error> {
+        let block_size = self.block_size as usize;
+        let mut block = vec![0u8; block_size];
+        let peeked = read_up_to(reader, &mut block[..self.max_inline_size() + 1])?;
+        if peeked <= self.max_inline_size() {
+            return self.create_inode_with_contents(
+                inode_num,
+                &block[..peeked],
+                FileType::RegularFile,
+            );
+        }
+        let mut len = peeked + read_up_to(reader, &mut block[peeked..])?;
+        let mut size = 0;
+        let mut allocations: Vec<Allocation> = vec![];
+        while len > 0 {
+            let allocation = self.allocate_blocks_up_to(1)?;
+            self.write_blocks(allocation, &block[..len])?;
+            size += len as u64;
+            match allocations.last_mut() {
+                Some(last) if last.end == allocation.start => last.end = allocation.end,
+                _ => allocations.push(allocation),
+            }
+            if len < block_size {
+                break;
+            }
+            len = read_up_to(reader, &mut block)?;
+        }
+        self.create_inode_with_extents(inode_num, size, &allocations, FileType::RegularFile)
+    }
+
+    fn create_inode_with_extents(
+        &mut self,
+        inode_num: u32,
+        size: u64,
+        allocations: &[Allocation],
+        ty: FileType,
+    ) -> Result<Ext4Inode, Ext4Error> {
+        let mut logical_block = 0;
+        let mapped: Vec<_> = allocations
+            .iter()
+            .map(|allocation| {
+                logical_block += allocation.len();
+                (logical_block - allocation.len(), *allocation)
+            })
+            .collect();
+        self.create_inode_with_mapped_extents(inode_num, size, &mapped, ty)
+    }
+
+    /// Like [`Ext4ImageWriter::create_inode_with_extents`], but each allocation starts at the given logical block.
+    /// The logical blocks that no allocation covers are holes, which read as zeros.
+    fn create_inode_with_mapped_extents(
+        &mut self,
+        inode_num: u32,
+        size: u64,
+        mapped: &[(u64, Allocation)],
+        ty: FileType,
+    ) -> Result<Ext4Inode, Ext4Error> {
+        if self.ext2 {
+            return self.create_inode_with_block_map(inode_num, size, mapped, ty);
+        }
+        let allocations: Vec<_> = mapped.iter().map(|(_, allocation)| *allocation).collect();
+        let data_blocks = allocations.iter().map(|allocation| allocation.len()).sum();
+        let leaves = Ext4ExtentLeafNode::from_mapped(mapped);
+        if leaves.len() <= Ext4InlineExtents::MAX_EXTENTS {
+            // we can fit the extents inline into the inode
+            let mut inode = Ext4Inode::new(size, Ext4InlineExtents::new(&leaves), ty);
+            inode.add_blocks(data_blocks, self.block_size);
+            self.file_blocks.insert(inode_num as u64, allocations);
+            Ok(inode)
+        } else {
+            // the leaves go into separate blocks, which are indexed by as many levels of index blocks
+            // as it takes until the root in the inode can point at all blocks of the top level
+            let max_entries = Ext4IndirectExtents::max_leaves(self.block_size);
+            let mut tree_blocks = vec![];
+            let mut level = vec![];
+            for chunk in leaves.chunks(max_entries) {
+                let block = Ext4IndirectExtents::create_block(
+                    chunk,
+                    inode_num,
+                    &self.uuid,
+                    self.block_size,
+                );
+                let allocation = self.allocate_blocks(1)?;
+                self.write_blocks(allocation, &block)?;
+                tree_blocks.push(allocation);
+                level.push((chunk[0].logical_block(), allocation.start));
+            }
+            let mut depth = 1;
+            while level.len() > Ext4InlineExtents::MAX_EXTENTS {
+                if depth == Ext4IndirectExtents::MAX_DEPTH {
+                    return Err(Ext4Error::ContentTooLarge(size));
+                }
+                let mut next_level = vec![];
+                for chunk in level.chunks(max_entries) {
+                    let block = Ext4IndirectExtents::create_index_block(
+                        chunk,
+                        depth,
+                        inode_num,
+                        &self.uuid,
+                        self.block_size,
+                    );
+                    let allocation = self.allocate_blocks(1)?;
+                    self.write_blocks(allocation, &block)?;
+                    tree_blocks.push(allocation);
+                    next_level.push((chunk[0].0, allocation.start));
+                }
+                level = next_level;
+                depth += 1;
+            }
+            let mut inode = Ext4Inode::new(size, Ext4IndirectExtents::new(&level, depth), ty);
+            inode.add_blocks(data_blocks + tree_blocks.len() as u64, self.block_size);
+            let mut blocks = allocations;
+            blocks.extend(tree_blocks);
+            self.file_blocks.insert(inode_num as u64, blocks);
+            Ok(inode)
+        }
+    }
+
+    /// Map the allocations with 12 direct pointers followed by a single, a double and a triple indirect tree.
+    /// Holes are null pointers, indirect blocks that would only hold null pointers are left out as well.
+    fn create_inode_with_block_map(
+        &mut self,
+        inode_num: u32,
+        size: u64,
+        mapped: &[(u64, Allocation)],
+        ty: FileType,
+    ) -> Result<Ext4Inode, Ext4Error> {
+        let mut blocks = vec![];
+        for &(logical_block, allocation) in mapped {
+            blocks.resize(logical_block as usize, 0);
+            for block in allocation.start..allocation.end {
+                blocks.push(u32::try_from(block).map_err(|_| Ext4Error::ContentTooLarge(size))?);
+            }
+        }
+        let allocations: Vec<_> = mapped.iter().map(|(_, allocation)| *allocation).collect();
+        let data_blocks: u64 = allocations.iter().map(|allocation| allocation.len()).sum();
+        let (direct, mut remaining) = blocks.split_at(blocks.len().min(12));
+        let mut indirect = [0u32; 3];
+        let mut tree_blocks = vec![];
+        for (depth, root) in indirect.iter_mut().enumerate() {
+            if remaining.is_empty() {
+                break;
+            }
+            let capacity = (self.block_size as usize / 4).pow(depth as u32 + 1);
+            let (mapped, rest) = remaining.split_at(remaining.len().min(capacity));
+            *root = self.write_indirect_tree(mapped, depth as u32, &mut tree_blocks)?;
+            remaining = rest;
+        }
+        if !remaining.is_empty() {
+            return Err(Ext4Error::ContentTooLarge(size));
+        }
+        let mut inode = Ext4Inode::default();
+        inode.set_file_type(ty);
+        inode.set_links_count(1);
+        LegacyBlockDescriptor::with_pointers(direct, indirect).write_buffer(inode.block_mut());
+        inode.set_size(size);
+        inode.add_blocks(data_blocks + tree_blocks.len() as u64, self.block_size);
+        tree_blocks.extend_from_slice(&allocations);
+        self.file_blocks.insert(inode_num as u64, tree_blocks);
+        Ok(inode)
+    }
+
+    /// Write the indirect block tree of the given `depth` (0 for a single indirect block) that maps `blocks`
+    /// and return its root block. All blocks of the tree are added to `tree_blocks`.
+    fn write_indirect_tree(
+        &mut self,
+        blocks: &[u32],
+        depth: u32,
+        tree_blocks: &mut Vec<Allocation>,
+    ) -> Result<u32, Ext4Error> {
+        if blocks.iter().all(|&block| block == 0) {
+            return Ok(0);
+        }
+        let root = self.allocate_blocks(1)?;
+        tree_blocks.push(root);
+        let pointers = if depth == 0 {
+            blocks.to_vec()
+        } else {
+            let child_capacity = (self.block_size as usize / 4).pow(depth);
+            blocks
+                .chunks(child_capacity)
+                .map(|chunk| self.write_indirect_tree(chunk, depth - 1, tree_blocks))
+                .collect::<Result<Vec<_>, Ext4Error>>()?
+        };
+        let bytes: Vec<u8> = pointers.iter().flat_map(|p| p.to_le_bytes()).collect();
+        self.write_blocks(root, &bytes)?;
+        u32::try_from(root.start)
+            .map_err(|_| Ext4Error::UnsupportedByExt2("block numbers of 2^32 or more"))
+    }
+
+    /// Fails once the inode number would no longer fit into the 32 bit fields of directory entries.
+    fn alloc_inode(&mut self) -> Result<u64, Ext4Error> {
+        if self.inodes.len() as u64 >= self.max_inodes {
+            return Err(Ext4Error::TooManyInodes(self.max_inodes));
+        }
+        Ok(self.push_inode())
+    }
+
+    fn push_inode(&mut self) -> u64 {
+        let n = self.inodes.len() as u64;
+        self.inodes.push(Ext4Inode::default());
+        self.used_inodes.mark_used(n);
+        n + 1
+    }
+
+    fn allocate_blocks(&mut self, n: u64) -> Result<Allocation, Ext4Error> {
+        self.reserve_superblock_backups();
+        let allocation = self.allocator.allocate(n);
+        if allocation.len() < n {
+            return Err(Ext4Error::OutOfSpace {
+                needed: n,
+                available: allocation.len(),
+            });
+        }
+        if allocation.len() > n {
+            return Err(Ext4Error::BlockUnavailable(format!(
+                "the allocator returned {} blocks instead of {}",
+                allocation.len(),
+                n
+            )));
+        }
+        self.claim_blocks(allocation)
+    }
+
+    fn allocate_blocks_up_to(&mut self, n: u64) -> Result<Allocation, Ext4Error> {
+        self.reserve_superblock_backups();
+        let allocation = self.allocator.allocate_up_to(n);
+        if allocation.is_empty() {
+            return Err(Ext4Error::OutOfSpace {
+                needed: n,
+                available: 0,
+            });
+        }
+        if allocation.len() > n {
+            return Err(Ext4Error::BlockUnavailable(format!(
+                "the allocator returned {} blocks instead of between 1 and {}",
+                allocation.len(),
+                n
+            )));
+        }
+        self.claim_blocks(allocation)
+    }
+
+    /// Fails without claiming anything if the allocator handed out a block that is already in use.
+    fn claim_blocks(&mut self, allocation: Allocation) -> Result<Allocation, Ext4Error> {
+        if let Some(block) =
+            (allocation.start..allocation.end).find(|&block| self.used_blocks.is_used(block))
+        {
+            return Err(Ext4Error::BlockUnavailable(format!(
+                "the allocator returned block {} which is already in use",
+                block
+            )));
+        }
+        for block in allocation.start..allocation.end {
+            self.used_blocks.mark_used(block);
+        }
+        Ok(allocation)
+    }
+
+    fn mark_block_used(&mut self, block_num: u64) {
+        self.allocator.mark_used(block_num);
+        self.used_blocks.mark_used(block_num);
+    }
+
+    /// `data` is passed to the writer as is, only the padding of a partial last block comes from a separate buffer.
+    fn write_blocks(&mut self, allocation: Allocation, data: &[u8]) -> io::Result<()> {
+        assert!(allocation.len() * self.block_size >= data.len() as u64);
+        self.writer
+            .seek(io::SeekFrom::Start(allocation.start * self.block_size))?;
+        self.writer.write_all(data)?;
+        // fill up the last block so that the image always ends on a block boundary
+        let block_size = self.block_size as usize;
+        let padding = (block_size - data.len() % block_size) % block_size;
+        self.writer
+            .write_all(&[0u8; BLOCK_SIZE as usize][..padding])
+    }
+
+    /// Write `data` to newly allocated blocks, potentially split over multiple fragments.
+    fn write_blocks_alloc(&mut self, data: &[u8]) -> io::Result<Vec<Allocation>> {
+        let mut allocations = vec![];
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            let num_blocks = (remaining.len() as u64).div_ceil(self.block_size);
+            let allocation = self.allocate_blocks_up_to(num_blocks)?;
+            let (chunk, rest) = remaining.split_at(
+                remaining
+                    .len()
+                    .min((allocation.len() * self.block_size) as usize),
+            );
+            self.write_blocks(allocation, chunk)?;
+            allocations.push(allocation);
+            remaining = rest;
+        }
+        Ok(allocations)
+    }
+}
+
+/// Fill `buf` from `reader` and return how many bytes were read, which is only less than `buf.len()` at the end.
+fn read_up_to(reader: &mut impl io::Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek};
+
+    /// Create an image in `target/` with `$test_code`, check it with [`run_e2fsck`] and, if given,
+    /// run `$check_code` with a copy of the finished image in `$image`. The type of `$image` has to be
+    /// spelled out, as the check would be mistaken for the test code after a block constructor otherwise.
+    macro_rules! test_create_fs {
+        (
+            $test_name:ident,
+            |$writer:ident| $test_code:tt
+            $(, |$image:ident: $image_type:ty| $check_code:tt)?
+        ) => {
+            test_create_fs!(
+                $test_name,
+                |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024 * 128),
+                |$writer| $test_code
+                $(, |$image: $image_type| $check_code)?
+            );
+        };
+        (
+            $test_name:ident,
+            |$file:ident| $constructor:expr,
+            |$writer:ident| $test_code:tt
+            $(, |$image:ident: $image_type:ty| $check_code:tt)?
+        ) => {
+            #[test]
+            #[allow(unused_braces)]
+            fn $test_name() {
+                let file_name = format!("target/{}.img", stringify!($test_name));
+                let _ = std::fs::remove_file(&file_name);
+                let $file = std::fs::File::create(&file_name).unwrap();
+                #[allow(unused_mut)]
+                let mut $writer = $constructor;
+                $test_code
+                let (file, info) = $writer.finish_with_info().unwrap();
+                assert!(info.blocks_count > 0 && info.blocks_count >= info.free_blocks_count);
+                assert_eq!(file.metadata().unwrap().len(), info.size);
+                run_e2fsck(&file_name);
+                $(
+                    let $image: $image_type = Cursor::new(std::fs::read(&file_name).unwrap());
+                    $check_code
+                )?
+            }
+        };
+    }
+
+    /// Called when `e2fsck` can't be run. Skipping it has to be requested with `SKIP_E2FSCK=1`,
+    /// so that a missing binary doesn't silently leave the images to `fsck_lite` and `verify`.
+    fn e2fsck_unavailable(error: io::Error) {
+        assert!(
+            error.kind() == io::ErrorKind::NotFound && std::env::var_os("SKIP_E2FSCK").is_some(),
+            "cannot run e2fsck ({}), set SKIP_E2FSCK=1 to skip it",
+            error
+        );
+    }
+
+    /// Check the image with [`fsck_lite`], [`verify`] and `e2fsck`.
+    fn run_e2fsck(file_name: &str) {
+        let file = std::fs::File::open(file_name).unwrap();
+        let image = unsafe { memmap2::Mmap::map(&file) }.unwrap();
+        if let Err(errors) = fsck_lite(&image) {
+            panic!("fsck_lite failed: {:#?}", errors);
+        }
+        let report = verify(&file).unwrap();
+        assert!(report.is_ok(), "verify failed: {:#?}", report.mismatches);
+        let (mut reader, writer) = std::io::pipe().unwrap();
+        let status = match std::process::Command::new("e2fsck")
+            .args(["-fn", file_name])
+            .stdout(writer.try_clone().unwrap())
+            .stderr(writer)
+            .status()
+        {
+            Ok(status) => status,
+            Err(error) => return e2fsck_unavailable(error),
+        };
+        if !status.success() {
+            let mut output = String::new();
+            reader.read_to_string(&mut output).unwrap();
+            panic!("e2fsck failed: {}", output);
+        }
+    }
+
+    test_create_fs!(test_ext4_image_writer_minimal, |writer| {});
 
     test_create_fs!(test_ext4_image_writer_many_files, |writer| {
         for i in 0..5000 {
             writer
-                .write_file(
-                    format!("hello, world {i}").as_bytes(),
-                    &format!("file-{i}.txt"),
-                    0o755,
-                )
+                .write_file(
+                    format!("hello, world {i}").as_bytes(),
+                    &format!("file-{i}.txt"),
+                    0o755,
+                )
+                .unwrap();
+        }
+    });
+
+    test_create_fs!(test_ext4_image_writer_zero_size_file, |writer| {
+        let zero_size_file = vec![];
+        writer
+            .write_file(&zero_size_file, "zero_size_file.bin", 0o644)
+            .unwrap();
+    });
+
+    test_create_fs!(
+        test_ext4_image_writer_zero_size_file_empty_extents,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_empty_file_representation(EmptyFileRepresentation::EmptyExtents),
+        |writer| {
+            writer.write_file(&[], "zero_size_file.bin", 0o644).unwrap();
+        }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_zero_size_file_bare,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_empty_file_representation(EmptyFileRepresentation::Bare),
+        |writer| {
+            writer.write_file(&[], "zero_size_file.bin", 0o644).unwrap();
+        }
+    );
+
+    #[test]
+    fn test_empty_file_representation() {
+        for (representation, flags) in [
+            (EmptyFileRepresentation::Inline, 0x10000000), // EXT4_INLINE_DATA_FL
+            (EmptyFileRepresentation::EmptyExtents, 0x80000), // EXT4_EXTENTS_FL
+            (EmptyFileRepresentation::Bare, 0),
+        ] {
+            let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+                .with_empty_file_representation(representation);
+            writer.write_file(&[], "empty", 0o644).unwrap();
+            writer.write_file(b"x", "small", 0o644).unwrap();
+            let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+            let inode_num = reader.lookup("empty").unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            assert_eq!(inode.flags(), flags, "{:?}", representation);
+            assert_eq!((inode.size(), inode.blocks()), (0, 0));
+            if representation == EmptyFileRepresentation::Bare {
+                assert_eq!(inode.block(), [0; 60]);
+            }
+            assert_eq!(reader.read_file("empty").unwrap(), b"");
+            // non-empty files are not affected
+            let inode_num = reader.lookup("small").unwrap();
+            assert!(reader.read_inode(inode_num).unwrap().has_inline_data());
+        }
+    }
+
+    test_create_fs!(test_ext4_image_writer_big_file, |writer| {
+        let big_file = vec![0xABu8; 1024 * 1024 * 1024];
+        writer.write_file(&big_file, "big-file.bin", 0o644).unwrap();
+    });
+
+    test_create_fs!(test_ext4_image_writer_big_file_from_reader, |writer| {
+        // spans several block groups without holding the contents in memory
+        let big_file = io::repeat(0xAB).take(300 * 1024 * 1024);
+        writer
+            .write_file_from_reader(big_file, "big-file.bin", 0o644)
+            .unwrap();
+    });
+
+    test_create_fs!(test_ext4_image_writer_file_at_block, |writer| {
+        writer
+            .write_file(&[1u8; 10000], "before.bin", 0o644)
+            .unwrap();
+        writer
+            .write_file_at_block(&[2u8; 10000], "kernel.bin", 0o644, 1000)
+            .unwrap();
+        writer
+            .write_file(&[3u8; 10000], "after.bin", 0o644)
+            .unwrap();
+    });
+
+    /// Places every allocation at the next multiple of 16 blocks.
+    #[derive(Default)]
+    struct AligningAllocator {
+        next_free: u64,
+    }
+    impl BlockAllocator for AligningAllocator {
+        fn allocate(&mut self, n: u64) -> Allocation {
+            let allocation = Allocation::from_start_len(self.next_free.next_multiple_of(16), n);
+            self.next_free = allocation.end;
+            allocation
+        }
+        fn mark_used(&mut self, block_num: u64) {
+            self.next_free = self.next_free.max(block_num + 1);
+        }
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_custom_allocator,
+        |file| Ext4ImageWriter::with_allocator(
+            file,
+            1024 * 1024 * 1024,
+            AligningAllocator::default()
+        ),
+        |writer| {
+            writer.mkdir("files").unwrap();
+            for i in 0..10 {
+                writer
+                    .write_file(&vec![i as u8; 5000 * i], &format!("files/{i}"), 0o644)
+                    .unwrap();
+            }
+        }
+    );
+
+    #[test]
+    fn test_custom_allocator_places_data() {
+        let mut writer = Ext4ImageWriter::with_allocator(
+            Cursor::new(Vec::new()),
+            1024 * 1024 * 1024,
+            AligningAllocator::default(),
+        );
+        writer.write_file(&[1u8; 5000], "a.bin", 0o644).unwrap();
+        writer.write_file(&[2u8; 5000], "b.bin", 0o644).unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        for (path, byte) in [("a.bin", 1u8), ("b.bin", 2u8)] {
+            let inode_num = reader.lookup(path).unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            assert_eq!(reader.read_extents(&inode).unwrap()[0].start() % 16, 0);
+            assert_eq!(reader.read_file(path).unwrap(), vec![byte; 5000]);
+        }
+    }
+
+    /// Hands out the blocks at the start of the image, which hold the superblock, or nothing at all.
+    struct BrokenAllocator {
+        empty: bool,
+    }
+    impl BlockAllocator for BrokenAllocator {
+        fn allocate(&mut self, n: u64) -> Allocation {
+            Allocation::from_start_len(0, if self.empty { 0 } else { n })
+        }
+        fn mark_used(&mut self, _block_num: u64) {}
+    }
+
+    #[test]
+    fn test_broken_allocator_fails() {
+        for empty in [false, true] {
+            let mut writer = Ext4ImageWriter::with_allocator(
+                Cursor::new(Vec::new()),
+                1024 * 1024 * 1024,
+                BrokenAllocator { empty },
+            );
+            // handing out nothing means the allocator is full, handing out the superblock is a bug
+            let failed_as_expected = |result: Result<(), Ext4Error>| match result {
+                Err(Ext4Error::OutOfSpace { available: 0, .. }) => empty,
+                Err(Ext4Error::BlockUnavailable(_)) => !empty,
+                _ => false,
+            };
+            assert!(failed_as_expected(writer.write_file(
+                &[1u8; 5000],
+                "a.bin",
+                0o644
+            )));
+            assert!(failed_as_expected(writer.write_file_from_reader(
+                &[1u8; 5000][..],
+                "b.bin",
+                0o644
+            )));
+        }
+    }
+
+    #[test]
+    fn test_write_file_at_block_extent_start() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer
+            .write_file_at_block(&[0x42u8; 3 * 4096], "kernel.bin", 0o644, 1000)
+            .unwrap();
+        assert!(matches!(
+            writer.write_file_at_block(&[0x42u8; 4096], "far.bin", 0o644, u64::MAX),
+            Err(Ext4Error::BlockUnavailable(_))
+        ));
+        let image = writer.finish().unwrap();
+        let mut reader = Ext4ImageReader::new(image).unwrap();
+        let inode_num = reader.lookup("kernel.bin").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        let extents = reader.read_extents(&inode).unwrap();
+        assert_eq!(extents[0].start(), 1000);
+        assert_eq!(
+            reader.read_file("kernel.bin").unwrap(),
+            vec![0x42u8; 3 * 4096]
+        );
+    }
+
+    #[test]
+    fn test_write_file_at_block_before_earlier_data() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer
+            .write_file_at_block(&[0x42u8; 3 * 4096], "kernel.bin", 0o644, 1000)
+            .unwrap();
+        // free blocks in front of earlier data can still be requested, used ones can't
+        writer
+            .write_file_at_block(&[0x43u8; 4096], "early.bin", 0o644, 500)
+            .unwrap();
+        assert!(matches!(
+            writer.write_file_at_block(&[0x44u8; 4096], "overlap.bin", 0o644, 1002),
+            Err(Ext4Error::BlockUnavailable(_))
+        ));
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        let inode_num = reader.lookup("early.bin").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert_eq!(reader.read_extents(&inode).unwrap()[0].start(), 500);
+        assert_eq!(reader.read_file("early.bin").unwrap(), vec![0x43u8; 4096]);
+        assert!(reader.lookup("overlap.bin").is_err());
+    }
+
+    #[test]
+    fn test_file_contents_are_written_verbatim() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        let contents: Vec<u8> = (0..3 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        writer
+            .write_file(&contents[..3 * BLOCK_SIZE as usize], "aligned.bin", 0o644)
+            .unwrap();
+        writer.write_file(&contents, "partial.bin", 0o644).unwrap();
+        let image = writer.finish().unwrap().into_inner();
+        let mut reader = Ext4ImageReader::new(Cursor::new(&image)).unwrap();
+        for (path, len) in [
+            ("aligned.bin", 3 * BLOCK_SIZE),
+            ("partial.bin", 4 * BLOCK_SIZE),
+        ] {
+            let inode_num = reader.lookup(path).unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            let start = (reader.read_extents(&inode).unwrap()[0].start() * BLOCK_SIZE) as usize;
+            let written = &image[start..start + len as usize];
+            let (data, padding) = written.split_at(written.len().min(contents.len()));
+            assert_eq!(data, &contents[..data.len()]);
+            assert!(padding.iter().all(|&b| b == 0));
+        }
+    }
+
+    #[test]
+    fn test_finish_flushes_the_writer() {
+        let writer = Ext4ImageWriter::new(
+            io::BufWriter::with_capacity(1024 * 1024, Cursor::new(Vec::new())),
+            1024 * 1024 * 1024,
+        );
+        let writer = writer.finish().unwrap();
+        assert!(writer.buffer().is_empty());
+        let image = writer.into_inner().unwrap().into_inner();
+        assert!(Ext4ImageReader::new(Cursor::new(image)).is_ok());
+    }
+
+    #[test]
+    fn test_write_file_from_mmap() {
+        let host_file_name = "target/test_write_file_from_mmap.bin";
+        let contents: Vec<u8> = (0..64 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(host_file_name, &contents).unwrap();
+        let host_file = std::fs::File::open(host_file_name).unwrap();
+        let mmap = unsafe { memmap2::Mmap::map(&host_file) }.unwrap();
+
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.write_file(&mmap, "big.bin", 0o644).unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert_eq!(reader.read_file("big.bin").unwrap(), contents);
+    }
+
+    #[test]
+    fn test_write_file_from_reader() {
+        let sizes = [
+            0,
+            1,
+            Ext4Inode::MAX_INLINE_SIZE,
+            Ext4Inode::MAX_INLINE_SIZE + 1,
+            BLOCK_SIZE as usize,
+            3 * BLOCK_SIZE as usize + 5,
+            1024 * 1024,
+        ];
+        let build = |from_reader: bool| {
+            let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+            for size in sizes {
+                let contents: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+                let path = format!("{size}.bin");
+                if from_reader {
+                    // chained readers return short reads at the boundary
+                    let (first, second) = contents.split_at(size / 3);
+                    writer
+                        .write_file_from_reader(first.chain(second), &path, 0o644)
+                        .unwrap();
+                } else {
+                    writer.write_file(&contents, &path, 0o644).unwrap();
+                }
+            }
+            writer.finish().unwrap().into_inner()
+        };
+        let image = build(true);
+        assert!(image == build(false));
+
+        let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+        for size in sizes {
+            let contents = reader.read_file(&format!("{size}.bin")).unwrap();
+            assert_eq!(contents.len(), size);
+            assert!(
+                contents
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &b)| b == (i % 251) as u8)
+            );
+        }
+    }
+
+    test_create_fs!(test_ext4_image_writer_journal_data, |writer| {
+        writer
+            .write_file(&[0u8; 10000], "db.sqlite", 0o644)
+            .unwrap();
+        writer.write_file(b"small", "small.txt", 0o644).unwrap();
+        writer.set_journal_data("db.sqlite").unwrap();
+        writer.set_journal_data("small.txt").unwrap();
+        assert!(writer.set_journal_data("lost+found").is_err());
+    });
+
+    #[test]
+    fn test_journal_data_flag_keeps_extents() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer
+            .write_file(&[0u8; 10000], "db.sqlite", 0o644)
+            .unwrap();
+        writer.set_journal_data("db.sqlite").unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        let inode_num = reader.lookup("db.sqlite").unwrap();
+        let flags = reader.read_inode(inode_num).unwrap().flags();
+        assert_eq!(flags & 0x4000, 0x4000);
+        assert_eq!(flags & 0x80000, 0x80000);
+    }
+
+    #[test]
+    fn test_inode_flags() {
+        let file_name = "target/test_inode_flags.img";
+        let file = std::fs::File::create(file_name).unwrap();
+        let mut writer = Ext4ImageWriter::new(file, 1024 * 1024 * 1024);
+        writer.mkdir("etc").unwrap();
+        writer
+            .write_file(&[0u8; 10000], "etc/passwd", 0o644)
+            .unwrap();
+        writer.write_file(b"inline", "log", 0o644).unwrap();
+        writer
+            .set_inode_flags("etc/passwd", Ext4Inode::IMMUTABLE_FL)
+            .unwrap();
+        writer.set_inode_flags("log", Ext4Inode::APPEND_FL).unwrap();
+        writer
+            .set_inode_flags("etc", Ext4Inode::IMMUTABLE_FL)
+            .unwrap();
+        assert!(matches!(
+            writer.set_inode_flags("log", 0x80000),
+            Err(Ext4Error::Invalid(_))
+        ));
+        assert!(matches!(
+            writer.set_inode_flags("missing", Ext4Inode::IMMUTABLE_FL),
+            Err(Ext4Error::NotFound(_))
+        ));
+        writer.finish().unwrap();
+        run_e2fsck(file_name);
+
+        let mut reader = Ext4ImageReader::new(std::fs::File::open(file_name).unwrap()).unwrap();
+        let flags = |reader: &mut Ext4ImageReader<std::fs::File>, path| {
+            let inode_num = reader.lookup(path).unwrap();
+            reader.read_inode(inode_num).unwrap().flags()
+        };
+        assert_eq!(flags(&mut reader, "etc/passwd"), 0x80000 | 0x10);
+        assert_eq!(flags(&mut reader, "log"), 0x10000000 | 0x20);
+        assert_eq!(flags(&mut reader, "etc") & 0x10, 0x10);
+
+        // lsattr needs a mounted filesystem, debugfs shows the same flags
+        let output = std::process::Command::new("debugfs")
+            .args(["-R", "stat etc/passwd", file_name])
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("Flags: 0x80010"), "{}", stdout);
+    }
+
+    test_create_fs!(test_ext4_image_writer_symlinks, |writer| {
+        writer.mkdir("bin").unwrap();
+        writer
+            .write_file(b"#!/bin/sh", "bin/busybox", 0o755)
+            .unwrap();
+        writer.symlink("busybox", "bin/sh").unwrap();
+        writer.symlink(&"a".repeat(60), "inline").unwrap();
+        writer.symlink(&"b/".repeat(200), "slow").unwrap();
+        assert!(matches!(
+            writer.symlink("", "empty"),
+            Err(Ext4Error::Invalid(_))
+        ));
+    });
+
+    test_create_fs!(test_ext4_image_writer_device_nodes, |writer| {
+        writer.mkdir("dev").unwrap();
+        writer
+            .mknod("dev/null", FileType::CharacterDevice, 1, 3, 0o666)
+            .unwrap();
+        writer
+            .mknod("dev/sda", FileType::BlockDevice, 8, 0, 0o660)
+            .unwrap();
+        writer
+            .mknod("dev/large", FileType::CharacterDevice, 300, 70000, 0o600)
+            .unwrap();
+        writer
+            .mknod("dev/initctl", FileType::Fifo, 0, 0, 0o600)
+            .unwrap();
+        writer
+            .mknod("dev/log", FileType::Socket, 0, 0, 0o666)
+            .unwrap();
+        assert!(matches!(
+            writer.mknod("dev/dir", FileType::Directory, 0, 0, 0o755),
+            Err(Ext4Error::Invalid(_))
+        ));
+        assert!(matches!(
+            writer.mknod("dev/huge", FileType::BlockDevice, 4096, 0, 0o660),
+            Err(Ext4Error::Invalid(_))
+        ));
+    });
+
+    #[test]
+    fn test_device_numbers() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer
+            .mknod("null", FileType::CharacterDevice, 1, 3, 0o666)
+            .unwrap();
+        writer
+            .mknod("sda", FileType::BlockDevice, 8, 0, 0o660)
+            .unwrap();
+        writer
+            .mknod("large", FileType::CharacterDevice, 300, 70000, 0o600)
+            .unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        for (path, file_type, device, first_words) in [
+            ("null", FileType::CharacterDevice, (1, 3), [0x0103, 0]),
+            ("sda", FileType::BlockDevice, (8, 0), [0x0800, 0]),
+            // (minor & 0xff) | (major << 8) | ((minor & ~0xff) << 12)
+            (
+                "large",
+                FileType::CharacterDevice,
+                (300, 70000),
+                [0, 0x11112c70],
+            ),
+        ] {
+            let inode_num = reader.lookup(path).unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            assert_eq!(inode.file_type(), file_type);
+            assert_eq!(inode.device_number(), device);
+            assert_eq!(inode.storage_mode(BLOCK_SIZE), StorageMode::NoData);
+            assert_eq!((inode.size(), inode.blocks()), (0, 0));
+            let words: Vec<u32> = inode.block()[..8]
+                .chunks(4)
+                .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+                .collect();
+            assert_eq!(words, first_words, "{}", path);
+        }
+    }
+
+    test_create_fs!(test_ext4_image_writer_fifos_and_sockets, |writer| {
+        writer.mkdir("run").unwrap();
+        writer.mkfifo("run/initctl", 0o600).unwrap();
+        writer.mksocket("run/log", 0o666).unwrap();
+        writer.mkfifo("fifo", 0o644).unwrap();
+        assert!(matches!(
+            writer.mkfifo("fifo", 0o644),
+            Err(Ext4Error::PathExists(_))
+        ));
+    });
+
+    // failed creations must not leave orphaned inodes or blocks behind
+    test_create_fs!(test_ext4_image_writer_existing_paths, |writer| {
+        writer.write_file(b"hello", "file", 0o644).unwrap();
+        assert!(matches!(
+            writer.write_file(&[0x42; 10000], "file", 0o644),
+            Err(Ext4Error::PathExists(_))
+        ));
+        assert!(matches!(
+            writer.write_symlink(&"x/".repeat(100), "file", 0o777),
+            Err(Ext4Error::PathExists(_))
+        ));
+        assert!(matches!(
+            writer.write_file_at_block(&[0x42; 10000], "file", 0o644, 600),
+            Err(Ext4Error::PathExists(_))
+        ));
+        assert!(matches!(
+            writer.write_file(b"", "file/child", 0o644),
+            Err(Ext4Error::NotADirectory(_))
+        ));
+        assert!(matches!(
+            writer.mkfifo("missing/fifo", 0o644),
+            Err(Ext4Error::ParentMissing(_))
+        ));
+    });
+
+    #[test]
+    fn test_fifo_and_socket_entries() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.mkfifo("fifo", 0o620).unwrap();
+        writer.mksocket("socket", 0o755).unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        let root = reader.read_inode(2).unwrap();
+        let entries = reader.read_directory_entries(&root).unwrap();
+        for (name, file_type, mode) in [
+            ("fifo", FileType::Fifo, 0o620),
+            ("socket", FileType::Socket, 0o755),
+        ] {
+            let entry = entries.iter().find(|entry| entry.name() == name).unwrap();
+            assert_eq!(entry.entry_type(), file_type.as_directory_entry_type());
+            let inode = reader.read_inode(entry.inode()).unwrap();
+            assert_eq!(inode.file_type(), file_type);
+            assert_eq!(inode.mode(), mode);
+            assert_eq!(inode.links_count(), 1);
+            assert_eq!((inode.size(), inode.blocks()), (0, 0));
+        }
+    }
+
+    test_create_fs!(test_ext4_image_writer_hard_links, |writer| {
+        writer.mkdir("bin").unwrap();
+        writer
+            .write_file(&[0x42; 10000], "bin/busybox", 0o755)
+            .unwrap();
+        writer.link("bin/busybox", "bin/sh").unwrap();
+        writer.link("bin/busybox", "ls").unwrap();
+        writer.symlink("busybox", "bin/ash").unwrap();
+        writer.link("bin/ash", "ash").unwrap();
+        writer.rename("ls", "bin/ls").unwrap();
+        assert!(matches!(
+            writer.link("bin", "bin2"),
+            Err(Ext4Error::IsADirectory(_))
+        ));
+        assert!(matches!(
+            writer.link("missing", "file"),
+            Err(Ext4Error::NotFound(_))
+        ));
+        assert!(matches!(
+            writer.link("bin/sh", "bin/ls"),
+            Err(Ext4Error::PathExists(_))
+        ));
+    });
+
+    test_create_fs!(test_ext4_image_writer_file_owner, |writer| {
+        writer
+            .write_file_with_owner(b"hello", "user.txt", 0o600, 1000, 100)
+            .unwrap();
+        writer
+            .write_file_with_owner(&[0x42; 10000], "large_ids", 0o640, 100000, 70000)
+            .unwrap();
+    });
+
+    #[test]
+    fn test_file_owner_round_trip() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer
+            .write_file_with_owner(b"hello", "hello.txt", 0o644, 0x12345, 0xfffffffe)
+            .unwrap();
+        writer.write_file(b"root", "root.txt", 0o644).unwrap();
+        let manifest = writer.manifest();
+        let entry = manifest.iter().find(|e| e.path == "hello.txt").unwrap();
+        assert_eq!((entry.uid, entry.gid), (0x12345, 0xfffffffe));
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        let inode_num = reader.lookup("hello.txt").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert_eq!((inode.uid(), inode.gid()), (0x12345, 0xfffffffe));
+        let inode_num = reader.lookup("root.txt").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert_eq!((inode.uid(), inode.gid()), (0, 0));
+    }
+
+    #[test]
+    fn test_hard_link_counts() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer.mkdir("dir").unwrap();
+        writer.link("hello.txt", "dir/hello.txt").unwrap();
+        writer.link("dir/hello.txt", "hi.txt").unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        let inode_num = reader.lookup("hello.txt").unwrap();
+        assert_eq!(reader.lookup("dir/hello.txt").unwrap(), inode_num);
+        assert_eq!(reader.lookup("hi.txt").unwrap(), inode_num);
+        assert_eq!(reader.read_inode(inode_num).unwrap().links_count(), 3);
+        assert_eq!(reader.read_file("hi.txt").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_file_timestamps_until_finish() {
+        let timestamps = Timestamps::all(Timestamp::from_seconds(1_700_000_000));
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer.link("hello.txt", "hi.txt").unwrap();
+        writer.set_timestamps("hi.txt", timestamps).unwrap();
+        writer.write_file(b"old", "replaced", 0o644).unwrap();
+        writer.set_timestamps("replaced", timestamps).unwrap();
+        // replacing a file starts its metadata over
+        writer
+            .write_file_replace(b"new", "replaced", 0o644)
+            .unwrap();
+        let image = writer.finish().unwrap();
+        fsck_lite(image.get_ref()).unwrap();
+        let mut reader = Ext4ImageReader::new(image).unwrap();
+        let inode_num = reader.lookup("hello.txt").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert_eq!(inode.timestamps().mtime, timestamps.mtime);
+        let inode_num = reader.lookup("replaced").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert_ne!(inode.timestamps().mtime, timestamps.mtime);
+    }
+
+    fn build_replace_test_fs<W: io::Write + io::Seek>(writer: &mut Ext4ImageWriter<W>) {
+        let big: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        writer.write_file(&big, "big", 0o644).unwrap();
+        writer
+            .write_file_replace(b"small now", "big", 0o600)
+            .unwrap();
+        writer.write_file(b"inline", "grows", 0o644).unwrap();
+        writer.link("grows", "grows-link").unwrap();
+        writer.write_file_replace(&big, "grows", 0o644).unwrap();
+        writer.symlink("big", "link").unwrap();
+        writer
+            .write_file_replace(b"not a link", "link", 0o644)
+            .unwrap();
+        writer.write_file_replace(b"new", "new", 0o644).unwrap();
+        assert!(matches!(
+            writer.write_file_replace(b"", "lost+found", 0o644),
+            Err(Ext4Error::IsADirectory(_))
+        ));
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_replace,
+        |writer| { build_replace_test_fs(&mut writer) },
+        |image: Cursor<Vec<u8>>| {
+            let big: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+            let mut reader = Ext4ImageReader::new(image.clone()).unwrap();
+            assert_eq!(reader.read_file("big").unwrap(), b"small now");
+            assert_eq!(reader.read_file("grows").unwrap(), big);
+            // hard links share the new contents
+            assert_eq!(reader.read_file("grows-link").unwrap(), big);
+            let inode_num = reader.lookup("grows").unwrap();
+            assert_eq!(reader.read_inode(inode_num).unwrap().links_count(), 2);
+            let inode_num = reader.lookup("link").unwrap();
+            let link = reader.read_inode(inode_num).unwrap();
+            assert_eq!(link.file_type(), FileType::RegularFile);
+            assert_eq!(reader.read_file("new").unwrap(), b"new");
+
+            // files of an opened image can be replaced as well
+            let mut writer = Ext4ImageWriter::open(image).unwrap();
+            writer
+                .write_file_replace(b"replaced", "grows", 0o644)
+                .unwrap();
+            let image = writer.finish().unwrap();
+            fsck_lite(image.get_ref()).unwrap();
+            let mut reader = Ext4ImageReader::new(image).unwrap();
+            assert_eq!(reader.read_file("grows-link").unwrap(), b"replaced");
+        }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_replace_ext2,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .ext2_compat()
+            .unwrap(),
+        |writer| { build_replace_test_fs(&mut writer) }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_remove,
+        |writer| {
+            let big: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+            writer.write_file(&big, "big", 0o644).unwrap();
+            writer.write_file(b"kept", "kept", 0o644).unwrap();
+            writer.link("kept", "kept-link").unwrap();
+            writer.mkdir_p("dir/sub").unwrap();
+            writer.write_file(&big, "dir/sub/file", 0o644).unwrap();
+            writer.link("dir/sub/file", "dir/link").unwrap();
+            writer.symlink("../big", "dir/symlink").unwrap();
+            writer.mkdir("empty").unwrap();
+            assert!(matches!(
+                writer.remove("dir"),
+                Err(Ext4Error::DirectoryNotEmpty(_))
+            ));
+            assert!(matches!(
+                writer.remove("lost+found"),
+                Err(Ext4Error::ReservedPath(_))
+            ));
+            writer.remove("big").unwrap();
+            writer.remove("kept-link").unwrap();
+            writer.remove("empty").unwrap();
+            writer.remove_dir_all("dir").unwrap();
+            assert!(matches!(writer.remove("big"), Err(Ext4Error::NotFound(_))));
+            // the name can be used again
+            writer.write_file(b"new", "big", 0o644).unwrap();
+        },
+        |image: Cursor<Vec<u8>>| {
+            let mut reader = Ext4ImageReader::new(image).unwrap();
+            for path in ["dir", "dir/sub/file", "kept-link", "empty"] {
+                assert!(reader.lookup(path).is_err(), "{}", path);
+            }
+            assert_eq!(reader.read_file("big").unwrap(), b"new");
+            let inode_num = reader.lookup("kept").unwrap();
+            assert_eq!(reader.read_inode(inode_num).unwrap().links_count(), 1);
+            // the reserved inodes, lost+found, kept and the new big, the removed files don't use inodes anymore
+            let superblock = reader.superblock();
+            assert_eq!(
+                superblock.inodes_count() - superblock.free_inodes_count(),
+                13
+            );
+        }
+    );
+
+    fn build_dedup_test_fs<W: io::Write + io::Seek>(writer: &mut Ext4ImageWriter<W>) {
+        let big: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        writer.write_file(&big, "a", 0o644).unwrap();
+        writer.write_file(&big, "b", 0o644).unwrap();
+        writer.write_file(&big, "c", 0o644).unwrap();
+        writer.write_file(&big[1..], "different", 0o644).unwrap();
+        writer.write_file(b"inline", "inline-a", 0o644).unwrap();
+        writer.write_file(b"inline", "inline-b", 0o644).unwrap();
+        // the blocks stay in use by the other files
+        writer.remove("a").unwrap();
+        writer.write_file_replace(b"replaced", "b", 0o644).unwrap();
+        writer.write_file(&big, "d", 0o644).unwrap();
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_dedup,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024).with_deduplication(),
+        |writer| { build_dedup_test_fs(&mut writer) },
+        |image: Cursor<Vec<u8>>| {
+            let big: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+            let mut reader = Ext4ImageReader::new(image.clone()).unwrap();
+            let superblock = reader.superblock().clone();
+            assert!(superblock.has_shared_blocks());
+            assert_eq!(reader.read_file("c").unwrap(), big);
+            assert_eq!(reader.read_file("d").unwrap(), big);
+            assert_eq!(reader.read_file("b").unwrap(), b"replaced");
+            assert_eq!(reader.read_file("different").unwrap(), big[1..]);
+            // every file accounts for the shared blocks in i_blocks
+            for path in ["c", "d"] {
+                let inode_num = reader.lookup(path).unwrap();
+                assert_eq!(reader.read_inode(inode_num).unwrap().blocks(), 25 * 8);
+            }
+
+            // without deduplication, c and d have their own copy of the contents
+            let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024);
+            build_dedup_test_fs(&mut writer);
+            let (_, without_deduplication) = writer.finish_with_info().unwrap();
+            assert_eq!(
+                superblock.blocks_count() - superblock.free_blocks_count() + 25,
+                without_deduplication.blocks_count - without_deduplication.free_blocks_count
+            );
+
+            // opened images keep the blocks that are still shared
+            let mut writer = Ext4ImageWriter::open(image).unwrap();
+            writer.remove("c").unwrap();
+            let image = writer.finish().unwrap();
+            fsck_lite(image.get_ref()).unwrap();
+            let mut reader = Ext4ImageReader::new(image).unwrap();
+            assert_eq!(reader.read_file("d").unwrap(), big);
+        }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_dedup_ext2,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .ext2_compat()
+            .unwrap()
+            .with_deduplication(),
+        |writer| { build_dedup_test_fs(&mut writer) }
+    );
+
+    fn sparse_test_files() -> Vec<(&'static str, Vec<u8>)> {
+        let mut disk = vec![0u8; 8 * 1024 * 1024];
+        disk[..10].copy_from_slice(b"bootsector");
+        disk[1024 * 1024 + 5..][..4096].fill(0x42);
+        *disk.last_mut().unwrap() = 1;
+        let mut trailing_hole = vec![0u8; 1024 * 1024];
+        trailing_hole[..100_000].fill(0x17);
+        // more fragments than fit into the inode
+        let fragmented: Vec<u8> = (0..40 * 2 * 4096).map(|i| ((i / 4096) % 2) as u8).collect();
+        vec![
+            ("disk.img", disk),
+            ("zeros", vec![0u8; 1024 * 1024]),
+            ("trailing-hole", trailing_hole),
+            ("fragmented", fragmented),
+            ("small", b"small".to_vec()),
+        ]
+    }
+
+    fn build_sparse_test_fs<W: io::Write + io::Seek>(writer: &mut Ext4ImageWriter<W>) {
+        for (path, contents) in sparse_test_files() {
+            writer.write_sparse_file(&contents, path, 0o644).unwrap();
+        }
+    }
+
+    fn check_sparse_test_fs(image: Cursor<Vec<u8>>) {
+        let mut reader = Ext4ImageReader::new(image).unwrap();
+        for (path, contents) in sparse_test_files() {
+            assert_eq!(reader.read_file(path).unwrap(), contents, "{}", path);
+        }
+        let inode_num = reader.lookup("zeros").unwrap();
+        assert_eq!(reader.read_inode(inode_num).unwrap().blocks(), 0);
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_sparse_files,
+        |writer| { build_sparse_test_fs(&mut writer) },
+        |image: Cursor<Vec<u8>>| { check_sparse_test_fs(image) }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_sparse_files_1k,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_block_size(1024)
+            .unwrap(),
+        |writer| { build_sparse_test_fs(&mut writer) },
+        |image: Cursor<Vec<u8>>| { check_sparse_test_fs(image) }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_sparse_files_ext2,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .ext2_compat()
+            .unwrap(),
+        |writer| { build_sparse_test_fs(&mut writer) },
+        |image: Cursor<Vec<u8>>| { check_sparse_test_fs(image) }
+    );
+
+    #[test]
+    fn test_sparse_file() {
+        let mut contents = vec![0u8; 256 * 1024 * 1024];
+        contents[100 * 1024 * 1024..][..3].copy_from_slice(b"abc");
+        contents[200 * 1024 * 1024 - 1] = 1;
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer
+            .write_sparse_file(&contents, "sparse", 0o644)
+            .unwrap();
+        let (image, info) = writer.finish_with_info().unwrap();
+        let mut reader = Ext4ImageReader::new(image).unwrap();
+        let inode_num = reader.lookup("sparse").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert_eq!(inode.size(), contents.len() as u64);
+        // two data blocks of 8 sectors each
+        assert_eq!(inode.blocks(), 2 * 8);
+        assert!(info.blocks_count - info.free_blocks_count < 1000);
+        assert!(reader.read_file("sparse").unwrap() == contents);
+    }
+
+    /// Every other block is a hole, so each written block is an extent of its own.
+    fn fragmented_contents(extents: usize, block_size: usize) -> Vec<u8> {
+        (0..extents * 2 * block_size)
+            .map(|i| ((i / block_size) % 2) as u8)
+            .collect()
+    }
+
+    fn build_extent_tree_test_fs<W: io::Write + io::Seek>(writer: &mut Ext4ImageWriter<W>) {
+        let block_size = writer.block_size as usize;
+        let max_entries = Ext4IndirectExtents::max_leaves(block_size as u64);
+        // two leaf blocks below the root
+        let two_leaves = fragmented_contents(max_entries + 10, block_size);
+        writer
+            .write_sparse_file(&two_leaves, "two-leaves", 0o644)
+            .unwrap();
+        // more leaf blocks than the root can point at, so they need an index block
+        let indexed = fragmented_contents(4 * max_entries + 10, block_size);
+        writer
+            .write_sparse_file(&indexed, "indexed", 0o644)
+            .unwrap();
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_extent_tree,
+        |writer| { build_extent_tree_test_fs(&mut writer) },
+        |image: Cursor<Vec<u8>>| {
+            // the tree blocks are kept when the image is opened and finished again
+            let image = Ext4ImageWriter::open(image).unwrap().finish().unwrap();
+            fsck_lite(image.get_ref()).unwrap();
+            let mut reader = Ext4ImageReader::new(image).unwrap();
+            for (path, extents, depth, tree_blocks) in [
+                ("two-leaves", 340 + 10, 1, 2),
+                ("indexed", 4 * 340 + 10, 2, 5 + 1),
+            ] {
+                assert_eq!(
+                    reader.read_file(path).unwrap(),
+                    fragmented_contents(extents, 4096)
+                );
+                let inode_num = reader.lookup(path).unwrap();
+                let inode = reader.read_inode(inode_num).unwrap();
+                let root = Ext4ExtentHeader::read_buffer(inode.block());
+                assert_eq!(root.depth(), depth, "{}", path);
+                assert_eq!(reader.read_extents(&inode).unwrap().len(), extents);
+                assert_eq!(
+                    inode.blocks(),
+                    (extents as u64 + tree_blocks) * 8,
+                    "{}",
+                    path
+                );
+            }
+        }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_extent_tree_1k,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_block_size(1024)
+            .unwrap(),
+        |writer| { build_extent_tree_test_fs(&mut writer) }
+    );
+
+    #[test]
+    fn test_deduplication_same_length() {
+        let big: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        // contents with the same length only share blocks if they are identical
+        let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024).with_deduplication();
+        writer.write_file(&big, "a", 0o644).unwrap();
+        let other = vec![1u8; big.len()];
+        writer.write_file(&other, "b", 0o644).unwrap();
+        assert!(!writer.shared_blocks);
+        assert_eq!(writer.deduplicated.len(), 2);
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert_eq!(reader.read_file("a").unwrap(), big);
+        assert_eq!(reader.read_file("b").unwrap(), other);
+    }
+
+    #[test]
+    fn test_directory_link_counts() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.mkdir("empty").unwrap();
+        writer.mkdir("files").unwrap();
+        writer.write_file(b"a", "files/a", 0o644).unwrap();
+        writer.write_file(b"b", "files/b", 0o644).unwrap();
+        writer.mkdir_p("one/sub").unwrap();
+        let image = writer.finish().unwrap().into_inner();
+        fsck::fsck_lite(&image).unwrap();
+        let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+        let mut links_count = |path: &str| {
+            let inode_num = reader.lookup(path).unwrap();
+            reader.read_inode(inode_num).unwrap().links_count()
+        };
+        assert_eq!(links_count("empty"), 2);
+        assert_eq!(links_count("files"), 2);
+        assert_eq!(links_count("one"), 3);
+        assert_eq!(links_count("one/sub"), 2);
+        // lost+found, empty, files and one
+        assert_eq!(links_count(""), 6);
+    }
+
+    #[cfg(feature = "glob")]
+    test_create_fs!(test_ext4_image_writer_add_glob, |writer| {
+        writer.mkdir("usr").unwrap();
+        writer.add_glob("src/*.rs", "usr/src").unwrap();
+        writer.add_glob("Cargo.*", "/").unwrap();
+    });
+
+    #[cfg(feature = "glob")]
+    #[test]
+    fn test_add_glob_preserves_subpaths() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.add_glob("./src/**/l*.rs", "code").unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert_eq!(
+            reader.read_file("code/lib.rs").unwrap(),
+            std::fs::read("src/lib.rs").unwrap()
+        );
+        assert!(reader.lookup("code/reader.rs").is_err());
+    }
+
+    #[cfg(feature = "glob")]
+    #[test]
+    fn test_add_glob_checks_all_matches_first() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.mkdir("code").unwrap();
+        // sorted by name, lib.rs comes before reader.rs which is already taken
+        writer
+            .write_file(b"taken", "code/reader.rs", 0o644)
+            .unwrap();
+        assert!(matches!(
+            writer.add_glob("src/*.rs", "code"),
+            Err(Ext4Error::PathExists(_))
+        ));
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert!(reader.lookup("code/lib.rs").is_err());
+        assert_eq!(reader.read_file("code/reader.rs").unwrap(), b"taken");
+    }
+
+    #[test]
+    fn test_sorted_directories() {
+        let build = |names: &[&str]| {
+            let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+            writer.set_entry_ordering(EntryOrdering::ByName);
+            for name in names {
+                writer.mkdir(name).unwrap();
+                writer.mkdir(&format!("{name}/sub")).unwrap();
+            }
+            writer.finish().unwrap().into_inner()
+        };
+        let image = build(&["b", "c", "a"]);
+        assert_eq!(image, build(&["a", "b", "c"]));
+
+        let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+        let root = reader.read_inode(2).unwrap();
+        let names: Vec<_> = reader
+            .read_directory_entries(&root)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.name().to_string())
+            .collect();
+        assert_eq!(names, ["..", "a", "b", "c", "lost+found"]);
+    }
+
+    #[test]
+    fn test_entry_ordering() {
+        let build = |ordering, names: &[&str]| {
+            let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024);
+            writer.set_entry_ordering(ordering);
+            writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+            for name in names {
+                writer.mkdir(name).unwrap();
+            }
+            for name in names {
+                writer.mkdir(&format!("{name}/sub-{name}")).unwrap();
+                writer.mkdir(&format!("{name}/another")).unwrap();
+            }
+            Ext4ImageReader::new(Cursor::new(writer.finish_to_vec().unwrap())).unwrap()
+        };
+        let directory_blocks = |reader: &mut Ext4ImageReader<_>| {
+            ["", "dir-a", "dir-b", "dir-c"].map(|path| {
+                let inode_num = reader.lookup(path).unwrap();
+                let inode = reader.read_inode(inode_num).unwrap();
+                reader.read_file_contents(&inode).unwrap()
+            })
+        };
+
+        let mut reader = build(EntryOrdering::ByName, &["dir-b", "dir-c", "dir-a"]);
+        let blocks = directory_blocks(&mut reader);
+        assert_eq!(
+            blocks,
+            directory_blocks(&mut build(
+                EntryOrdering::ByName,
+                &["dir-a", "dir-c", "dir-b"]
+            ))
+        );
+        let root = reader.read_inode(2).unwrap();
+        let names: Vec<_> = reader
+            .read_directory_entries(&root)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            ["..", "dir-a", "dir-b", "dir-c", "hello.txt", "lost+found"]
+        );
+
+        let mut reader = build(EntryOrdering::Insertion, &["dir-b", "dir-c", "dir-a"]);
+        assert_ne!(directory_blocks(&mut reader), blocks);
+        let root = reader.read_inode(2).unwrap();
+        let names: Vec<_> = reader
+            .read_directory_entries(&root)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            ["..", "lost+found", "hello.txt", "dir-b", "dir-c", "dir-a"]
+        );
+    }
+
+    #[test]
+    fn test_storage_mode_of() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        let max_inline = Ext4Inode::MAX_INLINE_SIZE;
+        writer
+            .write_file(&vec![1; max_inline], "inline", 0o644)
+            .unwrap();
+        writer
+            .write_file(&vec![1; max_inline + 1], "extents", 0o644)
+            .unwrap();
+        writer.symlink("target", "symlink").unwrap();
+        // more than four fragments need an extent tree block
+        for i in 1..=5 {
+            writer
+                .write_file_at_block(b"x", &format!("pinned-{i}"), 0o644, i * 100)
+                .unwrap();
+        }
+        writer
+            .write_file(&vec![1; 4096 * 500], "fragmented", 0o644)
+            .unwrap();
+        assert_eq!(
+            writer.storage_mode_of("inline").unwrap(),
+            StorageMode::Inline
+        );
+        assert_eq!(
+            writer.storage_mode_of("extents").unwrap(),
+            StorageMode::InlineExtents
+        );
+        assert_eq!(
+            writer.storage_mode_of("symlink").unwrap(),
+            StorageMode::NoData
+        );
+        assert_eq!(
+            writer.storage_mode_of("fragmented").unwrap(),
+            StorageMode::IndirectExtents
+        );
+        assert!(writer.storage_mode_of("lost+found").is_err());
+        assert!(writer.storage_mode_of("missing").is_err());
+    }
+
+    #[test]
+    fn test_i_blocks_match_allocation() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.write_file(b"inline", "inline", 0o644).unwrap();
+        writer.write_file(&[1; 5000], "two-blocks", 0o644).unwrap();
+        writer.symlink("target", "fast-symlink").unwrap();
+        writer.symlink(&"x".repeat(300), "slow-symlink").unwrap();
+        for i in 1..=5 {
+            writer
+                .write_file_at_block(b"x", &format!("pinned-{i}"), 0o644, i * 100)
+                .unwrap();
+        }
+        writer
+            .write_file(&vec![1; 4096 * 500], "fragmented", 0o644)
+            .unwrap();
+        writer.write_file(&[1; 5000], "xattr-block", 0o644).unwrap();
+        writer
+            .set_xattr("xattr-block", "user.large", &[7; 2000])
+            .unwrap();
+        writer.mkdir("many").unwrap();
+        for i in 0..200 {
+            writer
+                .write_file(&[], &format!("many/{i:020}"), 0o644)
+                .unwrap();
+        }
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+
+        let sectors = BLOCK_SIZE / 512;
+        let mut check = |path: &str, expected_blocks: u64| {
+            let inode_num = reader.lookup(path).unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            let xattr_blocks = (inode.file_acl() != 0) as u64;
+            let owned: u64 = reader
+                .read_data_blocks(&inode)
+                .unwrap()
+                .iter()
+                .map(|allocation| allocation.len())
+                .sum();
+            assert_eq!(owned + xattr_blocks, expected_blocks, "{}", path);
+            assert_eq!(inode.blocks(), expected_blocks * sectors, "{}", path);
+        };
+        check("inline", 0);
+        check("two-blocks", 2);
+        check("fast-symlink", 0);
+        check("slow-symlink", 1);
+        check("pinned-1", 1);
+        // 500 data blocks and the extent tree block
+        check("fragmented", 501);
+        check("xattr-block", 3);
+        check("many", 2 + 1 /* HTree root */);
+    }
+
+    #[test]
+    fn test_finish_with_info() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer
+            .write_file(&[1u8; 100000], "data.bin", 0o644)
+            .unwrap();
+        let (image, info) = writer.finish_with_info().unwrap();
+        let reader = Ext4ImageReader::new(Cursor::new(image.into_inner())).unwrap();
+        assert_eq!(info.size, reader.minimal_size());
+        assert_eq!(info.blocks_count, reader.superblock().blocks_count());
+        assert_eq!(info.block_groups, 1);
+        assert_eq!(info.uuid, *reader.superblock().uuid());
+        assert!(info.free_blocks_count < info.blocks_count);
+        assert_eq!(info.free_inodes_count, info.inodes_count - 12);
+        assert_eq!(
+            info.inodes_per_group,
+            reader.superblock().inodes_per_group() as u64
+        );
+        assert_eq!(info.inodes_count, info.block_groups * info.inodes_per_group);
+        // the used blocks are all at the start of the image
+        assert_eq!(info.used_blocks(), info.blocks_count);
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024);
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer
+            .write_sparse_file(&[0u8; 100000], "zeros", 0o644)
+            .unwrap();
+        let image = writer.finish_to_vec().unwrap();
+        fsck_lite(&image).unwrap();
+        let mut reader = Ext4ImageReader::new(Cursor::new(&image)).unwrap();
+        assert_eq!(image.len() as u64, reader.minimal_size());
+        assert_eq!(reader.read_file("hello.txt").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_fast_symlink() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.symlink("/usr/lib/os-release", "os-release").unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        let inode_num = reader.lookup("os-release").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert_eq!(inode.file_type(), FileType::SymbolicLink);
+        assert_eq!(inode.flags() & 0x10080000, 0); // neither extents nor inline data
+        assert_eq!(inode.blocks(), 0);
+        assert_eq!(inode.size(), 19);
+        assert_eq!(&inode.block()[..19], b"/usr/lib/os-release");
+        assert!(inode.block()[19..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_write_symlink() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.write_symlink("busybox", "sh", 0o755).unwrap();
+        writer
+            .write_symlink(&"x/".repeat(100), "slow", 0o700)
+            .unwrap();
+        let image = writer.finish().unwrap().into_inner();
+        let path = "target/test_write_symlink.img";
+        std::fs::write(path, &image).unwrap();
+        run_e2fsck(path);
+
+        let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+        for (name, target, mode) in [
+            ("sh", "busybox".to_string(), 0o755),
+            ("slow", "x/".repeat(100), 0o700),
+        ] {
+            let inode_num = reader.lookup(name).unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            assert_eq!(inode.file_type(), FileType::SymbolicLink);
+            assert_eq!(inode.mode(), mode);
+            assert_eq!(inode.size(), target.len() as u64);
+            assert_eq!(inode.links_count(), 1);
+            assert_eq!(reader.read_file(name).unwrap(), target.as_bytes());
+        }
+    }
+
+    test_create_fs!(test_ext4_image_writer_reserved_inodes, |writer| {
+        writer.reserve_inodes(20).unwrap();
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        assert!(matches!(
+            writer.reserve_inodes(1),
+            Err(Ext4Error::TooLate(_))
+        ));
+    });
+
+    #[test]
+    fn test_reserved_inodes_move_first_ino() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.reserve_inodes(5).unwrap();
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert_eq!(reader.lookup("lost+found").unwrap(), 16);
+        assert_eq!(reader.lookup("hello.txt").unwrap(), 17);
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_unsigned_directory_hash,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024).with_directory_hash_signedness(false),
+        |writer| {
+            // more than one block of entries, so the directory is indexed by the unsigned hashes
+            writer.mkdir("dir").unwrap();
+            for i in 0..500 {
+                writer
+                    .write_file(&[], &format!("dir/\u{e4}-{i}"), 0o644)
+                    .unwrap();
+            }
+        }
+    );
+
+    fn build_htree_test_fs<W: io::Write + io::Seek>(writer: &mut Ext4ImageWriter<W>) {
+        writer.write_file(b"x", "file", 0o644).unwrap();
+        for i in 0..300 {
+            writer.link("file", &format!("root-{i}")).unwrap();
+        }
+        writer.mkdir("small").unwrap();
+        writer.link("file", "small/only").unwrap();
+        writer.mkdir("many").unwrap();
+        for i in 0..3000 {
+            writer
+                .write_file(&[], &format!("many/file-{i}"), 0o644)
+                .unwrap();
+        }
+        for i in 0..10 {
+            writer.mkdir(&format!("many/dir-{i}")).unwrap();
+        }
+        // with 1024 byte blocks this needs more leaves than the root can index
+        writer.mkdir("long").unwrap();
+        for i in 0..500 {
+            writer.link("file", &format!("long/{i:0200}")).unwrap();
+        }
+    }
+
+    fn check_htree_test_fs(image: Cursor<Vec<u8>>, block_size: u64, root_indexed: bool) {
+        let image = image.into_inner();
+        let mut reader = Ext4ImageReader::new(Cursor::new(&image)).unwrap();
+        let mut indirect_levels = |path: &str| {
+            let inode_num = reader.lookup(path).unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            if inode.flags() & 0x1000 == 0 {
+                return None;
+            }
+            let root = reader.read_data_blocks(&inode).unwrap()[0].start;
+            Some(image[(root * block_size) as usize + 30])
+        };
+        assert_eq!(indirect_levels(""), root_indexed.then_some(0));
+        assert_eq!(indirect_levels("small"), None);
+        assert_eq!(indirect_levels("many"), Some(0));
+        assert_eq!(
+            indirect_levels("long"),
+            Some(if block_size == 1024 { 1 } else { 0 })
+        );
+
+        assert_eq!(
+            reader.read_file(&format!("long/{:0200}", 123)).unwrap(),
+            b"x"
+        );
+        let many = reader.lookup("many").unwrap();
+        let many = reader.read_inode(many).unwrap();
+        let entries = reader.read_directory_entries(&many).unwrap();
+        assert_eq!(entries.len(), 2 + 3000 + 10);
+        assert_eq!(many.links_count(), 2 + 10);
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_htree,
+        |writer| { build_htree_test_fs(&mut writer) },
+        |image: Cursor<Vec<u8>>| { check_htree_test_fs(image, 4096, true) }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_htree_1k_blocks,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_block_size(1024)
+            .unwrap(),
+        |writer| { build_htree_test_fs(&mut writer) },
+        |image: Cursor<Vec<u8>>| { check_htree_test_fs(image, 1024, true) }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_htree_ext2,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .ext2_compat()
+            .unwrap(),
+        |writer| { build_htree_test_fs(&mut writer) }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_htree_linear_root,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024).with_root_indexed(false),
+        |writer| { build_htree_test_fs(&mut writer) },
+        |image: Cursor<Vec<u8>>| { check_htree_test_fs(image, 4096, false) }
+    );
+
+    #[test]
+    fn test_directory_hash_signedness_flag() {
+        for (signed, flags) in [(true, 0x1), (false, 0x2)] {
+            let writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+                .with_directory_hash_signedness(signed);
+            let reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+            assert_eq!(reader.superblock().flags() & 0x3, flags);
+        }
+    }
+
+    test_create_fs!(test_ext4_image_writer_timestamps, |writer| {
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer.write_file(&[0u8; 10000], "big.bin", 0o644).unwrap();
+        let timestamps = Timestamps {
+            crtime: Some(Timestamp::from_seconds(1_000_000_000)),
+            ..Timestamps::all(Timestamp::from_seconds(1_700_000_000))
+        };
+        writer.set_timestamps("hello.txt", timestamps).unwrap();
+        writer.set_timestamps("big.bin", timestamps).unwrap();
+    });
+
+    #[test]
+    fn test_crtime() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.write_file(b"a", "explicit", 0o644).unwrap();
+        writer.write_file(b"b", "default", 0o644).unwrap();
+        let mtime = Timestamp {
+            seconds: 1_700_000_000,
+            nanoseconds: 500,
+        };
+        let crtime = Timestamp {
+            seconds: 1_600_000_000,
+            nanoseconds: 42,
+        };
+        let explicit = Timestamps {
+            crtime: Some(crtime),
+            ..Timestamps::all(mtime)
+        };
+        writer.set_timestamps("explicit", explicit).unwrap();
+        writer
+            .set_timestamps("default", Timestamps::all(mtime))
+            .unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        let inode_num = reader.lookup("explicit").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert_eq!(inode.crtime(), crtime.encode());
+        assert_eq!(inode.mtime(), mtime.encode());
+        let inode_num = reader.lookup("default").unwrap();
+        assert_eq!(
+            reader.read_inode(inode_num).unwrap().crtime(),
+            mtime.encode()
+        );
+    }
+
+    #[test]
+    fn test_default_timestamps() {
+        let build_date = Timestamps::all(Timestamp::from_seconds(1_700_000_000));
+        let explicit = Timestamps::all(Timestamp::from_seconds(1_000_000_000));
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .with_default_timestamps(build_date);
+        writer.mkdir("etc").unwrap();
+        writer.write_file(b"host", "etc/hostname", 0o644).unwrap();
+        writer.write_file(&[0x42; 10000], "big.bin", 0o644).unwrap();
+        writer.symlink("etc/hostname", "hostname").unwrap();
+        writer.write_file(b"old", "old.txt", 0o644).unwrap();
+        writer.set_timestamps("old.txt", explicit).unwrap();
+        let image = writer.finish().unwrap().into_inner();
+        let path = "target/test_default_timestamps.img";
+        std::fs::write(path, &image).unwrap();
+        run_e2fsck(path);
+
+        let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+        let crtime = Some(build_date.mtime);
+        for path in [
+            "",
+            "lost+found",
+            "etc",
+            "etc/hostname",
+            "big.bin",
+            "hostname",
+        ] {
+            let inode_num = reader.lookup(path).unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            assert_eq!(
+                inode.timestamps(),
+                Timestamps {
+                    crtime,
+                    ..build_date
+                },
+                "{}",
+                path
+            );
+        }
+        let inode_num = reader.lookup("old.txt").unwrap();
+        assert_eq!(
+            reader.read_inode(inode_num).unwrap().timestamps().mtime,
+            explicit.mtime
+        );
+    }
+
+    #[test]
+    fn test_lost_found_mode() {
+        let writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        let inode_num = reader.lookup("lost+found").unwrap();
+        assert_eq!(reader.read_inode(inode_num).unwrap().mode(), 0o700);
+
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.set_lost_found_mode(0o750);
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert_eq!(reader.read_inode(11).unwrap().mode(), 0o750);
+        assert_eq!(reader.read_inode(2).unwrap().mode(), 0o755);
+    }
+
+    #[test]
+    fn test_lost_found_size() {
+        for (block_size, ext2) in [(4096, false), (1024, false), (1024, true)] {
+            let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+                .with_block_size(block_size)
+                .unwrap();
+            if ext2 {
+                writer = writer.ext2_compat().unwrap();
+            }
+            writer
+                .write_file(b"found", "lost+found/file", 0o644)
+                .unwrap();
+            let image = writer.finish().unwrap();
+            fsck_lite(image.get_ref()).unwrap();
+            let mut reader = Ext4ImageReader::new(image).unwrap();
+            let inode = reader.read_inode(11).unwrap();
+            assert_eq!(inode.size(), 16384);
+            assert!(!inode.has_inline_data());
+            // a linear directory like the one of mkfs, not an HTree
+            assert_eq!(inode.flags() & 0x1000, 0);
+            let entries = reader.read_directory_entries(&inode).unwrap();
+            let names: Vec<_> = entries.iter().map(|entry| entry.name()).collect();
+            assert_eq!(names, [".", "..", "file"]);
+        }
+    }
+
+    #[test]
+    fn test_reserved_percent() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        for invalid in [-0.5, 50.5, f32::NAN] {
+            assert!(matches!(
+                writer.set_reserved_percent(invalid),
+                Err(Ext4Error::Invalid(_))
+            ));
+        }
+        writer.set_reserved_percent(5.0).unwrap();
+        writer.set_reserved_owner(0, 4);
+        writer
+            .write_file(&[0x42; 4 * 1024 * 1024], "data", 0o644)
+            .unwrap();
+        let (image, info) = writer.finish_with_info().unwrap();
+        fsck_lite(image.get_ref()).unwrap();
+        let reader = Ext4ImageReader::new(image).unwrap();
+        let superblock = reader.superblock();
+        assert_eq!(superblock.blocks_count(), info.blocks_count);
+        assert_eq!(
+            superblock.reserved_blocks_count(),
+            info.blocks_count * 5 / 100
+        );
+        assert_eq!(superblock.default_reserved_owner(), (0, 4));
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_inode_count,
+        |file| {
+            let mut writer = Ext4ImageWriter::new(file, 1024 * 1024 * 1024);
+            writer.set_inode_count(100_000).unwrap();
+            writer
+        },
+        |writer| {
+            for i in 0..10 {
+                writer
+                    .write_file(&vec![i as u8; i * 1000], &format!("{i}"), 0o644)
+                    .unwrap();
+            }
+        }
+    );
+
+    #[test]
+    fn test_inode_count() {
+        for block_size in [1024, 4096] {
+            let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+                .with_block_size(block_size)
+                .unwrap();
+            assert!(matches!(
+                writer.set_inode_count(1 << 32),
+                Err(Ext4Error::TooManyInodes(_))
+            ));
+            writer.set_inode_count(100_000).unwrap();
+            for i in 0..10 {
+                writer
+                    .write_file(&vec![i as u8; i * 1000], &format!("{i}"), 0o644)
+                    .unwrap();
+            }
+            let estimate = writer.estimated_size();
+            let (image, info) = writer.finish_with_info().unwrap();
+            assert!(info.size <= estimate);
+            assert_eq!(image.get_ref().len() as u64, info.size);
+            fsck_lite(image.get_ref()).unwrap();
+            assert!(info.inodes_count >= 100_000);
+            // a block group can't hold more than 8 inodes per byte of a block
+            assert!(info.block_groups >= 100_000_u64.div_ceil(block_size * 8));
+            let reader = Ext4ImageReader::new(image).unwrap();
+            let superblock = reader.superblock();
+            assert_eq!(superblock.inodes_count() as u64, info.inodes_count);
+            assert_eq!(
+                superblock.free_inodes_count() as u64,
+                info.inodes_count - 11 - 10
+            );
+        }
+    }
+
+    #[test]
+    fn test_volume_label() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        assert!(matches!(
+            writer.set_volume_label("a label that is too long"),
+            Err(Ext4Error::Invalid(_))
+        ));
+        writer.set_volume_label("rootfs-16-bytes!").unwrap();
+        let image = writer.finish().unwrap();
+        let reader = Ext4ImageReader::new(image.clone()).unwrap();
+        assert_eq!(reader.superblock().volume_name(), "rootfs-16-bytes!");
+        fsck_lite(image.get_ref()).unwrap();
+
+        // the label is kept when adding to an existing image
+        let mut writer = Ext4ImageWriter::open(image).unwrap();
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        let reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert_eq!(reader.superblock().volume_name(), "rootfs-16-bytes!");
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_builder,
+        |file| Ext4ImageWriterBuilder::new(1024 * 1024 * 1024)
+            .reserved_percent(5.0)
+            .volume_label("rootfs")
+            .fixed_group_metadata()
+            .block_size(1024)
+            .uuid([0x42; 16])
+            .build(file)
+            .unwrap(),
+        |writer| { build_block_size_test_fs(&mut writer) }
+    );
+
+    #[test]
+    fn test_builder() {
+        let mut writer = Ext4ImageWriterBuilder::new(1024 * 1024 * 1024)
+            .uuid([0x42; 16])
+            .volume_label("rootfs")
+            .block_size(2048)
+            .reserved_percent(5.0)
+            .inode_size(256)
+            .build(Cursor::new(Vec::new()))
+            .unwrap();
+        writer
+            .write_file(&[0x42; 1024 * 1024], "data", 0o644)
+            .unwrap();
+        let (image, info) = writer.finish_with_info().unwrap();
+        let reader = Ext4ImageReader::new(image.clone()).unwrap();
+        let superblock = reader.superblock();
+        assert_eq!(superblock.uuid(), &[0x42; 16]);
+        assert_eq!(superblock.volume_name(), "rootfs");
+        assert_eq!(superblock.block_size(), 2048);
+        assert_eq!(superblock.reserved_blocks_count(), info.blocks_count / 20);
+
+        // the reserved percentage is kept when adding to an existing image
+        let mut writer = Ext4ImageWriter::open(image).unwrap();
+        writer
+            .write_file(&[0x42; 1024 * 1024], "more", 0o644)
+            .unwrap();
+        let (image, info) = writer.finish_with_info().unwrap();
+        let reader = Ext4ImageReader::new(image).unwrap();
+        assert_eq!(
+            reader.superblock().reserved_blocks_count(),
+            info.blocks_count / 20
+        );
+    }
+
+    fn build_flex_bg_test_fs<W: io::Write + io::Seek>(writer: &mut Ext4ImageWriter<W>) {
+        // spans more than one block group both in data and in inodes
+        let big_file = vec![0xCDu8; 200 * 1024 * 1024];
+        writer.write_file(&big_file, "big-file.bin", 0o644).unwrap();
+        writer.mkdir("files").unwrap();
+        for i in 0..10000 {
+            writer
+                .write_file(&vec![i as u8; i % 7000], &format!("files/{i}"), 0o644)
+                .unwrap();
+        }
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_flex_bg_0,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_flex_bg_size(0)
+            .unwrap(),
+        |writer| { build_flex_bg_test_fs(&mut writer) }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_flex_bg_4,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_flex_bg_size(4)
+            .unwrap(),
+        |writer| { build_flex_bg_test_fs(&mut writer) }
+    );
+
+    #[test]
+    fn test_no_flex_bg_places_metadata_in_group() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .with_flex_bg_size(0)
+            .unwrap();
+        let contents: Vec<u8> = (0..40000 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        writer.write_file(&contents, "data.bin", 0o644).unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        for block_group in 0..2 {
+            let bgd = reader.read_block_group_descriptor(block_group).unwrap();
+            let group_blocks =
+                (block_group as u64 * BLOCK_SIZE * 8)..((block_group as u64 + 1) * BLOCK_SIZE * 8);
+            assert!(group_blocks.contains(&bgd.block_bitmap()));
+            assert!(group_blocks.contains(&bgd.inode_bitmap()));
+            assert!(group_blocks.contains(&bgd.inode_table()));
+        }
+        assert_eq!(reader.read_file("data.bin").unwrap(), contents);
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_fixed_group_metadata,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_fixed_group_metadata()
+            .unwrap(),
+        |writer| { build_flex_bg_test_fs(&mut writer) }
+    );
+
+    #[test]
+    fn test_fixed_group_metadata_offsets() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .with_fixed_group_metadata()
+            .unwrap();
+        let bgdt_blocks = writer.bgdt_blocks();
+        let contents: Vec<u8> = (0..40000 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        writer.write_file(&contents, "data.bin", 0o644).unwrap();
+        let image = writer.finish().unwrap().into_inner();
+        let mut reader = Ext4ImageReader::new(Cursor::new(image.clone())).unwrap();
+        assert_ne!(reader.superblock().feature_incompat() & 0x200, 0); // flex_bg
+        for block_group in 0..2u64 {
+            // group 1 starts with a backup of the superblock and the GDT
+            let group_start = block_group * BLOCK_SIZE * 8 + 1 + bgdt_blocks;
+            let bgd = reader
+                .read_block_group_descriptor(block_group as u32)
+                .unwrap();
+            assert_eq!(bgd.block_bitmap(), group_start);
+            assert_eq!(bgd.inode_bitmap(), group_start + 1);
+            assert_eq!(bgd.inode_table(), group_start + 2);
+        }
+        // the root directory is the second inode in the table of group 0
+        let root = reader.read_inode(2).unwrap();
+        let inode_size = Ext4Inode::DEFAULT_SIZE as usize;
+        let root_offset = (3 + bgdt_blocks) as usize * BLOCK_SIZE as usize + inode_size;
+        assert_eq!(
+            &image[root_offset..root_offset + inode_size],
+            &root.as_bytes()[..inode_size]
+        );
+        assert_eq!(reader.read_file("data.bin").unwrap(), contents);
+    }
+
+    #[test]
+    fn test_file_at_block_in_group_metadata() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .with_fixed_group_metadata()
+            .unwrap();
+        // the inode table of the second group
+        assert!(matches!(
+            writer.write_file_at_block(b"x", "x", 0o644, BLOCK_SIZE * 8 + 100),
+            Err(Ext4Error::BlockUnavailable(_))
+        ));
+        writer
+            .write_file_at_block(b"x", "x", 0o644, BLOCK_SIZE * 8 + 1000)
+            .unwrap();
+
+        // the superblock backup at the start of every group of a revision 0 image
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .with_revision(Revision::Rev0)
+            .unwrap();
+        assert!(matches!(
+            writer.write_file_at_block(b"x", "x", 0o644, 2 * BLOCK_SIZE * 8),
+            Err(Ext4Error::BlockUnavailable(_))
+        ));
+    }
+
+    /// The block bitmaps have to mark the bitmaps and inode tables of every group as used,
+    /// including the bitmap blocks themselves and those in the partial last group.
+    fn check_group_metadata_is_marked_used(image: &[u8]) {
+        let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+        let superblock = reader.superblock().clone();
+        let groups = superblock.block_groups_count();
+        let inode_table_blocks =
+            (superblock.inodes_per_group() as u64 * Ext4Inode::DEFAULT_SIZE).div_ceil(BLOCK_SIZE);
+        let descriptors: Vec<_> = (0..groups)
+            .map(|group| reader.read_block_group_descriptor(group).unwrap())
+            .collect();
+        let is_marked_used = |block: u64| {
+            let bitmap_block = descriptors[(block / (BLOCK_SIZE * 8)) as usize].block_bitmap();
+            let bit = block % (BLOCK_SIZE * 8);
+            image[(bitmap_block * BLOCK_SIZE + bit / 8) as usize] & (1 << (bit % 8)) != 0
+        };
+        for (group, bgd) in descriptors.iter().enumerate() {
+            let inode_table = bgd.inode_table()..bgd.inode_table() + inode_table_blocks;
+            for block in [bgd.block_bitmap(), bgd.inode_bitmap()]
+                .into_iter()
+                .chain(inode_table)
+            {
+                assert!(is_marked_used(block), "group {} block {}", group, block);
+            }
+        }
+    }
+
+    #[test]
+    fn test_group_metadata_is_marked_used() {
+        let contents = vec![0xCDu8; 150 * 1024 * 1024];
+        for constructor in [
+            |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024),
+            |file| {
+                Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+                    .with_flex_bg_size(0)
+                    .unwrap()
+            },
+            |file| {
+                Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+                    .with_fixed_group_metadata()
+                    .unwrap()
+            },
+        ] {
+            let mut writer: Ext4ImageWriter<_> = constructor(Cursor::new(Vec::new()));
+            writer.write_file(&contents, "big.bin", 0o644).unwrap();
+            let image = writer.finish().unwrap().into_inner();
+            check_group_metadata_is_marked_used(&image);
+        }
+    }
+
+    /// The double indirect block of the resize inode has to list exactly the reserved GDT blocks,
+    /// each at its index within the GDT. Each reserved GDT block in turn lists its backups.
+    fn check_resize_inode(file_name: &str) {
+        let mut image = std::fs::File::open(file_name).unwrap();
+        let mut reader = Ext4ImageReader::new(image.try_clone().unwrap()).unwrap();
+        let superblock = reader.superblock().clone();
+        let used_gdt_blocks = (superblock.block_groups_count() as u64
+            * Ext4BlockGroupDescriptor::SIZE)
+            .div_ceil(BLOCK_SIZE);
+        let reserved_gdt_blocks = superblock.reserved_gdt_blocks() as u64;
+        assert!(reserved_gdt_blocks > 0);
+
+        let resize_inode = reader.read_inode(7).unwrap();
+        let dind_block = LegacyBlockDescriptor::read_buffer(resize_inode.block()).double_indirect();
+        let read_block = |image: &mut std::fs::File, block: u64| {
+            let mut buf = vec![0u8; BLOCK_SIZE as usize];
+            image.seek(io::SeekFrom::Start(block * BLOCK_SIZE)).unwrap();
+            image.read_exact(&mut buf).unwrap();
+            buf
+        };
+        let dind = <[u32; 1024]>::read_buffer(&read_block(&mut image, dind_block as u64));
+        for (index, &block) in dind.iter().enumerate() {
+            let index = index as u64;
+            if (used_gdt_blocks..used_gdt_blocks + reserved_gdt_blocks).contains(&index) {
+                assert_eq!(block as u64, 1 + index, "dind entry {}", index);
+                let backups = <[u32; 1024]>::read_buffer(&read_block(&mut image, block as u64));
+                let backup_groups = (1..superblock.block_groups_count() as u64)
+                    .filter(|&group| Ext4SuperBlock::has_super(group));
+                let mut expected = backup_groups
+                    .map(|group| (block as u64 + group * BLOCK_SIZE * 8) as u32)
+                    .collect::<Vec<_>>();
+                expected.resize(1024, 0);
+                assert_eq!(backups[..], expected[..], "reserved GDT block {}", block);
+            } else {
+                assert_eq!(block, 0, "dind entry {}", index);
+            }
+        }
+    }
+
+    #[test]
+    fn test_resize_inode_lists_reserved_gdt_blocks() {
+        let file_name = "target/test_resize_inode.img";
+        let mut writer = Ext4ImageWriter::new(
+            std::fs::File::create(file_name).unwrap(),
+            64 * 1024 * 1024 * 1024,
+        );
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer.finish().unwrap();
+        check_resize_inode(file_name);
+        run_e2fsck(file_name);
+
+        // more than 64 block groups need a second GDT block, which shifts the reserved blocks in the dind block
+        let file_name = "target/test_resize_inode_two_gdt_blocks.img";
+        let _ = std::fs::remove_file(file_name);
+        let mut writer = Ext4ImageWriter::new(
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(file_name)
+                .unwrap(),
+            1024 * 1024 * 1024 * 1024,
+        );
+        writer
+            .write_file_at_block(b"far away", "far.txt", 0o644, 70 * BLOCK_SIZE * 8)
+            .unwrap();
+        writer.finish().unwrap();
+        check_resize_inode(file_name);
+        run_e2fsck(file_name);
+    }
+
+    #[test]
+    fn test_resize_inode_across_image_sizes() {
+        // data ending right in front of a group boundary makes the group metadata and the resize inode's
+        // double indirect block spill into the next group, which changes the GDT the resize inode refers to
+        let blocks_per_group = BLOCK_SIZE * 8;
+        for (groups, slack) in [(1, 0), (1, 1), (2, 2), (3, 40), (8, 1), (66, 3)] {
+            let file_name = format!("target/test_resize_inode_{}_{}.img", groups, slack);
+            let _ = std::fs::remove_file(&file_name);
+            let mut writer = Ext4ImageWriter::new(
+                std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&file_name)
+                    .unwrap(),
+                1024 * 1024 * 1024 * 1024,
+            );
+            writer
+                .write_file_at_block(
+                    b"at the end",
+                    "end.txt",
+                    0o644,
+                    groups * blocks_per_group - 1 - slack,
+                )
+                .unwrap();
+            writer.finish().unwrap();
+            check_resize_inode(&file_name);
+            run_e2fsck(&file_name);
+        }
+    }
+
+    #[test]
+    fn test_superblock_backups() {
+        use crate::serialization::CheckMagic;
+        let file_name = "target/test_superblock_backups.img";
+        let mut writer = Ext4ImageWriter::new(
+            std::fs::File::create(file_name).unwrap(),
+            1024 * 1024 * 1024,
+        )
+        .with_block_size(1024)
+        .unwrap();
+        let contents: Vec<u8> = (0..30 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        writer.write_file(&contents, "data.bin", 0o644).unwrap();
+        let (_, info) = writer.finish_with_info().unwrap();
+        assert_eq!(info.block_groups, 4);
+        run_e2fsck(file_name);
+
+        let image = std::fs::read(file_name).unwrap();
+        let primary = Ext4SuperBlock::read_buffer(&image[1024..2048]);
+        let bgdt_len = info.block_groups as usize * Ext4BlockGroupDescriptor::SIZE as usize;
+        for block_group in [1, 3] {
+            let start = (1 + block_group * 8192) * 1024;
+            let backup = Ext4SuperBlock::read_buffer(&image[start..start + 1024]);
+            assert_eq!(backup.block_group_nr(), block_group as u16);
+            assert!(backup.checksum_matches());
+            assert_eq!(backup.uuid(), primary.uuid());
+            assert_eq!(backup.blocks_count(), primary.blocks_count());
+            assert_eq!(image[start + 1024..][..bgdt_len], image[2048..][..bgdt_len]);
+        }
+        // group 2 has no backup
+        let start = (1 + 2 * 8192) * 1024;
+        assert!(
+            Ext4SuperBlock::read_buffer(&image[start..start + 1024])
+                .check_magic()
+                .is_err()
+        );
+        assert_eq!(
+            Ext4ImageReader::new(Cursor::new(image))
+                .unwrap()
+                .read_file("data.bin")
+                .unwrap(),
+            contents
+        );
+
+        // e2fsck can recover the filesystem from a backup
+        let status = match std::process::Command::new("e2fsck")
+            .args(["-fn", "-b", "8193", "-B", "1024", file_name])
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+        {
+            Ok(status) => status,
+            Err(error) => return e2fsck_unavailable(error),
+        };
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_flex_bg_size_reconfigured() {
+        let new = || Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+
+        let writer = new()
+            .with_flex_bg_size(2)
+            .unwrap()
+            .with_flex_bg_size(5)
+            .unwrap();
+        assert_eq!(writer.log_groups_per_flex, 5);
+        let writer = new()
+            .with_flex_bg_size(2)
+            .unwrap()
+            .with_flex_bg_size(0)
+            .unwrap();
+        assert_eq!(writer.log_groups_per_flex, 0);
+
+        for writer in [
+            new().with_flex_bg_size(0).unwrap(),
+            new().with_fixed_group_metadata().unwrap(),
+            new().ext2_compat().unwrap(),
+        ] {
+            assert!(matches!(
+                writer.with_flex_bg_size(4),
+                Err(Ext4Error::TooLate(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_flex_bg_size_after_adding_files() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.write_file(&[1u8; 5000], "a.bin", 0o644).unwrap();
+        assert!(matches!(
+            writer.with_flex_bg_size(0),
+            Err(Ext4Error::TooLate(_))
+        ));
+    }
+
+    #[test]
+    fn test_truncate_to_minimal_size() {
+        let file_name = "target/test_truncate_to_minimal_size.img";
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_name)
+            .unwrap();
+        // i.e. a preallocated image that is much larger than the content
+        file.set_len(1024 * 1024 * 1024).unwrap();
+        let mut writer = Ext4ImageWriter::new(file, 1024 * 1024 * 1024);
+        writer
+            .write_file(&[0x42u8; 100000], "data.bin", 0o644)
+            .unwrap();
+        let file = writer.finish().unwrap();
+
+        let mut reader = Ext4ImageReader::new(&file).unwrap();
+        let minimal_size = reader.minimal_size();
+        assert!(minimal_size < 1024 * 1024);
+        file.set_len(minimal_size).unwrap();
+        run_e2fsck(file_name);
+        assert_eq!(reader.read_file("data.bin").unwrap(), vec![0x42u8; 100000]);
+    }
+
+    #[test]
+    fn test_rebuild_with_minimal_max_size() {
+        // large enough for a superblock backup in the second group
+        let contents = vec![0x42u8; 130 * 1024 * 1024];
+        let build = |max_size| {
+            let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), max_size);
+            writer.write_file(&contents, "data.bin", 0o644).unwrap();
+            writer.finish_with_info().unwrap()
+        };
+        let (_, generous) = build(1 << 40);
+        assert!(generous.minimal_max_size < generous.size);
+
+        let (image, tight) = build(generous.minimal_max_size);
+        assert_eq!(tight.size, generous.minimal_max_size);
+        assert_eq!(tight.minimal_max_size, tight.size);
+        let file_name = "target/test_rebuild_with_minimal_max_size.img";
+        std::fs::write(file_name, image.get_ref()).unwrap();
+        run_e2fsck(file_name);
+        let mut reader = Ext4ImageReader::new(image).unwrap();
+        assert_eq!(reader.read_file("data.bin").unwrap(), contents);
+    }
+
+    #[test]
+    fn test_image_ends_on_block_boundary() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.write_file(&[1u8; 5000], "a.bin", 0o644).unwrap();
+        let image = writer.finish().unwrap().into_inner();
+        let reader = Ext4ImageReader::new(Cursor::new(&image)).unwrap();
+        assert_eq!(image.len() as u64, reader.minimal_size());
+    }
+
+    fn fscrypt_context_v2(nonce: u8) -> Vec<u8> {
+        let mut context = vec![
+            2, 1, /* AES-256-XTS */
+            4, /* AES-256-CTS */
+            0, 0, 0, 0, 0,
+        ];
+        context.extend_from_slice(&[0x11; 16]); // master key identifier
+        context.extend_from_slice(&[nonce; 16]);
+        context
+    }
+
+    test_create_fs!(test_ext4_image_writer_encryption_policy, |writer| {
+        writer.mkdir("secret").unwrap();
+        writer
+            .set_encryption_policy("secret", &fscrypt_context_v2(1))
+            .unwrap();
+        writer
+            .write_file(&[0x5Au8; 5000], "secret/0123456789abcdef0123456789", 0o600)
+            .unwrap();
+        writer
+            .set_encryption_policy("secret/0123456789abcdef0123456789", &fscrypt_context_v2(2))
+            .unwrap();
+    });
+
+    // names and contents have to be encrypted by the caller, these just stand in for ciphertext
+    const ENCRYPTED_NAME: &str = "0123456789abcdef0123456789";
+
+    test_create_fs!(test_ext4_image_writer_nested_encryption_policy, |writer| {
+        let nested = format!("secret/{ENCRYPTED_NAME}");
+        let file = format!("{nested}/{ENCRYPTED_NAME}");
+        writer.mkdir_p(&nested).unwrap();
+        writer.write_file(b"ciphertext", &file, 0o600).unwrap();
+        for (i, path) in ["secret", &nested, &file].iter().enumerate() {
+            writer
+                .set_encryption_policy(path, &fscrypt_context_v2(i as u8))
+                .unwrap();
+        }
+    });
+
+    #[test]
+    fn test_encryption_policy_combinations() {
+        fn build(
+            setup: impl FnOnce(&mut Ext4ImageWriter<Cursor<Vec<u8>>>),
+        ) -> Result<(), Ext4Error> {
+            let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+            writer.mkdir("secret").unwrap();
+            writer
+                .set_encryption_policy("secret", &fscrypt_context_v2(1))
+                .unwrap();
+            setup(&mut writer);
+            writer.finish().map(|_| ())
+        }
+        let file = format!("secret/{ENCRYPTED_NAME}");
+        // an empty encrypted directory is fine
+        build(|_| {}).unwrap();
+        build(|writer| {
+            writer.write_file(b"ciphertext", &file, 0o644).unwrap();
+            writer
+                .set_encryption_policy(&file, &fscrypt_context_v2(2))
+                .unwrap();
+        })
+        .unwrap();
+        // unencrypted entries in an encrypted directory
+        assert!(build(|writer| writer.write_file(b"plain", &file, 0o644).unwrap()).is_err());
+        assert!(build(|writer| writer.mkdir(&file).unwrap()).is_err());
+        // a different master key
+        assert!(
+            build(|writer| {
+                writer.write_file(b"ciphertext", &file, 0o644).unwrap();
+                let mut context = fscrypt_context_v2(2);
+                context[8] = 0x22;
+                writer.set_encryption_policy(&file, &context).unwrap();
+            })
+            .is_err()
+        );
+        // names shorter than one cipher block
+        assert!(
+            build(|writer| {
+                writer
+                    .write_file(b"ciphertext", "secret/short", 0o644)
+                    .unwrap();
+                writer
+                    .set_encryption_policy("secret/short", &fscrypt_context_v2(2))
+                    .unwrap();
+            })
+            .is_err()
+        );
+        // encrypted symlinks need an encrypted target
+        assert!(
+            build(|writer| {
+                writer.symlink("target", "link").unwrap();
+                writer
+                    .set_encryption_policy("link", &fscrypt_context_v2(2))
+                    .unwrap();
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_encryption_policy_xattr() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.mkdir("secret").unwrap();
+        assert!(writer.set_encryption_policy("secret", &[2, 1, 4]).is_err());
+        assert!(
+            writer
+                .set_encryption_policy("missing", &fscrypt_context_v2(1))
+                .is_err()
+        );
+        writer
+            .set_encryption_policy("secret", &fscrypt_context_v2(1))
+            .unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        let inode_num = reader.lookup("secret").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert_eq!(inode.xattr(9, b"c").unwrap(), Some(fscrypt_context_v2(1)));
+        assert_ne!(inode.flags() & 0x800, 0);
+        assert!(
+            reader
+                .read_inode(2)
+                .unwrap()
+                .xattr(9, b"c")
+                .unwrap()
+                .is_none()
+        );
+
+        // setting the policy again replaces the context of files and directories
+        let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024);
+        writer.mkdir("secret").unwrap();
+        writer.write_file(b"", "file", 0o644).unwrap();
+        for path in ["secret", "file"] {
+            for key in [1, 2] {
+                writer
+                    .set_encryption_policy(path, &fscrypt_context_v2(key))
+                    .unwrap();
+            }
+        }
+        let mut reader =
+            Ext4ImageReader::new(Cursor::new(writer.finish_to_vec().unwrap())).unwrap();
+        for path in ["secret", "file"] {
+            let inode_num = reader.lookup(path).unwrap();
+            let xattrs = reader.read_inode(inode_num).unwrap().xattrs().unwrap();
+            let contexts: Vec<_> = xattrs
+                .iter()
+                .filter(|xattr| (xattr.name_index, &xattr.name[..]) == (9, b"c"))
+                .map(|xattr| xattr.value.clone())
+                .collect();
+            assert_eq!(contexts, [fscrypt_context_v2(2)], "{}", path);
+        }
+    }
+
+    test_create_fs!(test_ext4_image_writer_casefold, |writer| {
+        writer.mkdir("Share").unwrap();
+        writer.set_casefold("Share").unwrap();
+        // enough entries for an HTree index
+        for i in 0..500 {
+            writer
+                .write_file(b"x", &format!("Share/Document-{i}.TXT"), 0o644)
+                .unwrap();
+        }
+        writer.mkdir("Share/Small").unwrap();
+        writer.set_casefold("Share/Small").unwrap();
+        writer
+            .write_file(b"inline", "Share/Small/README", 0o644)
+            .unwrap();
+        // spans several blocks, but stays linear as the names are not ASCII
+        writer.mkdir("Unicode").unwrap();
+        writer.set_casefold("Unicode").unwrap();
+        for i in 0..300 {
+            writer
+                .write_file(b"x", &format!("Unicode/Größe-{i}"), 0o644)
+                .unwrap();
+        }
+        writer.write_file(b"x", "readme", 0o644).unwrap();
+        writer.write_file(b"x", "README", 0o644).unwrap();
+    });
+
+    #[test]
+    fn test_casefold() {
+        let file_name = "target/test_casefold.img";
+        let file = std::fs::File::create(file_name).unwrap();
+        let mut writer = Ext4ImageWriter::new(file, 1024 * 1024 * 1024);
+        writer.mkdir("Share").unwrap();
+        writer.write_file(b"x", "Share/File.txt", 0o644).unwrap();
+        assert!(matches!(
+            writer.set_casefold("Share/File.txt"),
+            Err(Ext4Error::NotADirectory(_))
+        ));
+        assert!(matches!(
+            writer.set_casefold("missing"),
+            Err(Ext4Error::NotFound(_))
+        ));
+        writer.set_casefold("Share").unwrap();
+        writer.finish().unwrap();
+        run_e2fsck(file_name);
+
+        let output = std::process::Command::new("dumpe2fs")
+            .args(["-h", file_name])
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let features = stdout
+            .lines()
+            .find(|line| line.starts_with("Filesystem features:"))
+            .unwrap();
+        assert!(
+            features.split_whitespace().any(|f| f == "casefold"),
+            "{}",
+            features
+        );
+        assert!(
+            stdout
+                .lines()
+                .any(|line| line.starts_with("Character encoding:") && line.ends_with("utf8-12.1")),
+            "{}",
+            stdout
+        );
+
+        // the flag survives opening the image again
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(file_name)
+            .unwrap();
+        let mut writer = Ext4ImageWriter::open(file).unwrap();
+        writer.write_file(b"x", "Share/Other.txt", 0o644).unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert!(reader.superblock().has_casefold());
+        let inode_num = reader.lookup("Share").unwrap();
+        let flags = reader.read_inode(inode_num).unwrap().flags();
+        assert_eq!(flags & Ext4Inode::CASEFOLD_FL, Ext4Inode::CASEFOLD_FL);
+        run_e2fsck(file_name);
+
+        // names that only differ in case
+        let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024);
+        writer.mkdir("Share").unwrap();
+        writer.set_casefold("Share").unwrap();
+        writer.write_file(b"x", "Share/readme", 0o644).unwrap();
+        writer.write_file(b"x", "Share/README", 0o644).unwrap();
+        assert!(matches!(writer.finish(), Err(Ext4Error::PathExists(_))));
+        // encrypted case-insensitive directories
+        let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024);
+        writer.mkdir("secret").unwrap();
+        writer.set_casefold("secret").unwrap();
+        writer
+            .set_encryption_policy("secret", &fscrypt_context_v2(1))
+            .unwrap();
+        assert!(matches!(
+            writer.finish(),
+            Err(Ext4Error::InvalidEncryption(_))
+        ));
+        let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024)
+            .ext2_compat()
+            .unwrap();
+        writer.set_casefold("").unwrap();
+        assert!(matches!(
+            writer.finish(),
+            Err(Ext4Error::UnsupportedByExt2(_))
+        ));
+    }
+
+    /// ext4 is little-endian on disk, so the image must not depend on the host byte order.
+    /// Run this on a big-endian target (i.e. `cross test --target s390x-unknown-linux-gnu`) to check for native-endian leakage.
+    /// The checksums cover the superblock, the group descriptor with the bitmaps and the inodes, so a field that is
+    /// written in native byte order changes one of them. They are read from the raw bytes and were taken from
+    /// an image that passes `e2fsck`, so they only have to be updated when the on-disk layout changes intentionally.
+    #[test]
+    fn test_image_is_host_endian_independent() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.mkdir_p("a/b").unwrap();
+        writer.write_file(b"hello", "a/hello.txt", 0o644).unwrap();
+        let contents: Vec<u8> = (0..100000u32).map(|i| (i % 251) as u8).collect();
+        writer.write_file(&contents, "a/b/data.bin", 0o644).unwrap();
+        writer
+            .set_timestamps(
+                "a/hello.txt",
+                Timestamps::all(Timestamp::from_seconds(1_700_000_000)),
+            )
+            .unwrap();
+        let image = writer.finish().unwrap().into_inner();
+        let file_name = "target/test_image_is_host_endian_independent.img";
+        std::fs::write(file_name, &image).unwrap();
+        run_e2fsck(file_name);
+
+        let le16 =
+            |offset: u64| u16::from_le_bytes(image[offset as usize..][..2].try_into().unwrap());
+        let le32 =
+            |offset: u64| u32::from_le_bytes(image[offset as usize..][..4].try_into().unwrap());
+        assert_eq!(le16(1024 + 0x38), 0xEF53); // s_magic
+        assert_eq!(le32(1024 + 0x3FC), 0x034b_06bf); // s_checksum
+        let bgd = BLOCK_SIZE;
+        let group_checksums = [
+            le16(bgd + 0x18) as u32 | (le16(bgd + 0x38) as u32) << 16, // block bitmap
+            le16(bgd + 0x1A) as u32 | (le16(bgd + 0x3A) as u32) << 16, // inode bitmap
+            le16(bgd + 0x1E) as u32,                                   // bg_checksum
+        ];
+        assert_eq!(group_checksums, [0x6d77_d96c, 0xa1e5_6730, 0x36f3]);
+        let inode_table = (le32(bgd + 0x08) as u64 | (le32(bgd + 0x28) as u64) << 32) * BLOCK_SIZE;
+        let mut reader = Ext4ImageReader::new(Cursor::new(&image)).unwrap();
+        let inode_checksums: Vec<_> = ["", "a", "a/hello.txt", "a/b/data.bin"]
+            .into_iter()
+            .map(|path| {
+                let inode = inode_table + (reader.lookup(path).unwrap() as u64 - 1) * 256;
+                le16(inode + 0x7C) as u32 | (le16(inode + 0x82) as u32) << 16
+            })
+            .collect();
+        // the root directory, a directory, a file with inline data and one with extents
+        assert_eq!(
+            inode_checksums,
+            [0x3b8f_0d72, 0xea94_24e1, 0xac97_ab24, 0x810e_ea0f]
+        );
+    }
+
+    #[test]
+    fn test_inode_hook() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.mkdir("dir").unwrap();
+        writer.write_file(b"hello", "dir/hello.txt", 0o644).unwrap();
+        writer.write_file(&[1u8; 10000], "big.bin", 0o644).unwrap();
+        let mut hooked = vec![];
+        let image = writer
+            .finish_with_inode_hook(|inode_num, inode| {
+                hooked.push(inode_num);
+                inode.add_flags(0x80); // EXT4_NOATIME_FL
+            })
+            .unwrap()
+            .into_inner();
+        assert_eq!(hooked, (1..=14).collect::<Vec<_>>());
+
+        let file_name = "target/test_inode_hook.img";
+        std::fs::write(file_name, &image).unwrap();
+        run_e2fsck(file_name);
+        let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+        for path in ["dir", "dir/hello.txt", "big.bin", "lost+found"] {
+            let inode_num = reader.lookup(path).unwrap();
+            assert_ne!(reader.read_inode(inode_num).unwrap().flags() & 0x80, 0);
+        }
+    }
+
+    /// Files never own enough blocks for `i_blocks` to count blocks instead of sectors, so convert one
+    /// in the inode hook to check that such inodes are read like the kernel and e2fsck do.
+    #[test]
+    fn test_huge_file_flag() {
+        for ext2 in [false, true] {
+            let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+            if ext2 {
+                writer = writer.ext2_compat().unwrap();
+            } else {
+                writer.set_quota(true).unwrap();
+            }
+            writer
+                .write_file(&[1u8; 100_000], "big.bin", 0o644)
+                .unwrap();
+            let image = writer
+                .finish_with_inode_hook(|inode_num, inode| {
+                    if inode_num == 12 {
+                        let sectors = inode.sectors(BLOCK_SIZE);
+                        inode.add_flags(Ext4Inode::HUGE_FILE_FL);
+                        inode.set_blocks(sectors / (BLOCK_SIZE / 512));
+                    }
+                })
+                .unwrap()
+                .into_inner();
+            let mut reader = Ext4ImageReader::new(Cursor::new(&image)).unwrap();
+            assert_eq!(reader.lookup("big.bin").unwrap(), 12);
+            let inode = reader.read_inode(12).unwrap();
+            assert_ne!(inode.flags() & Ext4Inode::HUGE_FILE_FL, 0);
+            // 25 data blocks and for ext2 an indirect block
+            assert_eq!(inode.blocks(), 25 + ext2 as u64);
+            assert_eq!(inode.sectors(BLOCK_SIZE), inode.blocks() * 8);
+            if ext2 {
+                assert!(matches!(
+                    fsck_lite(&image).unwrap_err()[0],
+                    FsckError::Inode { inode: 12, .. }
+                ));
+            } else {
+                let file_name = "target/test_huge_file_flag.img";
+                std::fs::write(file_name, &image).unwrap();
+                run_e2fsck(file_name);
+            }
+        }
+    }
+
+    test_create_fs!(test_ext4_image_writer_rename, |writer| {
+        writer.mkdir_p("a/b").unwrap();
+        writer.write_file(b"hello", "a/b/hello.txt", 0o644).unwrap();
+        writer.mkdir("c").unwrap();
+        assert!(matches!(
+            writer.rename("a", "a/b/a"),
+            Err(Ext4Error::DirectoryCycle { .. })
+        ));
+        assert!(writer.rename("lost+found", "c/lost+found").is_err());
+        writer.rename("a/b", "c/b").unwrap();
+        writer.rename("c/b/hello.txt", "hello.txt").unwrap();
+    });
+
+    /// `.`, `..`, a free record and `hello.txt` (inode 12) followed by the checksum tail.
+    fn handcrafted_directory_block() -> [u8; 4096] {
+        let mut block = [0u8; 4096];
+        let mut offset = 0;
+        for (inode, rec_len, file_type, name) in [
+            (0u32, 12u16, 2u8, "."),
+            (0, 12, 2, ".."),
+            (0, 16, 0, ""),
+            (12, 4096 - 12 - 40, 1, "hello.txt"),
+        ] {
+            block[offset..offset + 4].copy_from_slice(&inode.to_le_bytes());
+            block[offset + 4..offset + 6].copy_from_slice(&rec_len.to_le_bytes());
+            block[offset + 6] = name.len() as u8;
+            block[offset + 7] = file_type;
+            block[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+            offset += rec_len as usize;
+        }
+        block[4096 - 8..4096 - 4].copy_from_slice(&[12, 0, 0, 0xDE]);
+        block
+    }
+
+    test_create_fs!(test_ext4_image_writer_raw_directory, |writer| {
+        writer.mkdir("raw").unwrap();
+        writer.write_file(b"hello", "raw/hello.txt", 0o644).unwrap();
+        writer
+            .write_directory_raw("raw", &[handcrafted_directory_block()])
+            .unwrap();
+    });
+
+    #[test]
+    fn test_write_directory_raw() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.mkdir("raw").unwrap();
+        writer.write_file(b"hello", "raw/hello.txt", 0o644).unwrap();
+        let block = handcrafted_directory_block();
+        let mut without_tail = block;
+        without_tail[4096 - 1] ^= 0xFF;
+        without_tail[4096 - 5] = 0;
+        assert!(writer.write_directory_raw("raw", &[without_tail]).is_err());
+        assert!(writer.write_directory_raw("raw", &[]).is_err());
+        assert!(
+            writer
+                .write_directory_raw("raw/hello.txt", &[block])
+                .is_err()
+        );
+        writer.write_directory_raw("raw", &[block]).unwrap();
+        let image = writer.finish().unwrap().into_inner();
+
+        let mut reader = Ext4ImageReader::new(Cursor::new(image.clone())).unwrap();
+        let inode_num = reader.lookup("raw").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        let start = reader.read_extents(&inode).unwrap()[0].start() * BLOCK_SIZE;
+        let written = &image[start as usize..(start + BLOCK_SIZE) as usize];
+        // only the inode numbers of `.` and `..` and the checksum differ
+        assert_eq!(&written[..4], &inode_num.to_le_bytes());
+        assert_eq!(&written[12..16], &2u32.to_le_bytes());
+        assert_eq!(&written[4..12], &block[4..12]);
+        assert_eq!(&written[16..4092], &block[16..4092]);
+        assert_eq!(reader.read_file("raw/hello.txt").unwrap(), b"hello");
+
+        // the blocks have to list exactly the files of the directory
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.mkdir("raw").unwrap();
+        writer.write_file(b"hello", "raw/hello.txt", 0o644).unwrap();
+        writer.write_file(b"other", "raw/other.txt", 0o644).unwrap();
+        writer.write_directory_raw("raw", &[block]).unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn test_would_fit() {
+        let max_size = 64 * 1024 * 1024;
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), max_size);
+        writer.mkdir("files").unwrap();
+        for i in 0..100 {
+            writer
+                .write_file(&vec![1; i * 100], &format!("files/{i}"), 0o644)
+                .unwrap();
+        }
+        assert!(writer.would_fit(0));
+        assert!(!writer.would_fit(max_size));
+        let largest = (0..max_size / BLOCK_SIZE)
+            .map(|blocks| blocks * BLOCK_SIZE)
+            .take_while(|&size| writer.would_fit(size))
+            .last()
+            .unwrap();
+        assert!(!writer.would_fit(largest + 1));
+        writer
+            .write_file(&vec![2; largest as usize], "largest.bin", 0o644)
+            .unwrap();
+        let (_, info) = writer.finish_with_info().unwrap();
+        assert!(info.size <= max_size);
+        // the estimate is conservative but not by much
+        assert!(info.size > max_size - 16 * BLOCK_SIZE);
+
+        // files that don't fit fail the image even though they need no further block group
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), max_size);
+        writer
+            .write_file(&vec![3; max_size as usize], "too-large.bin", 0o644)
+            .unwrap();
+        assert!(matches!(
+            writer.finish(),
+            Err(Ext4Error::OutOfSpace {
+                available: 16384,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_estimated_size() {
+        let new = || Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        for mut writer in [
+            new(),
+            new().with_block_size(1024).unwrap(),
+            new().with_fixed_group_metadata().unwrap(),
+            new().ext2_compat().unwrap(),
+        ] {
+            let empty = writer.estimated_size();
+            writer.mkdir_p("usr/share").unwrap();
+            for i in 0..300 {
+                writer
+                    .write_file(&vec![1; i * 50], &format!("usr/share/{i}"), 0o644)
+                    .unwrap();
+            }
+            writer
+                .write_file(&vec![2; 20 * 1024 * 1024], "usr/big", 0o644)
+                .unwrap();
+            writer
+                .set_xattr("usr/big", "user.comment", &[3; 500])
+                .unwrap();
+            writer.set_xattr("usr", "user.comment", b"dir").unwrap();
+            let estimate = writer.estimated_size();
+            assert!(estimate > empty);
+            let (_, info) = writer.finish_with_info().unwrap();
+            assert!(info.size <= estimate, "{} > {}", info.size, estimate);
+            // the estimate is conservative but not by much
+            assert!(
+                estimate - info.size <= 8 * info.block_size,
+                "{} is much smaller than {}",
+                info.size,
+                estimate
+            );
+        }
+
+        // the extent tree blocks, the journal and the quota files are covered as well
+        for build in [
+            build_extent_tree_test_fs as fn(&mut _),
+            build_journal_test_fs,
+            build_quota_test_fs,
+        ] {
+            let mut writer = new();
+            build(&mut writer);
+            let estimate = writer.estimated_size();
+            let (_, info) = writer.finish_with_info().unwrap();
+            assert!(info.size <= estimate, "{} > {}", info.size, estimate);
+        }
+    }
+
+    #[test]
+    fn test_manifest() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.mkdir_p("etc/ssh").unwrap();
+        writer
+            .write_file(b"localhost", "etc/hostname", 0o644)
+            .unwrap();
+        writer
+            .write_file(&[0u8; 10000], "etc/ssh/key", 0o600)
+            .unwrap();
+        writer.symlink("etc/hostname", "hostname").unwrap();
+        let entry = |path: &str, file_type, mode, size, inode| ManifestEntry {
+            path: path.to_string(),
+            file_type,
+            mode,
+            size,
+            uid: 0,
+            gid: 0,
+            inode,
+        };
+        let manifest = writer.manifest();
+        assert_eq!(
+            manifest,
+            [
+                entry("", FileType::Directory, 0o755, 0, 2),
+                entry("lost+found", FileType::Directory, 0o700, 0, 11),
+                entry("etc", FileType::Directory, 0o755, 0, 15),
+                entry("etc/ssh", FileType::Directory, 0o755, 0, 16),
+                entry("etc/ssh/key", FileType::RegularFile, 0o600, 10000, 13),
+                entry("etc/hostname", FileType::RegularFile, 0o644, 9, 12),
+                entry("hostname", FileType::SymbolicLink, 0o777, 12, 14),
+            ]
+        );
+        // the predicted inode numbers match the finished image
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        for entry in &manifest[1..] {
+            assert_eq!(
+                reader.lookup(&entry.path).unwrap(),
+                entry.inode,
+                "{}",
+                entry.path
+            );
+        }
+    }
+
+    test_create_fs!(test_ext4_image_writer_group_flags, |writer| {
+        writer.set_group_flags(0, 0x4).unwrap(); // EXT4_BG_INODE_ZEROED
+    });
+
+    #[test]
+    fn test_set_group_flags() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        assert!(writer.set_group_flags(8, 0x4).is_err());
+        writer.set_group_flags(0, 0x4).unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert_eq!(reader.read_block_group_descriptor(0).unwrap().flags(), 0x4);
+
+        // the image only has a single block group
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.set_group_flags(1, 0x4).unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    test_create_fs!(test_ext4_image_writer_sparse_groups, |writer| {
+        writer.write_file(b"near", "near.txt", 0o644).unwrap();
+        writer
+            .write_file_at_block(b"far away", "far.txt", 0o644, 5 * BLOCK_SIZE * 8 + 100)
+            .unwrap();
+    });
+
+    #[test]
+    fn test_uninitialized_groups() {
+        let file_name = "target/test_uninitialized_groups.img";
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_name)
+            .unwrap();
+        let mut writer = Ext4ImageWriter::new(file, 1024 * 1024 * 1024);
+        writer
+            .write_file_at_block(b"far away", "far.txt", 0o644, 5 * BLOCK_SIZE * 8 + 10)
+            .unwrap();
+        writer.set_group_flags(3, 0x4).unwrap();
+        writer.finish().unwrap();
+        run_e2fsck(file_name);
+
+        let mut reader = Ext4ImageReader::new(open_read_write(file_name)).unwrap();
+        let flags = (0..6)
+            .map(|group| reader.read_block_group_descriptor(group).unwrap().flags())
+            .collect::<Vec<_>>();
+        // groups with the metadata, data or a superblock backup are initialized, explicitly set flags win
+        assert_eq!(flags, [0, 0, 0x7, 0x4, 0x7, 0]);
+
+        // the groups stay uninitialized when adding to the image
+        let mut writer = Ext4ImageWriter::open(open_read_write(file_name)).unwrap();
+        writer.write_file(b"near", "near.txt", 0o644).unwrap();
+        writer.finish().unwrap();
+        run_e2fsck(file_name);
+        let mut reader = Ext4ImageReader::new(open_read_write(file_name)).unwrap();
+        assert_eq!(reader.read_block_group_descriptor(2).unwrap().flags(), 0x7);
+        assert_eq!(reader.read_file("far.txt").unwrap(), b"far away");
+    }
+
+    fn open_read_write(file_name: &str) -> std::fs::File {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(file_name)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_open_and_append() {
+        let file_name = "target/test_open_and_append.img";
+        let _ = std::fs::remove_file(file_name);
+        let big: Vec<u8> = (0..300000u32).map(|i| (i % 251) as u8).collect();
+        let mut writer = Ext4ImageWriter::new(
+            std::fs::File::create(file_name).unwrap(),
+            1024 * 1024 * 1024,
+        );
+        writer.mkdir_p("etc/ssh").unwrap();
+        writer
+            .write_file(b"localhost", "etc/hostname", 0o644)
+            .unwrap();
+        writer.write_file(&big, "etc/ssh/big.bin", 0o600).unwrap();
+        writer.symlink("etc/hostname", "hostname").unwrap();
+        writer.symlink(&"x/".repeat(100), "slow").unwrap();
+        // more than four fragments need an extent tree block
+        for i in 1..=5 {
+            writer
+                .write_file_at_block(b"x", &format!("pinned-{i}"), 0o644, i * 100)
+                .unwrap();
+        }
+        writer.write_file(&big, "fragmented.bin", 0o644).unwrap();
+        writer.set_lost_found_mode(0o750);
+        writer.finish().unwrap();
+
+        for round in 0..2 {
+            let mut writer = Ext4ImageWriter::open(open_read_write(file_name)).unwrap();
+            writer
+                .write_file(&big, &format!("etc/new-{round}.bin"), 0o644)
+                .unwrap();
+            writer.mkdir(&format!("dir-{round}")).unwrap();
+            let file = writer.finish().unwrap();
+            file.set_len(Ext4ImageReader::new(&file).unwrap().minimal_size())
+                .unwrap();
+            run_e2fsck(file_name);
+        }
+
+        let mut reader = Ext4ImageReader::new(std::fs::File::open(file_name).unwrap()).unwrap();
+        assert_eq!(reader.read_file("etc/hostname").unwrap(), b"localhost");
+        assert_eq!(reader.read_file("etc/ssh/big.bin").unwrap(), big);
+        assert_eq!(reader.read_file("fragmented.bin").unwrap(), big);
+        assert_eq!(
+            reader.read_file("slow").unwrap(),
+            "x/".repeat(100).as_bytes()
+        );
+        assert_eq!(reader.read_file("etc/new-0.bin").unwrap(), big);
+        assert_eq!(reader.read_file("etc/new-1.bin").unwrap(), big);
+        let lost_found = reader.lookup("lost+found").unwrap();
+        assert_eq!(reader.read_inode(lost_found).unwrap().mode(), 0o750);
+        assert!(reader.lookup("dir-1").is_ok());
+    }
+
+    #[test]
+    fn test_open_keeps_settings() {
+        let file_name = "target/test_open_keeps_settings.img";
+        let _ = std::fs::remove_file(file_name);
+        let mut writer = Ext4ImageWriter::new(
+            std::fs::File::create(file_name).unwrap(),
+            1024 * 1024 * 1024,
+        )
+        .with_fixed_group_metadata()
+        .unwrap()
+        .with_directory_hash_signedness(false);
+        writer.reserve_inodes(5).unwrap();
+        writer.mkdir("secret").unwrap();
+        writer
+            .set_encryption_policy("secret", &fscrypt_context_v2(1))
+            .unwrap();
+        writer.finish().unwrap();
+
+        let mut writer = Ext4ImageWriter::open(open_read_write(file_name)).unwrap();
+        assert!(writer.fixed_group_metadata);
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer.finish().unwrap();
+        run_e2fsck(file_name);
+        let mut reader = Ext4ImageReader::new(std::fs::File::open(file_name).unwrap()).unwrap();
+        assert_eq!(reader.superblock().flags() & 0x3, 0x2);
+        assert_eq!(reader.lookup("lost+found").unwrap(), 16);
+        let secret = reader.lookup("secret").unwrap();
+        assert_eq!(
+            reader.read_inode(secret).unwrap().xattr(9, b"c").unwrap(),
+            Some(fscrypt_context_v2(1))
+        );
+        assert_eq!(reader.read_file("hello.txt").unwrap(), b"hello");
+    }
+
+    test_create_fs!(test_ext4_image_writer_mkdir_p_twice, |writer| {
+        writer.mkdir_p("a/b").unwrap();
+        writer.write_file(b"hello", "a/b/hello.txt", 0o644).unwrap();
+        writer.mkdir_p("a/b").unwrap();
+        assert!(writer.mkdir_p("a/b/hello.txt").is_err());
+    });
+
+    test_create_fs!(test_ext4_image_writer_inline_dirs, |writer| {
+        writer.mkdir("dir").unwrap();
+        writer.write_file(&[], "dir/longer_entry", 0o755).unwrap();
+        writer.write_file(&[], "dir/short_entry", 0o755).unwrap();
+        writer.write_file(&[], "dir/over_the_edge", 0o755).unwrap();
+    });
+
+    /// Attributes that fit into the inode, ones that need an xattr block and ones on directories.
+    fn add_xattrs<A: BlockAllocator>(writer: &mut Ext4ImageWriter<impl io::Write + io::Seek, A>) {
+        writer.mkdir("dir").unwrap();
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer.write_file(&[0x42; 10000], "big.bin", 0o644).unwrap();
+        writer.symlink("hello.txt", "link").unwrap();
+        writer
+            .set_xattr("hello.txt", "user.comment", b"hi")
+            .unwrap();
+        writer
+            .set_xattr(
+                "big.bin",
+                "security.selinux",
+                b"system_u:object_r:bin_t:s0\0",
+            )
+            .unwrap();
+        writer
+            .set_xattr("big.bin", "user.large", &[7; 1000])
+            .unwrap();
+        writer.set_xattr("big.bin", "trusted.a", b"").unwrap();
+        writer
+            .set_xattr("link", "user.comment", b"symlink")
+            .unwrap();
+        writer.set_xattr("dir", "user.comment", &[1; 200]).unwrap();
+        writer.set_xattr("", "user.root", b"root").unwrap();
+    }
+
+    test_create_fs!(test_ext4_image_writer_xattrs, |writer| {
+        add_xattrs(&mut writer);
+    });
+
+    test_create_fs!(
+        test_ext4_image_writer_ext2_xattrs,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .ext2_compat()
+            .unwrap(),
+        |writer| {
+            add_xattrs(&mut writer);
+        }
+    );
+
+    #[test]
+    fn test_xattr_round_trip() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        add_xattrs(&mut writer);
+        writer
+            .set_xattr("hello.txt", "user.comment", b"replaced")
+            .unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert_eq!(
+            reader.read_xattrs("hello.txt").unwrap(),
+            vec![("user.comment".to_string(), b"replaced".to_vec())]
+        );
+        let mut xattrs = reader.read_xattrs("big.bin").unwrap();
+        xattrs.sort();
+        assert_eq!(
+            xattrs,
+            vec![
+                (
+                    "security.selinux".to_string(),
+                    b"system_u:object_r:bin_t:s0\0".to_vec()
+                ),
+                ("trusted.a".to_string(), vec![]),
+                ("user.large".to_string(), vec![7; 1000]),
+            ]
+        );
+        // the large value does not fit into the inode
+        let inode_num = reader.lookup("big.bin").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert_ne!(inode.file_acl(), 0);
+        assert_eq!(inode.blocks(), 4 * (BLOCK_SIZE / 512));
+        assert_eq!(
+            reader.read_xattrs("dir").unwrap(),
+            vec![("user.comment".to_string(), vec![1; 200])]
+        );
+        assert_eq!(
+            reader.read_xattrs("").unwrap(),
+            vec![("user.root".to_string(), b"root".to_vec())]
+        );
+        assert_eq!(reader.read_xattrs("lost+found").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_set_xattr_errors() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        assert!(matches!(
+            writer.set_xattr("hello.txt", "comment", b""),
+            Err(Ext4Error::Invalid(_))
+        ));
+        assert!(matches!(
+            writer.set_xattr("hello.txt", "user.", b""),
+            Err(Ext4Error::Invalid(_))
+        ));
+        assert!(matches!(
+            writer.set_xattr("hello.txt", "system.data", b""),
+            Err(Ext4Error::Invalid(_))
+        ));
+        assert!(matches!(
+            writer.set_xattr("hello.txt", &format!("user.{}", "a".repeat(256)), b""),
+            Err(Ext4Error::NameTooLong(_))
+        ));
+        assert!(matches!(
+            writer.set_xattr("hello.txt", "user.big", &[0; 4096]),
+            Err(Ext4Error::ContentTooLarge(4096))
+        ));
+        assert!(matches!(
+            writer.set_xattr("missing", "user.comment", b""),
+            Err(Ext4Error::NotFound(_))
+        ));
+        // each attribute fits into a block on its own, but not all of them together
+        writer.set_xattr("hello.txt", "user.a", &[0; 3000]).unwrap();
+        writer.set_xattr("hello.txt", "user.b", &[0; 3000]).unwrap();
+        assert!(matches!(
+            writer.finish(),
+            Err(Ext4Error::ContentTooLarge(6002))
+        ));
+    }
+
+    /// Contents spanning the direct blocks, the single indirect tree and part of the double indirect tree.
+    fn ext2_large_contents() -> Vec<u8> {
+        (0..(12 + 1024 + 100) * BLOCK_SIZE as usize + 123)
+            .map(|i| (i / BLOCK_SIZE as usize) as u8 ^ i as u8)
+            .collect()
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_ext2_compat,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024 * 128)
+            .ext2_compat()
+            .unwrap(),
+        |writer| {
+            writer.write_file(&[], "empty", 0o644).unwrap();
+            writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+            writer.mkdir_p("a/b").unwrap();
+            writer
+                .write_file(&ext2_large_contents(), "a/b/large", 0o644)
+                .unwrap();
+            writer.symlink("hello.txt", "fast").unwrap();
+            writer.symlink(&"x/".repeat(100), "slow").unwrap();
+        }
+    );
+
+    #[test]
+    fn test_ext2_compat() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .ext2_compat()
+            .unwrap();
+        writer.write_file(&[], "empty", 0o644).unwrap();
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer
+            .write_file(&ext2_large_contents(), "large", 0o644)
+            .unwrap();
+        writer.mkdir("dir").unwrap();
+        writer.symlink("hello.txt", "fast").unwrap();
+        writer.symlink(&"x/".repeat(100), "slow").unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert_eq!(reader.superblock().feature_incompat(), 0x2); // filetype
+        assert_eq!(reader.superblock().feature_ro_compat(), 0x3); // sparse_super and large_file
+        assert_eq!(reader.superblock().desc_size(), 32);
+        assert_eq!(reader.superblock().inode_size(), 128);
+        for path in ["hello.txt", "large", "dir", "slow"] {
+            let inode_num = reader.lookup(path).unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            assert_eq!(
+                inode.storage_mode(BLOCK_SIZE),
+                StorageMode::BlockMap,
+                "{}",
+                path
+            );
+        }
+        assert_eq!(reader.read_file("empty").unwrap(), b"");
+        assert_eq!(reader.read_file("hello.txt").unwrap(), b"hello");
+        assert_eq!(reader.read_file("large").unwrap(), ext2_large_contents());
+        assert_eq!(reader.read_file("fast").unwrap(), b"hello.txt");
+        assert_eq!(
+            reader.read_file("slow").unwrap(),
+            "x/".repeat(100).as_bytes()
+        );
+        // 1137 data blocks, one single indirect block and a double indirect block with one child
+        let large = reader.lookup("large").unwrap();
+        let large = reader.read_inode(large).unwrap();
+        assert_eq!(large.blocks(), (12 + 1024 + 101 + 1 + 1 + 1) * 8);
+    }
+
+    #[test]
+    fn test_ext2_compat_restrictions() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        assert!(matches!(writer.ext2_compat(), Err(Ext4Error::TooLate(_))));
+
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .ext2_compat()
+            .unwrap();
+        writer.mkdir("secret").unwrap();
+        writer
+            .set_encryption_policy("secret", &fscrypt_context_v2(1))
+            .unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_rev0,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_revision(Revision::Rev0)
+            .unwrap(),
+        |writer| {
+            writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+            writer
+                .write_file(&ext2_large_contents(), "large", 0o644)
+                .unwrap();
+            writer.mkdir("dir").unwrap();
+            // more entries than fit into a block, which stay linear without dir_index
+            for i in 0..300 {
+                writer
+                    .write_file(b"", &format!("dir/file-with-a-long-name-{}", i), 0o644)
+                    .unwrap();
+            }
+            writer.symlink("hello.txt", "fast").unwrap();
+            // spread the image over four groups, group 2 only has a backup without sparse_super
+            writer
+                .write_file_at_block(b"kernel", "vmlinuz", 0o644, 3 * 32768 + 2000)
                 .unwrap();
+        },
+        |image: Cursor<Vec<u8>>| {
+            let mut reader = Ext4ImageReader::new(image).unwrap();
+            let superblock = reader.superblock().clone();
+            assert_eq!(superblock.rev_level(), 0);
+            assert_eq!(superblock.feature_incompat(), 0);
+            assert_eq!(superblock.feature_ro_compat(), 0);
+            assert!(!superblock.has_resize_inode());
+            assert_eq!(superblock.reserved_gdt_blocks(), 0);
+            assert_eq!(superblock.inode_size(), 128);
+            assert_eq!(superblock.block_groups_count(), 4);
+            assert!(superblock.group_has_super(2));
+            let resize_inode = reader.read_inode(7).unwrap();
+            assert_eq!(resize_inode.blocks(), 0);
+            assert!(resize_inode.block().iter().all(|&byte| byte == 0));
+            let dir = reader.lookup("dir").unwrap();
+            let dir = reader.read_inode(dir).unwrap();
+            assert_eq!(dir.flags() & 0x1000, 0); // EXT4_INDEX_FL
+            let entries = reader.read_directory_entries(&dir).unwrap();
+            assert_eq!(entries.len(), 302);
+            assert!(entries.iter().all(|entry| entry.entry_type() == 0));
+            assert_eq!(reader.read_file("hello.txt").unwrap(), b"hello");
+            assert_eq!(reader.read_file("large").unwrap(), ext2_large_contents());
+            assert_eq!(reader.read_file("vmlinuz").unwrap(), b"kernel");
         }
-    });
+    );
 
-    test_create_fs!(test_ext4_image_writer_zero_size_file, |writer| {
-        let zero_size_file = vec![];
+    #[test]
+    fn test_revision_0_restrictions() {
+        let writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .with_revision(Revision::Rev0)
+            .unwrap();
+        assert!(matches!(
+            writer.with_revision(Revision::Dynamic),
+            Err(Ext4Error::UnsupportedByRevision0(_))
+        ));
+
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .with_revision(Revision::Rev0)
+            .unwrap();
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer.set_xattr("hello.txt", "user.a", b"b").unwrap();
+        assert!(matches!(
+            writer.finish_to_vec(),
+            Err(Ext4Error::UnsupportedByRevision0(_))
+        ));
+
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .with_revision(Revision::Rev0)
+            .unwrap();
+        writer.reserve_inodes(5).unwrap();
+        assert!(matches!(
+            writer.finish_to_vec(),
+            Err(Ext4Error::UnsupportedByRevision0(_))
+        ));
+    }
+
+    /// Records all events as strings in a list that is shared with the test.
+    struct RecordingObserver(std::rc::Rc<std::cell::RefCell<Vec<String>>>);
+    impl Observer for RecordingObserver {
+        fn on_file_added(&mut self, path: &str, inode: u64) {
+            self.0.borrow_mut().push(format!("file {} {}", path, inode));
+        }
+        fn on_block_group_finalized(&mut self, block_group: u64) {
+            self.0.borrow_mut().push(format!("group {}", block_group));
+        }
+        fn on_warning(&mut self, message: &str) {
+            self.0.borrow_mut().push(format!("warning {}", message));
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let events = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .with_observer(Box::new(RecordingObserver(events.clone())));
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer.mkdir("dir").unwrap();
+        writer.symlink("../hello.txt", "dir/link").unwrap();
         writer
-            .write_file(&zero_size_file, "zero_size_file.bin", 0o644)
+            .write_file_at_block(b"kernel", "vmlinuz", 0o100644, 1000)
             .unwrap();
-    });
+        let (_, info) = writer.finish_with_info().unwrap();
+        assert_eq!(info.block_groups, 1);
+        assert_eq!(
+            *events.borrow(),
+            [
+                "file hello.txt 12",
+                "file dir/link 13",
+                "warning ignoring the file type bits of mode 0o100644 for 'vmlinuz'",
+                "file vmlinuz 14",
+                "group 0",
+            ]
+        );
+    }
 
-    test_create_fs!(test_ext4_image_writer_big_file, |writer| {
-        let big_file = vec![0xABu8; 1024 * 1024 * 1024];
+    #[test]
+    fn test_flex_group_metadata_layout() {
+        for (log_groups_per_flex, flex_groups) in [(4, 16), (1, 2)] {
+            let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+                .with_block_size(1024)
+                .unwrap()
+                .with_flex_bg_size(log_groups_per_flex)
+                .unwrap();
+            writer
+                .write_file(&vec![1; 20 * 1024 * 1024], "big", 0o644)
+                .unwrap();
+            let (image, info) = writer.finish_with_info().unwrap();
+            fsck_lite(image.get_ref()).unwrap();
+            assert_eq!(info.block_groups, 3);
+            let mut reader = Ext4ImageReader::new(image).unwrap();
+            let descriptors: Vec<_> = (0..info.block_groups as u32)
+                .map(|group| reader.read_block_group_descriptor(group).unwrap())
+                .collect();
+            let inode_table_blocks = (info.inodes_per_group * 256).div_ceil(1024);
+            // the block bitmaps of a flex group are followed by its inode bitmaps and then its inode tables
+            for flex_group in descriptors.chunks(flex_groups) {
+                let first = &flex_group[0];
+                let groups = flex_group.len() as u64;
+                for (i, descriptor) in flex_group.iter().enumerate() {
+                    let i = i as u64;
+                    assert_eq!(descriptor.block_bitmap(), first.block_bitmap() + i);
+                    assert_eq!(descriptor.inode_bitmap(), first.block_bitmap() + groups + i);
+                    assert_eq!(
+                        descriptor.inode_table(),
+                        first.block_bitmap() + 2 * groups + i * inode_table_blocks
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_finish_with_progress() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .with_block_size(1024)
+            .unwrap();
+        writer
+            .write_file(&vec![1; 20 * 1024 * 1024], "big", 0o644)
+            .unwrap();
+        let mut events = vec![];
+        let image = writer
+            .finish_with_progress(|stage, done, total| events.push((stage, done, total)))
+            .unwrap();
+        fsck_lite(image.get_ref()).unwrap();
+        assert_eq!(
+            events,
+            [
+                (FinishStage::BlockGroup, 1, 3),
+                (FinishStage::BlockGroup, 2, 3),
+                (FinishStage::BlockGroup, 3, 3),
+                (FinishStage::GroupDescriptors, 3, 3),
+                (FinishStage::Superblock, 3, 3),
+            ]
+        );
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_tiny,
+        |file| Ext4ImageWriter::new(file, 256 * 1024),
+        |writer| {
+            writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+            writer.mkdir("etc").unwrap();
+            writer
+                .write_file(&[0x42; 10000], "etc/overlay.conf", 0o644)
+                .unwrap();
+        }
+    );
+
+    #[test]
+    fn test_tiny_image() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 256 * 1024);
+        writer.write_file(&[0x42; 10000], "data", 0o644).unwrap();
+        let (image, info) = writer.finish_with_info().unwrap();
+        assert_eq!(info.block_groups, 1);
+        assert!(info.size <= 256 * 1024, "{} bytes", info.size);
+        let mut reader = Ext4ImageReader::new(image).unwrap();
+        assert_eq!(reader.read_file("data").unwrap(), [0x42; 10000]);
+        let bgd = reader.read_block_group_descriptor(0).unwrap();
+        assert_eq!(bgd.free_blocks_count() as u64, info.free_blocks_count);
+    }
+
+    #[test]
+    fn test_uuid_checksum_seed() {
+        let file_name = "target/test_uuid_checksum_seed.img";
+        let file = std::fs::File::create(file_name).unwrap();
+        let mut writer = Ext4ImageWriter::new(file, 1024 * 1024 * 1024);
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        let (_, info) = writer.finish_with_info().unwrap();
+        // e2fsprogs computes the seed itself when switching to metadata_csum_seed
+        let status = std::process::Command::new("tune2fs")
+            .args(["-O", "metadata_csum_seed", file_name])
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        run_e2fsck(file_name);
+        let reader = Ext4ImageReader::new(std::fs::File::open(file_name).unwrap()).unwrap();
+        assert_eq!(
+            reader.superblock().checksum_seed(),
+            uuid_checksum_seed(&info.uuid)
+        );
+        assert_eq!(uuid_checksum_seed(&info.uuid), 0xa66087bd);
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_random_uuid,
+        |file| Ext4ImageWriter::new_with_random_uuid(file, 1024 * 1024 * 1024).unwrap(),
+        |writer| {
+            writer.mkdir("dir").unwrap();
+            writer
+                .write_file(&[0x42; 100000], "dir/big", 0o644)
+                .unwrap();
+        }
+    );
+
+    #[test]
+    fn test_random_uuid() {
+        let writer =
+            Ext4ImageWriter::new_with_random_uuid(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+                .unwrap();
+        let (_, info) = writer.finish_with_info().unwrap();
+        let (_, other) = Ext4ImageWriter::new_with_random_uuid(Cursor::new(Vec::new()), 1 << 30)
+            .unwrap()
+            .finish_with_info()
+            .unwrap();
+        assert_ne!(info.uuid, other.uuid);
+        for uuid in [info.uuid, other.uuid] {
+            assert_eq!(uuid[6] >> 4, 4); // version
+            assert_eq!(uuid[8] >> 6, 0b10); // variant
+        }
+
+        let uuid = [7; 16];
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .with_uuid(uuid)
+            .unwrap();
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        let (image, info) = writer.finish_with_info().unwrap();
+        assert_eq!(info.uuid, uuid);
+        fsck_lite(image.get_ref()).unwrap();
+
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        assert!(matches!(writer.with_uuid(uuid), Err(Ext4Error::TooLate(_))));
+    }
+
+    fn build_block_size_test_fs<W: io::Write + io::Seek>(writer: &mut Ext4ImageWriter<W>) {
+        // spans several block groups of small blocks and needs more than one extent
+        let big_file: Vec<u8> = (0..40 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
         writer.write_file(&big_file, "big-file.bin", 0o644).unwrap();
+        writer.mkdir("files").unwrap();
+        for i in 0..300 {
+            writer
+                .write_file(&vec![i as u8; i * 10], &format!("files/{i}"), 0o644)
+                .unwrap();
+        }
+        writer.symlink(&"long/".repeat(100), "long-link").unwrap();
+        writer
+            .set_xattr("files/299", "user.large", &[0x5a; 700])
+            .unwrap();
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_1k_blocks,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_block_size(1024)
+            .unwrap(),
+        |writer| { build_block_size_test_fs(&mut writer) }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_128_byte_inodes,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_inode_size(128)
+            .unwrap(),
+        |writer| {
+            build_block_size_test_fs(&mut writer);
+            writer.write_file(b"", "empty", 0o644).unwrap();
+            writer.mkdir("empty-dir").unwrap();
+            writer.link("files/1", "hard-link").unwrap();
+            writer.symlink("files/1", "fast-link").unwrap();
+            writer.set_xattr("empty-dir", "user.small", b"x").unwrap();
+        }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_128_byte_inodes_1k_blocks_fixed,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_block_size(1024)
+            .unwrap()
+            .with_inode_size(128)
+            .unwrap()
+            .with_fixed_group_metadata()
+            .unwrap(),
+        |writer| { build_block_size_test_fs(&mut writer) }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_128_byte_inodes_ext2,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_inode_size(128)
+            .unwrap()
+            .ext2_compat()
+            .unwrap(),
+        |writer| { build_block_size_test_fs(&mut writer) }
+    );
+
+    #[test]
+    fn test_128_byte_inodes() {
+        let new = || Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        assert!(matches!(
+            new().with_inode_size(1024),
+            Err(Ext4Error::Invalid(_))
+        ));
+        let mut writer = new();
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        assert!(matches!(
+            writer.with_inode_size(128),
+            Err(Ext4Error::TooLate(_))
+        ));
+
+        let mut writer = new().with_inode_size(128).unwrap();
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer.mkdir("dir").unwrap();
+        writer
+            .set_xattr("hello.txt", "user.comment", b"hi")
+            .unwrap();
+        writer.set_xattr("dir", "user.comment", b"dir").unwrap();
+        // no inline data without room for the system.data xattr
+        assert_eq!(
+            writer.storage_mode_of("hello.txt").unwrap(),
+            StorageMode::InlineExtents
+        );
+        let (image, info) = writer.finish_with_info().unwrap();
+        fsck_lite(image.get_ref()).unwrap();
+        let mut reader = Ext4ImageReader::new(image.clone()).unwrap();
+        let superblock = reader.superblock().clone();
+        assert_eq!(superblock.inode_size(), 128);
+        let inode_table_blocks =
+            (superblock.inodes_per_group() as u64 * 128).div_ceil(info.block_size);
+        // the inode table is only followed by the double indirect block of the resize inode
+        assert_eq!(
+            reader.read_block_group_descriptor(0).unwrap().inode_table() + inode_table_blocks + 1,
+            info.blocks_count
+        );
+        assert_eq!(reader.read_file("hello.txt").unwrap(), b"hello");
+        let inode_num = reader.lookup("hello.txt").unwrap();
+        assert_ne!(reader.read_inode(inode_num).unwrap().file_acl(), 0);
+
+        // opened images keep their inode size
+        let mut writer = Ext4ImageWriter::open(image).unwrap();
+        writer.write_file(b"more", "more.txt", 0o644).unwrap();
+        let image = writer.finish().unwrap();
+        fsck_lite(image.get_ref()).unwrap();
+        let mut reader = Ext4ImageReader::new(image).unwrap();
+        assert_eq!(reader.superblock().inode_size(), 128);
+        assert_eq!(reader.read_file("hello.txt").unwrap(), b"hello");
+        // the extended attributes in xattr blocks are kept as well
+        for path in ["hello.txt", "dir"] {
+            assert_eq!(reader.read_xattrs(path).unwrap().len(), 1, "{}", path);
+        }
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_512_byte_inodes,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_inode_size(512)
+            .unwrap(),
+        |writer| {
+            build_block_size_test_fs(&mut writer);
+            writer.write_file(b"inline", "inline", 0o644).unwrap();
+            writer.mkdir("inline-dir").unwrap();
+            writer.set_xattr("inline", "user.small", b"x").unwrap();
+        }
+    );
+
+    #[test]
+    fn test_512_byte_inodes() {
+        let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024)
+            .with_inode_size(512)
+            .unwrap();
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer.mkdir("dir").unwrap();
+        writer
+            .set_xattr("hello.txt", "user.comment", b"hi")
+            .unwrap();
+        assert_eq!(
+            writer.storage_mode_of("hello.txt").unwrap(),
+            StorageMode::Inline
+        );
+        let (image, info) = writer.finish_with_info().unwrap();
+        fsck_lite(image.get_ref()).unwrap();
+        assert!(verify(image.clone()).unwrap().is_ok());
+        let mut reader = Ext4ImageReader::new(image.clone()).unwrap();
+        let superblock = reader.superblock().clone();
+        assert_eq!(superblock.inode_size(), 512);
+        let inode_table_blocks =
+            (superblock.inodes_per_group() as u64 * 512).div_ceil(info.block_size);
+        assert_eq!(
+            reader.read_block_group_descriptor(0).unwrap().inode_table() + inode_table_blocks + 1,
+            info.blocks_count
+        );
+        assert_eq!(reader.read_file("hello.txt").unwrap(), b"hello");
+        assert_eq!(reader.read_xattrs("hello.txt").unwrap().len(), 1);
+
+        // opened images keep their inode size
+        let mut writer = Ext4ImageWriter::open(image).unwrap();
+        writer.write_file(b"more", "more.txt", 0o644).unwrap();
+        let image = writer.finish().unwrap();
+        fsck_lite(image.get_ref()).unwrap();
+        let mut reader = Ext4ImageReader::new(image).unwrap();
+        assert_eq!(reader.superblock().inode_size(), 512);
+        assert_eq!(reader.read_file("more.txt").unwrap(), b"more");
+    }
+
+    #[test]
+    fn test_inline_directory_uses_512_byte_inodes() {
+        for (inode_size, inline) in [(256, false), (512, true)] {
+            let file_name = format!(
+                "target/test_inline_directory_{}_byte_inodes.img",
+                inode_size
+            );
+            let file = std::fs::File::create(&file_name).unwrap();
+            let mut writer = Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+                .with_inode_size(inode_size)
+                .unwrap();
+            writer.mkdir("dir").unwrap();
+            // more entries than fit into 256 byte inodes, but few enough for 512 byte ones
+            for i in 0..12 {
+                writer
+                    .write_file(b"", &format!("dir/entry-{:02}", i), 0o644)
+                    .unwrap();
+            }
+            writer.finish().unwrap();
+            run_e2fsck(&file_name);
+            let mut reader =
+                Ext4ImageReader::new(std::fs::File::open(&file_name).unwrap()).unwrap();
+            let inode_num = reader.lookup("dir").unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            assert_eq!(inode.has_inline_data(), inline, "{}", inode_size);
+            assert_eq!(inode.blocks(), if inline { 0 } else { 8 }, "{}", inode_size);
+            assert_eq!(reader.read_file("dir/entry-11").unwrap(), b"");
+        }
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_2k_blocks,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_block_size(2048)
+            .unwrap(),
+        |writer| { build_block_size_test_fs(&mut writer) }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_1k_blocks_fixed_group_metadata,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_block_size(1024)
+            .unwrap()
+            .with_fixed_group_metadata()
+            .unwrap(),
+        |writer| { build_block_size_test_fs(&mut writer) }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_1k_blocks_ext2,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_block_size(1024)
+            .unwrap()
+            .ext2_compat()
+            .unwrap(),
+        |writer| { build_block_size_test_fs(&mut writer) }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_2k_blocks_rev0,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_block_size(2048)
+            .unwrap()
+            .with_revision(Revision::Rev0)
+            .unwrap(),
+        |writer| {
+            // the data runs past the superblock backups and the metadata at the start of each group
+            let big_file: Vec<u8> = (0..80 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+            writer.write_file(&big_file, "big-file.bin", 0o644).unwrap();
+        }
+    );
+
+    #[test]
+    fn test_block_size() {
+        for block_size in [1024, 2048, 4096] {
+            let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 64 * 1024 * 1024)
+                .with_block_size(block_size)
+                .unwrap();
+            let contents: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+            writer.write_file(&contents, "data.bin", 0o644).unwrap();
+            let (image, info) = writer.finish_with_info().unwrap();
+            assert_eq!(info.block_size, block_size);
+            assert_eq!(info.size, info.blocks_count * block_size);
+            fsck_lite(image.get_ref()).unwrap();
+
+            let mut reader = Ext4ImageReader::new(Cursor::new(image.get_ref())).unwrap();
+            let superblock = reader.superblock();
+            assert_eq!(superblock.block_size(), block_size);
+            assert_eq!(superblock.first_data_block(), (block_size == 1024) as u64);
+            assert_eq!(reader.read_file("data.bin").unwrap(), contents);
+
+            // the block size is taken over when adding to the image
+            let mut writer = Ext4ImageWriter::open(image).unwrap();
+            writer.write_file(b"more", "more.txt", 0o644).unwrap();
+            let (image, info) = writer.finish_with_info().unwrap();
+            assert_eq!(info.block_size, block_size);
+            fsck_lite(image.get_ref()).unwrap();
+            let mut reader = Ext4ImageReader::new(image).unwrap();
+            assert_eq!(reader.read_file("data.bin").unwrap(), contents);
+            assert_eq!(reader.read_file("more.txt").unwrap(), b"more");
+        }
+    }
+
+    #[test]
+    fn test_block_size_errors() {
+        let new = || Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        assert!(matches!(
+            new().with_block_size(512),
+            Err(Ext4Error::Invalid(_))
+        ));
+        // the GDT for 128 GiB needs more blocks than the resize inode can list with 1024 byte blocks
+        assert!(matches!(
+            Ext4ImageWriter::new(Cursor::new(Vec::new()), 128 * 1024 * 1024 * 1024)
+                .with_block_size(1024),
+            Err(Ext4Error::Invalid(_))
+        ));
+        assert!(matches!(
+            new()
+                .with_fixed_group_metadata()
+                .unwrap()
+                .with_block_size(1024),
+            Err(Ext4Error::TooLate(_))
+        ));
+        let mut writer = new();
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        assert!(matches!(
+            writer.with_block_size(1024),
+            Err(Ext4Error::TooLate(_))
+        ));
+        // raw directory blocks are always 4096 bytes large
+        let mut writer = new().with_block_size(2048).unwrap();
+        writer.mkdir("raw").unwrap();
+        assert!(matches!(
+            writer.write_directory_raw("raw", &[handcrafted_directory_block()]),
+            Err(Ext4Error::InvalidRawDirectory(_))
+        ));
+    }
+
+    #[test]
+    fn test_inode_numbers_beyond_u32() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        // pretend that the inode numbers after 13 don't fit into 32 bits
+        writer.max_inodes = 13;
+        writer.write_file(b"a", "a", 0o644).unwrap();
+        writer.symlink("a", "b").unwrap();
+        assert!(matches!(
+            writer.write_file(b"c", "c", 0o644),
+            Err(Ext4Error::TooManyInodes(13))
+        ));
+        assert!(matches!(
+            writer.symlink("a", "c"),
+            Err(Ext4Error::TooManyInodes(13))
+        ));
+        assert!(matches!(
+            writer.write_file_at_block(b"c", "c", 0o644, 100),
+            Err(Ext4Error::TooManyInodes(13))
+        ));
+        assert!(writer.finish().is_err());
+
+        // directory inodes are only allocated when finishing
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.max_inodes = 13;
+        writer.write_file(b"a", "a", 0o644).unwrap();
+        writer.mkdir("dir").unwrap();
+        writer.mkdir("dir2").unwrap();
+        assert!(matches!(writer.finish(), Err(Ext4Error::TooManyInodes(13))));
+    }
+
+    test_create_fs!(test_ext4_image_writer_directory_modes, |writer| {
+        writer.mkdir_p("usr/bin").unwrap();
+        writer.mkdir("tmp").unwrap();
+        writer.mkdir("home").unwrap();
+        writer
+            .write_file(b"hello", "home/hello.txt", 0o644)
+            .unwrap();
+        writer.set_directory_mode("", 0o750).unwrap();
+        writer.set_directory_mode("usr/bin", 0o711).unwrap();
+        writer.set_directory_mode("tmp", 0o1777).unwrap();
+        writer.set_directory_owner("home", 1000, 100).unwrap();
+        writer.set_directory_mode("lost+found", 0o755).unwrap();
     });
 
-    test_create_fs!(test_ext4_image_writer_inline_dirs, |writer| {
+    #[test]
+    fn test_mkdir_with() {
+        let file_name = "target/test_mkdir_with.img";
+        let mut writer = Ext4ImageWriter::new(
+            std::fs::File::create(file_name).unwrap(),
+            1024 * 1024 * 1024,
+        );
+        writer.mkdir_with("private", 0o700, 1000, 1000).unwrap();
+        writer
+            .write_file_with_owner(b"secret", "private/key", 0o600, 1000, 1000)
+            .unwrap();
+        assert!(matches!(
+            writer.mkdir_with("private", 0o755, 0, 0),
+            Err(Ext4Error::PathExists(_))
+        ));
+        assert!(matches!(
+            writer.mkdir_with("missing/dir", 0o755, 0, 0),
+            Err(Ext4Error::ParentMissing(_))
+        ));
+        writer.finish().unwrap();
+        run_e2fsck(file_name);
+
+        let mut reader = Ext4ImageReader::new(std::fs::File::open(file_name).unwrap()).unwrap();
+        let inode_num = reader.lookup("private").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert!(inode.is_directory());
+        assert_eq!(
+            (inode.mode(), inode.uid(), inode.gid()),
+            (0o700, 1000, 1000)
+        );
+        assert_eq!(reader.read_file("private/key").unwrap(), b"secret");
+    }
+
+    #[test]
+    fn test_chmod() {
+        let file_name = "target/test_chmod.img";
+        let mut writer = Ext4ImageWriter::new(
+            std::fs::File::create(file_name).unwrap(),
+            1024 * 1024 * 1024,
+        );
+        writer.write_file(b"secret", "key", 0o644).unwrap();
+        writer.link("key", "key-link").unwrap();
         writer.mkdir("dir").unwrap();
-        writer.write_file(&[], "dir/longer_entry", 0o755).unwrap();
-        writer.write_file(&[], "dir/short_entry", 0o755).unwrap();
-        writer.write_file(&[], "dir/over_the_edge", 0o755).unwrap();
+        writer.chmod("key", 0o600).unwrap();
+        writer.chmod("dir", 0o700).unwrap();
+        writer.chmod("", 0o750).unwrap();
+        assert!(matches!(
+            writer.chmod("missing", 0o644),
+            Err(Ext4Error::NotFound(_))
+        ));
+        let modes: Vec<_> = writer
+            .manifest()
+            .into_iter()
+            .map(|entry| (entry.path, entry.mode))
+            .filter(|(path, _)| path != "lost+found")
+            .collect();
+        let expected = [
+            ("", 0o750),
+            ("key", 0o600),
+            ("key-link", 0o600),
+            ("dir", 0o700),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(path, mode)| (path.to_string(), mode))
+            .collect();
+        assert_eq!(modes, expected);
+        writer.finish().unwrap();
+        run_e2fsck(file_name);
+
+        let mut reader = Ext4ImageReader::new(std::fs::File::open(file_name).unwrap()).unwrap();
+        for (path, mode) in expected {
+            let inode_num = reader.lookup(&path).unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            assert_eq!(inode.mode(), mode, "{}", path);
+        }
+        let inode_num = reader.lookup("key").unwrap();
+        assert_eq!(
+            reader.read_inode(inode_num).unwrap().file_type(),
+            FileType::RegularFile
+        );
+    }
+
+    #[test]
+    fn test_directory_metadata() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.mkdir_p("usr/bin").unwrap();
+        writer.mkdir("tmp").unwrap();
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        writer.set_directory_mode("", 0o750).unwrap();
+        writer.set_directory_mode("usr/bin", 0o711).unwrap();
+        writer.set_directory_mode("tmp", 0o1777).unwrap();
+        writer.set_directory_owner("tmp", 1000, 100).unwrap();
+        writer.set_directory_mode("lost+found", 0o750).unwrap();
+        let timestamps = Timestamps {
+            mtime: Timestamp::from_seconds(1_700_000_000),
+            ..Default::default()
+        };
+        writer.set_timestamps("usr", timestamps).unwrap();
+        assert!(matches!(
+            writer.set_directory_mode("hello.txt", 0o755),
+            Err(Ext4Error::NotADirectory(_))
+        ));
+        assert!(matches!(
+            writer.set_directory_owner("missing", 0, 0),
+            Err(Ext4Error::NotFound(_))
+        ));
+
+        let modes: Vec<_> = writer
+            .manifest()
+            .into_iter()
+            .filter(|entry| entry.file_type == FileType::Directory)
+            .map(|entry| (entry.path, entry.mode, entry.uid, entry.gid))
+            .collect();
+        let expected = [
+            ("", 0o750, 0, 0),
+            ("lost+found", 0o750, 0, 0),
+            ("usr", 0o755, 0, 0),
+            ("usr/bin", 0o711, 0, 0),
+            ("tmp", 0o1777, 1000, 100),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(path, mode, uid, gid)| (path.to_string(), mode, uid, gid))
+            .collect();
+        assert_eq!(modes, expected);
+
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        for (path, mode, uid, gid) in expected {
+            let inode_num = reader.lookup(&path).unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            assert_eq!(
+                (inode.mode(), inode.uid(), inode.gid()),
+                (mode, uid, gid),
+                "{}",
+                path
+            );
+        }
+        let usr = reader.lookup("usr").unwrap();
+        assert_eq!(reader.read_inode(usr).unwrap().mtime(), (1_700_000_000, 0));
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_special_bits,
+        |writer| {
+            writer.mkdir_p("usr/bin").unwrap();
+            writer.mkdir("tmp").unwrap();
+            writer.mkdir("shared").unwrap();
+            writer
+                .write_file(b"#!/bin/sh", "usr/bin/su", 0o4755)
+                .unwrap();
+            writer
+                .write_file(&[0x42; 10000], "usr/bin/wall", 0o2755)
+                .unwrap();
+            writer.write_file(b"all", "usr/bin/all", 0o7777).unwrap();
+            writer.set_directory_mode("tmp", 0o1777).unwrap();
+            writer.set_directory_mode("shared", 0o2775).unwrap();
+        },
+        |image: Cursor<Vec<u8>>| {
+            // opening the image and finishing it again keeps the bits as well
+            let image = Ext4ImageWriter::open(image).unwrap().finish().unwrap();
+            fsck_lite(image.get_ref()).unwrap();
+            let mut reader = Ext4ImageReader::new(image).unwrap();
+            for (path, file_type, mode) in [
+                ("usr/bin/su", FileType::RegularFile, 0o4755),
+                ("usr/bin/wall", FileType::RegularFile, 0o2755),
+                ("usr/bin/all", FileType::RegularFile, 0o7777),
+                ("tmp", FileType::Directory, 0o1777),
+                ("shared", FileType::Directory, 0o2775),
+            ] {
+                let inode_num = reader.lookup(path).unwrap();
+                let inode = reader.read_inode(inode_num).unwrap();
+                assert_eq!(
+                    (inode.file_type(), inode.mode()),
+                    (file_type, mode),
+                    "{}",
+                    path
+                );
+            }
+        }
+    );
+
+    #[test]
+    fn test_open_keeps_directory_modes() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.mkdir("tmp").unwrap();
+        writer.set_directory_mode("", 0o700).unwrap();
+        writer.set_directory_mode("tmp", 0o1777).unwrap();
+        writer.set_directory_owner("tmp", 1000, 100).unwrap();
+        let image = writer.finish().unwrap();
+
+        let mut writer = Ext4ImageWriter::open(image).unwrap();
+        writer.set_lost_found_mode(0o750);
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        for (path, mode, uid, gid) in [
+            ("", 0o700, 0, 0),
+            ("tmp", 0o1777, 1000, 100),
+            ("lost+found", 0o750, 0, 0),
+        ] {
+            let inode_num = reader.lookup(path).unwrap();
+            let inode = reader.read_inode(inode_num).unwrap();
+            assert_eq!(
+                (inode.mode(), inode.uid(), inode.gid()),
+                (mode, uid, gid),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_open_keeps_directory_metadata() {
+        let file_name = "target/test_open_keeps_directory_metadata.img";
+        let _ = std::fs::remove_file(file_name);
+        let mut writer = Ext4ImageWriter::new(
+            std::fs::File::create(file_name).unwrap(),
+            1024 * 1024 * 1024,
+        );
+        writer.mkdir("dir").unwrap();
+        // too many entries to be inline, which leaves room for more xattrs
+        for i in 0..8 {
+            let path = format!("dir/a_rather_long_file_name_{}", i);
+            writer.write_file(b"hello", &path, 0o644).unwrap();
+        }
+        writer.set_directory_mode("dir", 0o700).unwrap();
+        writer.set_directory_owner("dir", 1000, 1000).unwrap();
+        let timestamps = Timestamps {
+            atime: Timestamp::from_seconds(1_600_000_000),
+            mtime: Timestamp::from_seconds(1_700_000_000),
+            ctime: Timestamp::from_seconds(1_700_000_001),
+            crtime: Some(Timestamp::from_seconds(1_500_000_000)),
+        };
+        writer.set_timestamps("dir", timestamps).unwrap();
+        let dir = writer
+            .manifest()
+            .into_iter()
+            .find(|entry| entry.path == "dir")
+            .unwrap()
+            .inode;
+        let comment = Ext4Xattr::new(1, b"comment", b"keep me"); // user.comment
+        writer
+            .finish_with_inode_hook(|inode_num, inode| {
+                if inode_num == dir {
+                    inode
+                        .add_xattr(comment.clone(), Ext4Inode::DEFAULT_SIZE)
+                        .unwrap();
+                }
+            })
+            .unwrap();
+        run_e2fsck(file_name);
+
+        let mut writer = Ext4ImageWriter::open(open_read_write(file_name)).unwrap();
+        writer.write_file(b"world", "dir/world.txt", 0o644).unwrap();
+        writer.finish().unwrap();
+        run_e2fsck(file_name);
+        let mut reader = Ext4ImageReader::new(std::fs::File::open(file_name).unwrap()).unwrap();
+        let inode_num = reader.lookup("dir").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert_eq!(
+            (inode.mode(), inode.uid(), inode.gid()),
+            (0o700, 1000, 1000)
+        );
+        assert_eq!(inode.timestamps(), timestamps);
+        assert_eq!(
+            inode.xattr(1, b"comment").unwrap(),
+            Some(b"keep me".to_vec())
+        );
+        assert_eq!(reader.read_file("dir/world.txt").unwrap(), b"world");
+    }
+
+    #[test]
+    fn test_error_variants() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        let error = writer.write_file(b"hello", "hello.txt", 0o644).unwrap_err();
+        assert!(matches!(&error, Ext4Error::PathExists(path) if path == "hello.txt"));
+        // the variant survives the conversion into an io::Error
+        let error = io::Error::from(error);
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert!(matches!(Ext4Error::from(error), Ext4Error::PathExists(_)));
+        assert!(matches!(
+            writer.write_file(b"", "missing/file", 0o644),
+            Err(Ext4Error::ParentMissing(_))
+        ));
+        assert!(matches!(
+            writer.mkdir(&"x".repeat(256)),
+            Err(Ext4Error::NameTooLong(_))
+        ));
+        assert!(matches!(
+            writer.symlink(&"x".repeat(4096), "link"),
+            Err(Ext4Error::ContentTooLarge(4096))
+        ));
+        assert!(matches!(
+            writer.write_file_at_block(b"x", "taken", 0o644, 0),
+            Err(Ext4Error::BlockUnavailable(_))
+        ));
+
+        // more data than the GDT reserved for the maximum size can describe
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024);
+        writer
+            .write_file(&vec![0; 130 * 1024 * 1024], "big", 0o644)
+            .unwrap();
+        let error = writer.finish().unwrap_err();
+        assert!(matches!(
+            error,
+            Ext4Error::TooManyBlockGroups { needed: 2, max: 1 }
+        ));
+        assert_eq!(
+            io::Error::from(error).to_string(),
+            "the image needs 2 block groups but the maximum size only allows 1, try increasing it"
+        );
+
+        // so many block groups that the resize inode can't list all GDT blocks
+        let writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 16 << 40);
+        assert!(matches!(writer.finish(), Err(Ext4Error::Invalid(_))));
+    }
+
+    fn build_journal_test_fs<W: io::Write + io::Seek>(writer: &mut Ext4ImageWriter<W>) {
+        writer.set_journal_blocks(1024).unwrap();
+        writer.mkdir("etc").unwrap();
+        writer
+            .write_file(b"root:x:0:0::/root:/bin/sh", "etc/passwd", 0o644)
+            .unwrap();
+        writer
+            .write_file(&[0x42; 100000], "data.bin", 0o644)
+            .unwrap();
+        // enough entries that the root directory needs a block
+        for i in 0..10 {
+            writer
+                .write_file(b"log", &format!("var-log-{}", i), 0o644)
+                .unwrap();
+        }
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_journal,
+        |writer| { build_journal_test_fs(&mut writer) },
+        |image: Cursor<Vec<u8>>| {
+            // the journal is allocated anew when the image is opened and finished again
+            let image = Ext4ImageWriter::open(image).unwrap().finish().unwrap();
+            fsck_lite(image.get_ref()).unwrap();
+            let mut reader = Ext4ImageReader::new(image).unwrap();
+            assert!(reader.superblock().has_journal());
+            let journal = reader.read_inode(8).unwrap();
+            assert_eq!(journal.size(), 1024 * 4096);
+            assert_eq!(journal.mode(), 0o600);
+            let contents = reader.read_file_contents(&journal).unwrap();
+            let journal_superblock = JournalSuperBlock::parse(&contents[..1024]).unwrap();
+            assert_eq!(journal_superblock.max_len, 1024);
+            assert!(contents[1024..].iter().all(|&b| b == 0));
+            assert_eq!(reader.read_file("data.bin").unwrap(), [0x42; 100000]);
+        }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_journal_1k_fixed,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_block_size(1024)
+            .unwrap()
+            .with_fixed_group_metadata()
+            .unwrap(),
+        |writer| {
+            build_journal_test_fs(&mut writer);
+            // larger than a block group of 8192 blocks, so it is split around the group metadata
+            writer.set_journal_blocks(20000).unwrap();
+        }
+    );
+
+    #[test]
+    fn test_journal() {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        for invalid in [1, 1023, 10_240_001] {
+            assert!(matches!(
+                writer.set_journal_blocks(invalid),
+                Err(Ext4Error::Invalid(_))
+            ));
+        }
+
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024)
+            .ext2_compat()
+            .unwrap();
+        assert!(matches!(
+            writer.set_journal_blocks(1024),
+            Err(Ext4Error::UnsupportedByExt2(_))
+        ));
+        // removing the journal again
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        writer.set_journal_blocks(1024).unwrap();
+        writer.set_journal_blocks(0).unwrap();
+        let reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert!(!reader.superblock().has_journal());
+    }
+
+    fn build_quota_test_fs<W: io::Write + io::Seek>(writer: &mut Ext4ImageWriter<W>) {
+        writer.set_quota(true).unwrap();
+        writer.mkdir_p("home/alice").unwrap();
+        writer
+            .set_directory_owner("home/alice", 1000, 1000)
+            .unwrap();
+        writer
+            .write_file_with_owner(&[1; 10000], "home/alice/notes", 0o644, 1000, 1000)
+            .unwrap();
+        writer
+            .write_file_with_owner(b"inline", "home/alice/small", 0o644, 1000, 100)
+            .unwrap();
+        writer
+            .set_xattr("home/alice/small", "user.big", &[7; 800])
+            .unwrap();
+        writer.symlink("alice/notes", "home/link").unwrap();
+        // more ids than fit into one data block of the quota tree, some far apart
+        for i in 0..40 {
+            let id = 2000 + i * 70_001;
+            writer
+                .write_file_with_owner(&[2; 5000], &format!("user-{i}"), 0o600, id, id + 1)
+                .unwrap();
+        }
+    }
+
+    test_create_fs!(
+        test_ext4_image_writer_quota,
+        |writer| { build_quota_test_fs(&mut writer) },
+        |image: Cursor<Vec<u8>>| {
+            let image = image.into_inner();
+            let mut reader = Ext4ImageReader::new(Cursor::new(image.clone())).unwrap();
+            let superblock = reader.superblock().clone();
+            assert!(superblock.has_quota());
+            let [usr_inode, grp_inode, prj_inode] = superblock.quota_inodes();
+            assert_eq!((usr_inode, grp_inode), (3, 4));
+            assert!(prj_inode >= superblock.first_ino());
+            let quota_file = |reader: &mut Ext4ImageReader<_>, inode_num, quota_type| {
+                let inode = reader.read_inode(inode_num).unwrap();
+                assert_eq!(inode.mode(), 0o600);
+                assert_ne!(inode.flags() & Ext4Inode::IMMUTABLE_FL, 0);
+                let contents = reader.read_file_contents(&inode).unwrap();
+                QuotaFile::parse(&contents, quota_type).unwrap().usage
+            };
+            let users = quota_file(&mut reader, usr_inode, 0);
+            // the inline directory, the file with three data blocks and the one with an xattr block
+            assert_eq!(
+                users[&1000],
+                QuotaUsage {
+                    inodes: 3,
+                    space: (3 + 1) * 4096
+                }
+            );
+            assert_eq!(users.len(), 2 + 40);
+            let groups = quota_file(&mut reader, grp_inode, 1);
+            assert_eq!(groups[&100].inodes, 1);
+            let projects = quota_file(&mut reader, prj_inode, 2);
+            assert_eq!(projects.len(), 1);
+            assert_eq!(
+                projects[&0].inodes,
+                users.values().map(|usage| usage.inodes).sum::<u64>()
+            );
+
+            // the usage is recomputed after opening the image and adding more files
+            let mut writer = Ext4ImageWriter::open(Cursor::new(image)).unwrap();
+            writer
+                .write_file_with_owner(&[3; 4096], "home/alice/more", 0o644, 1000, 1000)
+                .unwrap();
+            let image = writer.finish().unwrap().into_inner();
+            fsck_lite(&image).unwrap();
+            let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+            let users = quota_file(&mut reader, usr_inode, 0);
+            assert_eq!(users[&1000].inodes, 4);
+        }
+    );
+
+    test_create_fs!(
+        test_ext4_image_writer_quota_1k_small_inodes,
+        |file| Ext4ImageWriter::new(file, 1024 * 1024 * 1024)
+            .with_block_size(1024)
+            .unwrap()
+            .with_inode_size(128)
+            .unwrap(),
+        |writer| {
+            build_quota_test_fs(&mut writer);
+            writer.set_journal_blocks(1024).unwrap();
+        }
+    );
+
+    #[test]
+    fn test_quota() {
+        let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024)
+            .ext2_compat()
+            .unwrap();
+        assert!(matches!(
+            writer.set_quota(true),
+            Err(Ext4Error::UnsupportedByExt2(_))
+        ));
+    }
+
+    test_create_fs!(test_ext4_image_writer_project_ids, |writer| {
+        writer.set_quota(true).unwrap();
+        writer.mkdir_p("tenants/a").unwrap();
+        writer.mkdir("tenants/b").unwrap();
+        writer
+            .write_file(&[1; 10000], "tenants/a/data", 0o644)
+            .unwrap();
+        writer.write_file(b"b", "tenants/b/data", 0o644).unwrap();
+        writer.set_project_id("tenants/a", 42).unwrap();
+        writer.set_project_id("tenants/a/data", 42).unwrap();
+        writer.set_project_id("tenants/b", 7).unwrap();
     });
+
+    #[test]
+    fn test_project_id() {
+        let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024);
+        writer.mkdir("tenant").unwrap();
+        writer.write_file(b"x", "tenant/file", 0o644).unwrap();
+        writer.set_project_id("tenant", 42).unwrap();
+        writer.set_project_id("tenant/file", 42).unwrap();
+        assert!(matches!(
+            writer.set_project_id("missing", 1),
+            Err(Ext4Error::NotFound(_))
+        ));
+        let image = writer.finish_to_vec().unwrap();
+        fsck_lite(&image).unwrap();
+        let mut reader = Ext4ImageReader::new(Cursor::new(image.clone())).unwrap();
+        assert!(reader.superblock().has_project());
+        assert!(!reader.superblock().has_quota());
+        let mut inode = |path| {
+            let inode_num = reader.lookup(path).unwrap();
+            reader.read_inode(inode_num).unwrap()
+        };
+        let directory = inode("tenant");
+        assert_eq!(directory.projid(), 42);
+        assert_ne!(directory.flags() & Ext4Inode::PROJINHERIT_FL, 0);
+        let file = inode("tenant/file");
+        assert_eq!(file.projid(), 42);
+        assert_eq!(file.flags() & Ext4Inode::PROJINHERIT_FL, 0);
+        assert_eq!(inode("lost+found").projid(), 0);
+
+        // project ids survive opening the image again
+        let mut writer = Ext4ImageWriter::open(Cursor::new(image)).unwrap();
+        writer.write_file(b"y", "other", 0o644).unwrap();
+        let mut reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert!(reader.superblock().has_project());
+        let inode_num = reader.lookup("tenant").unwrap();
+        assert_eq!(reader.read_inode(inode_num).unwrap().projid(), 42);
+
+        // without a non-zero id the feature stays off
+        let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024);
+        writer.set_project_id("lost+found", 0).unwrap();
+        let reader = Ext4ImageReader::new(writer.finish().unwrap()).unwrap();
+        assert!(!reader.superblock().has_project());
+
+        let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024)
+            .with_inode_size(128)
+            .unwrap();
+        assert!(matches!(
+            writer.set_project_id("", 1),
+            Err(Ext4Error::InodeTooSmall { .. })
+        ));
+
+        // ext2 has no project feature
+        let mut writer = Ext4ImageWriter::new_in_memory(1024 * 1024 * 1024)
+            .ext2_compat()
+            .unwrap();
+        assert!(matches!(
+            writer.set_project_id("", 1),
+            Err(Ext4Error::UnsupportedByExt2("project ids"))
+        ));
+        writer.project_ids = true;
+        assert!(matches!(
+            writer.finish(),
+            Err(Ext4Error::UnsupportedByExt2(_))
+        ));
+    }
 }
@@ -0,0 +1,132 @@
+const NANOS_PER_SECOND: u32 = 1_000_000_000;
+
+/// A point in time, stored with nanosecond precision like ext4 does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    /// Seconds since the unix epoch. ext4 can represent the years 1901 to 2446.
+    pub seconds: i64,
+    /// Nanoseconds within the second. Whole seconds beyond 999,999,999 are carried into `seconds` when encoding.
+    pub nanoseconds: u32,
+}
+impl Timestamp {
+    pub fn from_seconds(seconds: i64) -> Self {
+        Timestamp {
+            seconds,
+            nanoseconds: 0,
+        }
+    }
+
+    /// A timestamp of `seconds` and `nanoseconds`, where whole seconds in `nanoseconds` are carried into `seconds`.
+    pub fn new(seconds: i64, nanoseconds: u32) -> Self {
+        Timestamp {
+            seconds: seconds + (nanoseconds / NANOS_PER_SECOND) as i64,
+            nanoseconds: nanoseconds % NANOS_PER_SECOND,
+        }
+    }
+
+    /// Encode into the 32 bit seconds field and the `_extra` field (`nsec << 2 | epoch`) of an inode.
+    /// The field only has 30 bits for the nanoseconds, so they are normalized first.
+    pub(crate) fn encode(self) -> (u32, u32) {
+        let Timestamp {
+            seconds,
+            nanoseconds,
+        } = Self::new(self.seconds, self.nanoseconds);
+        let epoch = ((seconds - seconds as i32 as i64) >> 32) as u32 & 0b11;
+        (seconds as u32, (nanoseconds << 2) | epoch)
+    }
+
+    /// The inverse of [`Timestamp::encode`].
+    pub(crate) fn decode(seconds: u32, extra: u32) -> Self {
+        Timestamp {
+            seconds: seconds as i32 as i64 + (((extra & 0b11) as i64) << 32),
+            nanoseconds: extra >> 2,
+        }
+    }
+}
+
+/// The timestamps of an inode.
+/// If `crtime` (the creation / birth time) is not set, it defaults to `mtime`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timestamps {
+    pub atime: Timestamp,
+    pub mtime: Timestamp,
+    pub ctime: Timestamp,
+    pub crtime: Option<Timestamp>,
+}
+impl Timestamps {
+    /// Use the same timestamp for all fields.
+    pub fn all(timestamp: Timestamp) -> Self {
+        Timestamps {
+            atime: timestamp,
+            mtime: timestamp,
+            ctime: timestamp,
+            crtime: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_encoding() {
+        assert_eq!(Timestamp::from_seconds(0).encode(), (0, 0));
+        assert_eq!(
+            Timestamp::from_seconds(1758215058).encode(),
+            (1758215058, 0)
+        );
+        let pre_1970 = Timestamp::from_seconds(-1);
+        assert_eq!(pre_1970.encode(), (u32::MAX, 0));
+        // 2038-01-19T03:14:08Z is the first second that needs the epoch bits
+        assert_eq!(
+            Timestamp::from_seconds(i32::MAX as i64 + 1).encode(),
+            (1 << 31, 1)
+        );
+        let with_nanos = Timestamp {
+            seconds: 10,
+            nanoseconds: 999_999_999,
+        };
+        assert_eq!(with_nanos.encode(), (10, 999_999_999 << 2));
+    }
+
+    #[test]
+    fn test_timestamp_nanosecond_carry() {
+        assert_eq!(
+            Timestamp::new(10, 2_500_000_000),
+            Timestamp {
+                seconds: 12,
+                nanoseconds: 500_000_000
+            }
+        );
+        let in_range = Timestamp::new(-1, 999_999_999);
+        assert_eq!((in_range.seconds, in_range.nanoseconds), (-1, 999_999_999));
+        // without the carry, the nanoseconds would overflow into the epoch bits
+        let overflowing = Timestamp {
+            seconds: 10,
+            nanoseconds: u32::MAX,
+        };
+        let (lo, extra) = overflowing.encode();
+        assert_eq!((lo, extra & 0b11), (14, 0));
+        assert_eq!(Timestamp::decode(lo, extra), Timestamp::new(10, u32::MAX));
+    }
+
+    #[test]
+    fn test_timestamp_decoding() {
+        for seconds in [
+            0,
+            1758215058,
+            -1,
+            i32::MIN as i64,
+            i32::MAX as i64 + 1,
+            1 << 33,
+        ] {
+            let timestamp = Timestamp {
+                seconds,
+                nanoseconds: 123_456_789,
+            };
+            let (lo, extra) = timestamp.encode();
+            assert_eq!(Timestamp::decode(lo, extra), timestamp);
+        }
+    }
+}
@@ -0,0 +1,29 @@
+/// Receives events while [`crate::Ext4ImageWriter`] builds an image, i.e. to show progress or to log.
+/// Install one with [`crate::Ext4ImageWriter::with_observer`]. All callbacks default to doing nothing,
+/// so implementations only need to override the ones they are interested in.
+pub trait Observer {
+    /// A file or symlink was added at `path` (as passed by the caller) with the inode number `inode`.
+    fn on_file_added(&mut self, _path: &str, _inode: u64) {}
+
+    /// The bitmaps, the inode table and the descriptor of `block_group` were written while finishing.
+    fn on_block_group_finalized(&mut self, _block_group: u64) {}
+
+    /// Something was accepted but not fully honored, i.e. input that was silently adjusted.
+    fn on_warning(&mut self, _message: &str) {}
+}
+
+/// The default [`Observer`] which ignores all events.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct NoopObserver;
+impl Observer for NoopObserver {}
+
+/// What [`crate::Ext4ImageWriter::finish_with_progress`] has just written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishStage {
+    /// The bitmaps and the inode table of a block group. Reported once per block group.
+    BlockGroup,
+    /// The block group descriptor table.
+    GroupDescriptors,
+    /// The superblock and its backups, after which the image is complete.
+    Superblock,
+}
@@ -0,0 +1,432 @@
+use crate::{
+    Allocation, Ext4Error,
+    ext4_h::*,
+    serialization::{Buffer, CheckMagic},
+};
+use std::io::{self, SeekFrom};
+
+/// Reads files back from an ext4 image produced by [`crate::Ext4ImageWriter`].
+/// Only the subset of ext4 that this crate emits is supported.
+pub struct Ext4ImageReader<R: io::Read + io::Seek> {
+    reader: R,
+    superblock: Ext4SuperBlock,
+}
+impl<R: io::Read + io::Seek> Ext4ImageReader<R> {
+    /// Open an image for reading. This reads and validates the superblock.
+    pub fn new(mut reader: R) -> Result<Self, Ext4Error> {
+        let mut buf = [0u8; Ext4SuperBlock::SIZE as usize];
+        reader.seek(SeekFrom::Start(1024))?;
+        reader.read_exact(&mut buf)?;
+        let superblock = Ext4SuperBlock::read_buffer(&buf);
+        superblock.check_magic().map_err(malformed)?;
+        superblock.check_block_size().map_err(malformed)?;
+        Ok(Self { reader, superblock })
+    }
+
+    /// The size of the filesystem in bytes. An image file or device can be truncated to this size,
+    /// i.e. with [`std::fs::File::set_len`] after writing into a preallocated file.
+    pub fn minimal_size(&self) -> u64 {
+        self.superblock.blocks_count() * self.block_size()
+    }
+
+    /// Read the contents of the regular file at the given path.
+    /// The path must use '/' as the separator.
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, Ext4Error> {
+        let inode_num = self.lookup(path)?;
+        let inode = self.read_inode(inode_num)?;
+        if inode.is_directory() {
+            return Err(Ext4Error::IsADirectory(path.to_string()));
+        }
+        self.read_file_contents(&inode)
+    }
+
+    /// Check that the bits past the end of each group in its block and inode bitmap are all set,
+    /// as e2fsck requires.
+    pub fn check_bitmap_padding(&mut self) -> Result<(), Ext4Error> {
+        let block_size = self.block_size();
+        let data_blocks = self.superblock.blocks_count() - self.superblock.first_data_block();
+        let blocks_per_group = block_size * 8;
+        let inodes_per_group = self.superblock.inodes_per_group();
+        for group in 0..self.superblock.block_groups_count() {
+            let bgd = self.read_block_group_descriptor(group)?;
+            let group_blocks =
+                (data_blocks - group as u64 * blocks_per_group).min(blocks_per_group) as u32;
+            for (kind, block, len) in [
+                ("block", bgd.block_bitmap(), group_blocks),
+                ("inode", bgd.inode_bitmap(), inodes_per_group),
+            ] {
+                let buf = self.read_bytes(block * block_size, block_size as usize)?;
+                if !BitmapBlock::read_with_len(&buf, len).validate_padding() {
+                    return Err(Ext4Error::MalformedImage(format!(
+                        "the {} bitmap of group {} is not padded",
+                        kind, group
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the extended attributes of the file or directory at `path` as pairs of their full name
+    /// (i.e. `user.comment`) and value, from the inode body and the xattr block.
+    /// Internal attributes like the `system.data` of inline data and encryption contexts are left out.
+    pub fn read_xattrs(&mut self, path: &str) -> Result<Vec<(String, Vec<u8>)>, Ext4Error> {
+        let inode_num = self.lookup(path)?;
+        let inode = self.read_inode(inode_num)?;
+        Ok(self
+            .inode_xattrs(&inode)?
+            .into_iter()
+            .filter(|xattr| !(xattr.name_index == 7 && xattr.name == b"data"))
+            .filter_map(|xattr| Some((xattr.full_name()?, xattr.value)))
+            .collect())
+    }
+
+    /// All extended attributes of `inode`, from the inode body and the xattr block.
+    pub(crate) fn inode_xattrs(&mut self, inode: &Ext4Inode) -> Result<Vec<Ext4Xattr>, Ext4Error> {
+        let mut xattrs = inode.xattrs().map_err(malformed)?;
+        if inode.file_acl() != 0 {
+            let block = self.read_block(inode.file_acl())?;
+            xattrs.extend(Ext4ExtAttrHeader::parse_block(&block).map_err(malformed)?);
+        }
+        Ok(xattrs)
+    }
+
+    pub(crate) fn superblock(&self) -> &Ext4SuperBlock {
+        &self.superblock
+    }
+
+    fn block_size(&self) -> u64 {
+        self.superblock.block_size()
+    }
+
+    /// Resolve a path to its inode number.
+    pub(crate) fn lookup(&mut self, path: &str) -> Result<u32, Ext4Error> {
+        let mut inode_num = 2;
+        // the path up to the current directory
+        let mut parent = String::new();
+        for part in path.split('/').filter(|s| !s.is_empty()) {
+            let inode = self.read_inode(inode_num)?;
+            if !inode.is_directory() {
+                return Err(Ext4Error::NotADirectory(parent));
+            }
+            inode_num = self
+                .read_directory_entries(&inode)?
+                .into_iter()
+                .find(|entry| entry.name() == part)
+                .map(|entry| entry.inode())
+                .ok_or_else(|| Ext4Error::NotFound(path.to_string()))?;
+            if !parent.is_empty() {
+                parent.push('/');
+            }
+            parent.push_str(part);
+        }
+        Ok(inode_num)
+    }
+
+    pub(crate) fn read_block_group_descriptor(
+        &mut self,
+        block_group: u32,
+    ) -> Result<Ext4BlockGroupDescriptor, Ext4Error> {
+        let desc_size = self.superblock.desc_size() as u64;
+        let gdt_start = self.superblock.first_data_block() + 1;
+        let offset = gdt_start * self.block_size() + block_group as u64 * desc_size;
+        // without the 64bit feature the descriptors are truncated to their lower halves
+        let mut buf = self.read_bytes(offset, desc_size as usize)?;
+        buf.resize(Ext4BlockGroupDescriptor::SIZE as usize, 0);
+        Ok(Ext4BlockGroupDescriptor::read_buffer(&buf))
+    }
+
+    pub(crate) fn read_inode(&mut self, inode_num: u32) -> Result<Ext4Inode, Ext4Error> {
+        if inode_num == 0 || inode_num > self.superblock.inodes_count() {
+            return Err(Ext4Error::MalformedImage(format!(
+                "inode {} does not exist",
+                inode_num
+            )));
+        }
+        let inodes_per_group = self.superblock.inodes_per_group();
+        let block_group = (inode_num - 1) / inodes_per_group;
+        let index = (inode_num - 1) % inodes_per_group;
+        let bgd = self.read_block_group_descriptor(block_group)?;
+        let inode_size = self.superblock.inode_size();
+        let offset = bgd.inode_table() * self.block_size() + index as u64 * inode_size;
+        // 128 byte inodes end before `i_extra_isize`, the remaining fields read as zero
+        let mut buf = self.read_bytes(offset, inode_size as usize)?;
+        buf.resize(Ext4Inode::SIZE as usize, 0);
+        Ok(Ext4Inode::read_buffer(&buf))
+    }
+
+    /// Read the full contents of an inode, either from its inline data or by following its extents.
+    pub(crate) fn read_file_contents(&mut self, inode: &Ext4Inode) -> Result<Vec<u8>, Ext4Error> {
+        if inode.has_inline_data() {
+            return inode.inline_data().map_err(malformed);
+        }
+        let size = inode.size() as usize;
+        if size == 0 {
+            return Ok(vec![]); // empty files may have neither inline data nor extents
+        }
+        let block_size = self.block_size() as usize;
+        if inode.storage_mode(block_size as u64) == StorageMode::NoData
+            && inode.file_type() == FileType::SymbolicLink
+        {
+            return Ok(inode.block()[..size].to_vec()); // fast symlink
+        }
+        if inode.storage_mode(block_size as u64) == StorageMode::NoData {
+            return Ok(vec![0u8; size]); // a block map that only has holes
+        }
+        if inode.storage_mode(block_size as u64) == StorageMode::BlockMap {
+            let mut contents = vec![];
+            let descriptor = LegacyBlockDescriptor::read_buffer(inode.block());
+            for &block in descriptor.direct() {
+                self.read_mapped_block(block, 0, size, &mut contents)?;
+            }
+            for (depth, root) in descriptor.indirect().into_iter().enumerate() {
+                self.read_mapped_block(root, depth as u32 + 1, size, &mut contents)?;
+            }
+            contents.resize(size, 0);
+            return Ok(contents);
+        }
+        let mut contents = vec![0u8; size.div_ceil(block_size) * block_size];
+        for extent in self.read_extents(inode)? {
+            let start = extent.logical_block() as usize * block_size;
+            let len = extent.block_count() as usize * block_size;
+            if start + len > contents.len() {
+                return Err(Ext4Error::MalformedImage(
+                    "extent points past the end of the file".to_string(),
+                ));
+            }
+            let data = self.read_bytes(extent.start() * block_size as u64, len)?;
+            contents[start..start + len].copy_from_slice(&data);
+        }
+        contents.truncate(size);
+        Ok(contents)
+    }
+
+    /// Append the data referenced by a block pointer of a block map, following `depth` levels of
+    /// indirect blocks, until `contents` holds `size` bytes.
+    fn read_mapped_block(
+        &mut self,
+        block: u32,
+        depth: u32,
+        size: usize,
+        contents: &mut Vec<u8>,
+    ) -> Result<(), Ext4Error> {
+        if contents.len() >= size {
+            return Ok(());
+        }
+        if block == 0 {
+            // a hole that spans everything the pointer would map
+            let span = self.block_size() as usize * (self.block_size() as usize / 4).pow(depth);
+            contents.resize((contents.len() + span).min(size), 0);
+            return Ok(());
+        }
+        let data = self.read_block(block as u64)?;
+        if depth == 0 {
+            contents.extend_from_slice(&data);
+            return Ok(());
+        }
+        for pointer in data.chunks(4) {
+            self.read_mapped_block(u32::read_buffer(pointer), depth - 1, size, contents)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_directory_entries(
+        &mut self,
+        inode: &Ext4Inode,
+    ) -> Result<Vec<Ext4DirEntry>, Ext4Error> {
+        let mut entries = vec![];
+        if inode.has_inline_data() {
+            // the first four bytes of the inline data hold the parent inode number instead of a '..' entry
+            let data = inode.inline_data().map_err(malformed)?;
+            let parent = u32::read_buffer(&data[0..4]);
+            entries.push(Ext4DirEntry::new(parent, FileType::Directory, ".."));
+            Self::parse_directory_entries(&data[4..], &mut entries);
+        } else {
+            let data = self.read_file_contents(inode)?;
+            for block in data.chunks(self.block_size() as usize) {
+                Self::parse_directory_entries(block, &mut entries);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn parse_directory_entries(data: &[u8], entries: &mut Vec<Ext4DirEntry>) {
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let entry = Ext4DirEntry::read_buffer(&data[offset..]);
+            if entry.record_length() == 0 {
+                break;
+            }
+            offset += entry.record_length() as usize;
+            if entry.inode() != 0 {
+                entries.push(entry);
+            }
+        }
+    }
+
+    pub(crate) fn read_extents(
+        &mut self,
+        inode: &Ext4Inode,
+    ) -> Result<Vec<Ext4ExtentLeafNode>, Ext4Error> {
+        let mut extents = vec![];
+        self.collect_extents(inode.block(), &mut extents, &mut vec![])?;
+        Ok(extents)
+    }
+
+    /// All blocks owned by `inode`: its data and the blocks of its extent tree.
+    pub(crate) fn read_data_blocks(
+        &mut self,
+        inode: &Ext4Inode,
+    ) -> Result<Vec<Allocation>, Ext4Error> {
+        if !matches!(
+            inode.storage_mode(self.block_size()),
+            StorageMode::InlineExtents | StorageMode::IndirectExtents
+        ) {
+            return Ok(vec![]);
+        }
+        let mut extents = vec![];
+        let mut tree_blocks = vec![];
+        self.collect_extents(inode.block(), &mut extents, &mut tree_blocks)?;
+        Ok(extents
+            .iter()
+            .map(|extent| Allocation::from_start_len(extent.start(), extent.block_count() as u64))
+            .chain(
+                tree_blocks
+                    .into_iter()
+                    .map(|block| Allocation::from_start_len(block, 1)),
+            )
+            .collect())
+    }
+
+    fn collect_extents(
+        &mut self,
+        node: &[u8],
+        extents: &mut Vec<Ext4ExtentLeafNode>,
+        tree_blocks: &mut Vec<u64>,
+    ) -> Result<(), Ext4Error> {
+        let header = Ext4ExtentHeader::read_buffer(node);
+        if !header.has_valid_magic() {
+            return Err(Ext4Error::MalformedImage(
+                "invalid extent header".to_string(),
+            ));
+        }
+        for i in 0..header.entries() as usize {
+            let offset = Ext4ExtentHeader::SIZE as usize + i * Ext4ExtentLeafNode::SIZE as usize;
+            if header.depth() == 0 {
+                extents.push(Ext4ExtentLeafNode::read_buffer(&node[offset..]));
+            } else {
+                let index = Ext4ExtentInternalNode::read_buffer(&node[offset..]);
+                tree_blocks.push(index.leaf());
+                let child = self.read_block(index.leaf())?;
+                self.collect_extents(&child, extents, tree_blocks)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_block(&mut self, block: u64) -> Result<Vec<u8>, Ext4Error> {
+        self.read_bytes(block * self.block_size(), self.block_size() as usize)
+    }
+
+    pub(crate) fn read_bytes(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, Ext4Error> {
+        let mut buf = vec![0u8; len];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Errors of the on-disk structures in [`crate::ext4_h`] are about the image being read.
+fn malformed(error: io::Error) -> Ext4Error {
+    Ext4Error::MalformedImage(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Ext4ImageWriter;
+    use std::io::Cursor;
+
+    fn build_image(build: impl FnOnce(&mut Ext4ImageWriter<Cursor<Vec<u8>>>)) -> Vec<u8> {
+        let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+        build(&mut writer);
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_read_inline_file() {
+        let contents: Vec<u8> = (0..90).collect();
+        let image = build_image(|writer| {
+            writer.write_file(&contents, "inline.bin", 0o644).unwrap();
+        });
+        let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+        let inode_num = reader.lookup("inline.bin").unwrap();
+        let inode = reader.read_inode(inode_num).unwrap();
+        assert!(inode.has_inline_data());
+        assert_eq!(reader.read_file("inline.bin").unwrap(), contents);
+    }
+
+    #[test]
+    fn test_read_short_inline_file() {
+        let image = build_image(|writer| {
+            writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+            writer.write_file(&[], "empty", 0o644).unwrap();
+        });
+        let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+        assert_eq!(reader.read_file("hello.txt").unwrap(), b"hello");
+        assert_eq!(reader.read_file("empty").unwrap(), b"");
+    }
+
+    #[test]
+    fn test_read_extent_file_in_subdirectory() {
+        let contents: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        let image = build_image(|writer| {
+            writer.mkdir_p("a/b").unwrap();
+            writer.write_file(&contents, "a/b/data.bin", 0o644).unwrap();
+        });
+        let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+        assert_eq!(reader.read_file("a/b/data.bin").unwrap(), contents);
+        assert!(matches!(
+            reader.read_file("a/b/missing"),
+            Err(Ext4Error::NotFound(path)) if path == "a/b/missing"
+        ));
+        assert!(matches!(
+            reader.read_file("a/b"),
+            Err(Ext4Error::IsADirectory(path)) if path == "a/b"
+        ));
+        assert!(matches!(
+            reader.read_file("a/b/data.bin/c"),
+            Err(Ext4Error::NotADirectory(path)) if path == "a/b/data.bin"
+        ));
+    }
+
+    #[test]
+    fn test_read_inode_out_of_range() {
+        let image = build_image(|_| {});
+        let mut reader = Ext4ImageReader::new(Cursor::new(image)).unwrap();
+        let inodes_count = reader.superblock().inodes_count();
+        reader.read_inode(inodes_count).unwrap();
+        for inode_num in [0, inodes_count + 1] {
+            let error = reader.read_inode(inode_num).unwrap_err();
+            assert!(matches!(error, Ext4Error::MalformedImage(_)), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_check_bitmap_padding() {
+        let mut image = build_image(|writer| {
+            writer.write_file(b"hello", "hello.txt", 0o644).unwrap();
+        });
+        let mut reader = Ext4ImageReader::new(Cursor::new(&image)).unwrap();
+        reader.check_bitmap_padding().unwrap();
+        // the last bit of the inode bitmap lies past the inodes of the group
+        let inode_bitmap = reader
+            .read_block_group_descriptor(0)
+            .unwrap()
+            .inode_bitmap();
+        let bitmap_end = ((inode_bitmap + 1) * reader.block_size()) as usize;
+        image[bitmap_end - 1] = 0x7f;
+        let mut reader = Ext4ImageReader::new(Cursor::new(&image)).unwrap();
+        assert!(reader.check_bitmap_padding().is_err());
+    }
+}
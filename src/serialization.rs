@@ -80,6 +80,7 @@ macro_rules! impl_buffer_for_array {
 pub(crate) use impl_buffer_for_array;
 
 impl_buffer_for_array!(2, u32, 4);
+impl_buffer_for_array!(3, u32, 4);
 impl_buffer_for_array!(4, u32, 4);
 impl_buffer_for_array!(12, u32, 4);
 impl_buffer_for_array!(17, u32, 4);
@@ -92,7 +93,7 @@ pub struct StaticLenString<const N: usize> {
     pub data: [u8; N],
 }
 impl<const N: usize> StaticLenString<N> {
-    #[cfg(test)]
+    /// Longer strings are truncated.
     pub fn from_str(s: &str) -> Self {
         let mut data = [0u8; N];
         let bytes = s.as_bytes();
@@ -131,7 +132,6 @@ impl<const N: usize> Buffer<N> for StaticLenString<N> {
     }
 }
 
-#[allow(dead_code)]
 pub trait CheckMagic {
     fn check_magic(&self) -> io::Result<()>;
 }
@@ -0,0 +1,47 @@
+//! The image that the tests of [`crate::fsck_lite`] and [`crate::verify`] check and corrupt.
+
+use crate::{BLOCK_SIZE, Ext4ImageWriter};
+use std::io::Cursor;
+
+/// The length of the `fragmented` file, which fills the gaps between the `pinned-*` files.
+pub(crate) const FRAGMENTED_LEN: usize = 800 * 4096;
+
+/// An image with an xattr block, a symlink, an extent tree block, a superblock backup and an indexed
+/// directory. With `ext2`, it is written by [`Ext4ImageWriter::ext2_compat`].
+pub(crate) fn build_image(ext2: bool) -> Vec<u8> {
+    let mut writer = Ext4ImageWriter::new(Cursor::new(Vec::new()), 1024 * 1024 * 1024);
+    if ext2 {
+        writer = writer.ext2_compat().unwrap();
+    }
+    writer.mkdir_p("etc/ssh").unwrap();
+    writer
+        .write_file(b"localhost", "etc/hostname", 0o644)
+        .unwrap();
+    writer
+        .write_file(&[7u8; 10000], "etc/ssh/key", 0o600)
+        .unwrap();
+    writer
+        .set_xattr("etc/ssh/key", "user.comment", &[1u8; 2000])
+        .unwrap();
+    writer.symlink("etc/hostname", "hostname").unwrap();
+    // a file split into more than four extents needs a separate extent tree block,
+    // the pinned blocks lie behind the metadata of the first group in the ext2 layout
+    for i in 1..=5 {
+        writer
+            .write_file_at_block(b"x", &format!("pinned-{}", i), 0o644, 600 + 20 * i)
+            .unwrap();
+    }
+    // a second group holds a backup of the superblock
+    writer
+        .write_file_at_block(b"x", "far", 0o644, BLOCK_SIZE * 8 + 1000)
+        .unwrap();
+    writer
+        .write_file(&vec![0x42; FRAGMENTED_LEN], "fragmented", 0o644)
+        .unwrap();
+    writer.write_file(b"x", "file", 0o644).unwrap();
+    writer.mkdir("many").unwrap();
+    for i in 0..500 {
+        writer.link("file", &format!("many/{:040}", i)).unwrap();
+    }
+    writer.finish().unwrap().into_inner()
+}
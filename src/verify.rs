@@ -0,0 +1,452 @@
+use crate::{Ext4ImageReader, ext4_h::*, serialization::Buffer};
+use std::{
+    fmt,
+    io::{self, Read, Seek},
+};
+
+/// A metadata checksum that does not match the structure it protects, as found by [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChecksumMismatch {
+    /// The primary superblock (in block 0) or one of its backups.
+    Superblock {
+        block: u64,
+    },
+    GroupDescriptor {
+        group: u32,
+    },
+    BlockBitmap {
+        group: u32,
+        block: u64,
+    },
+    InodeBitmap {
+        group: u32,
+        block: u64,
+    },
+    Inode {
+        inode: u32,
+    },
+    ExtentBlock {
+        inode: u32,
+        block: u64,
+    },
+    /// The tail of a linear directory block or of an HTree leaf.
+    DirectoryBlock {
+        inode: u32,
+        block: u64,
+    },
+    /// The root or an interior node of an HTree index.
+    HtreeBlock {
+        inode: u32,
+        block: u64,
+    },
+    XattrBlock {
+        inode: u32,
+        block: u64,
+    },
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumMismatch::Superblock { block } => write!(f, "superblock in block {}", block),
+            ChecksumMismatch::GroupDescriptor { group } => {
+                write!(f, "descriptor of block group {}", group)
+            }
+            ChecksumMismatch::BlockBitmap { group, block } => {
+                write!(f, "block bitmap of group {} in block {}", group, block)
+            }
+            ChecksumMismatch::InodeBitmap { group, block } => {
+                write!(f, "inode bitmap of group {} in block {}", group, block)
+            }
+            ChecksumMismatch::Inode { inode } => write!(f, "inode {}", inode),
+            ChecksumMismatch::ExtentBlock { inode, block } => {
+                write!(f, "extent tree block {} of inode {}", block, inode)
+            }
+            ChecksumMismatch::DirectoryBlock { inode, block } => {
+                write!(f, "directory block {} of inode {}", block, inode)
+            }
+            ChecksumMismatch::HtreeBlock { inode, block } => {
+                write!(f, "HTree block {} of inode {}", block, inode)
+            }
+            ChecksumMismatch::XattrBlock { inode, block } => {
+                write!(f, "xattr block {} of inode {}", block, inode)
+            }
+        }
+    }
+}
+
+/// The result of [`verify`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The number of checksums that were recomputed, 0 for images without metadata_csum (i.e. ext2).
+    pub checked: usize,
+    pub mismatches: Vec<ChecksumMismatch>,
+}
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    fn check(&mut self, matches: bool, mismatch: ChecksumMismatch) {
+        self.checked += 1;
+        if !matches {
+            self.mismatches.push(mismatch);
+        }
+    }
+}
+
+/// Recompute every metadata checksum of an image and compare it with the stored one: the superblock and its backups,
+/// the group descriptors, the bitmaps, the inodes in use and their extent tree, directory, HTree and xattr blocks.
+///
+/// Unlike [`crate::fsck_lite`] this does not need the whole image in memory and does not check the consistency
+/// of the structures beyond what is needed to find the checksums. Structures that can't be parsed at all are
+/// reported as an error.
+pub fn verify(reader: impl Read + Seek) -> io::Result<VerifyReport> {
+    let mut reader = Ext4ImageReader::new(reader)?;
+    let superblock = reader.superblock().clone();
+    let mut report = VerifyReport::default();
+    if !superblock.has_metadata_csum() {
+        return Ok(report);
+    }
+    let uuid = *superblock.uuid();
+    let block_size = superblock.block_size();
+    let blocks_per_group = block_size * 8;
+    let group_start = |group: u64| superblock.first_data_block() + group * blocks_per_group;
+
+    report.check(
+        superblock.checksum_matches(),
+        ChecksumMismatch::Superblock { block: 0 },
+    );
+    for group in 1..superblock.block_groups_count() as u64 {
+        if superblock.group_has_super(group) {
+            let block = group_start(group);
+            let backup = Ext4SuperBlock::read_buffer(&reader.read_block(block)?[..1024]);
+            report.check(
+                backup.checksum_matches(),
+                ChecksumMismatch::Superblock { block },
+            );
+        }
+    }
+
+    let inodes_per_group = superblock.inodes_per_group();
+    let inode_size = superblock.inode_size();
+    let inode_table_blocks = (inodes_per_group as u64 * inode_size).div_ceil(block_size);
+    for group in 0..superblock.block_groups_count() {
+        let descriptor = reader.read_block_group_descriptor(group)?;
+        let mut expected = descriptor.clone();
+        expected.update_descriptor_checksum(&uuid, group, superblock.desc_size());
+        report.check(
+            expected == descriptor,
+            ChecksumMismatch::GroupDescriptor { group },
+        );
+
+        // uninitialized bitmaps are not stored, so they are not checksummed either
+        let flags = descriptor.flags();
+        let len = (superblock.blocks_count() - group_start(group as u64)).min(blocks_per_group);
+        let block_bitmap = match flags & Ext4BlockGroupDescriptor::BLOCK_UNINIT {
+            0 => Some(BitmapBlock::read_with_len(
+                &reader.read_block(descriptor.block_bitmap())?,
+                len as u32,
+            )),
+            _ => None,
+        };
+        let inode_bitmap = match flags & Ext4BlockGroupDescriptor::INODE_UNINIT {
+            0 => Some(BitmapBlock::read_with_len(
+                &reader.read_block(descriptor.inode_bitmap())?,
+                inodes_per_group,
+            )),
+            _ => None,
+        };
+        let mut expected = descriptor.clone();
+        expected.set_bitmap_checksums(
+            &uuid,
+            block_bitmap
+                .as_ref()
+                .unwrap_or(&BitmapBlock::from_bytes(&[], len as u32, block_size)),
+            inode_bitmap.as_ref().unwrap_or(&BitmapBlock::from_bytes(
+                &[],
+                inodes_per_group,
+                block_size,
+            )),
+            superblock.desc_size(),
+        );
+        if block_bitmap.is_some() {
+            report.check(
+                expected.block_bitmap_csum() == descriptor.block_bitmap_csum(),
+                ChecksumMismatch::BlockBitmap {
+                    group,
+                    block: descriptor.block_bitmap(),
+                },
+            );
+        }
+        let Some(inode_bitmap) = inode_bitmap else {
+            continue; // none of the inodes of the group is in use
+        };
+        report.check(
+            expected.inode_bitmap_csum() == descriptor.inode_bitmap_csum(),
+            ChecksumMismatch::InodeBitmap {
+                group,
+                block: descriptor.inode_bitmap(),
+            },
+        );
+
+        let table = reader.read_bytes(
+            descriptor.inode_table() * block_size,
+            (inode_table_blocks * block_size) as usize,
+        )?;
+        for index in 0..inodes_per_group {
+            let inode_num = group * inodes_per_group + index + 1;
+            // reserved inodes are marked as used even if they are zeroed
+            let in_use = match inode_num {
+                2 | 7 => true,
+                8 => superblock.has_journal(),
+                3 | 4 => superblock.has_quota(),
+                _ if inode_num < superblock.first_ino() => false,
+                _ => inode_bitmap.is_set(index),
+            };
+            if !in_use {
+                continue;
+            }
+            let mut buf =
+                table[(index as u64 * inode_size) as usize..][..inode_size as usize].to_vec();
+            buf.resize(Ext4Inode::SIZE as usize, 0);
+            let inode = Ext4Inode::read_buffer(&buf);
+            verify_inode(&mut reader, &mut report, inode_num, &inode)?;
+        }
+    }
+    Ok(report)
+}
+
+/// Check the checksum of an inode and of the blocks that it owns.
+fn verify_inode<R: Read + Seek>(
+    reader: &mut Ext4ImageReader<R>,
+    report: &mut VerifyReport,
+    inode_num: u32,
+    inode: &Ext4Inode,
+) -> io::Result<()> {
+    let superblock = reader.superblock().clone();
+    let uuid = superblock.uuid();
+    let block_size = superblock.block_size();
+    let mut expected = inode.clone();
+    expected.update_checksum_with_size(uuid, inode_num, superblock.inode_size());
+    report.check(
+        expected == *inode,
+        ChecksumMismatch::Inode { inode: inode_num },
+    );
+
+    if inode.file_acl() != 0 {
+        let block = inode.file_acl();
+        let data = reader.read_block(block)?;
+        report.check(
+            Ext4ExtAttrHeader::read_buffer(&data).checksum()
+                == Ext4ExtAttrHeader::block_checksum(&data, block, uuid),
+            ChecksumMismatch::XattrBlock {
+                inode: inode_num,
+                block,
+            },
+        );
+    }
+    if !matches!(
+        inode.storage_mode(block_size),
+        StorageMode::InlineExtents | StorageMode::IndirectExtents
+    ) {
+        return Ok(()); // block maps have no checksums
+    }
+
+    let mut extents = vec![];
+    let mut nodes = vec![inode.block().to_vec()];
+    while let Some(node) = nodes.pop() {
+        let header = Ext4ExtentHeader::read_buffer(&node);
+        if !header.has_valid_magic() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid extent header in inode {}", inode_num),
+            ));
+        }
+        for i in 0..header.entries() as usize {
+            let offset = Ext4ExtentHeader::SIZE as usize + i * Ext4ExtentLeafNode::SIZE as usize;
+            if header.depth() == 0 {
+                extents.push(Ext4ExtentLeafNode::read_buffer(&node[offset..]));
+                continue;
+            }
+            let block = Ext4ExtentInternalNode::read_buffer(&node[offset..]).leaf();
+            let child = reader.read_block(block)?;
+            let child_header = Ext4ExtentHeader::read_buffer(&child);
+            let tail = Ext4ExtentHeader::SIZE as usize
+                + child_header.max_entries() as usize * Ext4ExtentLeafNode::SIZE as usize;
+            if tail + 4 > child.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid extent tree block {} in inode {}", block, inode_num),
+                ));
+            }
+            report.check(
+                u32::read_buffer(&child[tail..])
+                    == Ext4IndirectExtents::block_checksum(
+                        &child[..tail],
+                        inode_num,
+                        inode.generation(),
+                        uuid,
+                    ),
+                ChecksumMismatch::ExtentBlock {
+                    inode: inode_num,
+                    block,
+                },
+            );
+            nodes.push(child);
+        }
+    }
+    if !inode.is_directory() {
+        return Ok(());
+    }
+
+    extents.sort_by_key(Ext4ExtentLeafNode::logical_block);
+    let blocks = extents
+        .iter()
+        .flat_map(|extent| extent.start()..extent.start() + extent.block_count() as u64);
+    let indexed = inode.flags() & 0x1000 != 0; // EXT4_INDEX_FL
+    for (index, block) in blocks.enumerate() {
+        let buf = reader.read_block(block)?;
+        // the interior nodes of an HTree hide behind an empty record that spans the whole block,
+        // while the records of a leaf end in front of the checksum tail
+        let root = indexed && index == 0;
+        let node = indexed
+            && u32::read_buffer(&buf[0..4]) == 0
+            && u16::read_buffer(&buf[4..6]) as u64 == block_size;
+        if root || node {
+            let (tail_offset, checksum) =
+                DxIndexBlock::raw_checksum(&buf, root, uuid, inode_num, inode.generation());
+            report.check(
+                buf.get(tail_offset + 4..tail_offset + 8)
+                    .is_some_and(|stored| u32::read_buffer(stored) == checksum),
+                ChecksumMismatch::HtreeBlock {
+                    inode: inode_num,
+                    block,
+                },
+            );
+        } else {
+            report.check(
+                u32::read_buffer(&buf[buf.len() - 4..])
+                    == LinearDirectoryBlock::raw_checksum(
+                        &buf,
+                        uuid,
+                        inode_num,
+                        inode.generation(),
+                    ),
+                ChecksumMismatch::DirectoryBlock {
+                    inode: inode_num,
+                    block,
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BLOCK_SIZE, test_image::build_image};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_verify_accepts_written_images() {
+        let report = verify(Cursor::new(build_image(false))).unwrap();
+        assert!(report.is_ok(), "{:?}", report.mismatches);
+        assert!(report.checked > 20, "{}", report.checked);
+
+        // there are no checksums to verify without metadata_csum
+        let report = verify(Cursor::new(build_image(true))).unwrap();
+        assert_eq!(report, VerifyReport::default());
+    }
+
+    #[test]
+    fn test_verify_reports_locations() {
+        let image = build_image(false);
+        let mut reader = Ext4ImageReader::new(Cursor::new(&image)).unwrap();
+        let descriptor = reader.read_block_group_descriptor(0).unwrap();
+        let inode_of = |reader: &mut Ext4ImageReader<_>, path| {
+            let inode_num = reader.lookup(path).unwrap();
+            (inode_num, reader.read_inode(inode_num).unwrap())
+        };
+        let (key, key_inode) = inode_of(&mut reader, "etc/ssh/key");
+        let (fragmented, fragmented_inode) = inode_of(&mut reader, "fragmented");
+        let (many, many_inode) = inode_of(&mut reader, "many");
+        let extent_block = reader
+            .read_data_blocks(&fragmented_inode)
+            .unwrap()
+            .last()
+            .unwrap()
+            .start;
+        let many_blocks = reader.read_data_blocks(&many_inode).unwrap();
+        let (htree_root, htree_leaf) = (many_blocks[0].start, many_blocks[0].start + 1);
+        assert!(many_blocks[0].len() > 1);
+
+        let block_offset = |block: u64| (block * BLOCK_SIZE) as usize;
+        for (offset, expected) in [
+            (1024 + 0x60, ChecksumMismatch::Superblock { block: 0 }),
+            (
+                block_offset(BLOCK_SIZE * 8) + 0x60,
+                ChecksumMismatch::Superblock {
+                    block: BLOCK_SIZE * 8,
+                },
+            ),
+            (
+                block_offset(1) + 0x0e, // bg_free_inodes_count
+                ChecksumMismatch::GroupDescriptor { group: 0 },
+            ),
+            (
+                block_offset(descriptor.block_bitmap()) + 100,
+                ChecksumMismatch::BlockBitmap {
+                    group: 0,
+                    block: descriptor.block_bitmap(),
+                },
+            ),
+            (
+                block_offset(descriptor.inode_bitmap()),
+                ChecksumMismatch::InodeBitmap {
+                    group: 0,
+                    block: descriptor.inode_bitmap(),
+                },
+            ),
+            (
+                block_offset(descriptor.inode_table()) + (key as usize - 1) * 256 + 26,
+                ChecksumMismatch::Inode { inode: key },
+            ),
+            (
+                block_offset(key_inode.file_acl()) + 100,
+                ChecksumMismatch::XattrBlock {
+                    inode: key,
+                    block: key_inode.file_acl(),
+                },
+            ),
+            (
+                block_offset(extent_block) + 100,
+                ChecksumMismatch::ExtentBlock {
+                    inode: fragmented,
+                    block: extent_block,
+                },
+            ),
+            (
+                block_offset(htree_leaf) + 20,
+                ChecksumMismatch::DirectoryBlock {
+                    inode: many,
+                    block: htree_leaf,
+                },
+            ),
+            (
+                block_offset(htree_root) + 40,
+                ChecksumMismatch::HtreeBlock {
+                    inode: many,
+                    block: htree_root,
+                },
+            ),
+        ] {
+            let mut corrupted = image.clone();
+            corrupted[offset] ^= 0x1;
+            let report = verify(Cursor::new(corrupted)).unwrap();
+            assert_eq!(report.mismatches, vec![expected.clone()], "{}", expected);
+        }
+    }
+}